@@ -0,0 +1,76 @@
+//! Runs the same programs on both backends, with their output thrown away.
+//!
+//! cargo bench --bench backends
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use lox::{Backend, Io, Lox, RunOptions};
+use std::io;
+
+// Each program runs as a whole, compiling included, in a fresh session
+// without the prelude. Add a row to measure another feature.
+const PROGRAMS: &[(&str, &str)] = &[
+    (
+        "fib",
+        "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\n\
+         print fib(20);",
+    ),
+    (
+        "arithmetic_loop",
+        "var i = 0; var sum = 0;\n\
+         while (i < 1000000) { sum = sum + i * 2 - i / 2; i = i + 1; }\n\
+         print sum;",
+    ),
+    (
+        "string_concat",
+        "var s = \"\"; var i = 0;\n\
+         while (i < 1000) { s = s + \"ab\"; i = i + 1; }\n\
+         print s;",
+    ),
+    (
+        "closures",
+        "fun add(n, x) { fun f() { return x + n; } return f(); }\n\
+         var i = 0; var sum = 0;\n\
+         while (i < 10000) { sum = add(i, sum); i = i + 1; }\n\
+         print sum;",
+    ),
+    (
+        "inherited_method",
+        "class A { get() { return 1; } }\n\
+         class B < A {} class C < B {} class D < C {}\n\
+         var d = D(); var i = 0; var sum = 0;\n\
+         while (i < 100000) { sum = sum + d.get(); i = i + 1; }\n\
+         print sum;",
+    ),
+];
+
+const BACKENDS: [(&str, Backend); 2] = [("vm", Backend::Vm), ("ast", Backend::Ast)];
+
+fn run(source: &str, backend: Backend) {
+    let options = RunOptions {
+        backend,
+        prelude: false,
+        ..RunOptions::default()
+    };
+    let io = Io {
+        out: Box::new(io::sink()),
+        err: Box::new(io::sink()),
+    };
+    Lox::with_io(options, io).run(source).unwrap();
+}
+
+fn backends(c: &mut Criterion) {
+    for (name, source) in PROGRAMS {
+        let mut group = c.benchmark_group(*name);
+        group.sample_size(10);
+        for (backend_name, backend) in BACKENDS {
+            group.bench_with_input(
+                BenchmarkId::from_parameter(backend_name),
+                source,
+                |b, source| b.iter(|| run(source, backend)),
+            );
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, backends);
+criterion_main!(benches);
@@ -0,0 +1,103 @@
+//! Parses and resolves a large generated script, without running it, and
+//! prints how much memory the syntax tree holds and the most it took at once.
+//!
+//! cargo bench --bench front_end
+use criterion::{criterion_group, criterion_main, Criterion};
+use lox::ast::Ast;
+use lox::parser::parse_ast;
+use lox::resolver::resolve;
+use lox::scanner::scan_source;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::{HashMap, LinkedList};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Tracks the bytes allocated and not yet freed, and the most there have been,
+// to measure what parsing and resolving cost in memory as well as time.
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let live = LIVE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(live, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size > layout.size() {
+            let live = LIVE.fetch_add(new_size - layout.size(), Ordering::Relaxed);
+            PEAK.fetch_max(live + new_size - layout.size(), Ordering::Relaxed);
+        } else {
+            LIVE.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// Classes, functions, loops and closures, repeated until the script is some
+// tens of thousands of lines.
+fn script() -> String {
+    let mut source = String::new();
+    for i in 0..2000 {
+        source.push_str(&format!(
+            "class Point{i} {{\n\
+             \x20   init(x, y) {{ this.x = x; this.y = y; }}\n\
+             \x20   sum() {{ return this.x + this.y * 2 - (this.x / 3); }}\n\
+             }}\n\
+             fun work{i}(n) {{\n\
+             \x20   var total = 0;\n\
+             \x20   var i = 0;\n\
+             \x20   while (i < n and !(total > 1000)) {{\n\
+             \x20       var p = Point{i}(i, n - i);\n\
+             \x20       if (p.sum() >= 10 or i == 3) total = total + p.sum(); else {{ total = total - 1; }}\n\
+             \x20       i = i + 1;\n\
+             \x20   }}\n\
+             \x20   fun inner() {{ return total + n; }}\n\
+             \x20   return inner;\n\
+             }}\n\
+             print work{i}(10)();\n"
+        ));
+    }
+    source
+}
+
+fn parse_and_resolve(source: &str) -> Ast {
+    let mut tokens = scan_source(source, 1).unwrap();
+    let ast = parse_ast(&mut tokens).unwrap();
+    let mut scopes = LinkedList::from([HashMap::new()]);
+    let mut table = HashMap::new();
+    resolve(&ast, &mut scopes, &mut table).unwrap();
+    ast
+}
+
+fn front_end(c: &mut Criterion) {
+    let source = script();
+    let before = LIVE.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let ast = parse_and_resolve(&source);
+    println!(
+        "{} lines: the tree holds {} bytes, {} at peak",
+        source.lines().count(),
+        LIVE.load(Ordering::Relaxed) - before,
+        PEAK.load(Ordering::Relaxed) - before
+    );
+    drop(ast);
+
+    let mut group = c.benchmark_group("front_end");
+    group.sample_size(10);
+    group.bench_function("parse_resolve", |b| b.iter(|| parse_and_resolve(&source)));
+    group.finish();
+}
+
+criterion_group!(benches, front_end);
+criterion_main!(benches);
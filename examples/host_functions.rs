@@ -0,0 +1,39 @@
+//! Registers Rust functions and calls them from a script.
+//!
+//! cargo run --example host_functions
+use lox::{Lox, RunOptions, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+fn main() {
+    let mut lox = Lox::new(RunOptions::default());
+
+    let prices = [("apple", 0.5), ("pear", 0.75)];
+    lox.register("price", 1, move |args| {
+        let name = args[0].as_string().ok_or("Expect a fruit name.")?;
+        prices
+            .iter()
+            .find(|(fruit, _)| **fruit == *name)
+            .map(|(_, price)| Value::Number(*price))
+            .ok_or_else(|| format!("No price for {}.", name))
+    });
+
+    // Host functions can keep state between calls. An atomic keeps this
+    // working with the sync feature, which needs natives to be Send + Sync.
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    lox.register("tick", 0, move |_| {
+        let count = counter.fetch_add(1, Ordering::Relaxed) + 1;
+        Ok(Value::Number(count as f64))
+    });
+
+    let source = r#"
+print price("apple") * 4;
+tick();
+print tick();
+print price("kiwi");
+"#;
+    if lox.run(source).is_err() {
+        println!("tick was called {} times", calls.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,16 @@
+//! Feeds arbitrary source to both front ends, which must accept or reject
+//! it without panicking.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lox::chunk::GlobalNames;
+use lox::compile::compile_to;
+use lox::parse_source;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(source) = std::str::from_utf8(data) {
+        let _ = parse_source(source);
+        let mut globals = GlobalNames::default();
+        let _ = compile_to(source, 1, &mut globals, &mut std::io::sink(), false);
+    }
+});
@@ -0,0 +1,583 @@
+use crate::expr::Expr;
+use crate::stmt::Stmt;
+use crate::token::{Span, Token};
+use crate::value::Value;
+use std::collections::LinkedList;
+use std::ops::Index;
+
+/// An expression in an Ast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExprId(u32);
+
+/// A statement in an Ast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StmtId(u32);
+
+/// A token kept in an Ast, such as a name or an operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TokenId(u32);
+
+/// Expr, with its children held in the same Ast by id.
+#[derive(Debug, Clone)]
+pub enum ExprNode {
+    Binary {
+        left: ExprId,
+        operator: TokenId,
+        right: ExprId,
+    },
+    Call {
+        callee: ExprId,
+        paren: TokenId,
+        arguments: Vec<ExprId>,
+    },
+    Get {
+        object: ExprId,
+        name: TokenId,
+    },
+    Grouping {
+        expression: ExprId,
+        span: Span,
+    },
+    Literal {
+        value: Value,
+        span: Span,
+    },
+    Logical {
+        left: ExprId,
+        operator: TokenId,
+        right: ExprId,
+    },
+    Set {
+        object: ExprId,
+        name: TokenId,
+        value: ExprId,
+    },
+    Super {
+        keyword: TokenId,
+        method: TokenId,
+        id: u64,
+    },
+    This {
+        keyword: TokenId,
+        id: u64,
+    },
+    Unary {
+        operator: TokenId,
+        right: ExprId,
+    },
+    Variable {
+        name: TokenId,
+        id: u64,
+    },
+    Assign {
+        name: TokenId,
+        value: ExprId,
+        id: u64,
+    },
+}
+
+/// Stmt, with its children held in the same Ast by id.
+#[derive(Debug, Clone)]
+pub enum StmtNode {
+    Block {
+        statements: Vec<StmtId>,
+    },
+    Class {
+        name: TokenId,
+        superclass: Option<ExprId>,
+        methods: Vec<StmtId>,
+    },
+    Expression {
+        expression: ExprId,
+    },
+    Function {
+        name: TokenId,
+        params: Vec<TokenId>,
+        body: Vec<StmtId>,
+    },
+    If {
+        condition: ExprId,
+        then_branch: StmtId,
+        else_branch: Option<StmtId>,
+    },
+    Print {
+        expression: ExprId,
+    },
+    Return {
+        value: Option<ExprId>,
+    },
+    Var {
+        name: TokenId,
+        initializer: Option<ExprId>,
+    },
+    While {
+        keyword: TokenId,
+        condition: ExprId,
+        body: StmtId,
+    },
+}
+
+const CHUNK: usize = 1024;
+
+// Items in chunks of a fixed size, so that growing never moves what's already
+// there, and no more than a chunk is left unused. The first chunk grows to the
+// full size as it fills, to keep small programs small.
+#[derive(Debug, Clone)]
+struct Arena<T> {
+    chunks: Vec<Vec<T>>,
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Arena { chunks: Vec::new() }
+    }
+}
+
+impl<T> Arena<T> {
+    fn push(&mut self, item: T) -> u32 {
+        match self.chunks.last_mut() {
+            Some(chunk) if chunk.len() < CHUNK => chunk.push(item),
+            None => self.chunks.push(vec![item]),
+            Some(_) => {
+                let mut chunk = Vec::with_capacity(CHUNK);
+                chunk.push(item);
+                self.chunks.push(chunk);
+            }
+        }
+        ((self.chunks.len() - 1) * CHUNK + self.chunks.last().unwrap().len() - 1) as u32
+    }
+
+    fn get(&self, index: u32) -> &T {
+        let index = index as usize;
+        &self.chunks[index / CHUNK][index % CHUNK]
+    }
+
+    // Takes back the last item pushed, which index must be.
+    fn pop(&mut self, index: u32) -> T {
+        let last = self.chunks.len() - 1;
+        let chunk = &mut self.chunks[last];
+        let item = chunk.pop().unwrap();
+        debug_assert_eq!(index as usize, last * CHUNK + chunk.len());
+        if chunk.is_empty() {
+            self.chunks.pop();
+        }
+        item
+    }
+}
+
+/// A program's syntax tree, with every node and the tokens they keep in
+/// arenas, and nodes referring to their children and tokens by id. A node's
+/// children always come before it in its arena.
+///
+/// The parser builds into an Ast and the resolver walks one. Passes still
+/// written over Box trees take one from to_tree.
+///
+/// ```
+/// use lox::ast::{ExprNode, StmtNode};
+/// use lox::parser::parse_ast;
+/// use lox::scanner::scan_source;
+///
+/// let mut tokens = scan_source("print 1 + 2;", 1).unwrap();
+/// let ast = parse_ast(&mut tokens).unwrap();
+/// let StmtNode::Print { expression } = &ast[ast.statements()[0]] else {
+///     panic!("Expect a print statement.");
+/// };
+/// assert!(matches!(&ast[*expression], ExprNode::Binary { .. }));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Ast {
+    exprs: Arena<ExprNode>,
+    stmts: Arena<StmtNode>,
+    tokens: Arena<Token>,
+    statements: Vec<StmtId>,
+}
+
+impl Index<ExprId> for Ast {
+    type Output = ExprNode;
+
+    fn index(&self, id: ExprId) -> &ExprNode {
+        self.exprs.get(id.0)
+    }
+}
+
+impl Index<StmtId> for Ast {
+    type Output = StmtNode;
+
+    fn index(&self, id: StmtId) -> &StmtNode {
+        self.stmts.get(id.0)
+    }
+}
+
+impl Index<TokenId> for Ast {
+    type Output = Token;
+
+    fn index(&self, id: TokenId) -> &Token {
+        self.tokens.get(id.0)
+    }
+}
+
+impl Ast {
+    pub fn new() -> Ast {
+        Ast::default()
+    }
+
+    /// The program's top-level statements, in order.
+    pub fn statements(&self) -> &[StmtId] {
+        &self.statements
+    }
+
+    pub fn push_expr(&mut self, node: ExprNode) -> ExprId {
+        ExprId(self.exprs.push(node))
+    }
+
+    pub fn push_stmt(&mut self, node: StmtNode) -> StmtId {
+        StmtId(self.stmts.push(node))
+    }
+
+    pub fn push_token(&mut self, token: Token) -> TokenId {
+        TokenId(self.tokens.push(token))
+    }
+
+    /// Adds a statement to the end of the program.
+    pub fn push_statement(&mut self, id: StmtId) {
+        self.statements.push(id);
+    }
+
+    // Takes back the last expression pushed, which id must be, to replace it.
+    pub(crate) fn pop_expr(&mut self, id: ExprId) -> ExprNode {
+        self.exprs.pop(id.0)
+    }
+
+    /// Builds the arena for a Box tree, as parsing its source would.
+    pub fn from_tree(stmts: &LinkedList<Box<Stmt>>) -> Ast {
+        let mut ast = Ast::new();
+        for stmt in stmts {
+            let id = ast.add_stmt(stmt);
+            ast.push_statement(id);
+        }
+        ast
+    }
+
+    fn add_token(&mut self, token: &Token) -> TokenId {
+        self.push_token(token.clone())
+    }
+
+    fn add_stmts(&mut self, stmts: &LinkedList<Box<Stmt>>) -> Vec<StmtId> {
+        stmts.iter().map(|stmt| self.add_stmt(stmt)).collect()
+    }
+
+    fn add_stmt(&mut self, stmt: &Stmt) -> StmtId {
+        let node = match stmt {
+            Stmt::Block { statements } => StmtNode::Block {
+                statements: self.add_stmts(statements),
+            },
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => StmtNode::Class {
+                name: self.add_token(name),
+                superclass: superclass.as_ref().map(|s| self.add_expr(s)),
+                methods: self.add_stmts(methods),
+            },
+            Stmt::Expression { expression } => StmtNode::Expression {
+                expression: self.add_expr(expression),
+            },
+            Stmt::Function { name, params, body } => StmtNode::Function {
+                name: self.add_token(name),
+                params: params.iter().map(|p| self.add_token(p)).collect(),
+                body: self.add_stmts(body),
+            },
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => StmtNode::If {
+                condition: self.add_expr(condition),
+                then_branch: self.add_stmt(then_branch),
+                else_branch: else_branch.as_ref().map(|s| self.add_stmt(s)),
+            },
+            Stmt::Print { expression } => StmtNode::Print {
+                expression: self.add_expr(expression),
+            },
+            Stmt::Return { value } => StmtNode::Return {
+                value: value.as_ref().map(|v| self.add_expr(v)),
+            },
+            Stmt::Var { name, initializer } => StmtNode::Var {
+                name: self.add_token(name),
+                initializer: initializer.as_ref().map(|i| self.add_expr(i)),
+            },
+            Stmt::While {
+                keyword,
+                condition,
+                body,
+            } => StmtNode::While {
+                keyword: self.add_token(keyword),
+                condition: self.add_expr(condition),
+                body: self.add_stmt(body),
+            },
+        };
+        self.push_stmt(node)
+    }
+
+    fn add_expr(&mut self, expr: &Expr) -> ExprId {
+        let node = match expr {
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => ExprNode::Binary {
+                left: self.add_expr(left),
+                operator: self.add_token(operator),
+                right: self.add_expr(right),
+            },
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+            } => ExprNode::Call {
+                callee: self.add_expr(callee),
+                paren: self.add_token(paren),
+                arguments: arguments.iter().map(|a| self.add_expr(a)).collect(),
+            },
+            Expr::Get { object, name } => ExprNode::Get {
+                object: self.add_expr(object),
+                name: self.add_token(name),
+            },
+            Expr::Grouping { expression, span } => ExprNode::Grouping {
+                expression: self.add_expr(expression),
+                span: *span,
+            },
+            Expr::Literal { value, span } => ExprNode::Literal {
+                value: value.clone(),
+                span: *span,
+            },
+            Expr::Logical {
+                left,
+                operator,
+                right,
+            } => ExprNode::Logical {
+                left: self.add_expr(left),
+                operator: self.add_token(operator),
+                right: self.add_expr(right),
+            },
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => ExprNode::Set {
+                object: self.add_expr(object),
+                name: self.add_token(name),
+                value: self.add_expr(value),
+            },
+            Expr::Super {
+                keyword,
+                method,
+                id,
+            } => ExprNode::Super {
+                keyword: self.add_token(keyword),
+                method: self.add_token(method),
+                id: *id,
+            },
+            Expr::This { keyword, id } => ExprNode::This {
+                keyword: self.add_token(keyword),
+                id: *id,
+            },
+            Expr::Unary { operator, right } => ExprNode::Unary {
+                operator: self.add_token(operator),
+                right: self.add_expr(right),
+            },
+            Expr::Variable { name, id } => ExprNode::Variable {
+                name: self.add_token(name),
+                id: *id,
+            },
+            Expr::Assign { name, value, id } => ExprNode::Assign {
+                name: self.add_token(name),
+                value: self.add_expr(value),
+                id: *id,
+            },
+        };
+        self.push_expr(node)
+    }
+
+    /// The program as a Box tree, for passes not yet written over the arena.
+    pub fn to_tree(&self) -> LinkedList<Box<Stmt>> {
+        self.tree_stmts(&self.statements)
+    }
+
+    fn tree_stmts(&self, ids: &[StmtId]) -> LinkedList<Box<Stmt>> {
+        ids.iter().map(|&id| self.tree_stmt(id)).collect()
+    }
+
+    fn tree_stmt(&self, id: StmtId) -> Box<Stmt> {
+        Box::new(match &self[id] {
+            StmtNode::Block { statements } => Stmt::Block {
+                statements: self.tree_stmts(statements),
+            },
+            StmtNode::Class {
+                name,
+                superclass,
+                methods,
+            } => Stmt::Class {
+                name: self[*name].clone(),
+                superclass: superclass.map(|s| self.tree_expr(s)),
+                methods: self.tree_stmts(methods),
+            },
+            StmtNode::Expression { expression } => Stmt::Expression {
+                expression: self.tree_expr(*expression),
+            },
+            StmtNode::Function { name, params, body } => Stmt::Function {
+                name: self[*name].clone(),
+                params: params.iter().map(|&p| self[p].clone()).collect(),
+                body: self.tree_stmts(body),
+            },
+            StmtNode::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => Stmt::If {
+                condition: self.tree_expr(*condition),
+                then_branch: self.tree_stmt(*then_branch),
+                else_branch: else_branch.map(|s| self.tree_stmt(s)),
+            },
+            StmtNode::Print { expression } => Stmt::Print {
+                expression: self.tree_expr(*expression),
+            },
+            StmtNode::Return { value } => Stmt::Return {
+                value: value.map(|v| self.tree_expr(v)),
+            },
+            StmtNode::Var { name, initializer } => Stmt::Var {
+                name: self[*name].clone(),
+                initializer: initializer.map(|i| self.tree_expr(i)),
+            },
+            StmtNode::While {
+                keyword,
+                condition,
+                body,
+            } => Stmt::While {
+                keyword: self[*keyword].clone(),
+                condition: self.tree_expr(*condition),
+                body: self.tree_stmt(*body),
+            },
+        })
+    }
+
+    fn tree_expr(&self, id: ExprId) -> Box<Expr> {
+        Box::new(match &self[id] {
+            ExprNode::Binary {
+                left,
+                operator,
+                right,
+            } => Expr::Binary {
+                left: self.tree_expr(*left),
+                operator: self[*operator].clone(),
+                right: self.tree_expr(*right),
+            },
+            ExprNode::Call {
+                callee,
+                paren,
+                arguments,
+            } => Expr::Call {
+                callee: self.tree_expr(*callee),
+                paren: self[*paren].clone(),
+                arguments: arguments.iter().map(|&a| self.tree_expr(a)).collect(),
+            },
+            ExprNode::Get { object, name } => Expr::Get {
+                object: self.tree_expr(*object),
+                name: self[*name].clone(),
+            },
+            ExprNode::Grouping { expression, span } => Expr::Grouping {
+                expression: self.tree_expr(*expression),
+                span: *span,
+            },
+            ExprNode::Literal { value, span } => Expr::Literal {
+                value: value.clone(),
+                span: *span,
+            },
+            ExprNode::Logical {
+                left,
+                operator,
+                right,
+            } => Expr::Logical {
+                left: self.tree_expr(*left),
+                operator: self[*operator].clone(),
+                right: self.tree_expr(*right),
+            },
+            ExprNode::Set {
+                object,
+                name,
+                value,
+            } => Expr::Set {
+                object: self.tree_expr(*object),
+                name: self[*name].clone(),
+                value: self.tree_expr(*value),
+            },
+            ExprNode::Super {
+                keyword,
+                method,
+                id,
+            } => Expr::Super {
+                keyword: self[*keyword].clone(),
+                method: self[*method].clone(),
+                id: *id,
+            },
+            ExprNode::This { keyword, id } => Expr::This {
+                keyword: self[*keyword].clone(),
+                id: *id,
+            },
+            ExprNode::Unary { operator, right } => Expr::Unary {
+                operator: self[*operator].clone(),
+                right: self.tree_expr(*right),
+            },
+            ExprNode::Variable { name, id } => Expr::Variable {
+                name: self[*name].clone(),
+                id: *id,
+            },
+            ExprNode::Assign { name, value, id } => Expr::Assign {
+                name: self[*name].clone(),
+                value: self.tree_expr(*value),
+                id: *id,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formatter::print_program;
+    use crate::parse_source;
+    use crate::parser::parse_ast;
+    use crate::scanner::scan_source;
+
+    #[test]
+    fn test_tree_round_trip() {
+        let source = "class A < B { init(x) { this.x = -x; } }\n\
+                      fun f(a, b) { if (a and !b) return (a + 1) * 2; else return; }\n\
+                      var c = f(1, nil); while (c) { print c.d = \"s\"; c = false; }";
+        let tree = parse_source(source).unwrap();
+        let ast = Ast::from_tree(&tree);
+        assert_eq!(ast.statements().len(), 4);
+        assert_eq!(ast.to_tree(), tree);
+        // Children come before their parents.
+        let StmtNode::Var { initializer, .. } = &ast[ast.statements()[2]] else {
+            panic!("Expect a var statement.");
+        };
+        let ExprNode::Call { callee, .. } = &ast[initializer.unwrap()] else {
+            panic!("Expect a call.");
+        };
+        assert!(callee.0 < initializer.unwrap().0);
+    }
+
+    #[test]
+    fn test_chunks() {
+        // Enough nodes to fill several chunks, with assignments taking back
+        // the last node pushed on either side of where chunks meet.
+        let source = "a = b.c = 1;\n".repeat(1000);
+        let mut tokens = scan_source(&source, 1).unwrap();
+        let ast = parse_ast(&mut tokens).unwrap();
+        assert!(ast.exprs.chunks.len() > 2);
+        assert_eq!(ast.statements().len(), 1000);
+        assert_eq!(print_program(&ast.to_tree()), source);
+    }
+}
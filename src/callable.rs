@@ -1,26 +1,36 @@
-use crate::error::RuntimeError;
-use crate::interpreter::execute;
+use crate::error::{ErrorKind, Members, RuntimeError};
+use crate::interpreter::{execute, Context};
+use crate::shared::{Shared, SharedCell, WeakShared};
 use crate::stmt::{Environment, Stmt};
-use crate::token::{BasicType, Token};
-use std::cell::RefCell;
+use crate::token::Token;
+use crate::value::{Native, Value};
 use std::collections::{HashMap, LinkedList};
-use std::rc::Rc;
 
 pub trait Callable {
     fn call(
         &self,
-        arguments: &mut LinkedList<BasicType>,
+        arguments: &mut LinkedList<Value>,
         line_number: i32,
-    ) -> Result<BasicType, RuntimeError>;
+        ctx: &mut Context,
+    ) -> Result<Value, RuntimeError>;
     fn arity(&self) -> usize;
 }
 
+// A declared function is stored in the environment it closes over. That
+// copy holds the environment weakly so the two don't keep each other alive;
+// reading it back out of the environment hands out a strong copy.
+#[derive(Clone)]
+enum Closure {
+    Strong(Shared<SharedCell<Environment>>),
+    Weak(WeakShared<SharedCell<Environment>>),
+}
+
 #[derive(Clone)]
 pub struct LoxFunction {
     pub name: Token,
     params: LinkedList<Token>,
     body: LinkedList<Box<Stmt>>,
-    closure: Rc<RefCell<Environment>>,
+    closure: Closure,
     table: HashMap<u64, i32>,
 }
 
@@ -29,48 +39,90 @@ impl LoxFunction {
         name: Token,
         params: LinkedList<Token>,
         body: LinkedList<Box<Stmt>>,
-        env: Rc<RefCell<Environment>>,
+        env: Shared<SharedCell<Environment>>,
         table: HashMap<u64, i32>,
     ) -> LoxFunction {
         LoxFunction {
             name,
             params,
             body,
-            closure: env,
+            closure: Closure::Strong(env),
             table,
         }
     }
 
-    pub fn bind(self, instance: Rc<RefCell<LoxInstance>>) -> LoxFunction {
-        let new_env = self.closure.clone();
+    fn closure(&self) -> Option<Shared<SharedCell<Environment>>> {
+        match &self.closure {
+            Closure::Strong(env) => Some(env.clone()),
+            Closure::Weak(env) => env.upgrade(),
+        }
+    }
+
+    pub fn downgrade(mut self) -> LoxFunction {
+        if let Closure::Strong(env) = &self.closure {
+            self.closure = Closure::Weak(Shared::downgrade(env));
+        }
+        self
+    }
+
+    pub fn upgrade(self: &Shared<Self>) -> Shared<LoxFunction> {
+        match &self.closure {
+            Closure::Weak(env) => match env.upgrade() {
+                Some(env) => Shared::new(LoxFunction {
+                    closure: Closure::Strong(env),
+                    ..(**self).clone()
+                }),
+                None => self.clone(),
+            },
+            Closure::Strong(_) => self.clone(),
+        }
+    }
+
+    // Methods close over a scope holding only "this". Each binding gets its
+    // own copy of that scope so bound methods keep their receivers apart.
+    pub fn bind(self, instance: Shared<SharedCell<LoxInstance>>) -> LoxFunction {
+        let this_env = self.closure().expect("Methods hold their environment");
+        let enclosing = this_env
+            .borrow()
+            .enclosing()
+            .expect("The this scope has an enclosing scope");
+        let new_env = Shared::new(SharedCell::new(Environment::from(enclosing)));
         new_env
             .borrow_mut()
-            .define("this".to_string(), BasicType::Instance(instance.clone()));
+            .define("this".to_string(), Value::LoxInstance(instance));
         Self::new(self.name, self.params, self.body, new_env, self.table)
     }
-}
 
-impl Callable for LoxFunction {
-    fn arity(&self) -> usize {
-        self.params.len()
-    }
-    fn call(
+    // Runs the body once. A call the body returns in tail position comes
+    // back unmade as a TailCall.
+    fn run(
         &self,
-        arguments: &mut LinkedList<BasicType>,
+        arguments: &mut LinkedList<Value>,
         line_number: i32,
-    ) -> Result<BasicType, RuntimeError> {
+        ctx: &mut Context,
+    ) -> Result<Value, RuntimeError> {
         if self.arity() != arguments.len() {
-            return Err(RuntimeError::new(
+            return Err(RuntimeError::of(
                 line_number,
-                "Wrong argument number.".to_string(),
+                ErrorKind::ArityMismatch {
+                    callee: self.name.lexeme.clone(),
+                    expected: self.arity(),
+                    got: arguments.len(),
+                },
             ));
         }
-        let env = Rc::new(RefCell::new(Environment::from(self.closure.clone())));
+        ctx.tick(line_number)?;
+        let closure = self.closure().ok_or(RuntimeError::new(
+            line_number,
+            format!(
+                "Environment of function {} no longer exists.",
+                self.name.lexeme
+            ),
+        ))?;
+        let env = Shared::new(SharedCell::new(Environment::from(closure)));
         for param in self.params.clone() {
             env.borrow_mut().define(
-                (param.lexeme.expect("Well defined variables."))
-                    .as_string()
-                    .unwrap(),
+                param.lexeme.clone(),
                 arguments.pop_front().ok_or(RuntimeError::new(
                     param.line,
                     "Invalid Argument".to_string(),
@@ -78,64 +130,146 @@ impl Callable for LoxFunction {
             );
         }
         for stmt in self.body.clone() {
-            match execute(*stmt, env.clone(), &self.table) {
+            match execute(*stmt, env.clone(), &self.table, ctx) {
                 Ok(()) => {}
                 Err(e) => match e {
                     RuntimeError::ReturnValue(e) => return Ok(e),
-                    RuntimeError::Reason { line, reason } => {
-                        return Err(RuntimeError::new(
+                    RuntimeError::Reason {
+                        line,
+                        kind,
+                        mut functions,
+                    } => {
+                        functions.push(self.name.lexeme.clone());
+                        return Err(RuntimeError::Reason {
                             line,
-                            format!(
-                                "Error in function {}\n{}",
-                                self.name.lexeme.clone().unwrap(),
-                                reason
-                            ),
-                        ))
+                            kind,
+                            functions,
+                        });
                     }
+                    RuntimeError::TailCall { .. } => return Err(e),
                 },
             }
         }
-        Ok(BasicType::Bool(true))
+        Ok(Value::Bool(true))
+    }
+}
+
+impl Callable for LoxFunction {
+    fn arity(&self) -> usize {
+        self.params.len()
+    }
+    fn call(
+        &self,
+        arguments: &mut LinkedList<Value>,
+        line_number: i32,
+        ctx: &mut Context,
+    ) -> Result<Value, RuntimeError> {
+        ctx.enter_call(line_number)?;
+        let mut tail: Shared<LoxFunction>;
+        let mut function = self;
+        let mut arguments = std::mem::take(arguments);
+        let mut line_number = line_number;
+        loop {
+            match function.run(&mut arguments, line_number, ctx) {
+                Err(RuntimeError::TailCall {
+                    callee: Value::LoxFunction(next),
+                    arguments: next_arguments,
+                    line,
+                }) => {
+                    tail = next;
+                    function = &tail;
+                    arguments = next_arguments;
+                    line_number = line;
+                }
+                result => {
+                    ctx.leave_call();
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+// A host function. Both backends share its values, so arguments and
+// results pass through as they are.
+impl Callable for Native {
+    fn arity(&self) -> usize {
+        self.arity as usize
+    }
+    fn call(
+        &self,
+        arguments: &mut LinkedList<Value>,
+        line_number: i32,
+        _: &mut Context,
+    ) -> Result<Value, RuntimeError> {
+        if self.arity() != arguments.len() {
+            return Err(RuntimeError::of(
+                line_number,
+                ErrorKind::ArityMismatch {
+                    callee: self.name.clone(),
+                    expected: self.arity(),
+                    got: arguments.len(),
+                },
+            ));
+        }
+        let args: Vec<Value> = std::mem::take(arguments).into_iter().collect();
+        (self.function)(&args)
+            .map_err(|reason| RuntimeError::new(line_number, format!("{}: {}", self.name, reason)))
     }
 }
 
 #[derive(Clone)]
 pub struct LoxClass {
     pub name: Token,
-    superclass: Option<Rc<LoxClass>>,
+    superclass: Option<Shared<LoxClass>>,
     methods: HashMap<String, LoxFunction>,
 }
 
 impl LoxClass {
+    // Classes don't change once declared, so the superclass's methods are
+    // copied down here, under the class's own, and a lookup never walks
+    // the chain.
     pub fn new(
         name: Token,
-        superclass: Option<Rc<LoxClass>>,
+        superclass: Option<Shared<LoxClass>>,
         methods: HashMap<String, LoxFunction>,
     ) -> LoxClass {
+        let mut all = superclass
+            .as_ref()
+            .map(|klass| klass.methods.clone())
+            .unwrap_or_default();
+        all.extend(methods);
         LoxClass {
             name,
             superclass,
-            methods,
+            methods: all,
         }
     }
 
-    pub fn find_method(&self, method: String) -> Option<LoxFunction> {
-        self.methods.get(&method).cloned()
+    // Finds a method on the class or, failing that, its superclasses.
+    pub fn find_method(&self, name: &str) -> Option<LoxFunction> {
+        self.methods.get(name).cloned()
     }
 
-    pub fn superclass(&self) -> Option<Rc<LoxClass>> {
+    pub fn superclass(&self) -> Option<Shared<LoxClass>> {
         self.superclass.clone()
     }
+
+    // The names of its methods and its superclasses'.
+    pub fn method_names(&self) -> Vec<String> {
+        self.methods.keys().cloned().collect()
+    }
 }
 
 impl Callable for LoxClass {
     fn call(
         &self,
-        _arguments: &mut LinkedList<BasicType>,
+        _arguments: &mut LinkedList<Value>,
         _: i32,
-    ) -> Result<BasicType, RuntimeError> {
-        Ok(BasicType::Instance(Rc::new(RefCell::new(
-            LoxInstance::new(Rc::new(self.clone())),
+        _: &mut Context,
+    ) -> Result<Value, RuntimeError> {
+        Ok(Value::LoxInstance(Shared::new(SharedCell::new(
+            LoxInstance::new(Shared::new(self.clone())),
         ))))
     }
     fn arity(&self) -> usize {
@@ -145,20 +279,33 @@ impl Callable for LoxClass {
 
 #[derive(Clone)]
 pub struct LoxInstance {
-    pub klass: Rc<LoxClass>,
-    pub fields: HashMap<String, BasicType>,
+    pub klass: Shared<LoxClass>,
+    pub fields: HashMap<String, Value>,
 }
 
 impl LoxInstance {
-    pub fn new(klass: Rc<LoxClass>) -> LoxInstance {
+    pub fn new(klass: Shared<LoxClass>) -> LoxInstance {
         LoxInstance {
             klass,
             fields: HashMap::new(),
         }
     }
 
-    pub fn set(&mut self, name: Token, value: BasicType) -> Option<BasicType> {
-        let st = name.lexeme.unwrap().as_string().unwrap();
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.fields.get(name).cloned()
+    }
+
+    pub fn set(&mut self, name: Token, value: Value) -> Option<Value> {
+        let st = name.lexeme;
         self.fields.insert(st, value.clone())
     }
+
+    // Its class and the names of its fields and methods, for errors.
+    pub fn members(&self) -> Members {
+        Members::new(
+            self.klass.name.lexeme.clone(),
+            self.fields.keys().cloned().collect(),
+            self.klass.method_names(),
+        )
+    }
 }
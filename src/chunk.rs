@@ -1,44 +1,459 @@
-use crate::object::LoxType;
-use crate::vm::RuntimeError;
+use crate::object::{Class, Function, Member, PropertyCache};
+use crate::shared::{Shared, SharedCell};
+use crate::value::Value;
 use crate::USIZE;
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::Write;
 
-pub const OP_RETURN: u8 = 0;
-pub const OP_CONSTANT: u8 = 1;
-pub const OP_NEGATE: u8 = 2;
-pub const OP_ADD: u8 = 3;
-pub const OP_SUBTRACT: u8 = 4;
-pub const OP_MULTIPLY: u8 = 5;
-pub const OP_DIVIDE: u8 = 6;
-pub const OP_NIL: u8 = 7;
-pub const OP_TRUE: u8 = 8;
-pub const OP_FALSE: u8 = 9;
-pub const OP_NOT: u8 = 10;
-pub const OP_EQUAL: u8 = 11;
-pub const OP_GREATER: u8 = 12;
-pub const OP_LESS: u8 = 13;
-pub const OP_PRINT: u8 = 14;
-pub const OP_POP: u8 = 15;
-pub const OP_DEFINE_GLOBAL: u8 = 16;
-pub const OP_GET_GLOBAL: u8 = 17;
-pub const OP_SET_GLOBAL: u8 = 18;
-pub const OP_GET_LOCAL: u8 = 19;
-pub const OP_SET_LOCAL: u8 = 20;
-pub const OP_JUMP_IF_FALSE: u8 = 21;
-pub const OP_JUMP: u8 = 22;
-pub const OP_LOOP: u8 = 23;
-pub const OP_CALL: u8 = 24;
-pub const OP_CLASS: u8 = 25;
-pub const OP_GET_PROPERTY: u8 = 26;
-pub const OP_SET_PROPERTY: u8 = 27;
-pub const OP_CLOSURE: u8 = 28;
-pub const OP_GET_UPVALUE: u8 = 29;
-pub const OP_SET_UPVALUE: u8 = 30;
-pub const OP_CLOSE_UPVALUE: u8 = 31;
-pub const OP_METHOD: u8 = 32;
-pub const OP_INHERIT: u8 = 33;
-pub const OP_GET_SUPER: u8 = 34;
-
-pub type Value = LoxType;
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum OpCode {
+    Return = 0,
+    Constant,
+    Negate,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Nil,
+    True,
+    False,
+    Not,
+    Equal,
+    Greater,
+    Less,
+    GreaterEqual,
+    LessEqual,
+    NotEqual,
+    Dup,
+    Print,
+    Pop,
+    DefineGlobal,
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    JumpIfFalse,
+    JumpIfTrue,
+    Jump,
+    Loop,
+    Call,
+    Class,
+    GetProperty,
+    SetProperty,
+    Closure,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
+    Method,
+    Inherit,
+    GetSuper,
+    PopN,
+    Array,
+    Map,
+    IndexGet,
+    IndexSet,
+    PushHandler,
+    PopHandler,
+    Throw,
+}
+
+// What follows an opcode in the chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Simple,
+    Byte,
+    Constant,
+    Short,
+    // A constant naming the property, then a u16 inline cache slot.
+    Property,
+    Jump,
+    Loop,
+    // A constant followed by an (is_local, index) pair per upvalue.
+    Closure,
+}
+
+// A decoded instruction. Constants are resolved to their values and jumps
+// to the absolute offset they land on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Return,
+    Constant(Value),
+    Negate,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Nil,
+    True,
+    False,
+    Not,
+    Equal,
+    Greater,
+    Less,
+    GreaterEqual,
+    LessEqual,
+    NotEqual,
+    Dup,
+    Print,
+    Pop,
+    DefineGlobal(u16),
+    GetGlobal(u16),
+    SetGlobal(u16),
+    GetLocal(u8),
+    SetLocal(u8),
+    JumpIfFalse(usize),
+    JumpIfTrue(usize),
+    Jump(usize),
+    Loop(usize),
+    Call(u8),
+    Class(Value),
+    // The name's constant index and the inline cache slot.
+    GetProperty(u8, u16),
+    SetProperty(u8, u16),
+    // The function and an (is_local, index) pair per captured upvalue.
+    Closure(Value, Vec<(bool, u8)>),
+    GetUpvalue(u8),
+    SetUpvalue(u8),
+    CloseUpvalue,
+    Method(Value),
+    Inherit,
+    GetSuper(Value),
+    PopN(u8),
+    Array(u8),
+    Map(u8),
+    IndexGet,
+    IndexSet,
+    PushHandler(usize),
+    PopHandler,
+    Throw,
+}
+
+impl Instruction {
+    pub fn op(&self) -> OpCode {
+        match self {
+            Instruction::Return => OpCode::Return,
+            Instruction::Constant(_) => OpCode::Constant,
+            Instruction::Negate => OpCode::Negate,
+            Instruction::Add => OpCode::Add,
+            Instruction::Subtract => OpCode::Subtract,
+            Instruction::Multiply => OpCode::Multiply,
+            Instruction::Divide => OpCode::Divide,
+            Instruction::Nil => OpCode::Nil,
+            Instruction::True => OpCode::True,
+            Instruction::False => OpCode::False,
+            Instruction::Not => OpCode::Not,
+            Instruction::Equal => OpCode::Equal,
+            Instruction::Greater => OpCode::Greater,
+            Instruction::Less => OpCode::Less,
+            Instruction::GreaterEqual => OpCode::GreaterEqual,
+            Instruction::LessEqual => OpCode::LessEqual,
+            Instruction::NotEqual => OpCode::NotEqual,
+            Instruction::Dup => OpCode::Dup,
+            Instruction::Print => OpCode::Print,
+            Instruction::Pop => OpCode::Pop,
+            Instruction::DefineGlobal(_) => OpCode::DefineGlobal,
+            Instruction::GetGlobal(_) => OpCode::GetGlobal,
+            Instruction::SetGlobal(_) => OpCode::SetGlobal,
+            Instruction::GetLocal(_) => OpCode::GetLocal,
+            Instruction::SetLocal(_) => OpCode::SetLocal,
+            Instruction::JumpIfFalse(_) => OpCode::JumpIfFalse,
+            Instruction::JumpIfTrue(_) => OpCode::JumpIfTrue,
+            Instruction::Jump(_) => OpCode::Jump,
+            Instruction::Loop(_) => OpCode::Loop,
+            Instruction::Call(_) => OpCode::Call,
+            Instruction::Class(_) => OpCode::Class,
+            Instruction::GetProperty(..) => OpCode::GetProperty,
+            Instruction::SetProperty(..) => OpCode::SetProperty,
+            Instruction::Closure(..) => OpCode::Closure,
+            Instruction::GetUpvalue(_) => OpCode::GetUpvalue,
+            Instruction::SetUpvalue(_) => OpCode::SetUpvalue,
+            Instruction::CloseUpvalue => OpCode::CloseUpvalue,
+            Instruction::Method(_) => OpCode::Method,
+            Instruction::Inherit => OpCode::Inherit,
+            Instruction::GetSuper(_) => OpCode::GetSuper,
+            Instruction::PopN(_) => OpCode::PopN,
+            Instruction::Array(_) => OpCode::Array,
+            Instruction::Map(_) => OpCode::Map,
+            Instruction::IndexGet => OpCode::IndexGet,
+            Instruction::IndexSet => OpCode::IndexSet,
+            Instruction::PushHandler(_) => OpCode::PushHandler,
+            Instruction::PopHandler => OpCode::PopHandler,
+            Instruction::Throw => OpCode::Throw,
+        }
+    }
+
+    // Values the instruction leaves on the stack less those it takes off,
+    // on the path that continues to the next instruction.
+    pub fn stack_effect(&self) -> isize {
+        match self {
+            Instruction::Constant(_)
+            | Instruction::Nil
+            | Instruction::True
+            | Instruction::False
+            | Instruction::GetGlobal(_)
+            | Instruction::GetLocal(_)
+            | Instruction::Closure(..)
+            | Instruction::GetUpvalue(_)
+            | Instruction::Class(_)
+            | Instruction::Dup => 1,
+            Instruction::Add
+            | Instruction::Subtract
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Equal
+            | Instruction::Greater
+            | Instruction::Less
+            | Instruction::GreaterEqual
+            | Instruction::LessEqual
+            | Instruction::NotEqual
+            | Instruction::Print
+            | Instruction::Pop
+            | Instruction::DefineGlobal(_)
+            | Instruction::SetProperty(..)
+            | Instruction::CloseUpvalue
+            | Instruction::Method(_)
+            | Instruction::Inherit
+            | Instruction::GetSuper(_)
+            | Instruction::IndexGet
+            | Instruction::Return
+            | Instruction::Throw => -1,
+            Instruction::IndexSet => -2,
+            Instruction::Call(cnt) | Instruction::PopN(cnt) => -(*cnt as isize),
+            Instruction::Array(cnt) => 1 - *cnt as isize,
+            Instruction::Map(cnt) => 1 - 2 * *cnt as isize,
+            Instruction::Negate
+            | Instruction::Not
+            | Instruction::SetGlobal(_)
+            | Instruction::SetLocal(_)
+            | Instruction::JumpIfFalse(_)
+            | Instruction::JumpIfTrue(_)
+            | Instruction::Jump(_)
+            | Instruction::Loop(_)
+            | Instruction::GetProperty(..)
+            | Instruction::SetUpvalue(_)
+            | Instruction::PushHandler(_)
+            | Instruction::PopHandler => 0,
+        }
+    }
+
+    // Values the instruction reads off the top of the stack, whether or not
+    // it pops them.
+    pub fn stack_input(&self) -> usize {
+        match self {
+            Instruction::Constant(_)
+            | Instruction::Nil
+            | Instruction::True
+            | Instruction::False
+            | Instruction::GetGlobal(_)
+            | Instruction::GetLocal(_)
+            | Instruction::Closure(..)
+            | Instruction::GetUpvalue(_)
+            | Instruction::Class(_)
+            | Instruction::Jump(_)
+            | Instruction::Loop(_)
+            | Instruction::PushHandler(_)
+            | Instruction::PopHandler => 0,
+            Instruction::Dup
+            | Instruction::Negate
+            | Instruction::Not
+            | Instruction::Print
+            | Instruction::Pop
+            | Instruction::DefineGlobal(_)
+            | Instruction::SetGlobal(_)
+            | Instruction::SetLocal(_)
+            | Instruction::SetUpvalue(_)
+            | Instruction::JumpIfFalse(_)
+            | Instruction::JumpIfTrue(_)
+            | Instruction::GetProperty(..)
+            | Instruction::CloseUpvalue
+            | Instruction::Return
+            | Instruction::Throw => 1,
+            Instruction::Add
+            | Instruction::Subtract
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Equal
+            | Instruction::Greater
+            | Instruction::Less
+            | Instruction::GreaterEqual
+            | Instruction::LessEqual
+            | Instruction::NotEqual
+            | Instruction::SetProperty(..)
+            | Instruction::Method(_)
+            | Instruction::Inherit
+            | Instruction::GetSuper(_)
+            | Instruction::IndexGet => 2,
+            Instruction::IndexSet => 3,
+            Instruction::Call(cnt) => *cnt as usize + 1,
+            Instruction::PopN(cnt) | Instruction::Array(cnt) => *cnt as usize,
+            Instruction::Map(cnt) => 2 * *cnt as usize,
+        }
+    }
+}
+
+impl OpCode {
+    pub fn name(self) -> &'static str {
+        match self {
+            OpCode::Return => "OP_RETURN",
+            OpCode::Constant => "OP_CONSTANT",
+            OpCode::Negate => "OP_NEGATE",
+            OpCode::Add => "OP_ADD",
+            OpCode::Subtract => "OP_SUBTRACT",
+            OpCode::Multiply => "OP_MULTIPLY",
+            OpCode::Divide => "OP_DIVIDE",
+            OpCode::Nil => "OP_NIL",
+            OpCode::True => "OP_TRUE",
+            OpCode::False => "OP_FALSE",
+            OpCode::Not => "OP_NOT",
+            OpCode::Equal => "OP_EQUAL",
+            OpCode::Greater => "OP_GREATER",
+            OpCode::Less => "OP_LESS",
+            OpCode::GreaterEqual => "OP_GREATER_EQUAL",
+            OpCode::LessEqual => "OP_LESS_EQUAL",
+            OpCode::NotEqual => "OP_NOT_EQUAL",
+            OpCode::Dup => "OP_DUP",
+            OpCode::Print => "OP_PRINT",
+            OpCode::Pop => "OP_POP",
+            OpCode::DefineGlobal => "OP_DEFINE_GLOBAL",
+            OpCode::GetGlobal => "OP_GET_GLOBAL",
+            OpCode::SetGlobal => "OP_SET_GLOBAL",
+            OpCode::GetLocal => "OP_GET_LOCAL",
+            OpCode::SetLocal => "OP_SET_LOCAL",
+            OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
+            OpCode::JumpIfTrue => "OP_JUMP_IF_TRUE",
+            OpCode::Jump => "OP_JUMP",
+            OpCode::Loop => "OP_LOOP",
+            OpCode::Call => "OP_CALL",
+            OpCode::Class => "OP_CLASS",
+            OpCode::GetProperty => "OP_GET_PROPERTY",
+            OpCode::SetProperty => "OP_SET_PROPERTY",
+            OpCode::Closure => "OP_CLOSURE",
+            OpCode::GetUpvalue => "OP_GET_UPVALUE",
+            OpCode::SetUpvalue => "OP_SET_UPVALUE",
+            OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE",
+            OpCode::Method => "OP_METHOD",
+            OpCode::Inherit => "OP_INHERIT",
+            OpCode::GetSuper => "OP_GET_SUPER",
+            OpCode::PopN => "OP_POPN",
+            OpCode::Array => "OP_ARRAY",
+            OpCode::Map => "OP_MAP",
+            OpCode::IndexGet => "OP_INDEX_GET",
+            OpCode::IndexSet => "OP_INDEX_SET",
+            OpCode::PushHandler => "OP_PUSH_HANDLER",
+            OpCode::PopHandler => "OP_POP_HANDLER",
+            OpCode::Throw => "OP_THROW",
+        }
+    }
+
+    pub fn operand(self) -> Operand {
+        match self {
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::Call
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::PopN
+            | OpCode::Array
+            | OpCode::Map => Operand::Byte,
+            OpCode::Constant | OpCode::Class | OpCode::Method | OpCode::GetSuper => {
+                Operand::Constant
+            }
+            OpCode::DefineGlobal | OpCode::GetGlobal | OpCode::SetGlobal => Operand::Short,
+            OpCode::GetProperty | OpCode::SetProperty => Operand::Property,
+            OpCode::JumpIfFalse | OpCode::JumpIfTrue | OpCode::Jump | OpCode::PushHandler => {
+                Operand::Jump
+            }
+            OpCode::Loop => Operand::Loop,
+            OpCode::Closure => Operand::Closure,
+            _ => Operand::Simple,
+        }
+    }
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        FromPrimitive::from_u8(byte).ok_or(byte)
+    }
+}
+
+// Global names interned to the slot the VM stores them under. The table
+// outlives a single chunk so REPL lines agree on every global's slot.
+#[derive(Default)]
+pub struct GlobalNames {
+    names: Vec<String>,
+    slots: HashMap<String, usize>,
+}
+
+impl GlobalNames {
+    pub fn slot(&mut self, name: &str) -> usize {
+        if let Some(slot) = self.slots.get(name) {
+            return *slot;
+        }
+        self.names.push(name.to_string());
+        self.slots.insert(name.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    pub fn name(&self, slot: usize) -> &str {
+        &self.names[slot]
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+}
+
+// A read outside a chunk's code or constants, or code the compiler never
+// makes, from a truncated or corrupt chunk.
+#[derive(Debug, PartialEq)]
+pub enum ChunkError {
+    Code { offset: usize, len: usize },
+    Constant { index: usize, len: usize },
+    Opcode { offset: usize, byte: u8 },
+    Jump { offset: usize },
+    Stack { offset: usize },
+    Operand { offset: usize },
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkError::Code { offset, len } => write!(
+                f,
+                "Offset {} is past the end of the chunk ({} bytes)",
+                offset, len
+            ),
+            ChunkError::Constant { index, len } => {
+                write!(f, "Constant {} is out of range ({} constants)", index, len)
+            }
+            ChunkError::Opcode { offset, byte } => {
+                write!(f, "Unknown opcode {} at offset {}", byte, offset)
+            }
+            ChunkError::Jump { offset } => {
+                write!(f, "Jump at offset {} leaves the chunk", offset)
+            }
+            ChunkError::Stack { offset } => write!(
+                f,
+                "Instruction at offset {} reads past the bottom of its frame or disagrees on its depth",
+                offset
+            ),
+            ChunkError::Operand { offset } => {
+                write!(f, "Operand at offset {} is out of range", offset)
+            }
+        }
+    }
+}
 
 #[derive(Clone)]
 struct ValueArray {
@@ -55,17 +470,35 @@ impl ValueArray {
         self.values.len() - 1
     }
 
-    pub fn get_value(&self, pos: usize) -> Value {
-        self.values[pos].clone()
+    pub fn get_value(&self, pos: usize) -> Option<Value> {
+        self.values.get(pos).cloned()
         // Hopefully remove clone in the future
     }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+}
+
+// The first offset of a run of bytes from the same source line.
+#[derive(Clone, Copy)]
+struct LineStart {
+    offset: usize,
+    line: i32,
 }
 
 #[derive(Clone)]
 pub struct Chunk {
     code: Vec<u8>,
     constants: ValueArray,
-    lines: Vec<i32>,
+    lines: Vec<LineStart>,
+    caches: SharedCell<Vec<Option<PropertyCache>>>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Chunk::new()
+    }
 }
 
 impl Chunk {
@@ -74,163 +507,419 @@ impl Chunk {
             code: Vec::new(),
             constants: ValueArray::new(),
             lines: Vec::new(),
+            caches: SharedCell::new(Vec::new()),
         }
     }
 
-    pub fn read_chunk(&self, pos: usize) -> Result<u8, RuntimeError> {
-        if pos >= self.code.len() {
-            Err(RuntimeError {
-                line: -1,
-                reason: "Index out of Chunk".to_string(),
-            })
-        } else {
-            Ok(self.code[pos])
-        }
+    pub fn read_chunk(&self, pos: usize) -> Result<u8, ChunkError> {
+        self.code.get(pos).copied().ok_or(ChunkError::Code {
+            offset: pos,
+            len: self.code.len(),
+        })
     }
 
-    pub fn read_jump(&self, pos: usize) -> Result<usize, RuntimeError> {
+    pub fn read_jump(&self, pos: usize) -> Result<usize, ChunkError> {
         let mut bytes: [u8; USIZE] = [0; USIZE];
         for (i, byte) in bytes.iter_mut().enumerate() {
             *byte = self.read_chunk(pos + i)?;
         }
-        Ok(usize::from_ne_bytes(bytes))
+        Ok(usize::from_le_bytes(bytes))
+    }
+
+    pub fn read_short(&self, pos: usize) -> Result<u16, ChunkError> {
+        Ok(u16::from_le_bytes([
+            self.read_chunk(pos)?,
+            self.read_chunk(pos + 1)?,
+        ]))
     }
 
     pub fn modify_chunk(&mut self, pos: usize, byte: u8) {
         self.code[pos] = byte;
     }
 
-    pub fn read_line(&self, pos: usize) -> Result<i32, RuntimeError> {
+    pub fn read_line(&self, pos: usize) -> Result<i32, ChunkError> {
         if pos >= self.code.len() {
-            Err(RuntimeError {
-                line: -1,
-                reason: "Index out of Chunk".to_string(),
-            })
-        } else {
-            Ok(self.lines[pos])
+            return Err(ChunkError::Code {
+                offset: pos,
+                len: self.code.len(),
+            });
         }
+        // The first run starts at offset 0, so some run holds pos.
+        let run = self.lines.partition_point(|start| start.offset <= pos) - 1;
+        Ok(self.lines[run].line)
     }
 
     pub fn write_chunk(&mut self, byte: u8, line: i32) {
+        if self.lines.last().is_none_or(|start| start.line != line) {
+            self.lines.push(LineStart {
+                offset: self.code.len(),
+                line,
+            });
+        }
         self.code.push(byte);
-        self.lines.push(line);
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        let runs = self.lines.partition_point(|start| start.offset < len);
+        self.lines.truncate(runs);
     }
 
     pub fn len(&self) -> usize {
         self.code.len()
     }
 
-    pub fn disassemble_chunk(&self) {
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    // Each source line with the number of consecutive bytes it covers.
+    pub fn line_runs(&self) -> impl Iterator<Item = (usize, i32)> + '_ {
+        self.lines.iter().enumerate().map(|(i, start)| {
+            let end = self
+                .lines
+                .get(i + 1)
+                .map_or(self.code.len(), |next| next.offset);
+            (end - start.offset, start.line)
+        })
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants.values
+    }
+
+    pub fn disassemble_chunk(&self) -> Result<(), ChunkError> {
+        self.disassemble_chunk_to(&mut io::stderr())
+    }
+
+    pub fn disassemble_instruction(&self, offset: usize) -> Result<usize, ChunkError> {
+        self.disassemble_instruction_to(offset, &mut io::stderr())
+    }
+
+    /// The listing disassemble_chunk writes, as a string.
+    pub fn disassemble_to_string(&self) -> Result<String, ChunkError> {
+        let mut out = Vec::new();
+        self.disassemble_chunk_to(&mut out)?;
+        Ok(String::from_utf8_lossy(&out).into_owned())
+    }
+
+    // Failing to write a listing is not an error in the chunk, so write
+    // errors are ignored like those of eprintln!.
+    pub fn disassemble_chunk_to(&self, out: &mut dyn Write) -> Result<(), ChunkError> {
         let mut offset: usize = 0;
         while offset < self.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.disassemble_instruction_to(offset, out)?;
         }
-        eprintln!();
+        let _ = writeln!(out);
+        Ok(())
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        let instruction: u8 = self.code[offset];
-        match instruction {
-            OP_RETURN => self.simple_instruction("OP_RETURN".to_string(), offset),
-            OP_CONSTANT => self.constant_instruction("OP_CONSTANT".to_string(), offset),
-            OP_NEGATE => self.simple_instruction("OP_NEGATE".to_string(), offset),
-            OP_ADD => self.simple_instruction("OP_ADD".to_string(), offset),
-            OP_SUBTRACT => self.simple_instruction("OP_SUBTRACT".to_string(), offset),
-            OP_MULTIPLY => self.simple_instruction("OP_MULTIPLY".to_string(), offset),
-            OP_DIVIDE => self.simple_instruction("OP_DIVIDE".to_string(), offset),
-            OP_NIL => self.simple_instruction("OP_NIL".to_string(), offset),
-            OP_TRUE => self.simple_instruction("OP_TRUE".to_string(), offset),
-            OP_FALSE => self.simple_instruction("OP_FALSE".to_string(), offset),
-            OP_NOT => self.simple_instruction("OP_NOT".to_string(), offset),
-            OP_EQUAL => self.simple_instruction("OP_EQUAL".to_string(), offset),
-            OP_GREATER => self.simple_instruction("OP_GREATER".to_string(), offset),
-            OP_LESS => self.simple_instruction("OP_LESS".to_string(), offset),
-            OP_PRINT => self.simple_instruction("OP_PRINT".to_string(), offset),
-            OP_POP => self.simple_instruction("OP_POP".to_string(), offset),
-            OP_DEFINE_GLOBAL => self.constant_instruction("OP_DEFINE_GLOBAL".to_string(), offset),
-            OP_GET_GLOBAL => self.constant_instruction("OP_GET_GLOBAL".to_string(), offset),
-            OP_SET_GLOBAL => self.constant_instruction("OP_SET_GLOBAL".to_string(), offset),
-            OP_GET_LOCAL => self.byte_instruction("OP_GET_LOCAL".to_string(), offset),
-            OP_SET_LOCAL => self.byte_instruction("OP_SET_LOCAL".to_string(), offset),
-            OP_JUMP_IF_FALSE => self.jump_instruction("OP_JUMP_IF_FALSE".to_string(), offset),
-            OP_JUMP => self.jump_instruction("OP_JUMP".to_string(), offset),
-            OP_LOOP => self.loop_instruction("OP_LOOP".to_string(), offset),
-            OP_CALL => self.byte_instruction("OP_CALL".to_string(), offset),
-            OP_CLASS => self.constant_instruction("OP_CLASS".to_string(), offset),
-            OP_GET_PROPERTY => self.byte_instruction("OP_GET_PROPERTY".to_string(), offset),
-            OP_SET_PROPERTY => self.byte_instruction("OP_SET_PROPERTY".to_string(), offset),
-            OP_CLOSURE => {
-                let pos = self.code[offset + 1];
-                let val = self.constants.get_value(pos as usize);
-                eprintln!("[{}] OP_CLOSURE {}", offset, val);
-                let func = val.as_function().expect("Value is not a function");
-                let upvalue = func.upvalue as usize;
-                for i in 0..upvalue {
-                    let is_local = self.code[offset + 2 + 2 * i];
-                    let index = self.code[offset + 2 + 2 * i];
-                    eprintln!(
-                        "[{}] {}: {}",
+    pub fn disassemble_instruction_to(
+        &self,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> Result<usize, ChunkError> {
+        let (instruction, next) = self.decode_instruction(offset)?;
+        let prefix = self.line_prefix(offset)?;
+        let name = instruction.op().name();
+        let text = match &instruction {
+            Instruction::Constant(val)
+            | Instruction::Class(val)
+            | Instruction::Method(val)
+            | Instruction::GetSuper(val) => format!("{} {} {}", prefix, name, val),
+            Instruction::GetLocal(byte)
+            | Instruction::SetLocal(byte)
+            | Instruction::Call(byte)
+            | Instruction::GetProperty(byte, _)
+            | Instruction::SetProperty(byte, _)
+            | Instruction::GetUpvalue(byte)
+            | Instruction::SetUpvalue(byte)
+            | Instruction::PopN(byte)
+            | Instruction::Array(byte)
+            | Instruction::Map(byte) => format!("{} {} {}", prefix, name, byte),
+            Instruction::DefineGlobal(slot)
+            | Instruction::GetGlobal(slot)
+            | Instruction::SetGlobal(slot) => format!("{} {} {}", prefix, name, slot),
+            Instruction::JumpIfFalse(target)
+            | Instruction::JumpIfTrue(target)
+            | Instruction::Jump(target)
+            | Instruction::Loop(target)
+            | Instruction::PushHandler(target) => format!("{} {} -> {}", prefix, name, target),
+            Instruction::Closure(val, upvalues) => {
+                let mut text = format!("{} {} {}", prefix, name, val);
+                for (i, (is_local, index)) in upvalues.iter().enumerate() {
+                    text += &format!(
+                        "\n[{}]    | {}: {}",
                         offset + 2 + 2 * i,
-                        if is_local == 1 { "Local" } else { "Upvalue" },
+                        if *is_local { "Local" } else { "Upvalue" },
                         index
                     );
                 }
-                offset + 2 + 2 * upvalue
+                text
             }
-            OP_GET_UPVALUE => self.byte_instruction("OP_GET_UPVALUE".to_string(), offset),
-            OP_SET_UPVALUE => self.byte_instruction("OP_SET_UPVALUE".to_string(), offset),
-            OP_CLOSE_UPVALUE => self.simple_instruction("OP_CLOSE_UPVALUE".to_string(), offset),
-            OP_METHOD => self.constant_instruction("OP_SET_GLOBAL".to_string(), offset),
-            OP_INHERIT => self.simple_instruction("OP_INHERIT".to_string(), offset),
-            OP_GET_SUPER => self.constant_instruction("GET_SUPER".to_string(), offset),
-            _ => {
-                panic!("Line {}: Unknown code {}", self.lines[offset], instruction);
+            _ => format!("{} {}", prefix, name),
+        };
+        let _ = writeln!(out, "{}", text);
+        Ok(next)
+    }
+
+    // Decodes the instruction at offset, returning it with the offset of
+    // the next one.
+    pub fn decode_instruction(&self, offset: usize) -> Result<(Instruction, usize), ChunkError> {
+        let byte = self.read_chunk(offset)?;
+        let op = OpCode::try_from(byte).map_err(|byte| ChunkError::Opcode { offset, byte })?;
+        let byte = || self.read_chunk(offset + 1);
+        let short = || self.read_short(offset + 1);
+        let constant = || self.read_constant(self.read_chunk(offset + 1)? as usize);
+        // Jump operands are relative to the end of the instruction.
+        let end = offset + 1 + USIZE;
+        let forward = || {
+            end.checked_add(self.read_jump(offset + 1)?)
+                .ok_or(ChunkError::Jump { offset })
+        };
+        let instruction = match op {
+            OpCode::Return => Instruction::Return,
+            OpCode::Constant => Instruction::Constant(constant()?),
+            OpCode::Negate => Instruction::Negate,
+            OpCode::Add => Instruction::Add,
+            OpCode::Subtract => Instruction::Subtract,
+            OpCode::Multiply => Instruction::Multiply,
+            OpCode::Divide => Instruction::Divide,
+            OpCode::Nil => Instruction::Nil,
+            OpCode::True => Instruction::True,
+            OpCode::False => Instruction::False,
+            OpCode::Not => Instruction::Not,
+            OpCode::Equal => Instruction::Equal,
+            OpCode::Greater => Instruction::Greater,
+            OpCode::Less => Instruction::Less,
+            OpCode::GreaterEqual => Instruction::GreaterEqual,
+            OpCode::LessEqual => Instruction::LessEqual,
+            OpCode::NotEqual => Instruction::NotEqual,
+            OpCode::Dup => Instruction::Dup,
+            OpCode::Print => Instruction::Print,
+            OpCode::Pop => Instruction::Pop,
+            OpCode::DefineGlobal => Instruction::DefineGlobal(short()?),
+            OpCode::GetGlobal => Instruction::GetGlobal(short()?),
+            OpCode::SetGlobal => Instruction::SetGlobal(short()?),
+            OpCode::GetLocal => Instruction::GetLocal(byte()?),
+            OpCode::SetLocal => Instruction::SetLocal(byte()?),
+            OpCode::JumpIfFalse => Instruction::JumpIfFalse(forward()?),
+            OpCode::JumpIfTrue => Instruction::JumpIfTrue(forward()?),
+            OpCode::Jump => Instruction::Jump(forward()?),
+            OpCode::Loop => Instruction::Loop(
+                end.checked_sub(self.read_jump(offset + 1)?)
+                    .ok_or(ChunkError::Jump { offset })?,
+            ),
+            OpCode::Call => Instruction::Call(byte()?),
+            OpCode::Class => Instruction::Class(constant()?),
+            OpCode::GetProperty => Instruction::GetProperty(byte()?, self.read_short(offset + 2)?),
+            OpCode::SetProperty => Instruction::SetProperty(byte()?, self.read_short(offset + 2)?),
+            OpCode::Closure => {
+                let val = constant()?;
+                let count = val.as_function().map_or(0, |func| func.upvalue as usize);
+                let mut upvalues = Vec::with_capacity(count);
+                for i in 0..count {
+                    let is_local = self.read_chunk(offset + 2 + 2 * i)? == 1;
+                    upvalues.push((is_local, self.read_chunk(offset + 3 + 2 * i)?));
+                }
+                Instruction::Closure(val, upvalues)
             }
+            OpCode::GetUpvalue => Instruction::GetUpvalue(byte()?),
+            OpCode::SetUpvalue => Instruction::SetUpvalue(byte()?),
+            OpCode::CloseUpvalue => Instruction::CloseUpvalue,
+            OpCode::Method => Instruction::Method(constant()?),
+            OpCode::Inherit => Instruction::Inherit,
+            OpCode::GetSuper => Instruction::GetSuper(constant()?),
+            OpCode::PopN => Instruction::PopN(byte()?),
+            OpCode::Array => Instruction::Array(byte()?),
+            OpCode::Map => Instruction::Map(byte()?),
+            OpCode::IndexGet => Instruction::IndexGet,
+            OpCode::IndexSet => Instruction::IndexSet,
+            OpCode::PushHandler => Instruction::PushHandler(forward()?),
+            OpCode::PopHandler => Instruction::PopHandler,
+            OpCode::Throw => Instruction::Throw,
+        };
+        let next = match (&instruction, op.operand()) {
+            (Instruction::Closure(_, upvalues), _) => offset + 2 + 2 * upvalues.len(),
+            (_, Operand::Simple) => offset + 1,
+            (_, Operand::Byte | Operand::Constant) => offset + 2,
+            (_, Operand::Short) => offset + 3,
+            (_, Operand::Property) => offset + 4,
+            _ => end,
+        };
+        Ok((instruction, next))
+    }
+
+    #[cfg(test)]
+    pub fn instructions(&self) -> Result<Vec<Instruction>, ChunkError> {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < self.len() {
+            let (instruction, next) = self.decode_instruction(offset)?;
+            instructions.push(instruction);
+            offset = next;
         }
+        Ok(instructions)
     }
 
-    pub fn read_constant(&self, offset: usize) -> Result<Value, RuntimeError> {
-        if offset >= self.code.len() {
-            Err(RuntimeError {
-                line: -1,
-                reason: "Index out of Constant".to_string(),
-            })
-        } else {
-            Ok(self.constants.get_value(offset))
+    // The most stack slots a frame running this chunk holds at once, given
+    // the `start` slots its callee and arguments take. The compiler keeps
+    // the depth at each instruction the same on every path to it, so each
+    // instruction is visited once.
+    pub fn max_depth(&self, start: usize) -> Result<usize, ChunkError> {
+        self.verify(start, usize::MAX, usize::MAX)
+    }
+
+    // Like max_depth, but also checks what the VM trusts the compiler for,
+    // for code loaded from a file: that every instruction it can reach is
+    // in the chunk, has the same depth on every path to it and finds the
+    // values it reads in its frame, and refers only to locals in the frame,
+    // to the closure's first `upvalues` upvalues, to the first `globals`
+    // global slots and to the chunk's constants and caches.
+    pub fn verify(
+        &self,
+        start: usize,
+        upvalues: usize,
+        globals: usize,
+    ) -> Result<usize, ChunkError> {
+        let mut depths: Vec<Option<usize>> = vec![None; self.len()];
+        let mut work = vec![(0, start)];
+        let mut max = start;
+        while let Some((offset, depth)) = work.pop() {
+            match depths.get(offset) {
+                None => return Err(ChunkError::Jump { offset }),
+                Some(Some(seen)) if *seen == depth => continue,
+                Some(Some(_)) => return Err(ChunkError::Stack { offset }),
+                Some(None) => depths[offset] = Some(depth),
+            }
+            let (instruction, next) = self.decode_instruction(offset)?;
+            if depth < start + instruction.stack_input() {
+                return Err(ChunkError::Stack { offset });
+            }
+            if !self.in_range(&instruction, depth, upvalues, globals) {
+                return Err(ChunkError::Operand { offset });
+            }
+            let after = depth.saturating_add_signed(instruction.stack_effect());
+            max = max.max(after);
+            match instruction {
+                Instruction::Return | Instruction::Throw => {}
+                Instruction::Jump(target) | Instruction::Loop(target) => work.push((target, after)),
+                Instruction::JumpIfFalse(target) | Instruction::JumpIfTrue(target) => {
+                    work.push((target, after));
+                    work.push((next, after));
+                }
+                // A caught exception resumes at the handler with the
+                // thrown value on top.
+                Instruction::PushHandler(target) => {
+                    work.push((target, depth + 1));
+                    work.push((next, after));
+                }
+                _ => work.push((next, after)),
+            }
         }
+        Ok(max)
     }
 
-    pub fn add_constant(&mut self, val: Value) -> usize {
-        self.constants.write_value(val)
+    fn in_range(
+        &self,
+        instruction: &Instruction,
+        depth: usize,
+        upvalues: usize,
+        globals: usize,
+    ) -> bool {
+        match instruction {
+            Instruction::GetLocal(slot) | Instruction::SetLocal(slot) => (*slot as usize) < depth,
+            Instruction::GetUpvalue(index) | Instruction::SetUpvalue(index) => {
+                (*index as usize) < upvalues
+            }
+            Instruction::DefineGlobal(slot)
+            | Instruction::GetGlobal(slot)
+            | Instruction::SetGlobal(slot) => (*slot as usize) < globals,
+            Instruction::GetProperty(name, cache) | Instruction::SetProperty(name, cache) => {
+                matches!(
+                    self.constants.get_value(*name as usize),
+                    Some(Value::String(_))
+                ) && (*cache as usize) < self.cache_count()
+            }
+            Instruction::Class(name) | Instruction::Method(name) | Instruction::GetSuper(name) => {
+                matches!(name, Value::String(_))
+            }
+            Instruction::Closure(function, captures) => {
+                function.as_function().is_some()
+                    && captures.iter().all(|&(is_local, index)| {
+                        if is_local {
+                            (index as usize) < depth
+                        } else {
+                            (index as usize) < upvalues
+                        }
+                    })
+            }
+            _ => true,
+        }
     }
 
-    fn simple_instruction(&self, name: String, offset: usize) -> usize {
-        eprintln!("[{}] {}", offset, name);
-        offset + 1
+    // Reserves an inline cache slot for a property instruction. The count
+    // of slots, like each slot, fits in a u16.
+    pub fn add_cache(&mut self) -> Option<u16> {
+        let caches = self.caches.get_mut();
+        let slot = u16::try_from(caches.len()).ok().filter(|&s| s < u16::MAX)?;
+        caches.push(None);
+        Some(slot)
     }
 
-    fn constant_instruction(&self, name: String, offset: usize) -> usize {
-        let pos = self.code[offset + 1];
-        let val = self.constants.get_value(pos as usize);
-        eprintln!("[{}] {} {}", offset, name, val);
-        offset + 2
+    pub fn cache_count(&self) -> usize {
+        self.caches.borrow().len()
     }
 
-    fn byte_instruction(&self, name: String, offset: usize) -> usize {
-        eprintln!("[{}] {} {}", offset, name, self.code[offset + 1]);
-        offset + 2
+    pub fn cached(
+        &self,
+        slot: usize,
+        class: &Shared<SharedCell<Class>>,
+        fields: usize,
+    ) -> Option<Member> {
+        let caches = self.caches.borrow();
+        let cache = caches.get(slot)?.as_ref()?;
+        cache.matches(class, fields).then_some(cache.member)
     }
 
-    fn jump_instruction(&self, name: String, offset: usize) -> usize {
-        let address = self.read_jump(offset + 1).expect("Can not get address");
-        eprintln!("[{}] {} -> {}", offset, name, offset + USIZE + 1 + address);
-        offset + 1 + USIZE
+    pub fn fill_cache(&self, slot: usize, cache: PropertyCache) {
+        if let Some(entry) = self.caches.borrow_mut().get_mut(slot) {
+            *entry = Some(cache);
+        }
     }
 
-    fn loop_instruction(&self, name: String, offset: usize) -> usize {
-        let address = self.read_jump(offset + 1).expect("Can not get address");
-        eprintln!("[{}] {} -> {}", offset, name, offset + USIZE + 1 - address);
-        offset + 1 + USIZE
+    // Functions defined directly in this chunk.
+    pub fn functions(&self) -> impl Iterator<Item = Shared<Function>> + '_ {
+        self.constants
+            .values
+            .iter()
+            .filter_map(|val| val.as_function())
+    }
+
+    pub fn read_constant(&self, index: usize) -> Result<Value, ChunkError> {
+        self.constants.get_value(index).ok_or(ChunkError::Constant {
+            index,
+            len: self.constants.len(),
+        })
+    }
+
+    pub fn add_constant(&mut self, val: Value) -> usize {
+        self.constants.write_value(val)
+    }
+
+    // Offset and source line of an instruction, with "|" when the line is
+    // unchanged from the previous instruction.
+    fn line_prefix(&self, offset: usize) -> Result<String, ChunkError> {
+        let line = self.read_line(offset)?;
+        if offset > 0 && self.read_line(offset - 1)? == line {
+            Ok(format!("[{}]    |", offset))
+        } else {
+            Ok(format!("[{}] {:4}", offset, line))
+        }
     }
 }
 
@@ -247,14 +936,129 @@ mod tests {
     }
 
     #[test]
-    #[should_panic = "Unknown code"]
     fn test_chunk_disassemble() {
         let mut chunk = Chunk::new();
-        chunk.add_constant(LoxType::Number(1.0));
+        chunk.add_constant(Value::Number(1.0));
         chunk.write_chunk(0, 1);
         chunk.write_chunk(1, 1);
         chunk.write_chunk(0, 1);
         chunk.write_chunk(100, 1);
-        chunk.disassemble_chunk();
+        assert_eq!(
+            chunk.disassemble_chunk(),
+            Err(ChunkError::Opcode {
+                offset: 3,
+                byte: 100
+            })
+        );
+    }
+
+    #[test]
+    fn test_opcode_round_trip() {
+        for byte in 0..=u8::MAX {
+            if let Ok(op) = OpCode::try_from(byte) {
+                assert_eq!(op as u8, byte);
+                assert!(op.name().starts_with("OP_"));
+            }
+        }
+        assert_eq!(
+            OpCode::try_from(OpCode::Throw as u8 + 1),
+            Err(OpCode::Throw as u8 + 1)
+        );
+    }
+
+    #[test]
+    fn test_truncated_chunk() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Constant as u8, 1);
+        assert_eq!(chunk.read_chunk(0), Ok(OpCode::Constant as u8));
+        assert_eq!(
+            chunk.read_chunk(1),
+            Err(ChunkError::Code { offset: 1, len: 1 })
+        );
+        assert_eq!(
+            chunk.read_short(0),
+            Err(ChunkError::Code { offset: 1, len: 1 })
+        );
+        assert_eq!(
+            chunk.read_jump(1),
+            Err(ChunkError::Code { offset: 1, len: 1 })
+        );
+        assert_eq!(chunk.read_line(0), Ok(1));
+        assert_eq!(
+            chunk.read_line(5),
+            Err(ChunkError::Code { offset: 5, len: 1 })
+        );
+    }
+
+    #[test]
+    fn test_jump_out_of_range() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Loop as u8, 1);
+        for byte in 100usize.to_le_bytes() {
+            chunk.write_chunk(byte, 1);
+        }
+        assert_eq!(
+            chunk.decode_instruction(0),
+            Err(ChunkError::Jump { offset: 0 })
+        );
+        // A jump inside the chunk still has to land on code that keeps the
+        // stack it needs.
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Pop as u8, 1);
+        chunk.write_chunk(OpCode::Return as u8, 1);
+        assert_eq!(chunk.max_depth(1), Err(ChunkError::Stack { offset: 0 }));
+    }
+
+    #[test]
+    fn test_constant_out_of_range() {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::Number(1.0));
+        // Indices past the constants but inside the code used to pass the
+        // bound check.
+        chunk.write_chunk(OpCode::Constant as u8, 1);
+        chunk.write_chunk(1, 1);
+        chunk.write_chunk(OpCode::Return as u8, 1);
+        assert_eq!(chunk.read_constant(0), Ok(Value::Number(1.0)));
+        let err = chunk.read_constant(1).expect_err("Read a missing constant");
+        assert_eq!(err, ChunkError::Constant { index: 1, len: 1 });
+        assert_eq!(err.to_string(), "Constant 1 is out of range (1 constants)");
+    }
+
+    #[test]
+    fn test_disassemble_to_string() {
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constant(Value::Number(1.5));
+        chunk.write_chunk(OpCode::Constant as u8, 1);
+        chunk.write_chunk(constant as u8, 1);
+        chunk.write_chunk(OpCode::Negate as u8, 1);
+        chunk.write_chunk(OpCode::Return as u8, 2);
+        assert_eq!(
+            chunk.disassemble_to_string(),
+            Ok("[0]    1 OP_CONSTANT 1.5\n[2]    | OP_NEGATE\n[3]    2 OP_RETURN\n\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_line_runs() {
+        let mut chunk = Chunk::new();
+        for line in [1, 1, 1, 3, 3, 1, 7] {
+            chunk.write_chunk(OpCode::Nil as u8, line);
+        }
+        let lines: Vec<i32> = (0..chunk.len())
+            .map(|pos| chunk.read_line(pos).expect("Missing line"))
+            .collect();
+        assert_eq!(lines, [1, 1, 1, 3, 3, 1, 7]);
+        assert_eq!(chunk.lines.len(), 4);
+
+        // Truncating inside a run keeps its start; writing the same line
+        // extends it again.
+        chunk.truncate(4);
+        assert_eq!(chunk.lines.len(), 2);
+        chunk.write_chunk(OpCode::Nil as u8, 3);
+        assert_eq!(chunk.lines.len(), 2);
+        assert_eq!(chunk.read_line(4), Ok(3));
+        chunk.truncate(3);
+        assert_eq!(chunk.lines.len(), 1);
+        assert!(chunk.read_line(3).is_err());
     }
 }
@@ -1,12 +1,16 @@
 use crate::chunk::*;
 use crate::object::Function;
+use crate::parser::MAX_NESTING;
 use crate::scanner::keywords;
-use crate::token::TokenType;
-use crate::{DEBUG, USIZE};
+use crate::shared::Shared;
+use crate::token::{Span, TokenType};
+use crate::value::Value;
+use crate::USIZE;
 
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use std::rc::Rc;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Write};
 
 #[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Eq)]
 pub enum Prec {
@@ -48,9 +52,12 @@ impl Ord for Prec {
 
 #[derive(Debug)]
 pub struct ParseError {
-    line: i32,
-    token: String,
-    reason: String,
+    pub line: i32,
+    pub token: String,
+    pub reason: String,
+    /// The 1-based column of the token's first character.
+    pub column: Option<usize>,
+    pub span: Option<Span>,
 }
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -59,6 +66,30 @@ impl std::fmt::Display for ParseError {
 }
 impl std::error::Error for ParseError {}
 
+impl ParseError {
+    pub fn line(&self) -> i32 {
+        self.line
+    }
+
+    pub fn column(&self) -> Option<usize> {
+        self.column
+    }
+
+    /// The bytes of source the token covers.
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    pub fn message(&self) -> &str {
+        &self.reason
+    }
+
+    /// The token the error was found at, or "end" at the end of the source.
+    pub fn lexeme(&self) -> Option<&str> {
+        Some(&self.token)
+    }
+}
+
 #[derive(Clone, Copy)]
 struct NewToken {
     pub ttype: TokenType,
@@ -75,12 +106,13 @@ struct Scanner {
 }
 
 impl Scanner {
-    fn init_scanner(src: &str) -> Scanner {
+    fn init_scanner(src: &str, line: i32) -> Scanner {
+        let source: Vec<char> = src.chars().collect();
         Scanner {
-            source: src.chars().collect(),
-            length: src.len(),
+            length: source.len(),
+            source,
             pos: 0,
-            line: 1,
+            line,
         }
     }
 
@@ -100,7 +132,10 @@ impl Scanner {
             ')' => return Ok(self.make_token(TokenType::RightParen, start)),
             '{' => return Ok(self.make_token(TokenType::LeftBrace, start)),
             '}' => return Ok(self.make_token(TokenType::RightBrace, start)),
+            '[' => return Ok(self.make_token(TokenType::LeftBracket, start)),
+            ']' => return Ok(self.make_token(TokenType::RightBracket, start)),
             ';' => return Ok(self.make_token(TokenType::Semicolon, start)),
+            ':' => return Ok(self.make_token(TokenType::Colon, start)),
             ',' => return Ok(self.make_token(TokenType::Comma, start)),
             '.' => return Ok(self.make_token(TokenType::Dot, start)),
             '-' => return Ok(self.make_token(TokenType::Minus, start)),
@@ -140,7 +175,7 @@ impl Scanner {
                 return Ok(self.make_token(ttype, start));
             }
             '"' => {
-                while self.peek() != '"' && !self.is_at_end() {
+                while !self.is_at_end() && self.peek() != '"' {
                     if self.peek() == '\n' {
                         self.line += 1;
                     }
@@ -151,6 +186,8 @@ impl Scanner {
                         line: self.line,
                         token: "end".to_string(),
                         reason: "Unterminated string.".to_string(),
+                        column: Some(self.column(start)),
+                        span: Some(self.span(start, self.pos - start)),
                     });
                 }
                 self.pos += 1;
@@ -160,7 +197,7 @@ impl Scanner {
                 while !self.is_at_end() && is_digit(self.peek()) {
                     self.advance();
                 }
-                if !self.is_at_end() && self.peek() == '.' && is_digit(self.source[self.pos + 1]) {
+                if !self.is_at_end() && self.peek() == '.' && is_digit(self.peek_next()) {
                     self.advance();
                     while !self.is_at_end() && is_digit(self.peek()) {
                         self.advance();
@@ -186,6 +223,8 @@ impl Scanner {
             line: self.line,
             token: self.source[start..self.pos].iter().collect(),
             reason: "Unknown Error".to_string(),
+            column: Some(self.column(start)),
+            span: Some(self.span(start, self.pos - start)),
         })
     }
 
@@ -193,6 +232,11 @@ impl Scanner {
         self.source[self.pos]
     }
 
+    // The character after the next, or '\0' past the end.
+    fn peek_next(&self) -> char {
+        self.source.get(self.pos + 1).copied().unwrap_or('\0')
+    }
+
     fn skip_whitespace(&mut self) {
         while !self.is_at_end() {
             match self.peek() {
@@ -201,13 +245,9 @@ impl Scanner {
                     self.line += 1;
                     self.pos += 1;
                 }
-                '/' => {
-                    if self.source[self.pos + 1] == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.pos += 1;
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    while !self.is_at_end() && self.peek() != '\n' {
+                        self.pos += 1;
                     }
                 }
                 _ => return,
@@ -243,34 +283,84 @@ impl Scanner {
     fn get_string(&self, start: usize, length: i32) -> String {
         self.source[start..start + length as usize].iter().collect()
     }
+
+    // The 1-based column of the character at pos.
+    fn column(&self, pos: usize) -> usize {
+        let line_start = self.source[..pos]
+            .iter()
+            .rposition(|&c| c == '\n')
+            .map_or(0, |i| i + 1);
+        pos - line_start + 1
+    }
+
+    // The bytes of the length characters from start.
+    fn span(&self, start: usize, length: usize) -> Span {
+        let bytes = |chars: &[char]| chars.iter().map(|c| c.len_utf8()).sum::<usize>();
+        let start_byte = bytes(&self.source[..start]);
+        Span::new(
+            start_byte,
+            start_byte + bytes(&self.source[start..start + length]),
+        )
+    }
 }
 
-pub fn compile(src: &str) -> Option<Rc<Function>> {
-    let scanner = Scanner::init_scanner(src);
-    let mut parser = Parser {
-        previous: NewToken {
-            ttype: TokenType::Eof,
-            start: 0,
-            length: 0,
-            line: -1,
-        },
-        current: NewToken {
-            ttype: TokenType::Eof,
-            start: 0,
-            length: 0,
-            line: -1,
-        },
-        had_error: false,
-        scanner: Box::new(scanner),
-        chunk: Box::new(Chunk::new()),
-        scope: Box::new(Scope::init("".to_string())),
-        chunk_history: Vec::new(),
-        scope_history: Vec::new(),
-        class_level: 0,
-    };
+// Every error is reported on stderr; the first is returned.
+pub fn compile(src: &str, globals: &mut GlobalNames) -> Result<Shared<Function>, ParseError> {
+    compile_to(src, 1, globals, &mut io::stderr(), false)
+}
+
+// Like compile, but numbering lines from line and reporting errors and
+// warnings to err. With eval, a trailing expression statement's value is
+// left for the script to return instead of popped.
+pub fn compile_to(
+    src: &str,
+    line: i32,
+    globals: &mut GlobalNames,
+    err: &mut dyn Write,
+    eval: bool,
+) -> Result<Shared<Function>, ParseError> {
+    let mut parser = Parser::init_parser(src, line, globals, err);
+    parser.eval = eval;
     parser.parse()
 }
 
+/// The globals that a compiled function, or any function nested in it, reads
+/// or sets but that none of them defines and `defined` doesn't know of. Each
+/// comes by slot with the first line it is used on, in order of those lines.
+pub fn undefined_globals(
+    function: &Shared<Function>,
+    defined: impl Fn(usize) -> bool,
+) -> Vec<(usize, i32)> {
+    let mut used: BTreeMap<usize, i32> = BTreeMap::new();
+    let mut definitions = HashSet::new();
+    let mut work = vec![function.clone()];
+    while let Some(function) = work.pop() {
+        let chunk = &function.chunk;
+        let mut offset = 0;
+        while let Ok((instruction, next)) = chunk.decode_instruction(offset) {
+            match instruction {
+                Instruction::DefineGlobal(slot) => {
+                    definitions.insert(slot as usize);
+                }
+                Instruction::GetGlobal(slot) | Instruction::SetGlobal(slot) => {
+                    let line = chunk.read_line(offset).unwrap_or_default();
+                    let first = used.entry(slot as usize).or_insert(line);
+                    *first = line.min(*first);
+                }
+                _ => {}
+            }
+            offset = next;
+        }
+        work.extend(chunk.functions());
+    }
+    let mut undefined: Vec<(usize, i32)> = used
+        .into_iter()
+        .filter(|(slot, _)| !definitions.contains(slot) && !defined(*slot))
+        .collect();
+    undefined.sort_by_key(|&(slot, line)| (line, slot));
+    undefined
+}
+
 macro_rules! add_upvalue {
     ($scope: expr, $pos: expr, $is_local: expr) => {{
         let current_val = Upvalue {
@@ -287,27 +377,75 @@ macro_rules! add_upvalue {
             }
         }
         if found {
-            index as u8
+            Some(index as u8)
+        } else if $scope.upvalues.len() >= u8::MAX as usize {
+            // Function::upvalue is a u8, so the count must stay below 256
+            None
         } else {
             $scope.upvalues.push(current_val);
-            $scope.upvalues.len() as u8 - 1
+            Some($scope.upvalues.len() as u8 - 1)
         }
     }};
 }
 
-struct Parser {
+struct Parser<'a> {
     current: NewToken,
     previous: NewToken,
-    had_error: bool,
+    // The first error; the rest are only reported.
+    error: Option<ParseError>,
     scanner: Box<Scanner>,
     chunk: Box<Chunk>,
     scope: Box<Scope>,
     chunk_history: Vec<Chunk>,
     scope_history: Vec<Scope>,
     class_level: usize,
+    // How deeply the declarations and expressions being compiled nest.
+    nesting: usize,
+    terminated: bool,
+    // Whether the script returns its trailing expression statement's value,
+    // and whether it has one.
+    eval: bool,
+    evaluated: bool,
+    globals: &'a mut GlobalNames,
+    err: &'a mut dyn Write,
 }
 
-impl Parser {
+impl<'a> Parser<'a> {
+    fn init_parser(
+        src: &str,
+        line: i32,
+        globals: &'a mut GlobalNames,
+        err: &'a mut dyn Write,
+    ) -> Parser<'a> {
+        Parser {
+            previous: NewToken {
+                ttype: TokenType::Eof,
+                start: 0,
+                length: 0,
+                line: -1,
+            },
+            current: NewToken {
+                ttype: TokenType::Eof,
+                start: 0,
+                length: 0,
+                line: -1,
+            },
+            error: None,
+            scanner: Box::new(Scanner::init_scanner(src, line)),
+            chunk: Box::new(Chunk::new()),
+            scope: Box::new(Scope::init("".to_string())),
+            chunk_history: Vec::new(),
+            scope_history: Vec::new(),
+            class_level: 0,
+            nesting: 0,
+            terminated: false,
+            eval: false,
+            evaluated: false,
+            globals,
+            err,
+        }
+    }
+
     fn advance(&mut self) -> Result<(), ParseError> {
         self.previous = self.current;
         let token = self.scanner.scan_token()?;
@@ -315,27 +453,60 @@ impl Parser {
         Ok(())
     }
 
-    fn emit_byte(&mut self, byte: u8) {
-        self.chunk.write_chunk(byte, self.previous.line);
+    // Every byte of an instruction, operands included, carries the line of the
+    // token that introduced the instruction.
+    fn emit_byte(&mut self, byte: u8, line: i32) {
+        self.chunk.write_chunk(byte, line);
     }
 
-    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
-        self.emit_byte(byte1);
-        self.emit_byte(byte2);
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8, line: i32) {
+        self.emit_byte(byte1, line);
+        self.emit_byte(byte2, line);
     }
 
-    fn emit_return(&mut self) {
-        self.emit_bytes(OP_NIL, OP_RETURN);
+    fn emit_op(&mut self, op: OpCode, line: i32) {
+        self.emit_byte(op as u8, line);
+    }
+
+    fn emit_op_byte(&mut self, op: OpCode, byte: u8, line: i32) {
+        self.emit_op(op, line);
+        self.emit_byte(byte, line);
+    }
+
+    fn emit_global(&mut self, op: OpCode, global: u16, line: i32) {
+        self.emit_op(op, line);
+        for byte in global.to_le_bytes() {
+            self.emit_byte(byte, line);
+        }
+    }
+
+    // Only chunks left broken by a compile error fail to decode, and those
+    // never run.
+    fn frame_size(&self, arity: u8) -> usize {
+        self.chunk
+            .max_depth(arity as usize + 1)
+            .unwrap_or(usize::MAX)
+    }
+
+    fn emit_return(&mut self, line: i32) {
+        self.emit_op(OpCode::Nil, line);
+        self.emit_op(OpCode::Return, line);
     }
 
     fn number(&mut self) -> Result<(), ParseError> {
-        let value = self.get_string(&self.previous).parse::<f64>().unwrap();
-        self.emit_constant(Value::Number(value))
+        // Literals too large for an f64 parse to inf rather than failing.
+        let text = self.get_string(&self.previous);
+        match text.parse::<f64>() {
+            Ok(value) => self.emit_constant(Value::Number(value), self.previous.line),
+            Err(_) => {
+                Err(self.error_at(&self.previous, format!("Invalid number literal {}.", text)))
+            }
+        }
     }
 
-    fn emit_constant(&mut self, val: Value) -> Result<(), ParseError> {
+    fn emit_constant(&mut self, val: Value, line: i32) -> Result<(), ParseError> {
         let pos = self.make_constant(val)?;
-        self.emit_bytes(OP_CONSTANT, pos);
+        self.emit_op_byte(OpCode::Constant, pos, line);
         Ok(())
     }
 
@@ -343,25 +514,38 @@ impl Parser {
         let pos = self.chunk.add_constant(val);
         // Deal with possibly too many constants
         if pos >= 256 {
-            return Err(ParseError {
-                line: self.previous.line,
-                token: self.get_string(&self.previous),
-                reason: "Too many constants".to_string(),
-            });
+            return Err(self.error_at(&self.previous, "Too many constants".to_string()));
         }
         Ok(pos as u8)
     }
 
     fn declaration(&mut self) -> Result<(), ParseError> {
-        if self.match_advance(TokenType::Class) {
-            self.class_declaration()
-        } else if self.match_advance(TokenType::Fun) {
-            self.fun_declaration()
-        } else if self.match_advance(TokenType::Var) {
-            self.var_declaration()
-        } else {
-            self.statement()
+        self.nested(|parser| {
+            if parser.match_advance(TokenType::Class) {
+                parser.class_declaration()
+            } else if parser.match_advance(TokenType::Fun) {
+                parser.fun_declaration()
+            } else if parser.match_advance(TokenType::Var) {
+                parser.var_declaration()
+            } else {
+                parser.statement()
+            }
+        })
+    }
+
+    // Runs compile one level of nesting deeper, failing past MAX_NESTING
+    // instead of overflowing the stack.
+    fn nested(
+        &mut self,
+        compile: impl FnOnce(&mut Self) -> Result<(), ParseError>,
+    ) -> Result<(), ParseError> {
+        if self.nesting == MAX_NESTING {
+            return Err(self.error_at(&self.current, "Too much nesting.".to_string()));
         }
+        self.nesting += 1;
+        let result = compile(self);
+        self.nesting -= 1;
+        result
     }
 
     fn class_declaration(&mut self) -> Result<(), ParseError> {
@@ -372,8 +556,13 @@ impl Parser {
         self.class_level += 1;
         let constant = self.identifier_constant()?;
         self.declare_variable()?;
-        self.emit_bytes(OP_CLASS, constant);
-        self.define_variable(constant)?;
+        let global = if self.scope.depth > 0 {
+            0
+        } else {
+            self.global_slot(&class_name)?
+        };
+        self.emit_op_byte(OpCode::Class, constant, class.line);
+        self.define_variable(global)?;
 
         let has_super = self.match_advance(TokenType::Less);
         if has_super {
@@ -381,18 +570,14 @@ impl Parser {
             self.variable(false)?;
             let prev_name = self.get_string(&self.previous);
             if self.identifier_equal(&class_name, &prev_name) {
-                return Err(ParseError {
-                    line: self.previous.line,
-                    token: self.get_string(&self.previous),
-                    reason: "Class can't inherit itself".to_string(),
-                });
+                return Err(self.error_at(&self.previous, "Class can't inherit itself".to_string()));
             }
 
             self.begin_scope();
             self.add_local("super".to_string())?;
             self.define_variable(0)?;
             self.named_variable(&class_name, false)?;
-            self.emit_byte(OP_INHERIT);
+            self.emit_op(OpCode::Inherit, class.line);
         }
 
         self.named_variable(&class_name, false)?;
@@ -402,7 +587,7 @@ impl Parser {
             self.method()?;
         }
         self.expect(TokenType::RightBrace)?;
-        self.emit_byte(OP_POP);
+        self.emit_op(OpCode::Pop, self.previous.line);
 
         if has_super {
             self.end_scope();
@@ -413,9 +598,10 @@ impl Parser {
 
     fn method(&mut self) -> Result<(), ParseError> {
         self.expect(TokenType::Identifier)?;
+        let line = self.previous.line;
         let constant = self.identifier_constant()?;
         self.function(true)?;
-        self.emit_bytes(OP_METHOD, constant);
+        self.emit_op_byte(OpCode::Method, constant, line);
         Ok(())
     }
 
@@ -429,6 +615,7 @@ impl Parser {
     fn function(&mut self, is_method: bool) -> Result<(), ParseError> {
         // store old values
         let name = self.get_string(&self.previous);
+        let line = self.previous.line;
         let old_chunk = std::mem::replace(&mut self.chunk, Box::new(Chunk::new()));
         let old_scope = std::mem::replace(
             &mut self.scope,
@@ -441,6 +628,7 @@ impl Parser {
 
         self.chunk_history.push(*old_chunk);
         self.scope_history.push(*old_scope);
+        let outer_terminated = std::mem::replace(&mut self.terminated, false);
         let name = self.get_string(&self.previous);
         self.begin_scope();
         let mut arity: u8 = 0;
@@ -458,12 +646,18 @@ impl Parser {
         self.expect(TokenType::RightParen)?;
         self.expect(TokenType::LeftBrace)?;
         self.block()?;
-        self.emit_return();
-        let func: Rc<Function> = Rc::new(Function {
-            upvalue: self.scope.upvalues.len() as u8,
+        self.terminated = outer_terminated;
+        self.emit_return(self.previous.line);
+        let upvalue = match u8::try_from(self.scope.upvalues.len()) {
+            Ok(cnt) => cnt,
+            Err(_) => return Err(self.upvalue_overflow()),
+        };
+        let func: Shared<Function> = Shared::new(Function {
+            upvalue,
             arity,
             chunk: self.chunk.clone(), // Hopefully, remove clone in the future.
             name,
+            frame_size: self.frame_size(arity),
         });
 
         // Restore old chunk and scope
@@ -471,26 +665,25 @@ impl Parser {
         *self.chunk = self.chunk_history.pop().expect("Chunk history is empty");
         *self.scope = self.scope_history.pop().expect("Scope history is empty");
         let pos = self.make_constant(Value::Function(func.clone()))?;
-        self.emit_bytes(OP_CLOSURE, pos);
+        self.emit_op_byte(OpCode::Closure, pos, line);
         for value in current.upvalues {
-            self.emit_byte(if value.is_local { 1 } else { 0 });
-            self.emit_byte(value.index);
+            self.emit_bytes(if value.is_local { 1 } else { 0 }, value.index, line);
         }
         Ok(())
     }
 
     fn var_declaration(&mut self) -> Result<(), ParseError> {
-        let global: u8 = self.parse_variable()?;
+        let global = self.parse_variable()?;
         if self.match_advance(TokenType::Equal) {
             self.expression()?;
         } else {
-            self.emit_byte(OP_NIL);
+            self.emit_op(OpCode::Nil, self.previous.line);
         }
         self.expect(TokenType::Semicolon)?;
         self.define_variable(global)
     }
 
-    fn parse_variable(&mut self) -> Result<u8, ParseError> {
+    fn parse_variable(&mut self) -> Result<u16, ParseError> {
         self.expect(TokenType::Identifier)?;
 
         self.declare_variable()?;
@@ -498,7 +691,13 @@ impl Parser {
             return Ok(0);
         }
 
-        self.identifier_constant()
+        let name = self.get_string(&self.previous);
+        self.global_slot(&name)
+    }
+
+    fn global_slot(&mut self, name: &str) -> Result<u16, ParseError> {
+        u16::try_from(self.globals.slot(name))
+            .map_err(|_| self.error_at(&self.previous, "Too many global variables.".to_string()))
     }
 
     fn declare_variable(&mut self) -> Result<(), ParseError> {
@@ -512,11 +711,10 @@ impl Parser {
                 break;
             }
             if self.identifier_equal(&name, &self.scope.locals[i].name) {
-                return Err(ParseError {
-                    line: self.previous.line,
-                    token: self.get_string(&self.previous),
-                    reason: "A variable with same name defined in this scope".to_string(),
-                });
+                return Err(self.error_at(
+                    &self.previous,
+                    "A variable with same name defined in this scope".to_string(),
+                ));
             }
         }
         self.add_local(name)
@@ -541,15 +739,15 @@ impl Parser {
     }
 
     fn identifier_constant_helper(&mut self, name: &str) -> Result<u8, ParseError> {
-        self.make_constant(Value::String(name.to_owned()))
+        self.make_constant(Value::String(name.into()))
     }
 
-    fn define_variable(&mut self, id: u8) -> Result<(), ParseError> {
+    fn define_variable(&mut self, global: u16) -> Result<(), ParseError> {
         if self.scope.depth > 0 {
             self.make_initialized();
             return Ok(());
         }
-        self.emit_bytes(OP_DEFINE_GLOBAL, id);
+        self.emit_global(OpCode::DefineGlobal, global, self.previous.line);
         Ok(())
     }
 
@@ -567,93 +765,165 @@ impl Parser {
 
     fn end_scope(&mut self) {
         self.scope.depth -= 1;
+        let line = self.previous.line;
 
-        for i in (0..self.scope.locals.len()).rev() {
-            if self.scope.locals[i].depth > self.scope.depth {
-                if self.scope.locals[i].is_captured {
-                    self.emit_byte(OP_CLOSE_UPVALUE);
-                } else {
-                    self.emit_byte(OP_POP);
-                }
-                self.scope.locals.pop();
+        // Consecutive uncaptured locals are discarded together by one OP_POPN.
+        let mut pending: u8 = 0;
+        while let Some(local) = self.scope.locals.last() {
+            if local.depth <= self.scope.depth {
+                break;
+            }
+            if local.is_captured {
+                self.emit_pops(pending, line);
+                pending = 0;
+                self.emit_op(OpCode::CloseUpvalue, line);
             } else {
-                return;
+                if pending == u8::MAX {
+                    self.emit_pops(pending, line);
+                    pending = 0;
+                }
+                pending += 1;
             }
+            self.scope.locals.pop();
         }
+        self.emit_pops(pending, line);
     }
 
-    fn statement(&mut self) -> Result<(), ParseError> {
-        if self.match_advance(TokenType::Print) {
-            self.print_statement()
-        } else if self.match_advance(TokenType::If) {
-            self.if_statement()
-        } else if self.match_advance(TokenType::Return) {
-            self.return_statement()
-        } else if self.match_advance(TokenType::While) {
-            self.while_statement()
-        } else if self.match_advance(TokenType::LeftBrace) {
-            self.begin_scope();
-            self.block()?;
-            self.end_scope();
-            Ok(())
-        } else {
-            self.expression_statement()
+    fn emit_pops(&mut self, cnt: u8, line: i32) {
+        match cnt {
+            0 => {}
+            1 => self.emit_op(OpCode::Pop, line),
+            _ => self.emit_op_byte(OpCode::PopN, cnt, line),
         }
     }
 
+    fn statement(&mut self) -> Result<(), ParseError> {
+        self.nested(|parser| {
+            if parser.match_advance(TokenType::Print) {
+                parser.print_statement()
+            } else if parser.match_advance(TokenType::If) {
+                parser.if_statement()
+            } else if parser.match_advance(TokenType::Return) {
+                parser.return_statement()
+            } else if parser.match_advance(TokenType::While) {
+                parser.while_statement()
+            } else if parser.match_advance(TokenType::Try) {
+                parser.try_statement()
+            } else if parser.match_advance(TokenType::Throw) {
+                parser.throw_statement()
+            } else if parser.match_advance(TokenType::LeftBrace) {
+                parser.begin_scope();
+                parser.block()?;
+                parser.end_scope();
+                Ok(())
+            } else {
+                parser.expression_statement()
+            }
+        })
+    }
+
     fn return_statement(&mut self) -> Result<(), ParseError> {
+        let line = self.previous.line;
         if self.match_advance(TokenType::Semicolon) {
-            self.emit_return();
-            Ok(())
+            self.emit_return(line);
         } else {
             self.expression()?;
             self.expect(TokenType::Semicolon)?;
-            self.emit_byte(OP_RETURN);
-            Ok(())
+            self.emit_op(OpCode::Return, line);
         }
+        self.terminated = true;
+        Ok(())
     }
 
     fn if_statement(&mut self) -> Result<(), ParseError> {
+        let line = self.previous.line;
         self.expect(TokenType::LeftParen)?;
         self.expression()?;
         self.expect(TokenType::RightParen)?;
 
-        let then_jump = self.emit_jump(OP_JUMP_IF_FALSE)?;
-        self.emit_byte(OP_POP);
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse, line)?;
+        self.emit_op(OpCode::Pop, line);
+        self.terminated = false;
         self.statement()?;
+        let then_terminated = std::mem::replace(&mut self.terminated, false);
 
         if self.match_advance(TokenType::Else) {
-            let else_jump = self.emit_jump(OP_JUMP)?;
+            let else_line = self.previous.line;
+            let else_jump = self.emit_jump(OpCode::Jump, else_line)?;
             self.patch_jump(then_jump)?;
-            self.emit_byte(OP_POP);
+            self.emit_op(OpCode::Pop, else_line);
             self.statement()?;
             self.patch_jump(else_jump)?;
+            // Code after the if is only unreachable when both branches return
+            self.terminated = then_terminated && self.terminated;
         } else {
+            // The false condition still has to come off the stack.
+            let end_jump = self.emit_jump(OpCode::Jump, line)?;
             self.patch_jump(then_jump)?;
+            self.emit_op(OpCode::Pop, line);
+            self.patch_jump(end_jump)?;
         }
         Ok(())
     }
 
-    fn emit_jump(&mut self, op: u8) -> Result<usize, ParseError> {
-        self.emit_byte(op);
+    fn try_statement(&mut self) -> Result<(), ParseError> {
+        let line = self.previous.line;
+        // The VM unwinds the stack to its depth at OP_PUSH_HANDLER and pushes the
+        // thrown value there, which is exactly the slot of the catch variable.
+        let handler = self.emit_jump(OpCode::PushHandler, line)?;
+        self.expect(TokenType::LeftBrace)?;
+        self.begin_scope();
+        self.block()?;
+        self.end_scope();
+        let try_terminated = std::mem::replace(&mut self.terminated, false);
+        self.emit_op(OpCode::PopHandler, self.previous.line);
+        let end_jump = self.emit_jump(OpCode::Jump, self.previous.line)?;
+
+        self.expect(TokenType::Catch)?;
+        self.patch_jump(handler)?;
+        self.begin_scope();
+        self.expect(TokenType::LeftParen)?;
+        self.expect(TokenType::Identifier)?;
+        self.declare_variable()?;
+        self.make_initialized();
+        self.expect(TokenType::RightParen)?;
+        self.expect(TokenType::LeftBrace)?;
+        self.block()?;
+        self.end_scope();
+        self.patch_jump(end_jump)?;
+        self.terminated = try_terminated && self.terminated;
+        Ok(())
+    }
+
+    fn throw_statement(&mut self) -> Result<(), ParseError> {
+        let line = self.previous.line;
+        self.expression()?;
+        self.expect(TokenType::Semicolon)?;
+        self.emit_op(OpCode::Throw, line);
+        self.terminated = true;
+        Ok(())
+    }
+
+    fn emit_jump(&mut self, op: OpCode, line: i32) -> Result<usize, ParseError> {
+        self.emit_op(op, line);
         for _ in 0..USIZE {
-            self.emit_byte(0xff);
+            self.emit_byte(0xff, line);
         }
         Ok(self.chunk.len() - USIZE)
     }
 
-    fn emit_loop(&mut self, start: usize) -> Result<(), ParseError> {
-        self.emit_byte(OP_LOOP);
+    fn emit_loop(&mut self, start: usize, line: i32) -> Result<(), ParseError> {
+        self.emit_op(OpCode::Loop, line);
         let offset = self.chunk.len() - start + USIZE;
-        for byte in offset.to_ne_bytes() {
-            self.emit_byte(byte);
+        for byte in offset.to_le_bytes() {
+            self.emit_byte(byte, line);
         }
         Ok(())
     }
 
     fn patch_jump(&mut self, offset: usize) -> Result<(), ParseError> {
         let jump = self.chunk.len() - offset - USIZE;
-        let bytes = jump.to_ne_bytes();
+        let bytes = jump.to_le_bytes();
         for (i, &byte) in bytes.iter().enumerate() {
             self.chunk.modify_chunk(offset + i, byte);
         }
@@ -661,38 +931,64 @@ impl Parser {
     }
 
     fn while_statement(&mut self) -> Result<(), ParseError> {
+        let line = self.previous.line;
         let start = self.chunk.len();
         self.expect(TokenType::LeftParen)?;
         self.expression()?;
         self.expect(TokenType::RightParen)?;
-        let exit_jump = self.emit_jump(OP_JUMP_IF_FALSE)?;
-        self.emit_byte(OP_POP);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse, line)?;
+        self.emit_op(OpCode::Pop, line);
         self.statement()?;
-        self.emit_loop(start)?;
+        self.terminated = false;
+        self.emit_loop(start, line)?;
         self.patch_jump(exit_jump)?;
-        self.emit_byte(OP_POP);
+        self.emit_op(OpCode::Pop, line);
         Ok(())
     }
 
     fn block(&mut self) -> Result<(), ParseError> {
+        let outer_terminated = std::mem::replace(&mut self.terminated, false);
+        let mut dead = false;
         while (!self.is_match(TokenType::RightBrace)) && (!self.is_match(TokenType::Eof)) {
-            self.declaration()?;
+            if !dead && self.terminated {
+                dead = true;
+                let token = self.current;
+                self.warning(&token, "Unreachable code.");
+            }
+            if dead {
+                // Still compile dead code to report errors, but drop its bytecode.
+                let len = self.chunk.len();
+                self.declaration()?;
+                self.chunk.truncate(len);
+            } else {
+                self.declaration()?;
+            }
         }
+        self.terminated = outer_terminated || dead || self.terminated;
 
         self.expect(TokenType::RightBrace)
     }
 
     fn expression_statement(&mut self) -> Result<(), ParseError> {
         self.expression()?;
-        self.expect(TokenType::Semicolon)?;
-        self.emit_byte(OP_POP);
+        // Eval takes a last expression without its semicolon.
+        if !(self.eval && self.current.ttype == TokenType::Eof) {
+            self.expect(TokenType::Semicolon)?;
+        }
+        // Only a statement at the end of the script can be last.
+        if self.eval && self.current.ttype == TokenType::Eof {
+            self.evaluated = true;
+        } else {
+            self.emit_op(OpCode::Pop, self.previous.line);
+        }
         Ok(())
     }
 
     fn print_statement(&mut self) -> Result<(), ParseError> {
+        let line = self.previous.line;
         self.expression()?;
         self.expect(TokenType::Semicolon)?;
-        self.emit_byte(OP_PRINT);
+        self.emit_op(OpCode::Print, line);
         Ok(())
     }
 
@@ -703,20 +999,20 @@ impl Parser {
 
     fn unary(&mut self) -> Result<(), ParseError> {
         let op = self.previous.ttype;
-        self.expression()?;
+        let line = self.previous.line;
+        self.parse_precedence(Prec::Unary)?;
         match op {
             TokenType::Minus => {
-                self.emit_byte(OP_NEGATE);
+                self.emit_op(OpCode::Negate, line);
             }
             TokenType::Bang => {
-                self.emit_byte(OP_NOT);
+                self.emit_op(OpCode::Not, line);
             }
             _ => {
-                return Err(ParseError {
-                    line: self.previous.line,
-                    token: self.get_string(&self.previous),
-                    reason: format!("{:?} is not an unary operator.", self.previous.ttype),
-                })
+                return Err(self.error_at(
+                    &self.previous,
+                    format!("{:?} is not an unary operator.", self.previous.ttype),
+                ))
             }
         }
         Ok(())
@@ -724,42 +1020,42 @@ impl Parser {
 
     fn binary(&mut self) -> Result<(), ParseError> {
         let op = self.previous.ttype;
+        let line = self.previous.line;
 
         let prec = get_precedence(op);
         self.parse_precedence(prec.next())?;
         match op {
-            TokenType::Plus => self.emit_byte(OP_ADD),
-            TokenType::Minus => self.emit_byte(OP_SUBTRACT),
-            TokenType::Star => self.emit_byte(OP_MULTIPLY),
-            TokenType::Slash => self.emit_byte(OP_DIVIDE),
-            TokenType::BangEqual => self.emit_bytes(OP_EQUAL, OP_NOT),
-            TokenType::EqualEqual => self.emit_byte(OP_EQUAL),
-            TokenType::Greater => self.emit_byte(OP_GREATER),
-            TokenType::GreaterEqual => self.emit_bytes(OP_LESS, OP_NOT),
-            TokenType::Less => self.emit_byte(OP_LESS),
-            TokenType::LessEqual => self.emit_bytes(OP_GREATER, OP_NOT),
+            TokenType::Plus => self.emit_op(OpCode::Add, line),
+            TokenType::Minus => self.emit_op(OpCode::Subtract, line),
+            TokenType::Star => self.emit_op(OpCode::Multiply, line),
+            TokenType::Slash => self.emit_op(OpCode::Divide, line),
+            TokenType::BangEqual => self.emit_op(OpCode::NotEqual, line),
+            TokenType::EqualEqual => self.emit_op(OpCode::Equal, line),
+            TokenType::Greater => self.emit_op(OpCode::Greater, line),
+            TokenType::GreaterEqual => self.emit_op(OpCode::GreaterEqual, line),
+            TokenType::Less => self.emit_op(OpCode::Less, line),
+            TokenType::LessEqual => self.emit_op(OpCode::LessEqual, line),
             _ => {
-                return Err(ParseError {
-                    line: self.previous.line,
-                    token: self.get_string(&self.previous),
-                    reason: format!("{:?} is not a binary operator.", self.previous.ttype),
-                })
+                return Err(self.error_at(
+                    &self.previous,
+                    format!("{:?} is not a binary operator.", self.previous.ttype),
+                ))
             }
         }
         Ok(())
     }
 
     fn literal(&mut self) -> Result<(), ParseError> {
+        let line = self.previous.line;
         match self.previous.ttype {
-            TokenType::False => self.emit_byte(OP_FALSE),
-            TokenType::Nil => self.emit_byte(OP_NIL),
-            TokenType::True => self.emit_byte(OP_TRUE),
+            TokenType::False => self.emit_op(OpCode::False, line),
+            TokenType::Nil => self.emit_op(OpCode::Nil, line),
+            TokenType::True => self.emit_op(OpCode::True, line),
             _ => {
-                return Err(ParseError {
-                    line: self.previous.line,
-                    token: self.get_string(&self.previous),
-                    reason: format!("{:?} expect expression.", self.previous.ttype),
-                })
+                return Err(self.error_at(
+                    &self.previous,
+                    format!("{:?} expect expression.", self.previous.ttype),
+                ))
             }
         }
         Ok(())
@@ -771,7 +1067,10 @@ impl Parser {
 
     fn string(&mut self) -> Result<(), ParseError> {
         let string = self.get_string(&self.previous);
-        self.emit_constant(Value::String(string[1..string.len() - 1].to_string()))
+        self.emit_constant(
+            Value::String(string[1..string.len() - 1].into()),
+            self.previous.line,
+        )
     }
 
     fn variable(&mut self, can_assign: bool) -> Result<(), ParseError> {
@@ -797,25 +1096,43 @@ impl Parser {
         let mut current = pos as u8;
         self.scope_history[depth].locals[pos].is_captured = true;
         if depth + 1 == self.scope_history.len() {
-            Ok(Some(add_upvalue!(self.scope, current, true)))
+            match add_upvalue!(self.scope, current, true) {
+                Some(index) => Ok(Some(index)),
+                None => Err(self.upvalue_overflow()),
+            }
         } else {
-            current = add_upvalue!(self.scope_history[depth + 1], current, true);
+            current = match add_upvalue!(self.scope_history[depth + 1], current, true) {
+                Some(index) => index,
+                None => return Err(self.upvalue_overflow()),
+            };
             for i in depth + 2..self.scope_history.len() {
-                current = add_upvalue!(self.scope_history[i], current, false);
+                current = match add_upvalue!(self.scope_history[i], current, false) {
+                    Some(index) => index,
+                    None => return Err(self.upvalue_overflow()),
+                };
+            }
+            match add_upvalue!(self.scope, current, false) {
+                Some(index) => Ok(Some(index)),
+                None => Err(self.upvalue_overflow()),
             }
-            Ok(Some(add_upvalue!(self.scope, current, false)))
         }
     }
 
+    fn upvalue_overflow(&self) -> ParseError {
+        self.error_at(
+            &self.previous,
+            "Too many closure variables in function.".to_string(),
+        )
+    }
+
     fn resolve_local(&mut self, name: &String) -> Result<Option<u8>, ParseError> {
         for i in (0..self.scope.locals.len()).rev() {
             if self.identifier_equal(name, &self.scope.locals[i].name) {
                 if self.scope.locals[i].depth == -1 {
-                    return Err(ParseError {
-                        line: self.previous.line,
-                        token: self.get_string(&self.previous),
-                        reason: "Can't read local variable in its own identifier".to_string(),
-                    });
+                    return Err(self.error_at(
+                        &self.previous,
+                        "Can't read local variable in its own identifier".to_string(),
+                    ));
                 }
                 return Ok(Some(i as u8));
             }
@@ -824,38 +1141,46 @@ impl Parser {
     }
 
     fn named_variable(&mut self, name: &String, can_assign: bool) -> Result<(), ParseError> {
+        let line = self.previous.line;
         let mut arg = self.resolve_local(name)?;
         let (get_op, set_op) = if arg.is_some() {
-            (OP_GET_LOCAL, OP_SET_LOCAL)
+            (OpCode::GetLocal, OpCode::SetLocal)
         } else {
             arg = self.resolve_upvalue(name)?;
             if arg.is_some() {
-                (OP_GET_UPVALUE, OP_SET_UPVALUE)
+                (OpCode::GetUpvalue, OpCode::SetUpvalue)
             } else {
-                (OP_GET_GLOBAL, OP_SET_GLOBAL)
+                (OpCode::GetGlobal, OpCode::SetGlobal)
             }
         };
 
-        let pos = if let Some(u) = arg {
-            u
-        } else {
-            self.identifier_constant_helper(name)?
-        };
-
-        if can_assign && self.match_advance(TokenType::Equal) {
+        let op = if can_assign && self.match_advance(TokenType::Equal) {
             self.expression()?;
-            self.emit_bytes(set_op, pos);
+            set_op
         } else {
-            self.emit_bytes(get_op, pos);
+            get_op
+        };
+        match arg {
+            Some(pos) => self.emit_op_byte(op, pos, line),
+            None => {
+                let global = self.global_slot(name)?;
+                self.emit_global(op, global, line);
+            }
         }
         Ok(())
     }
 
     fn parse_precedence(&mut self, prec: Prec) -> Result<(), ParseError> {
+        self.nested(|parser| parser.parse_operators(prec))
+    }
+
+    fn parse_operators(&mut self, prec: Prec) -> Result<(), ParseError> {
         self.advance()?;
         let can_assign = prec <= Prec::Assignment;
         match self.previous.ttype {
             TokenType::LeftParen => self.grouping(),
+            TokenType::LeftBracket => self.array(),
+            TokenType::LeftBrace => self.map(),
             TokenType::Number => self.number(),
             TokenType::Minus | TokenType::Bang => self.unary(),
             TokenType::False | TokenType::True | TokenType::Nil => self.literal(),
@@ -863,11 +1188,10 @@ impl Parser {
             TokenType::Identifier => self.variable(can_assign),
             TokenType::This => self.this_(),
             TokenType::Super => self.super_(),
-            _ => Err(ParseError {
-                line: self.previous.line,
-                token: self.get_string(&self.previous),
-                reason: format!("{:?} expect expression.", self.previous.ttype),
-            }),
+            _ => Err(self.error_at(
+                &self.previous,
+                format!("{:?} expect expression.", self.previous.ttype),
+            )),
         }?;
 
         while prec <= get_precedence(self.current.ttype) {
@@ -886,69 +1210,82 @@ impl Parser {
                 TokenType::And => self.and(),
                 TokenType::Or => self.or(),
                 TokenType::LeftParen => self.call(),
+                TokenType::LeftBracket => self.subscript(can_assign),
                 TokenType::Dot => self.dot(can_assign),
                 _ => Ok(()),
             }?
         }
         if can_assign && self.is_match(TokenType::Equal) {
-            return Err(ParseError {
-                line: self.current.line,
-                token: self.get_string(&self.current),
-                reason: "Invalid assignment statement.".to_string(),
-            });
+            return Err(self.error_at(&self.current, "Invalid assignment statement.".to_string()));
         }
         Ok(())
     }
 
     fn this_(&mut self) -> Result<(), ParseError> {
         if self.class_level == 0 {
-            return Err(ParseError {
-                line: self.previous.line,
-                token: self.get_string(&self.previous),
-                reason: "Invalid this keyword outside Class definition.".to_string(),
-            });
+            return Err(self.error_at(
+                &self.previous,
+                "Invalid this keyword outside Class definition.".to_string(),
+            ));
         }
         self.variable(false)
     }
 
     fn super_(&mut self) -> Result<(), ParseError> {
         if self.class_level == 0 {
-            return Err(ParseError {
-                line: self.previous.line,
-                token: self.get_string(&self.previous),
-                reason: "Invalid super keyword outside Class definition.".to_string(),
-            });
+            return Err(self.error_at(
+                &self.previous,
+                "Invalid super keyword outside Class definition.".to_string(),
+            ));
         }
         // Not check whether current class has superclass.
 
         self.expect(TokenType::Dot)?;
         self.expect(TokenType::Identifier)?;
+        let line = self.previous.line;
         let name = self.identifier_constant()?;
 
         let this_str = "this".to_string();
         let super_str = "super".to_string();
         self.named_variable(&this_str, false)?;
         self.named_variable(&super_str, false)?;
-        self.emit_bytes(OP_GET_SUPER, name);
+        self.emit_op_byte(OpCode::GetSuper, name, line);
         Ok(())
     }
 
     fn dot(&mut self, can_assign: bool) -> Result<(), ParseError> {
         self.expect(TokenType::Identifier)?;
+        let line = self.previous.line;
         let pos = self.identifier_constant()?;
 
         if can_assign && self.match_advance(TokenType::Equal) {
             self.expression()?;
-            self.emit_bytes(OP_SET_PROPERTY, pos);
+            self.emit_property(OpCode::SetProperty, pos, line)
         } else {
-            self.emit_bytes(OP_GET_PROPERTY, pos);
+            self.emit_property(OpCode::GetProperty, pos, line)
+        }
+    }
+
+    // Property accesses carry the name and an inline cache slot of their own.
+    fn emit_property(&mut self, op: OpCode, name: u8, line: i32) -> Result<(), ParseError> {
+        let cache = self.chunk.add_cache().ok_or_else(|| ParseError {
+            line,
+            ..self.error_at(
+                &self.previous,
+                "Too many property accesses in one function".to_string(),
+            )
+        })?;
+        self.emit_op_byte(op, name, line);
+        for byte in cache.to_le_bytes() {
+            self.emit_byte(byte, line);
         }
         Ok(())
     }
 
     fn call(&mut self) -> Result<(), ParseError> {
+        let line = self.previous.line;
         let cnt = self.arg_list()?;
-        self.emit_bytes(OP_CALL, cnt);
+        self.emit_op_byte(OpCode::Call, cnt, line);
         Ok(())
     }
 
@@ -967,18 +1304,81 @@ impl Parser {
         Ok(cnt)
     }
 
+    fn array(&mut self) -> Result<(), ParseError> {
+        let line = self.previous.line;
+        let mut cnt: u8 = 0;
+        if !self.is_match(TokenType::RightBracket) {
+            loop {
+                self.expression()?;
+                if cnt == u8::MAX {
+                    return Err(self.error_at(
+                        &self.previous,
+                        "Can't have more than 255 elements in an array literal.".to_string(),
+                    ));
+                }
+                cnt += 1;
+                if !self.match_advance(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.expect(TokenType::RightBracket)?;
+        self.emit_op_byte(OpCode::Array, cnt, line);
+        Ok(())
+    }
+
+    // Each entry leaves its key and then its value on the stack.
+    fn map(&mut self) -> Result<(), ParseError> {
+        let line = self.previous.line;
+        let mut cnt: u8 = 0;
+        if !self.is_match(TokenType::RightBrace) {
+            loop {
+                self.expression()?;
+                self.expect(TokenType::Colon)?;
+                self.expression()?;
+                if cnt == u8::MAX {
+                    return Err(self.error_at(
+                        &self.previous,
+                        "Can't have more than 255 entries in a map literal.".to_string(),
+                    ));
+                }
+                cnt += 1;
+                if !self.match_advance(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.expect(TokenType::RightBrace)?;
+        self.emit_op_byte(OpCode::Map, cnt, line);
+        Ok(())
+    }
+
+    fn subscript(&mut self, can_assign: bool) -> Result<(), ParseError> {
+        let line = self.previous.line;
+        self.expression()?;
+        self.expect(TokenType::RightBracket)?;
+
+        if can_assign && self.match_advance(TokenType::Equal) {
+            self.expression()?;
+            self.emit_op(OpCode::IndexSet, line);
+        } else {
+            self.emit_op(OpCode::IndexGet, line);
+        }
+        Ok(())
+    }
+
     fn and(&mut self) -> Result<(), ParseError> {
-        let end_jump = self.emit_jump(OP_JUMP_IF_FALSE)?;
-        self.emit_byte(OP_POP);
+        let line = self.previous.line;
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse, line)?;
+        self.emit_op(OpCode::Pop, line);
         self.parse_precedence(Prec::And)?;
         self.patch_jump(end_jump)
     }
 
     fn or(&mut self) -> Result<(), ParseError> {
-        let else_jump = self.emit_jump(OP_JUMP_IF_FALSE)?;
-        let end_jump = self.emit_jump(OP_JUMP)?;
-        self.patch_jump(else_jump)?;
-        self.emit_byte(OP_POP);
+        let line = self.previous.line;
+        let end_jump = self.emit_jump(OpCode::JumpIfTrue, line)?;
+        self.emit_op(OpCode::Pop, line);
         self.parse_precedence(Prec::Or)?;
         self.patch_jump(end_jump)
     }
@@ -987,16 +1387,25 @@ impl Parser {
         self.scanner.get_string(token.start, token.length)
     }
 
+    fn error_at(&self, token: &NewToken, reason: String) -> ParseError {
+        ParseError {
+            line: token.line,
+            token: self.get_string(token),
+            reason,
+            column: Some(self.scanner.column(token.start)),
+            span: Some(self.scanner.span(token.start, token.length as usize)),
+        }
+    }
+
     fn expect(&mut self, ttype: TokenType) -> Result<(), ParseError> {
         if self.current.ttype == ttype {
             self.advance()?;
             Ok(())
         } else {
-            Err(ParseError {
-                line: self.current.line,
-                token: self.get_string(&self.current),
-                reason: format!("Expected {:?} but get {:?}", ttype, self.current.ttype),
-            })
+            Err(self.error_at(
+                &self.current,
+                format!("Expected {:?} but get {:?}", ttype, self.current.ttype),
+            ))
         }
     }
 
@@ -1013,10 +1422,6 @@ impl Parser {
         }
     }
 
-    fn disassemble_chunk(&self) {
-        self.chunk.disassemble_chunk();
-    }
-
     fn synchronize(&mut self) {
         while self.current.ttype != TokenType::Eof {
             if self.previous.ttype == TokenType::Semicolon {
@@ -1038,35 +1443,45 @@ impl Parser {
         }
     }
 
+    fn warning(&mut self, token: &NewToken, reason: &str) {
+        let lexeme = self.get_string(token);
+        let _ = writeln!(
+            self.err,
+            "[line {}] at {}: Warning: {}",
+            token.line, lexeme, reason
+        );
+    }
+
     fn handle_result(&mut self, res: Result<(), ParseError>) {
         if let Err(e) = res {
-            self.had_error = true;
-            eprintln!("{}", e);
+            let _ = writeln!(self.err, "{}", e);
+            self.error.get_or_insert(e);
             let _ = self.advance();
             self.synchronize();
         }
     }
 
-    fn parse(&mut self) -> Option<Rc<Function>> {
+    fn parse(&mut self) -> Result<Shared<Function>, ParseError> {
         let res = self.advance();
         self.handle_result(res);
         while !self.match_advance(TokenType::Eof) {
             let res = self.declaration();
             self.handle_result(res);
         }
-        self.emit_return();
-        if DEBUG && !self.had_error {
-            self.disassemble_chunk();
-        }
-        if self.had_error {
-            None
+        if self.evaluated {
+            self.emit_op(OpCode::Return, self.previous.line);
         } else {
-            Some(Rc::new(Function {
+            self.emit_return(self.previous.line);
+        }
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(Shared::new(Function {
                 arity: 0,
                 upvalue: 0,
                 chunk: self.chunk.clone(), // Hopefully, remove clone in the future.
                 name: "".to_string(),
-            }))
+                frame_size: self.frame_size(0),
+            })),
         }
     }
 }
@@ -1119,7 +1534,7 @@ fn get_precedence(ttype: TokenType) -> Prec {
         TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {
             Prec::Comparison
         }
-        TokenType::LeftParen | TokenType::Dot => Prec::Call,
+        TokenType::LeftParen | TokenType::LeftBracket | TokenType::Dot => Prec::Call,
         TokenType::And => Prec::And,
         TokenType::Or => Prec::Or,
         _ => Prec::None,
@@ -1129,83 +1544,127 @@ fn get_precedence(ttype: TokenType) -> Prec {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::vm::VM;
+    use crate::error::ErrorKind;
+    use crate::vm::{RuntimeError, VmOptions, VM};
+    use crate::{Io, Lox, LoxError, RunOptions};
 
-    fn run(content: &str) {
-        let mut vm = VM::init();
-        if let Some(function) = compile(content) {
-            vm.interpret(function);
-        }
+    // Runs through the library API, returning what was printed to each stream.
+    fn capture(content: &str) -> (Result<(), LoxError>, String, String) {
+        let (io, out, err) = Io::capture();
+        let result = Lox::with_io(RunOptions::default(), io).run(content);
+        (result, out.contents(), err.contents())
+    }
+
+    fn run(content: &str) -> String {
+        let (result, out, err) = capture(content);
+        assert!(result.is_ok(), "{}", err);
+        out
+    }
+
+    fn run_err(content: &str) -> RuntimeError {
+        let mut vm = VM::init(VmOptions::default());
+        let function = compile(content, vm.global_names()).expect("Compile error");
+        vm.interpret(function).expect_err("No runtime error")
     }
 
     #[test]
     fn test_compile_prec1() {
-        let _ = run("1 + 2 - 3 * 4;");
+        assert_eq!(run("print 1 + 2 - 3 * 4;"), "-9\n");
     }
 
     #[test]
     fn test_compile_prec2() {
-        let _ = run("1 - (2 - 3) * 4;");
+        assert_eq!(run("print 1 - (2 - 3) * 4;"), "5\n");
     }
 
     #[test]
     fn test_bool() {
-        let _ = run("true;");
+        assert_eq!(run("true;"), "");
     }
 
     #[test]
     fn test_type_mismatch() {
-        let _ = run("- true;");
+        assert_eq!(
+            *run_err("- true;").kind(),
+            ErrorKind::TypeMismatch {
+                expected: "a number".to_string(),
+                found: "true".to_string()
+            }
+        );
     }
 
     #[test]
     fn test_invalid_assignment() {
-        let _ = run("var a = 1;\nvar b = 2;\na * b = 3;");
+        let (result, out, err) = capture("var a = 1;\nvar b = 2;\na * b = 3;");
+        assert!(matches!(result, Err(LoxError::Parse(e)) if e.line == 3));
+        assert_eq!(out, "");
+        assert!(err.starts_with("[line 3] at =: Invalid assignment statement."));
     }
 
     #[test]
     fn test_string_concatenation() {
-        let _ = run("\"test\" + \"output\";");
+        assert_eq!(run("print \"test\" + \"output\";"), "testoutput\n");
     }
 
     #[test]
     fn test_compile() {
-        let _ = run("var x = \"test\";\nvar y = \"output\";\nprint x + y;\n");
+        assert_eq!(
+            run("var x = \"test\";\nvar y = \"output\";\nprint x + y;\n"),
+            "testoutput\n"
+        );
     }
 
     #[test]
     fn test_local_variable() {
-        let _ = run("var x = 1;\n{\nvar x = 2;\nprint x;\nvar y=2;\nprint x + y;\n}\nprint x;\n");
+        assert_eq!(
+            run("var x = 1;\n{\nvar x = 2;\nprint x;\nvar y=2;\nprint x + y;\n}\nprint x;\n"),
+            "2\n4\n1\n"
+        );
     }
 
     #[test]
     fn test_while_statement() {
-        let _ = run("var x = 1;\nvar y = 5;\nwhile (x <= y)\n{\nprint x;\nx = x + 1;\n}\n");
+        assert_eq!(
+            run("var x = 1;\nvar y = 5;\nwhile (x <= y)\n{\nprint x;\nx = x + 1;\n}\n"),
+            "1\n2\n3\n4\n5\n"
+        );
     }
 
     #[test]
     fn test_if_statement() {
-        let _ = run("var x = true;\nvar y = false;\nif (x or y)\n print \"Correct\";\nelse\nprint \"Wrong\";\n");
+        assert_eq!(
+            run("var x = true;\nvar y = false;\nif (x or y)\n print \"Correct\";\nelse\nprint \"Wrong\";\n"),
+            "Correct\n"
+        );
     }
 
     #[test]
     fn test_if_statement2() {
-        let _ = run("var x = true;\nvar y = false;\nif (x and y)\n print \"Wrong\";\nelse\nprint \"Correct\";\n");
+        assert_eq!(
+            run("var x = true;\nvar y = false;\nif (x and y)\n print \"Wrong\";\nelse\nprint \"Correct\";\n"),
+            "Correct\n"
+        );
     }
 
     #[test]
     fn test_fun_statement() {
-        let _ = run("fun hello(x)\n{\n print x;\n print \"Hello world\";\n}\n hello(1);\n");
+        assert_eq!(
+            run("fun hello(x)\n{\n print x;\n print \"Hello world\";\n}\n hello(1);\n"),
+            "1\nHello world\n"
+        );
     }
 
     #[test]
     fn test_class_without_method() {
-        let _ = run("class Pair {}\n var pair = Pair();\npair.first = 1;\npair.second = 2;\nprint pair.first + pair.second;\n");
+        assert_eq!(
+            run("class Pair {}\n var pair = Pair();\npair.first = 1;\npair.second = 2;\nprint pair.first + pair.second;\n"),
+            "3\n"
+        );
     }
 
     #[test]
     fn tets_closure1() {
-        let _ = run(r#"fun outer() {
+        let out = run(r#"fun outer() {
   var x = "outside";
   fun inner() {
     print x;
@@ -1213,11 +1672,12 @@ mod tests {
   inner();
 }
 outer();"#);
+        assert_eq!(out, "outside\n");
     }
 
     #[test]
     fn test_closure2() {
-        let _ = run(r#"{
+        let out = run(r#"{
   var a = 1;
   fun f() {
     print a;
@@ -1235,11 +1695,12 @@ outer();"#);
   h();
 }
 "#);
+        assert_eq!(out, "1\n2\n3\n");
     }
 
     #[test]
     fn test_closure3() {
-        let _ = run(r#"fun outer() {
+        let out = run(r#"fun outer() {
   var x = "outside";
   fun inner() {
     print x;
@@ -1250,11 +1711,39 @@ outer();"#);
 
 var closure = outer();
 closure();"#);
+        assert_eq!(out, "outside\n");
+    }
+
+    #[test]
+    fn test_closures_over_different_locals() {
+        // Returning closes both upvalues, each over its own local.
+        let out = run(r#"var first;
+var second;
+fun make() {
+  var a = "a";
+  var b = "b";
+  fun f() { return a; }
+  fun g() { return b; }
+  first = f;
+  second = g;
+  return "done";
+}
+print make();
+print first();
+print second();
+{
+  var x = 1;
+  var y = 2;
+  fun h() { return x + y; }
+  first = h;
+}
+print first();"#);
+        assert_eq!(out, "done\na\nb\n3\n");
     }
 
     #[test]
     fn test_this() {
-        let _ = run(r#"class A {
+        let out = run(r#"class A {
  p () {
 print this.t;
 }
@@ -1263,5 +1752,540 @@ var a = A();
 a.t = 1;
 a.p();
 "#);
+        assert_eq!(out, "1\n");
+    }
+
+    #[test]
+    fn test_property_cache_miss() {
+        // The cached slot of x is empty on an instance that never set it.
+        let err = run_err(
+            "class A {}\nfun get(o) { return o.x; }\nvar a = A();\na.x = 1;\nget(a);\nget(A());\n",
+        );
+        assert_eq!(err.reason, "Undefined property 'x' on A instance.");
+        assert_eq!(err.line, 2);
+    }
+
+    #[test]
+    fn test_assign_undefined_global() {
+        let mut vm = VM::init(VmOptions::default());
+        let function = compile("x = 1;\n", vm.global_names()).expect("Compile error");
+        let err = vm.interpret(function).expect_err("Assignment succeeded");
+        assert_eq!(
+            *err.kind(),
+            ErrorKind::UndefinedVariable {
+                name: "x".to_string()
+            }
+        );
+        let function = compile("print x;\n", vm.global_names()).expect("Compile error");
+        let err = vm.interpret(function).expect_err("Read succeeded");
+        assert_eq!(err.reason, "Undefined variable 'x'.");
+    }
+
+    #[test]
+    fn test_operand_errors() {
+        let mut vm = VM::init(VmOptions::default());
+        let function = compile("1 + \"a\";\n", vm.global_names()).expect("Compile error");
+        let err = vm.interpret(function).expect_err("Addition succeeded");
+        assert_eq!(
+            err.reason,
+            "Expected two numbers or two strings but got 1 and a."
+        );
+        let function = compile("\"a\" - 1;\n", vm.global_names()).expect("Compile error");
+        let err = vm.interpret(function).expect_err("Subtraction succeeded");
+        assert_eq!(err.reason, "Expected numbers for - but got a and 1.");
+    }
+
+    #[test]
+    fn test_string_length() {
+        assert_eq!(run("print \"\".length;\n"), "0\n");
+        assert_eq!(run("print \"h\u{e9}llo \u{1f600}\".length;\n"), "7\n");
+        let err = run_err("\"abc\".size;\n");
+        assert_eq!(
+            *err.kind(),
+            ErrorKind::UndefinedProperty {
+                name: "size".to_string(),
+                on: None
+            }
+        );
+        let err = run_err("var a = 1;\na.length;\n");
+        assert_eq!(err.reason, "1 is not an instance.");
+    }
+
+    #[test]
+    fn test_corrupt_chunk() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Constant as u8, 1);
+        let function = Shared::new(Function {
+            arity: 0,
+            upvalue: 0,
+            chunk: Box::new(chunk),
+            name: "".to_string(),
+            frame_size: 1,
+        });
+        let mut vm = VM::init(VmOptions::default());
+        let err = vm.interpret(function).expect_err("Ran a truncated chunk");
+        assert_eq!(
+            err.reason,
+            "Offset 1 is past the end of the chunk (1 bytes) in script."
+        );
+
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(200, 1);
+        let function = Shared::new(Function {
+            arity: 0,
+            upvalue: 0,
+            chunk: Box::new(chunk),
+            name: "".to_string(),
+            frame_size: 1,
+        });
+        let err = vm.interpret(function).expect_err("Ran an unknown opcode");
+        assert_eq!(err.reason, "Unknown opcode 200.");
+    }
+
+    #[test]
+    fn test_dup() {
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constant(Value::Number(2.0));
+        chunk.write_chunk(OpCode::Constant as u8, 1);
+        chunk.write_chunk(constant as u8, 1);
+        chunk.write_chunk(OpCode::Dup as u8, 1);
+        chunk.write_chunk(OpCode::Multiply as u8, 1);
+        chunk.write_chunk(OpCode::Throw as u8, 1);
+        let frame_size = chunk.max_depth(1).expect("Corrupt chunk");
+        assert_eq!(frame_size, 3);
+        let function = Shared::new(Function {
+            arity: 0,
+            upvalue: 0,
+            chunk: Box::new(chunk),
+            name: "".to_string(),
+            frame_size,
+        });
+        let mut vm = VM::init(VmOptions::default());
+        let err = vm.interpret(function).expect_err("Nothing thrown");
+        assert_eq!(err.reason, "Uncaught exception: 4");
+    }
+
+    #[test]
+    fn test_cyclic_garbage_bounded() {
+        let mut vm = VM::init(VmOptions::default());
+        let source = r#"class Node {}
+var i = 0;
+while (i < 20000) {
+  var n = Node();
+  n.next = n;
+  var f;
+  {
+    var x;
+    fun g() { x; }
+    f = g;
+    x = f;
+  }
+  i = i + 1;
+}
+"#;
+        let function = compile(source, vm.global_names()).expect("Compile error");
+        vm.interpret(function).expect("Runtime error");
+        assert!(vm.live_objects() <= 2048);
+        vm.collect_garbage();
+        // Only the Node class is still reachable.
+        assert_eq!(vm.live_objects(), 1);
+    }
+
+    #[test]
+    fn test_string_comparison() {
+        let source = r#"print "apple" < "banana";
+print "banana" < "apple";
+print "app" < "apple";
+print "apple" >= "app";
+print "b" > "B";
+print "a" <= "a" and "a" == "a";
+print "a" < "b" == true;
+"#;
+        let expected = "true\nfalse\ntrue\ntrue\ntrue\ntrue\ntrue\n";
+        assert_eq!(run(source), expected);
+        let err = run_err("print \"a\" < 1;");
+        assert_eq!(
+            err.reason,
+            "Expected two numbers or two strings for < but got a string and a number."
+        );
+
+        // The tree-walk backend agrees.
+        let (io, out, err) = Io::capture();
+        let options = RunOptions {
+            backend: crate::Backend::Ast,
+            ..RunOptions::default()
+        };
+        let mut lox = Lox::with_io(options, io);
+        lox.run(source).unwrap();
+        assert_eq!(out.contents(), expected);
+        assert!(lox.run("print \"a\" < 1;").is_err());
+        assert!(err
+            .contents()
+            .contains("Expected two numbers or two strings for < but got a string and a number."));
+    }
+
+    #[test]
+    fn test_map() {
+        let source = r#"var m = {"a": 1, 2: "two"};
+m["b"] = m["a"] + 1;
+m["a"] = "one";
+print m["a"];
+print m["b"];
+print m[2];
+print m[1 + 1];
+m[0] = "zero";
+print m[-0];
+print m["missing"];
+print m;
+print {};
+"#;
+        assert_eq!(
+            run(source),
+            "one\n2\ntwo\ntwo\nzero\nNil\n{0: \"zero\", 2: \"two\", a: \"one\", b: 2}\n{}\n"
+        );
+        assert_eq!(
+            *run_err("var m = {}; m[nil] = 1;").kind(),
+            ErrorKind::TypeMismatch {
+                expected: "a string or a number key".to_string(),
+                found: "Nil".to_string()
+            }
+        );
+        assert_eq!(
+            run_err("print {1: 2}[0/0];").reason,
+            "Expected a string or a number key but got NaN."
+        );
+    }
+
+    #[test]
+    fn test_print_collections() {
+        let source = r#"print [1, [2, 3], {"k": nil}];
+print "x";
+print ["x"];
+print {"b": ["y", {1: true}], "a": "z"};
+var a = [0, 1];
+a[0] = a;
+print a;
+var n = {"self": nil};
+n["self"] = n;
+n["list"] = [n, a];
+print n;
+var shared = [1];
+print [shared, shared];
+"#;
+        assert_eq!(
+            run(source),
+            "[1, [2, 3], {k: nil}]\nx\n[\"x\"]\n{a: \"z\", b: [\"y\", {1: true}]}\n\
+             [[...], 1]\n{list: [{...}, [[...], 1]], self: {...}}\n[[1], [1]]\n"
+        );
+    }
+
+    #[test]
+    fn test_map_cycle_collected() {
+        let mut vm = VM::init(VmOptions::default());
+        let source = r#"var i = 0;
+while (i < 5000) {
+  var m = {"i": i};
+  m["self"] = m;
+  i = i + 1;
+}
+"#;
+        let function = compile(source, vm.global_names()).expect("Compile error");
+        vm.interpret(function).expect("Runtime error");
+        vm.collect_garbage();
+        assert_eq!(vm.live_objects(), 0);
+    }
+
+    #[test]
+    fn test_end_scope_popn() {
+        let function = compile(
+            r#"{
+  var a = 1;
+  var b = 2;
+  var c = 3;
+  fun f() {
+    print c;
+  }
+  var d = 4;
+  var e = 5;
+}
+"#,
+            &mut GlobalNames::default(),
+        )
+        .expect("Compile error");
+        let code = function.chunk.code();
+        assert!(code.ends_with(&[
+            OpCode::PopN as u8,
+            3,
+            OpCode::CloseUpvalue as u8,
+            OpCode::PopN as u8,
+            2,
+            OpCode::Nil as u8,
+            OpCode::Return as u8
+        ]));
+    }
+
+    #[test]
+    fn test_end_scope_single_pop() {
+        let function =
+            compile("{\nvar a = 1;\n}\n", &mut GlobalNames::default()).expect("Compile error");
+        assert_eq!(
+            function.chunk.code(),
+            &[
+                OpCode::Constant as u8,
+                0,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unreachable_code_dropped() {
+        let function = compile(
+            r#"fun f() {
+  return 1;
+  print 2;
+  var x = 3;
+}
+"#,
+            &mut GlobalNames::default(),
+        )
+        .expect("Compile error");
+        let f = function
+            .chunk
+            .read_constant(0)
+            .expect("Missing constant")
+            .as_function()
+            .expect("Not a function");
+        assert_eq!(
+            f.chunk.code(),
+            &[
+                OpCode::Constant as u8,
+                0,
+                OpCode::Return as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn test_operator_line() {
+        let function = compile(
+            include_str!("../tests/input/line_error.lox"),
+            &mut GlobalNames::default(),
+        )
+        .expect("Compile error");
+        let code = function.chunk.code();
+        let pos = code
+            .iter()
+            .position(|&byte| byte == OpCode::Add as u8)
+            .expect("No OpCode::Add as u8 emitted");
+        assert_eq!(function.chunk.read_line(pos).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_malformed_number() {
+        let mut globals = GlobalNames::default();
+        let mut err = io::sink();
+        let mut parser = Parser::init_parser("1.2.3", 1, &mut globals, &mut err);
+        parser.previous = NewToken {
+            ttype: TokenType::Number,
+            start: 0,
+            length: 5,
+            line: 1,
+        };
+        let err = parser.number().expect_err("Malformed number accepted");
+        assert_eq!(err.reason, "Invalid number literal 1.2.3.");
+        assert_eq!(err.line, 1);
+    }
+
+    #[test]
+    fn test_script_display() {
+        let function = compile("", &mut GlobalNames::default()).expect("Compile error");
+        assert_eq!(function.to_string(), "<script>");
+    }
+
+    #[test]
+    fn test_number_overflow() {
+        let source = format!("print 1{};", "0".repeat(400));
+        let function = compile(&source, &mut GlobalNames::default()).expect("Compile error");
+        let value = function.chunk.read_constant(0).expect("Missing constant");
+        assert_eq!(value.as_number(), Some(f64::INFINITY));
+    }
+
+    fn instructions(source: &str) -> Vec<Instruction> {
+        let function = compile(source, &mut GlobalNames::default()).expect("Compile error");
+        function.chunk.instructions().expect("Corrupt chunk")
+    }
+
+    #[test]
+    fn test_if_else_instructions() {
+        use Instruction::*;
+        // Jumps take an opcode byte and a USIZE operand.
+        let else_branch = 15 + 2 * USIZE;
+        assert_eq!(
+            instructions("{ var a = 1; if (a < 2) a = 2; else a = 3; }"),
+            [
+                Constant(Value::Number(1.0)),
+                GetLocal(1),
+                Constant(Value::Number(2.0)),
+                Less,
+                JumpIfFalse(else_branch),
+                Pop,
+                Constant(Value::Number(2.0)),
+                SetLocal(1),
+                Pop,
+                Jump(else_branch + 6),
+                Pop,
+                Constant(Value::Number(3.0)),
+                SetLocal(1),
+                Pop,
+                Pop,
+                Nil,
+                Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_while_instructions() {
+        use Instruction::*;
+        let exit = 18 + 2 * USIZE;
+        assert_eq!(
+            instructions("{ var i = 0; while (i < 3) i = i + 1; }"),
+            [
+                Constant(Value::Number(0.0)),
+                GetLocal(1),
+                Constant(Value::Number(3.0)),
+                Less,
+                JumpIfFalse(exit),
+                Pop,
+                GetLocal(1),
+                Constant(Value::Number(1.0)),
+                Add,
+                SetLocal(1),
+                Pop,
+                Loop(2),
+                Pop,
+                Pop,
+                Nil,
+                Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unary_instructions() {
+        use Instruction::*;
+        // The operand stops before any binary operator.
+        assert_eq!(
+            instructions("print -2 + 3;"),
+            [
+                Constant(Value::Number(2.0)),
+                Negate,
+                Constant(Value::Number(3.0)),
+                Add,
+                Print,
+                Nil,
+                Return,
+            ]
+        );
+        assert_eq!(run("print -2 + 3;"), "1\n");
+        assert_eq!(run("print !true == false;"), "true\n");
+        assert_eq!(run("print -2 * 3 - 1;"), "-7\n");
+        assert_eq!(run("print !nil and false;"), "false\n");
+        assert_eq!(run("print --2;"), "2\n");
+    }
+
+    #[test]
+    fn test_or_instructions() {
+        use Instruction::*;
+        // One conditional jump skips the right operand.
+        let end = 8 + USIZE;
+        assert_eq!(
+            instructions("{ var a = 1; print a or 2; }"),
+            [
+                Constant(Value::Number(1.0)),
+                GetLocal(1),
+                JumpIfTrue(end),
+                Pop,
+                Constant(Value::Number(2.0)),
+                Print,
+                Pop,
+                Nil,
+                Return,
+            ]
+        );
+        assert_eq!(
+            run("print nil or 2; print 1 or 2; print false or false;"),
+            "2\n1\nfalse\n"
+        );
+    }
+
+    #[test]
+    fn test_comparison_instructions() {
+        use Instruction::*;
+        assert_eq!(
+            instructions("{ var a = 1; print a >= 2; print a <= 2; print a != 2; }"),
+            [
+                Constant(Value::Number(1.0)),
+                GetLocal(1),
+                Constant(Value::Number(2.0)),
+                GreaterEqual,
+                Print,
+                GetLocal(1),
+                Constant(Value::Number(2.0)),
+                LessEqual,
+                Print,
+                GetLocal(1),
+                Constant(Value::Number(2.0)),
+                NotEqual,
+                Print,
+                Pop,
+                Nil,
+                Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_closure_instructions() {
+        use Instruction::*;
+        let function = compile(
+            "{ var x = 1; fun get() { return x; } }",
+            &mut GlobalNames::default(),
+        )
+        .expect("Compile error");
+        let code = function.chunk.instructions().expect("Corrupt chunk");
+        assert_eq!(code[0], Constant(Value::Number(1.0)));
+        match &code[1] {
+            Closure(val, upvalues) => {
+                assert_eq!(val.to_string(), "<fn get>");
+                assert_eq!(upvalues, &[(true, 1)]);
+            }
+            other => panic!("Expected a closure, got {:?}", other),
+        }
+        assert_eq!(code[2..], [Pop, CloseUpvalue, Nil, Return]);
+        let get = function.chunk.functions().next().expect("No function");
+        assert_eq!(
+            get.chunk.instructions(),
+            Ok(vec![GetUpvalue(0), Return, Nil, Return])
+        );
+    }
+
+    #[test]
+    fn test_frame_size() {
+        let function = compile(
+            "fun add(a, b) { var c = a + b; return c; } print add(1, 2);",
+            &mut GlobalNames::default(),
+        )
+        .expect("Compile error");
+        // The script holds itself, then add and its two arguments.
+        assert_eq!(function.frame_size, 4);
+        let add = function.chunk.functions().next().expect("No function");
+        // add, a and b, then c is built from a and b on top of them.
+        assert_eq!(add.frame_size, 5);
     }
 }
@@ -0,0 +1,127 @@
+use crate::scanner::keywords;
+use crate::{Lox, Value};
+use std::collections::HashMap;
+
+/// What the REPL can complete a word to: keywords, the session's globals,
+/// and after `name.` the fields of the instance the global `name` holds.
+/// A snapshot, taken again after each line runs.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Names {
+    globals: Vec<String>,
+    fields: HashMap<String, Vec<String>>,
+}
+
+impl Names {
+    /// The names lox defines now.
+    pub fn of(lox: &Lox) -> Names {
+        let mut names = Names::default();
+        for (name, _) in lox.globals() {
+            let fields: Vec<String> = match lox.get_global(&name) {
+                Some(Value::Instance(instance)) => instance
+                    .borrow()
+                    .field_names()
+                    .iter()
+                    .map(|field| field.to_string())
+                    .collect(),
+                Some(Value::LoxInstance(instance)) => {
+                    instance.borrow().fields.keys().cloned().collect()
+                }
+                _ => Vec::new(),
+            };
+            if !fields.is_empty() {
+                names.fields.insert(name.clone(), fields);
+            }
+            names.globals.push(name);
+        }
+        names
+    }
+
+    /// Where the word that ends at byte pos of line starts, and what it
+    /// could be, sorted.
+    ///
+    /// ```
+    /// use lox::completion::Names;
+    /// use lox::{Lox, RunOptions};
+    ///
+    /// let mut lox = Lox::new(RunOptions::default());
+    /// lox.run("var whistle = 1;").unwrap();
+    /// let names = Names::of(&lox);
+    /// assert_eq!(names.complete("print whi", 9), (6, vec!["while".to_string(), "whistle".to_string()]));
+    /// ```
+    pub fn complete(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let before = &line[..pos];
+        let start = word_start(before);
+        let word = &before[start..];
+        let mut candidates: Vec<String> = match before[..start].strip_suffix('.') {
+            Some(receiver) => {
+                let name = &receiver[word_start(receiver)..];
+                self.fields.get(name).cloned().unwrap_or_default()
+            }
+            None => keywords.keys().chain(&self.globals).cloned().collect(),
+        };
+        candidates.retain(|name| name.starts_with(word));
+        candidates.sort();
+        candidates.dedup();
+        (start, candidates)
+    }
+}
+
+// The byte offset where the identifier text ends with starts.
+fn word_start(text: &str) -> usize {
+    text.char_indices()
+        .rev()
+        .take_while(|&(_, c)| c.is_alphanumeric() || c == '_')
+        .last()
+        .map_or(text.len(), |(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Backend, Io, RunOptions};
+
+    fn names(source: &str, backend: Backend) -> Names {
+        let options = RunOptions {
+            backend,
+            ..RunOptions::default()
+        };
+        let mut lox = Lox::with_io(options, Io::capture().0);
+        lox.run(source).unwrap();
+        Names::of(&lox)
+    }
+
+    #[test]
+    fn test_complete() {
+        for backend in [Backend::Vm, Backend::Ast] {
+            let names = names(
+                "var counter = 0;\n\
+                 fun printer() {}\n\
+                 class Point {}\n\
+                 var p = Point();\n\
+                 p.x = 1;\n\
+                 p.xs = 2;\n\
+                 p.y = 3;",
+                backend,
+            );
+            assert_eq!(names.complete("whi", 3), (0, vec!["while".to_string()]));
+            assert_eq!(
+                names.complete("print cou", 9),
+                (6, vec!["counter".to_string()])
+            );
+            assert_eq!(
+                names.complete("pri", 3),
+                (0, vec!["print".to_string(), "printer".to_string()])
+            );
+            assert_eq!(
+                names.complete("print p.x + 1", 9),
+                (8, vec!["x".to_string(), "xs".to_string()])
+            );
+            assert_eq!(
+                names.complete("p.", 2),
+                (2, vec!["x".to_string(), "xs".to_string(), "y".to_string()])
+            );
+            assert_eq!(names.complete("counter.", 8), (8, Vec::new()));
+            assert_eq!(names.complete("zzz", 3), (0, Vec::new()));
+        }
+    }
+}
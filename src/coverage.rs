@@ -0,0 +1,165 @@
+use crate::expr::Expr;
+use crate::object::Function;
+use crate::parser::parser_to;
+use crate::scanner::scan_source;
+use crate::stmt::Stmt;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// The lines a script's runs executed, out of the lines that hold a
+/// statement. Lines with only braces or comments hold none, so they are
+/// never missed.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Coverage {
+    pub executable: BTreeSet<i32>,
+    pub executed: BTreeSet<i32>,
+}
+
+impl Coverage {
+    /// The executable lines that ran.
+    pub fn hit(&self) -> impl Iterator<Item = i32> + '_ {
+        self.executable.intersection(&self.executed).copied()
+    }
+
+    /// The executable lines that never ran.
+    pub fn missed(&self) -> impl Iterator<Item = i32> + '_ {
+        self.executable.difference(&self.executed).copied()
+    }
+
+    /// The coverage as an lcov tracefile record for path.
+    ///
+    /// ```
+    /// use lox::coverage::Coverage;
+    ///
+    /// let coverage = Coverage {
+    ///     executable: [1, 2].into(),
+    ///     executed: [1].into(),
+    /// };
+    /// assert_eq!(
+    ///     coverage.lcov("a.lox"),
+    ///     "SF:a.lox\nDA:1,1\nDA:2,0\nLF:2\nLH:1\nend_of_record\n"
+    /// );
+    /// ```
+    pub fn lcov(&self, path: &str) -> String {
+        let mut out = format!("SF:{}\n", path);
+        for line in &self.executable {
+            let count = self.executed.contains(line) as u8;
+            let _ = writeln!(out, "DA:{},{}", line, count);
+        }
+        let _ = writeln!(out, "LF:{}", self.executable.len());
+        let _ = writeln!(out, "LH:{}", self.hit().count());
+        out.push_str("end_of_record\n");
+        out
+    }
+}
+
+/// The lines of source that hold a statement, numbering its lines from
+/// line, or None if the tree-walk parser doesn't accept it.
+pub fn executable_lines(source: &str, line: i32) -> Option<BTreeSet<i32>> {
+    let mut tokens = scan_source(source, line).ok()?;
+    let stmts = parser_to(&mut tokens, &mut std::io::sink()).ok()?;
+    let mut lines = BTreeSet::new();
+    for stmt in &stmts {
+        statement_lines(stmt, &mut lines);
+    }
+    Some(lines)
+}
+
+fn statement_lines(stmt: &Stmt, lines: &mut BTreeSet<i32>) {
+    lines.extend(stmt_line(stmt));
+    match stmt {
+        Stmt::Block { statements } => statements.iter().for_each(|s| statement_lines(s, lines)),
+        // Declaring a class doesn't run its methods' declarations.
+        Stmt::Class { methods, .. } => {
+            for method in methods {
+                if let Stmt::Function { body, .. } = &**method {
+                    body.iter().for_each(|s| statement_lines(s, lines));
+                }
+            }
+        }
+        Stmt::Function { body, .. } => body.iter().for_each(|s| statement_lines(s, lines)),
+        Stmt::If {
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            statement_lines(then_branch, lines);
+            if let Some(else_branch) = else_branch {
+                statement_lines(else_branch, lines);
+            }
+        }
+        Stmt::While { body, .. } => statement_lines(body, lines),
+        Stmt::Expression { .. } | Stmt::Print { .. } | Stmt::Return { .. } | Stmt::Var { .. } => {}
+    }
+}
+
+// The line a statement counts as running on: its first token's that the
+// parser kept. Blocks run as their statements, and literals carry no line,
+// so a while whose condition is one runs on its keyword's.
+pub(crate) fn stmt_line(stmt: &Stmt) -> Option<i32> {
+    match stmt {
+        Stmt::Block { .. } => None,
+        Stmt::Class { name, .. } | Stmt::Function { name, .. } | Stmt::Var { name, .. } => {
+            Some(name.line)
+        }
+        Stmt::Expression { expression } | Stmt::Print { expression } => expr_line(expression),
+        Stmt::If { condition, .. } => expr_line(condition),
+        Stmt::While {
+            keyword, condition, ..
+        } => expr_line(condition).or(Some(keyword.line)),
+        Stmt::Return { value } => value.as_deref().and_then(expr_line),
+    }
+}
+
+fn expr_line(expr: &Expr) -> Option<i32> {
+    let line = match expr {
+        Expr::Binary { left, operator, .. } | Expr::Logical { left, operator, .. } => {
+            return expr_line(left).or(Some(operator.line))
+        }
+        Expr::Call { callee, .. } => return expr_line(callee),
+        Expr::Get { object, name } | Expr::Set { object, name, .. } => {
+            return expr_line(object).or(Some(name.line))
+        }
+        Expr::Grouping { expression, .. } => return expr_line(expression),
+        Expr::Literal { .. } => return None,
+        Expr::Super { keyword, .. } | Expr::This { keyword, .. } => keyword.line,
+        Expr::Unary { operator, .. } => operator.line,
+        Expr::Variable { name, .. } | Expr::Assign { name, .. } => name.line,
+    };
+    Some(line)
+}
+
+/// The lines a compiled function and the functions in it have code for,
+/// for scripts the tree-walk parser doesn't accept.
+pub fn compiled_lines(function: &Function) -> BTreeSet<i32> {
+    let mut lines: BTreeSet<i32> = function.chunk.line_runs().map(|(_, line)| line).collect();
+    for inner in function.chunk.functions() {
+        lines.extend(compiled_lines(&inner));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_executable_lines() {
+        let source = "var a = 1;\n\
+                      // A comment.\n\
+                      fun f(x) {\n\
+                      \x20 if (x > 0) {\n\
+                      \x20   return x;\n\
+                      \x20 }\n\
+                      }\n\
+                      class A {\n\
+                      \x20 m() {\n\
+                      \x20   print this;\n\
+                      \x20 }\n\
+                      }\n\
+                      while (a < 2) a = a + 1;";
+        let lines = executable_lines(source, 1).unwrap();
+        assert_eq!(lines, [1, 3, 4, 5, 8, 10, 13].into());
+        assert!(executable_lines("print 1 +;", 1).is_none());
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashSet;
+use std::io;
+use std::io::Write;
+
+pub enum Command {
+    Step,
+    Continue,
+    // A breakpoint in the named function, or the current one if None.
+    Break(Option<String>, usize),
+    Print,
+    Global(String),
+    Quit,
+}
+
+// Breakpoints are keyed by function name and chunk offset; the top-level
+// script is named "script".
+pub struct Debugger {
+    breakpoints: HashSet<(String, usize)>,
+    stepping: bool,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: HashSet::new(),
+            stepping: true,
+        }
+    }
+
+    pub fn should_pause(&self, function: &str, offset: usize) -> bool {
+        self.stepping
+            || self
+                .breakpoints
+                .contains(&(function_name(function).to_string(), offset))
+    }
+
+    pub fn set_stepping(&mut self, stepping: bool) {
+        self.stepping = stepping;
+    }
+
+    pub fn add_breakpoint(&mut self, function: &str, offset: usize) {
+        self.breakpoints
+            .insert((function_name(function).to_string(), offset));
+    }
+
+    // Prompts until a valid command is read. End of input continues.
+    pub fn read_command(&self) -> Command {
+        loop {
+            eprint!("(debug) ");
+            let _ = io::stderr().flush();
+            let mut line = String::new();
+            match io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => return Command::Continue,
+                Ok(_) => match parse(&line) {
+                    Ok(command) => return command,
+                    Err(e) => eprintln!("{}", e),
+                },
+            }
+        }
+    }
+}
+
+pub fn function_name(name: &str) -> &str {
+    if name.is_empty() {
+        "script"
+    } else {
+        name
+    }
+}
+
+fn parse(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let offset = |word: &str| {
+        word.parse::<usize>()
+            .map_err(|_| format!("Invalid offset {}.", word))
+    };
+    match words.as_slice() {
+        ["s"] => Ok(Command::Step),
+        ["c"] => Ok(Command::Continue),
+        ["b", at] => Ok(Command::Break(None, offset(at)?)),
+        ["b", function, at] => Ok(Command::Break(Some(function.to_string()), offset(at)?)),
+        ["p"] => Ok(Command::Print),
+        ["g", name] => Ok(Command::Global(name.to_string())),
+        ["q"] => Ok(Command::Quit),
+        _ => Err("Commands: s, c, b [function] <offset>, p, g <name>, q.".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_breakpoints() {
+        assert!(matches!(parse("b 12\n"), Ok(Command::Break(None, 12))));
+        match parse("b fib 3") {
+            Ok(Command::Break(Some(name), 3)) => assert_eq!(name, "fib"),
+            _ => panic!("Expected a breakpoint in fib"),
+        }
+        assert_eq!(parse("b x").err(), Some("Invalid offset x.".to_string()));
+        assert!(parse("x").is_err());
+    }
+}
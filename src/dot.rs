@@ -0,0 +1,362 @@
+use crate::error::LoxError;
+use crate::expr::{Expr, ExprVisitor};
+use crate::formatter::symbol;
+use crate::parser::parser_to;
+use crate::scanner::scan_source;
+use crate::stmt::{Stmt, StmtVisitor};
+use crate::token::{Span, Token};
+use crate::value::Value;
+use std::collections::LinkedList;
+use std::io::{self, Write};
+
+/// Writes the program in source to out as a Graphviz digraph: a node for
+/// each statement and expression, labelled with its kind and the lexeme
+/// that tells it apart, with edges to its parts. Statements are blue boxes
+/// and expressions yellow ellipses. Nodes are numbered in the order they
+/// are written, so the same program always gives the same graph.
+///
+/// Only what the tree-walk parser accepts can be drawn. Errors are reported
+/// to err as they happen, and the first is returned.
+///
+/// ```
+/// use lox::dot::write_dot;
+///
+/// let mut out = Vec::new();
+/// write_dot("print -1;", &mut out, &mut std::io::sink()).unwrap();
+/// let dot = String::from_utf8(out).unwrap();
+/// assert!(dot.contains("n1 [label=\"Print\", shape=box"));
+/// assert!(dot.contains("n1 -> n2;\n"));
+/// ```
+pub fn write_dot(source: &str, out: &mut dyn Write, err: &mut dyn Write) -> Result<(), LoxError> {
+    let mut tokens = scan_source(source, 1).map_err(|errors| {
+        for e in &errors {
+            let _ = writeln!(err, "{}", e);
+        }
+        errors.into_iter().next().unwrap()
+    })?;
+    let stmts = parser_to(&mut tokens, err)?;
+    let mut graph = Graph { out, next: 0 };
+    graph.program(&stmts)?;
+    Ok(())
+}
+
+struct Graph<'a> {
+    out: &'a mut dyn Write,
+    // The id of the next node.
+    next: usize,
+}
+
+impl Graph<'_> {
+    fn program(&mut self, stmts: &LinkedList<Box<Stmt>>) -> io::Result<()> {
+        writeln!(self.out, "digraph ast {{")?;
+        writeln!(self.out, "  node [fontname=\"monospace\"];")?;
+        let root = self.node("Program", "shape=doubleoctagon")?;
+        for stmt in stmts {
+            let child = self.stmt(stmt)?;
+            self.edge(root, child, None)?;
+        }
+        writeln!(self.out, "}}")
+    }
+
+    fn node(&mut self, label: &str, style: &str) -> io::Result<usize> {
+        let id = self.next;
+        self.next += 1;
+        writeln!(
+            self.out,
+            "  n{} [label=\"{}\", {}];",
+            id,
+            escape(label),
+            style
+        )?;
+        Ok(id)
+    }
+
+    fn edge(&mut self, from: usize, to: usize, label: Option<&str>) -> io::Result<()> {
+        match label {
+            Some(label) => writeln!(self.out, "  n{} -> n{} [label=\"{}\"];", from, to, label),
+            None => writeln!(self.out, "  n{} -> n{};", from, to),
+        }
+    }
+
+    fn stmt_node(&mut self, label: &str) -> io::Result<usize> {
+        self.node(label, "shape=box, style=filled, fillcolor=lightblue")
+    }
+
+    fn expr_node(&mut self, label: &str) -> io::Result<usize> {
+        self.node(label, "shape=ellipse, style=filled, fillcolor=lightyellow")
+    }
+
+    // Draws the node and its parts, returning the node's id.
+    fn stmt(&mut self, stmt: &Stmt) -> io::Result<usize> {
+        stmt.accept(self)
+    }
+
+    fn stmts(&mut self, parent: usize, stmts: &LinkedList<Box<Stmt>>) -> io::Result<()> {
+        for stmt in stmts {
+            let child = self.stmt(stmt)?;
+            self.edge(parent, child, None)?;
+        }
+        Ok(())
+    }
+
+    fn child(&mut self, parent: usize, expr: &Expr, label: Option<&str>) -> io::Result<()> {
+        let child = expr.accept(self)?;
+        self.edge(parent, child, label)
+    }
+
+    fn operator(
+        &mut self,
+        kind: &str,
+        left: &Expr,
+        operator: &Token,
+        right: &Expr,
+    ) -> io::Result<usize> {
+        let id = self.expr_node(&format!("{} {}", kind, symbol(operator.ttype)))?;
+        self.child(id, left, None)?;
+        self.child(id, right, None)?;
+        Ok(id)
+    }
+}
+
+impl StmtVisitor<io::Result<usize>> for Graph<'_> {
+    fn visit_block(&mut self, statements: &LinkedList<Box<Stmt>>) -> io::Result<usize> {
+        let id = self.stmt_node("Block")?;
+        self.stmts(id, statements)?;
+        Ok(id)
+    }
+
+    fn visit_class(
+        &mut self,
+        name: &Token,
+        superclass: Option<&Expr>,
+        methods: &LinkedList<Box<Stmt>>,
+    ) -> io::Result<usize> {
+        let id = self.stmt_node(&format!("Class {}", name.lexeme))?;
+        if let Some(superclass) = superclass {
+            self.child(id, superclass, Some("superclass"))?;
+        }
+        self.stmts(id, methods)?;
+        Ok(id)
+    }
+
+    fn visit_expression(&mut self, expression: &Expr) -> io::Result<usize> {
+        let id = self.stmt_node("Expression")?;
+        self.child(id, expression, None)?;
+        Ok(id)
+    }
+
+    fn visit_function(
+        &mut self,
+        name: &Token,
+        params: &LinkedList<Token>,
+        body: &LinkedList<Box<Stmt>>,
+    ) -> io::Result<usize> {
+        let params: Vec<String> = params.iter().map(|p| p.lexeme.clone()).collect();
+        let label = format!("Function {}({})", name.lexeme, params.join(", "));
+        let id = self.stmt_node(&label)?;
+        self.stmts(id, body)?;
+        Ok(id)
+    }
+
+    fn visit_if(
+        &mut self,
+        condition: &Expr,
+        then_branch: &Stmt,
+        else_branch: Option<&Stmt>,
+    ) -> io::Result<usize> {
+        let id = self.stmt_node("If")?;
+        self.child(id, condition, Some("condition"))?;
+        let child = self.stmt(then_branch)?;
+        self.edge(id, child, Some("then"))?;
+        if let Some(else_branch) = else_branch {
+            let child = self.stmt(else_branch)?;
+            self.edge(id, child, Some("else"))?;
+        }
+        Ok(id)
+    }
+
+    fn visit_print(&mut self, expression: &Expr) -> io::Result<usize> {
+        let id = self.stmt_node("Print")?;
+        self.child(id, expression, None)?;
+        Ok(id)
+    }
+
+    fn visit_return(&mut self, value: Option<&Expr>) -> io::Result<usize> {
+        let id = self.stmt_node("Return")?;
+        if let Some(value) = value {
+            self.child(id, value, None)?;
+        }
+        Ok(id)
+    }
+
+    fn visit_var(&mut self, name: &Token, initializer: Option<&Expr>) -> io::Result<usize> {
+        let id = self.stmt_node(&format!("Var {}", name.lexeme))?;
+        if let Some(initializer) = initializer {
+            self.child(id, initializer, None)?;
+        }
+        Ok(id)
+    }
+
+    fn visit_while(&mut self, condition: &Expr, body: &Stmt) -> io::Result<usize> {
+        let id = self.stmt_node("While")?;
+        self.child(id, condition, Some("condition"))?;
+        let child = self.stmt(body)?;
+        self.edge(id, child, Some("body"))?;
+        Ok(id)
+    }
+}
+
+impl ExprVisitor<io::Result<usize>> for Graph<'_> {
+    fn visit_binary(&mut self, left: &Expr, operator: &Token, right: &Expr) -> io::Result<usize> {
+        self.operator("Binary", left, operator, right)
+    }
+
+    fn visit_call(
+        &mut self,
+        callee: &Expr,
+        _: &Token,
+        arguments: &LinkedList<Box<Expr>>,
+    ) -> io::Result<usize> {
+        let id = self.expr_node("Call")?;
+        self.child(id, callee, Some("callee"))?;
+        for argument in arguments {
+            self.child(id, argument, None)?;
+        }
+        Ok(id)
+    }
+
+    fn visit_get(&mut self, object: &Expr, name: &Token) -> io::Result<usize> {
+        let id = self.expr_node(&format!("Get {}", name.lexeme))?;
+        self.child(id, object, None)?;
+        Ok(id)
+    }
+
+    fn visit_grouping(&mut self, expression: &Expr, _: Span) -> io::Result<usize> {
+        let id = self.expr_node("Grouping")?;
+        self.child(id, expression, None)?;
+        Ok(id)
+    }
+
+    fn visit_literal(&mut self, value: &Value, _: Span) -> io::Result<usize> {
+        match value {
+            Value::String(s) => self.expr_node(&format!("Literal \"{}\"", s)),
+            Value::None => self.expr_node("Literal nil"),
+            value => self.expr_node(&format!("Literal {}", value)),
+        }
+    }
+
+    fn visit_logical(&mut self, left: &Expr, operator: &Token, right: &Expr) -> io::Result<usize> {
+        self.operator("Logical", left, operator, right)
+    }
+
+    fn visit_set(&mut self, object: &Expr, name: &Token, value: &Expr) -> io::Result<usize> {
+        let id = self.expr_node(&format!("Set {}", name.lexeme))?;
+        self.child(id, object, None)?;
+        self.child(id, value, Some("value"))?;
+        Ok(id)
+    }
+
+    fn visit_super(&mut self, _: &Token, method: &Token, _: u64) -> io::Result<usize> {
+        self.expr_node(&format!("Super {}", method.lexeme))
+    }
+
+    fn visit_this(&mut self, _: &Token, _: u64) -> io::Result<usize> {
+        self.expr_node("This")
+    }
+
+    fn visit_unary(&mut self, operator: &Token, right: &Expr) -> io::Result<usize> {
+        let id = self.expr_node(&format!("Unary {}", symbol(operator.ttype)))?;
+        self.child(id, right, None)?;
+        Ok(id)
+    }
+
+    fn visit_variable(&mut self, name: &Token, _: u64) -> io::Result<usize> {
+        self.expr_node(&format!("Variable {}", name.lexeme))
+    }
+
+    fn visit_assign(&mut self, name: &Token, value: &Expr, _: u64) -> io::Result<usize> {
+        let id = self.expr_node(&format!("Assign {}", name.lexeme))?;
+        self.child(id, value, None)?;
+        Ok(id)
+    }
+}
+
+// Quotes and backslashes would end or escape the label's string, and a
+// newline would break its line. Braces only mean something in record
+// shapes, which no node has.
+fn escape(label: &str) -> String {
+    let mut escaped = String::new();
+    for c in label.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dot(source: &str) -> String {
+        let mut out = Vec::new();
+        write_dot(source, &mut out, &mut io::sink()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_graph() {
+        let graph = dot("var a = 1 + 2;\nif (a > 2) print a; else { a = -a; }");
+        assert_eq!(
+            graph,
+            "digraph ast {\n  node [fontname=\"monospace\"];\n  \
+             n0 [label=\"Program\", shape=doubleoctagon];\n  \
+             n1 [label=\"Var a\", shape=box, style=filled, fillcolor=lightblue];\n  \
+             n2 [label=\"Binary +\", shape=ellipse, style=filled, fillcolor=lightyellow];\n  \
+             n3 [label=\"Literal 1\", shape=ellipse, style=filled, fillcolor=lightyellow];\n  \
+             n2 -> n3;\n  \
+             n4 [label=\"Literal 2\", shape=ellipse, style=filled, fillcolor=lightyellow];\n  \
+             n2 -> n4;\n  \
+             n1 -> n2;\n  \
+             n0 -> n1;\n  \
+             n5 [label=\"If\", shape=box, style=filled, fillcolor=lightblue];\n  \
+             n6 [label=\"Binary >\", shape=ellipse, style=filled, fillcolor=lightyellow];\n  \
+             n7 [label=\"Variable a\", shape=ellipse, style=filled, fillcolor=lightyellow];\n  \
+             n6 -> n7;\n  \
+             n8 [label=\"Literal 2\", shape=ellipse, style=filled, fillcolor=lightyellow];\n  \
+             n6 -> n8;\n  \
+             n5 -> n6 [label=\"condition\"];\n  \
+             n9 [label=\"Print\", shape=box, style=filled, fillcolor=lightblue];\n  \
+             n10 [label=\"Variable a\", shape=ellipse, style=filled, fillcolor=lightyellow];\n  \
+             n9 -> n10;\n  \
+             n5 -> n9 [label=\"then\"];\n  \
+             n11 [label=\"Block\", shape=box, style=filled, fillcolor=lightblue];\n  \
+             n12 [label=\"Expression\", shape=box, style=filled, fillcolor=lightblue];\n  \
+             n13 [label=\"Assign a\", shape=ellipse, style=filled, fillcolor=lightyellow];\n  \
+             n14 [label=\"Unary -\", shape=ellipse, style=filled, fillcolor=lightyellow];\n  \
+             n15 [label=\"Variable a\", shape=ellipse, style=filled, fillcolor=lightyellow];\n  \
+             n14 -> n15;\n  \
+             n13 -> n14;\n  \
+             n12 -> n13;\n  \
+             n11 -> n12;\n  \
+             n5 -> n11 [label=\"else\"];\n  \
+             n0 -> n5;\n\
+             }\n"
+        );
+        assert_eq!(
+            graph,
+            dot("var a = 1 + 2;\nif (a > 2) print a; else { a = -a; }")
+        );
+    }
+
+    #[test]
+    fn test_escaped_labels() {
+        let graph = dot("print \"say {hi} \\ \" + nil;");
+        assert!(graph.contains("[label=\"Literal \\\"say {hi} \\\\ \\\"\", shape=ellipse"));
+        assert!(graph.contains("[label=\"Literal nil\", shape=ellipse"));
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
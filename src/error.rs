@@ -1,9 +1,74 @@
-use crate::token::BasicType;
+use crate::compile::ParseError as CompileError;
+use crate::token::Span;
+use crate::value::Value;
+use crate::vm::RuntimeError as VmRuntimeError;
+use std::collections::LinkedList;
 
-#[derive(Debug)]
+/// Getters for the fields an error's position and message are kept in,
+/// and builders for the optional ones.
+macro_rules! error_fields {
+    ($error:ty) => {
+        impl $error {
+            pub fn line(&self) -> i32 {
+                self.line
+            }
+
+            /// The 1-based column the error was found at, if known.
+            pub fn column(&self) -> Option<usize> {
+                self.column
+            }
+
+            pub fn message(&self) -> &str {
+                &self.reason
+            }
+
+            /// The bytes of source the error was found at, if known.
+            pub fn span(&self) -> Option<Span> {
+                self.span
+            }
+
+            /// The source text the error was found at, if known.
+            pub fn lexeme(&self) -> Option<&str> {
+                self.lexeme.as_deref()
+            }
+
+            /// The script the error was found in, if it was read from one.
+            pub fn source_name(&self) -> Option<&str> {
+                self.source_name.as_deref()
+            }
+
+            pub fn with_column(mut self, column: usize) -> Self {
+                self.column = Some(column);
+                self
+            }
+
+            pub fn with_span(mut self, span: Span) -> Self {
+                self.span = Some(span);
+                self
+            }
+
+            pub fn with_lexeme(mut self, lexeme: impl Into<String>) -> Self {
+                self.lexeme = Some(lexeme.into());
+                self
+            }
+
+            pub fn with_source_name(mut self, name: impl Into<String>) -> Self {
+                self.source_name = Some(name.into().into_boxed_str());
+                self
+            }
+        }
+    };
+}
+pub(crate) use error_fields;
+
+#[derive(Debug, Default)]
 pub struct ScanError {
-    line: i32,
-    reason: String,
+    pub line: i32,
+    pub reason: String,
+    pub column: Option<usize>,
+    pub span: Option<Span>,
+    pub lexeme: Option<String>,
+    pub source_name: Option<Box<str>>,
 }
 
 impl std::fmt::Display for ScanError {
@@ -15,14 +80,24 @@ impl std::error::Error for ScanError {}
 
 impl ScanError {
     pub fn new(line: i32, reason: String) -> ScanError {
-        ScanError { line, reason }
+        ScanError {
+            line,
+            reason,
+            ..ScanError::default()
+        }
     }
 }
 
-#[derive(Debug)]
+error_fields!(ScanError);
+
+#[derive(Debug, Default)]
 pub struct ParseError {
-    line: i32,
-    reason: String,
+    pub line: i32,
+    pub reason: String,
+    pub column: Option<usize>,
+    pub span: Option<Span>,
+    pub lexeme: Option<String>,
+    pub source_name: Option<Box<str>>,
 }
 
 impl std::fmt::Display for ParseError {
@@ -34,23 +109,203 @@ impl std::error::Error for ParseError {}
 
 impl ParseError {
     pub fn new(line: i32, reason: String) -> ParseError {
-        ParseError { line, reason }
+        ParseError {
+            line,
+            reason,
+            ..ParseError::default()
+        }
+    }
+}
+
+error_fields!(ParseError);
+
+#[derive(Debug, Default)]
+pub struct ResolveError {
+    pub line: i32,
+    pub reason: String,
+    pub column: Option<usize>,
+    pub span: Option<Span>,
+    pub lexeme: Option<String>,
+    pub source_name: Option<Box<str>>,
+}
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Resolver Error: Line {}, {}", self.line, self.reason)
+    }
+}
+impl std::error::Error for ResolveError {}
+
+impl ResolveError {
+    pub fn new(line: i32, reason: String) -> ResolveError {
+        ResolveError {
+            line,
+            reason,
+            ..ResolveError::default()
+        }
+    }
+}
+
+error_fields!(ResolveError);
+
+/// What went wrong at runtime, for hosts and tests to match on without
+/// reading the message. Its Display is the message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// Operands of the wrong type: the types wanted, and the values given.
+    TypeMismatch {
+        expected: String,
+        found: String,
+    },
+    UndefinedVariable {
+        name: String,
+    },
+    /// A property missing from an instance, which names the instance's
+    /// class and members when there is one.
+    UndefinedProperty {
+        name: String,
+        on: Option<Box<Members>>,
+    },
+    ArityMismatch {
+        callee: String,
+        expected: usize,
+        got: usize,
+    },
+    DivisionByZero,
+    NotCallable,
+    StackOverflow,
+    /// Stopped by an interrupt, such as Ctrl-C.
+    Interrupted,
+    /// Any other failure, by its message.
+    Other(String),
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::TypeMismatch { expected, found } => {
+                write!(f, "Expected {} but got {}.", expected, found)
+            }
+            ErrorKind::UndefinedVariable { name } => write!(f, "Undefined variable '{}'.", name),
+            ErrorKind::UndefinedProperty { name, on } => {
+                write!(f, "Undefined property '{}'", name)?;
+                match on {
+                    Some(members) => {
+                        write!(f, " on {} instance.", members.class)?;
+                        write_known(f, "fields", &members.fields)?;
+                        write_known(f, "methods", &members.methods)
+                    }
+                    None => write!(f, "."),
+                }
+            }
+            ErrorKind::ArityMismatch {
+                callee,
+                expected,
+                got,
+            } => write!(
+                f,
+                "Expected {} argument{} to '{}' but got {}.",
+                expected,
+                if *expected == 1 { "" } else { "s" },
+                callee,
+                got
+            ),
+            ErrorKind::DivisionByZero => write!(f, "Divide by 0."),
+            ErrorKind::NotCallable => write!(f, "Can only call functions and classes."),
+            ErrorKind::StackOverflow => write!(f, "Stack overflow."),
+            ErrorKind::Interrupted => write!(f, "Interrupted."),
+            ErrorKind::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// The most names of each sort an undefined property error lists.
+const MAX_KNOWN: usize = 8;
+
+// " Known fields: a, b." or the like, or nothing when there are none.
+fn write_known(f: &mut std::fmt::Formatter<'_>, sort: &str, names: &[String]) -> std::fmt::Result {
+    if names.is_empty() {
+        return Ok(());
+    }
+    write!(
+        f,
+        " Known {}: {}",
+        sort,
+        names[..names.len().min(MAX_KNOWN)].join(", ")
+    )?;
+    if names.len() > MAX_KNOWN {
+        write!(f, " and {} more", names.len() - MAX_KNOWN)?;
+    }
+    write!(f, ".")
+}
+
+/// The class of an instance and the names of its fields and methods, in
+/// order and without repeats.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Members {
+    pub class: String,
+    pub fields: Vec<String>,
+    pub methods: Vec<String>,
+}
+
+impl Members {
+    pub fn new(
+        class: impl Into<String>,
+        mut fields: Vec<String>,
+        mut methods: Vec<String>,
+    ) -> Members {
+        fields.sort();
+        fields.dedup();
+        methods.sort();
+        methods.dedup();
+        Members {
+            class: class.into(),
+            fields,
+            methods,
+        }
+    }
+}
+
+impl ErrorKind {
+    pub(crate) fn type_mismatch(expected: &str, found: impl std::fmt::Display) -> ErrorKind {
+        ErrorKind::TypeMismatch {
+            expected: expected.to_string(),
+            found: found.to_string(),
+        }
     }
 }
 
 #[derive(Debug)]
 pub enum RuntimeError {
-    Reason { line: i32, reason: String },
-    ReturnValue(BasicType),
+    /// A failure, with the functions it passed out of, innermost first.
+    Reason {
+        line: i32,
+        kind: ErrorKind,
+        functions: Vec<String>,
+    },
+    ReturnValue(Value),
+    // A function returning the result of a call hands the call back to its
+    // caller, which makes it in place of recursing.
+    TailCall {
+        callee: Value,
+        arguments: LinkedList<Value>,
+        line: i32,
+    },
 }
 
 impl std::fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            RuntimeError::Reason { line, reason } => {
-                write!(f, "[Line {}] in script, Runtime Error: {}", line, reason)
+            RuntimeError::Reason { line, .. } => {
+                if *line > 0 {
+                    write!(f, "[Line {}] in script, ", line)?;
+                }
+                write!(f, "Runtime Error: ")?;
+                self.write_message(f)
+            }
+            RuntimeError::ReturnValue(_) | RuntimeError::TailCall { .. } => {
+                write!(f, "Uncaught return.")
             }
-            RuntimeError::ReturnValue(_s) => write!(f, "Uncaught return."),
         }
     }
 }
@@ -58,6 +313,231 @@ impl std::error::Error for RuntimeError {}
 
 impl RuntimeError {
     pub fn new(line: i32, reason: String) -> RuntimeError {
-        RuntimeError::Reason { line, reason }
+        RuntimeError::of(line, ErrorKind::Other(reason))
+    }
+
+    pub fn of(line: i32, kind: ErrorKind) -> RuntimeError {
+        RuntimeError::Reason {
+            line,
+            kind,
+            functions: Vec::new(),
+        }
+    }
+
+    /// The kind of a failure, or None for a return or tail call.
+    pub fn kind(&self) -> Option<&ErrorKind> {
+        match self {
+            RuntimeError::Reason { kind, .. } => Some(kind),
+            RuntimeError::ReturnValue(_) | RuntimeError::TailCall { .. } => None,
+        }
+    }
+
+    // The functions the error passed out of, outermost first, then the kind.
+    fn write_message(&self, f: &mut impl std::fmt::Write) -> std::fmt::Result {
+        match self {
+            RuntimeError::Reason {
+                kind, functions, ..
+            } => {
+                for function in functions.iter().rev() {
+                    writeln!(f, "Error in function {}", function)?;
+                }
+                write!(f, "{}", kind)
+            }
+            RuntimeError::ReturnValue(_) | RuntimeError::TailCall { .. } => {
+                write!(f, "Uncaught return.")
+            }
+        }
+    }
+}
+
+/// A Lox value of the wrong type for the Rust type it was converted to.
+#[derive(Debug, PartialEq)]
+pub struct ConversionError {
+    pub expected: &'static str,
+    pub found: String,
+}
+
+impl std::fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Expected {} but got {}.", self.expected, self.found)
+    }
+}
+impl std::error::Error for ConversionError {}
+
+impl ConversionError {
+    pub fn new(expected: &'static str, found: &impl std::fmt::Display) -> ConversionError {
+        ConversionError {
+            expected,
+            found: found.to_string(),
+        }
+    }
+}
+
+/// Why a run failed, from whichever phase failed first. Errors are also
+/// reported on stderr as they happen, including any after the first.
+#[derive(Debug)]
+pub enum LoxError {
+    Scan(ScanError),
+    /// On the VM backend, scanning, parsing and resolving happen in one
+    /// compiler pass, and all of its errors are Parse errors.
+    Parse(ParseError),
+    Resolve(ResolveError),
+    Runtime(VmRuntimeError),
+    Io(std::io::Error),
+}
+
+impl LoxError {
+    /// The error, naming the script it was found in.
+    pub fn with_source_name(self, name: impl Into<String>) -> LoxError {
+        match self {
+            LoxError::Scan(e) => LoxError::Scan(e.with_source_name(name)),
+            LoxError::Parse(e) => LoxError::Parse(e.with_source_name(name)),
+            LoxError::Resolve(e) => LoxError::Resolve(e.with_source_name(name)),
+            LoxError::Runtime(e) => LoxError::Runtime(e.with_source_name(name)),
+            LoxError::Io(e) => LoxError::Io(e),
+        }
+    }
+}
+
+impl std::fmt::Display for LoxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoxError::Scan(e) => write!(f, "{}", e),
+            LoxError::Parse(e) => write!(f, "{}", e),
+            LoxError::Resolve(e) => write!(f, "{}", e),
+            LoxError::Runtime(e) => write!(f, "{}", e),
+            LoxError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoxError::Scan(e) => Some(e),
+            LoxError::Parse(e) => Some(e),
+            LoxError::Resolve(e) => Some(e),
+            LoxError::Runtime(e) => Some(e),
+            LoxError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<ScanError> for LoxError {
+    fn from(e: ScanError) -> Self {
+        LoxError::Scan(e)
+    }
+}
+
+impl From<ParseError> for LoxError {
+    fn from(e: ParseError) -> Self {
+        LoxError::Parse(e)
+    }
+}
+
+// The compiler's errors name the token they were found at.
+impl From<CompileError> for LoxError {
+    fn from(e: CompileError) -> Self {
+        LoxError::Parse(ParseError {
+            line: e.line,
+            reason: format!("at {}: {}", e.token, e.reason),
+            column: e.column,
+            span: e.span,
+            lexeme: Some(e.token),
+            source_name: None,
+        })
+    }
+}
+
+impl From<ResolveError> for LoxError {
+    fn from(e: ResolveError) -> Self {
+        LoxError::Resolve(e)
+    }
+}
+
+// Both backends report runtime errors as a line, a kind and a message.
+impl From<RuntimeError> for LoxError {
+    fn from(e: RuntimeError) -> Self {
+        let mut reason = String::new();
+        let _ = e.write_message(&mut reason);
+        let (line, kind) = match e {
+            RuntimeError::Reason { line, kind, .. } => (line, kind),
+            RuntimeError::ReturnValue(_) | RuntimeError::TailCall { .. } => {
+                (-1, ErrorKind::Other(reason.clone()))
+            }
+        };
+        let mut e = VmRuntimeError::of(line, kind);
+        e.reason = reason;
+        LoxError::Runtime(e)
+    }
+}
+
+impl From<VmRuntimeError> for LoxError {
+    fn from(e: VmRuntimeError) -> Self {
+        LoxError::Runtime(e)
+    }
+}
+
+impl From<std::io::Error> for LoxError {
+    fn from(e: std::io::Error) -> Self {
+        LoxError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accessors() {
+        let e = ScanError::new(3, "Unterminated string.".to_string());
+        assert_eq!((e.line(), e.message()), (3, "Unterminated string."));
+        assert_eq!(
+            (e.column(), e.lexeme(), e.source_name()),
+            (None, None, None)
+        );
+        let e = e
+            .with_column(7)
+            .with_lexeme("\"abc")
+            .with_source_name("a.lox");
+        assert_eq!(e.column(), Some(7));
+        assert_eq!(e.lexeme(), Some("\"abc"));
+        assert_eq!(e.source_name(), Some("a.lox"));
+        assert_eq!(e.to_string(), "Scanner Error: Line 3, Unterminated string.");
+
+        let e = ParseError::new(1, "Expect ';'.".to_string()).with_lexeme("}");
+        assert_eq!(
+            (e.line(), e.message(), e.lexeme()),
+            (1, "Expect ';'.", Some("}"))
+        );
+        assert_eq!(e.to_string(), "Parser Error: Line 1, Expect ';'.");
+
+        let e = VmRuntimeError::new(2, "Type mismatch.".to_string()).with_source_name("b.lox");
+        assert_eq!((e.line(), e.message()), (2, "Type mismatch."));
+        assert_eq!((e.column(), e.source_name()), (None, Some("b.lox")));
+        assert_eq!(
+            e.to_string(),
+            "[Line 2] in script, Runtime Error: Type mismatch."
+        );
+    }
+
+    #[test]
+    fn test_compile_error_fields() {
+        let e = CompileError {
+            line: 4,
+            token: "+".to_string(),
+            reason: "Expect expression.".to_string(),
+            column: Some(9),
+            span: Some(Span::new(12, 13)),
+        };
+        assert_eq!((e.line(), e.column()), (4, Some(9)));
+        assert_eq!((e.message(), e.lexeme()), ("Expect expression.", Some("+")));
+        let LoxError::Parse(e) = LoxError::from(e).with_source_name("c.lox") else {
+            panic!("Expect a parse error.");
+        };
+        assert_eq!(e.message(), "at +: Expect expression.");
+        assert_eq!((e.column(), e.lexeme()), (Some(9), Some("+")));
+        assert_eq!(e.span(), Some(Span::new(12, 13)));
+        assert_eq!(e.source_name(), Some("c.lox"));
     }
 }
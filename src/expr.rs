@@ -1,8 +1,9 @@
-use crate::token::{BasicType, Token};
+use crate::token::{Span, Token};
+use crate::value::Value;
 use std::collections::LinkedList;
 use std::fmt;
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub enum Expr {
     Binary {
         left: Box<Expr>,
@@ -18,11 +19,14 @@ pub enum Expr {
         object: Box<Expr>,
         name: Token,
     },
+    // Grouping and literals keep the span of the tokens they drop.
     Grouping {
         expression: Box<Expr>,
+        span: Span,
     },
     Literal {
-        value: BasicType,
+        value: Value,
+        span: Span,
     },
     Logical {
         left: Box<Expr>,
@@ -58,6 +62,64 @@ pub enum Expr {
     },
 }
 
+/// A pass over expressions, with a method for each kind that takes its fields.
+/// A new kind of expression adds a method here, so the compiler points out
+/// every pass it has to be handled in.
+pub trait ExprVisitor<R> {
+    fn visit_binary(&mut self, left: &Expr, operator: &Token, right: &Expr) -> R;
+    fn visit_call(&mut self, callee: &Expr, paren: &Token, arguments: &LinkedList<Box<Expr>>) -> R;
+    fn visit_get(&mut self, object: &Expr, name: &Token) -> R;
+    fn visit_grouping(&mut self, expression: &Expr, span: Span) -> R;
+    fn visit_literal(&mut self, value: &Value, span: Span) -> R;
+    fn visit_logical(&mut self, left: &Expr, operator: &Token, right: &Expr) -> R;
+    fn visit_set(&mut self, object: &Expr, name: &Token, value: &Expr) -> R;
+    fn visit_super(&mut self, keyword: &Token, method: &Token, id: u64) -> R;
+    fn visit_this(&mut self, keyword: &Token, id: u64) -> R;
+    fn visit_unary(&mut self, operator: &Token, right: &Expr) -> R;
+    fn visit_variable(&mut self, name: &Token, id: u64) -> R;
+    fn visit_assign(&mut self, name: &Token, value: &Expr, id: u64) -> R;
+}
+
+impl Expr {
+    /// Calls the visitor's method for this kind of expression.
+    pub fn accept<R, V: ExprVisitor<R> + ?Sized>(&self, visitor: &mut V) -> R {
+        match self {
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => visitor.visit_binary(left, operator, right),
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+            } => visitor.visit_call(callee, paren, arguments),
+            Expr::Get { object, name } => visitor.visit_get(object, name),
+            Expr::Grouping { expression, span } => visitor.visit_grouping(expression, *span),
+            Expr::Literal { value, span } => visitor.visit_literal(value, *span),
+            Expr::Logical {
+                left,
+                operator,
+                right,
+            } => visitor.visit_logical(left, operator, right),
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => visitor.visit_set(object, name, value),
+            Expr::Super {
+                keyword,
+                method,
+                id,
+            } => visitor.visit_super(keyword, method, *id),
+            Expr::This { keyword, id } => visitor.visit_this(keyword, *id),
+            Expr::Unary { operator, right } => visitor.visit_unary(operator, right),
+            Expr::Variable { name, id } => visitor.visit_variable(name, *id),
+            Expr::Assign { name, value, id } => visitor.visit_assign(name, value, *id),
+        }
+    }
+}
+
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -70,10 +132,10 @@ impl fmt::Display for Expr {
                 callee,
                 paren,
                 arguments: _,
-            } => write!(f, "{} {}", callee, paren.lexeme.clone().unwrap()),
-            Expr::Get { object, name } => write!(f, "{}.{}", object, name.lexeme.clone().unwrap()),
-            Expr::Grouping { expression } => write!(f, "({})", expression),
-            Expr::Literal { value } => write!(f, "{}", value), // Don't know why but it works.
+            } => write!(f, "{} {}", callee, paren.lexeme),
+            Expr::Get { object, name } => write!(f, "{}.{}", object, name.lexeme),
+            Expr::Grouping { expression, .. } => write!(f, "({})", expression),
+            Expr::Literal { value, .. } => write!(f, "{}", value), // Don't know why but it works.
             Expr::Logical {
                 left,
                 operator,
@@ -88,13 +150,103 @@ impl fmt::Display for Expr {
                 keyword: _,
                 method,
                 id,
-            } => write!(f, "super {} {}", method.lexeme.clone().unwrap(), id),
+            } => write!(f, "super {} {}", method.lexeme, id),
             Expr::This { keyword: _, id } => write!(f, "this {}", id),
             Expr::Unary { operator, right } => write!(f, "({} {})", operator, right),
-            Expr::Variable { name, id } => write!(f, "{} {}", name.lexeme.clone().unwrap(), id),
+            Expr::Variable { name, id } => write!(f, "{} {}", name.lexeme, id),
             Expr::Assign { name, value, id } => {
-                write!(f, "({} {} = {})", name.lexeme.clone().unwrap(), value, id)
+                write!(f, "({} {} = {})", name.lexeme, value, id)
+            }
+        }
+    }
+}
+
+// Structural equality: the ids the parser numbers variables with differ
+// between parses of the same source, so they're ignored, as are spans.
+impl PartialEq for Expr {
+    fn eq(&self, other: &Expr) -> bool {
+        match (self, other) {
+            (
+                Expr::Binary {
+                    left,
+                    operator,
+                    right,
+                },
+                Expr::Binary {
+                    left: l,
+                    operator: o,
+                    right: r,
+                },
+            )
+            | (
+                Expr::Logical {
+                    left,
+                    operator,
+                    right,
+                },
+                Expr::Logical {
+                    left: l,
+                    operator: o,
+                    right: r,
+                },
+            ) => left == l && operator == o && right == r,
+            (
+                Expr::Call {
+                    callee,
+                    paren,
+                    arguments,
+                },
+                Expr::Call {
+                    callee: c,
+                    paren: p,
+                    arguments: a,
+                },
+            ) => callee == c && paren == p && arguments == a,
+            (Expr::Get { object, name }, Expr::Get { object: o, name: n }) => {
+                object == o && name == n
+            }
+            (Expr::Grouping { expression, .. }, Expr::Grouping { expression: e, .. }) => {
+                expression == e
             }
+            (Expr::Literal { value, .. }, Expr::Literal { value: v, .. }) => value == v,
+            (
+                Expr::Set {
+                    object,
+                    name,
+                    value,
+                },
+                Expr::Set {
+                    object: o,
+                    name: n,
+                    value: v,
+                },
+            ) => object == o && name == n && value == v,
+            (
+                Expr::Super {
+                    keyword, method, ..
+                },
+                Expr::Super {
+                    keyword: k,
+                    method: m,
+                    ..
+                },
+            ) => keyword == k && method == m,
+            (Expr::This { keyword, .. }, Expr::This { keyword: k, .. }) => keyword == k,
+            (
+                Expr::Unary { operator, right },
+                Expr::Unary {
+                    operator: o,
+                    right: r,
+                },
+            ) => operator == o && right == r,
+            (Expr::Variable { name, .. }, Expr::Variable { name: n, .. }) => name == n,
+            (
+                Expr::Assign { name, value, .. },
+                Expr::Assign {
+                    name: n, value: v, ..
+                },
+            ) => name == n && value == v,
+            _ => false,
         }
     }
 }
@@ -113,8 +265,8 @@ impl Expr {
                 arguments: _,
             } => paren.line,
             Expr::Get { object: _, name } => name.line,
-            Expr::Grouping { expression: _ } => -1,
-            Expr::Literal { value: _ } => -1,
+            Expr::Grouping { .. } => -1,
+            Expr::Literal { .. } => -1,
             Expr::Logical {
                 left: _,
                 operator,
@@ -140,4 +292,47 @@ impl Expr {
             } => name.line,
         }
     }
+
+    /// The bytes of source the expression was parsed from.
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+                left.span().merge(right.span())
+            }
+            Expr::Call { callee, paren, .. } => callee.span().merge(paren.span),
+            Expr::Get { object, name } => object.span().merge(name.span),
+            Expr::Grouping { span, .. } | Expr::Literal { span, .. } => *span,
+            Expr::Set { object, value, .. } => object.span().merge(value.span()),
+            Expr::Super {
+                keyword, method, ..
+            } => keyword.span.merge(method.span),
+            Expr::This { keyword, .. } => keyword.span,
+            Expr::Unary { operator, right } => operator.span.merge(right.span()),
+            Expr::Variable { name, .. } => name.span,
+            Expr::Assign { name, value, .. } => name.span.merge(value.span()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse_source;
+    use crate::stmt::Stmt;
+    use crate::token::Span;
+
+    #[test]
+    fn test_spans() {
+        let source = "print -(1 + 2) * abc.d;\nvar s = \"é\" + s;";
+        let stmts = parse_source(source).unwrap();
+        let Stmt::Print { expression } = &**stmts.front().unwrap() else {
+            panic!("Expect a print statement.");
+        };
+        assert_eq!(
+            &source[expression.span().start..expression.span().end],
+            "-(1 + 2) * abc.d"
+        );
+        let spans: Vec<_> = stmts.iter().map(|s| s.span().unwrap()).collect();
+        assert_eq!(spans, [Span::new(6, 22), Span::new(28, 40)]);
+        assert_eq!(&source[28..40], "s = \"é\" + s");
+    }
 }
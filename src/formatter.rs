@@ -0,0 +1,548 @@
+use crate::chunk::GlobalNames;
+use crate::compile::compile_to;
+use crate::error::{LoxError, ParseError};
+use crate::expr::{Expr, ExprVisitor};
+use crate::parser::parser_to;
+use crate::scanner::scan_source;
+use crate::stmt::{Stmt, StmtVisitor};
+use crate::token::{Span, Token, TokenType};
+use crate::value::Value;
+use std::collections::LinkedList;
+use std::io::Write;
+
+const INDENT: &str = "    ";
+
+/// Prints a program in the canonical style: four-space indents, one
+/// statement per line, spaces around binary operators and opening braces on
+/// the line they belong to. Numbers print as Lox prints them.
+///
+/// The scanner drops comments, so they are put back from the source: a
+/// comment on a line of its own goes before the statement that follows it,
+/// and one after code goes at the end of the line that code ends up on. At
+/// most one blank line is kept between statements.
+///
+/// Only what the tree-walk parser accepts can be formatted. Source only the
+/// VM compiles, such as arrays and maps, is one unsupported syntax error.
+/// Errors are reported to err, and the first is returned.
+///
+/// ```
+/// use lox::formatter::format_source;
+///
+/// let source = "var a=1;// one\nif(a>0){print a*2;}else print-a;";
+/// let formatted = format_source(source, &mut std::io::sink()).unwrap();
+/// assert_eq!(
+///     formatted,
+///     "var a = 1; // one\nif (a > 0) {\n    print a * 2;\n} else print -a;\n"
+/// );
+/// ```
+pub fn format_source(source: &str, err: &mut dyn Write) -> Result<String, LoxError> {
+    let mut errors = Vec::new();
+    let formatted = format_parsed(source, &mut errors);
+    let vm_only = || {
+        compile_to(
+            source,
+            1,
+            &mut GlobalNames::default(),
+            &mut std::io::sink(),
+            false,
+        )
+        .is_ok()
+    };
+    match formatted {
+        Err(LoxError::Scan(e)) if vm_only() => Err(unsupported(e.line, err)),
+        Err(LoxError::Parse(e)) if vm_only() => Err(unsupported(e.line, err)),
+        formatted => {
+            let _ = err.write_all(&errors);
+            formatted
+        }
+    }
+}
+
+fn unsupported(line: i32, err: &mut dyn Write) -> LoxError {
+    let e = ParseError::new(
+        line,
+        "Unsupported syntax: only the VM backend parses this, and the formatter can't format it yet."
+            .to_string(),
+    );
+    let _ = writeln!(err, "{}", e);
+    e.into()
+}
+
+fn format_parsed(source: &str, err: &mut dyn Write) -> Result<String, LoxError> {
+    let mut tokens = scan_source(source, 1).map_err(|errors| {
+        for e in &errors {
+            let _ = writeln!(err, "{}", e);
+        }
+        errors.into_iter().next().unwrap()
+    })?;
+    // The printer writes the tokens the parser read, in the same order, so
+    // the nth token it writes came from the line the nth token was on.
+    let mut lines: Vec<i32> = tokens.iter().map(|token| token.line).collect();
+    lines.pop();
+    lines.push(i32::MAX);
+    let stmts = parser_to(&mut tokens, err)?;
+
+    let mut printer = Printer::new(source, lines);
+    for stmt in &stmts {
+        printer.start_line();
+        stmt.accept(&mut printer);
+        printer.end_line();
+    }
+    printer.flush_comments();
+    debug_assert_eq!(printer.next, printer.lines.len() - 1);
+    Ok(printer.out)
+}
+
+/// Prints a syntax tree as source in the canonical style of format_source.
+/// Parsing what it prints gives back an equal tree.
+///
+/// ```
+/// use lox::formatter::print_program;
+/// use lox::parse_source;
+///
+/// let stmts = parse_source("var a=1;print(a+2)*3;").unwrap();
+/// assert_eq!(print_program(&stmts), "var a = 1;\nprint (a + 2) * 3;\n");
+/// ```
+pub fn print_program(stmts: &LinkedList<Box<Stmt>>) -> String {
+    let mut printer = Printer::new("", Vec::new());
+    for stmt in stmts {
+        printer.start_line();
+        stmt.accept(&mut printer);
+        printer.end_line();
+    }
+    printer.out
+}
+
+struct Comment {
+    line: i32,
+    text: String,
+    // Whether code comes before it on its line.
+    trailing: bool,
+}
+
+// Finds each line's comment, skipping "//" inside strings.
+fn comments(source: &str) -> Vec<Comment> {
+    let mut comments = Vec::new();
+    for (line, text) in (1..).zip(source.lines()) {
+        let mut in_string = false;
+        let mut chars = text.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => in_string = !in_string,
+                '/' if !in_string && chars.peek().is_some_and(|&(_, next)| next == '/') => {
+                    comments.push(Comment {
+                        line,
+                        text: text[i..].trim_end().to_string(),
+                        trailing: !text[..i].trim().is_empty(),
+                    });
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    comments
+}
+
+struct Printer {
+    out: String,
+    indent: usize,
+    // The line of each token the parser read, then one past the end. A tree
+    // printed without its source has none, and stays on the last line.
+    lines: Vec<i32>,
+    next: usize,
+    comments: Vec<Comment>,
+    pending: usize,
+    // The last source line anything written came from.
+    last_line: i32,
+    // Nothing written yet in this block, so no blank line is kept.
+    fresh: bool,
+}
+
+impl Printer {
+    fn new(source: &str, lines: Vec<i32>) -> Printer {
+        Printer {
+            out: String::new(),
+            indent: 0,
+            lines,
+            next: 0,
+            comments: comments(source),
+            pending: 0,
+            last_line: 0,
+            fresh: true,
+        }
+    }
+
+    fn next_line(&self) -> i32 {
+        self.lines.get(self.next).copied().unwrap_or(self.last_line)
+    }
+
+    fn token(&mut self, text: &str) {
+        self.out.push_str(text);
+        self.last_line = self.next_line();
+        self.next += 1;
+    }
+
+    fn name(&mut self, name: &Token) {
+        self.token(&name.lexeme);
+    }
+
+    fn space(&mut self) {
+        self.out.push(' ');
+    }
+
+    fn write_indent(&mut self) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    // Keeps one blank line where the source had any before line.
+    fn blank_line_before(&mut self, line: i32) {
+        if !self.fresh && line > self.last_line + 1 {
+            self.out.push('\n');
+        }
+        self.fresh = false;
+    }
+
+    // Writes the comments before line, each on a line of its own.
+    fn comments_before(&mut self, line: i32) {
+        while self.pending < self.comments.len() && self.comments[self.pending].line < line {
+            let comment = &self.comments[self.pending];
+            let (line, text) = (comment.line, comment.text.clone());
+            self.pending += 1;
+            self.blank_line_before(line);
+            self.write_indent();
+            self.out.push_str(&text);
+            self.out.push('\n');
+            self.last_line = line;
+        }
+    }
+
+    fn flush_comments(&mut self) {
+        self.comments_before(i32::MAX);
+    }
+
+    fn start_line(&mut self) {
+        let line = self.next_line();
+        self.comments_before(line);
+        self.blank_line_before(line);
+        self.write_indent();
+    }
+
+    // Ends the line, with the comment that followed its last token if no
+    // more tokens from that source line are to come.
+    fn end_line(&mut self) {
+        if let Some(comment) = self.comments.get(self.pending) {
+            if comment.trailing && comment.line <= self.last_line && comment.line < self.next_line()
+            {
+                self.out.push(' ');
+                self.out.push_str(&comment.text);
+                self.pending += 1;
+            }
+        }
+        self.out.push('\n');
+    }
+
+    // Writes the statements between braces, the braces included.
+    fn block<'a>(&mut self, stmts: impl IntoIterator<Item = &'a Stmt>, method: bool) {
+        self.token("{");
+        let mut stmts = stmts.into_iter().peekable();
+        let has_comments = self
+            .comments
+            .get(self.pending)
+            .is_some_and(|comment| comment.line < self.next_line());
+        if stmts.peek().is_none() && !has_comments {
+            self.token("}");
+            return;
+        }
+        self.end_line();
+        self.indent += 1;
+        self.fresh = true;
+        for stmt in stmts {
+            self.start_line();
+            match stmt {
+                Stmt::Function { name, params, body } if method => {
+                    self.function(name, params, body)
+                }
+                stmt => stmt.accept(self),
+            }
+            self.end_line();
+        }
+        self.comments_before(self.next_line());
+        self.indent -= 1;
+        self.write_indent();
+        self.token("}");
+    }
+
+    // A function from its name on, as declared or as a method.
+    fn function(&mut self, name: &Token, params: &LinkedList<Token>, body: &LinkedList<Box<Stmt>>) {
+        self.name(name);
+        self.token("(");
+        for (i, param) in params.iter().enumerate() {
+            if i > 0 {
+                self.token(",");
+                self.space();
+            }
+            self.name(param);
+        }
+        self.token(")");
+        self.space();
+        self.block(body.iter().map(|s| &**s), false);
+    }
+
+    // A binary or logical operation, with spaces around the operator.
+    fn operation(&mut self, left: &Expr, operator: &Token, right: &Expr) {
+        left.accept(self);
+        self.space();
+        self.token(symbol(operator.ttype));
+        self.space();
+        right.accept(self);
+    }
+}
+
+impl StmtVisitor<()> for Printer {
+    fn visit_block(&mut self, statements: &LinkedList<Box<Stmt>>) {
+        self.block(statements.iter().map(|s| &**s), false);
+    }
+
+    fn visit_class(
+        &mut self,
+        name: &Token,
+        superclass: Option<&Expr>,
+        methods: &LinkedList<Box<Stmt>>,
+    ) {
+        self.token("class");
+        self.space();
+        self.name(name);
+        if let Some(superclass) = superclass {
+            self.space();
+            self.token("<");
+            self.space();
+            superclass.accept(self);
+        }
+        self.space();
+        self.block(methods.iter().map(|s| &**s), true);
+    }
+
+    fn visit_expression(&mut self, expression: &Expr) {
+        expression.accept(self);
+        self.token(";");
+    }
+
+    fn visit_function(
+        &mut self,
+        name: &Token,
+        params: &LinkedList<Token>,
+        body: &LinkedList<Box<Stmt>>,
+    ) {
+        self.token("fun");
+        self.space();
+        self.function(name, params, body);
+    }
+
+    fn visit_if(&mut self, condition: &Expr, then_branch: &Stmt, else_branch: Option<&Stmt>) {
+        self.token("if");
+        self.space();
+        self.token("(");
+        condition.accept(self);
+        self.token(")");
+        self.space();
+        then_branch.accept(self);
+        if let Some(else_branch) = else_branch {
+            if let Stmt::Block { .. } = then_branch {
+                self.space();
+            } else {
+                self.end_line();
+                self.write_indent();
+            }
+            self.token("else");
+            self.space();
+            else_branch.accept(self);
+        }
+    }
+
+    fn visit_print(&mut self, expression: &Expr) {
+        self.token("print");
+        self.space();
+        expression.accept(self);
+        self.token(";");
+    }
+
+    fn visit_return(&mut self, value: Option<&Expr>) {
+        self.token("return");
+        if let Some(value) = value {
+            self.space();
+            value.accept(self);
+        }
+        self.token(";");
+    }
+
+    fn visit_var(&mut self, name: &Token, initializer: Option<&Expr>) {
+        self.token("var");
+        self.space();
+        self.name(name);
+        if let Some(initializer) = initializer {
+            self.space();
+            self.token("=");
+            self.space();
+            initializer.accept(self);
+        }
+        self.token(";");
+    }
+
+    fn visit_while(&mut self, condition: &Expr, body: &Stmt) {
+        self.token("while");
+        self.space();
+        self.token("(");
+        condition.accept(self);
+        self.token(")");
+        self.space();
+        body.accept(self);
+    }
+}
+
+impl ExprVisitor<()> for Printer {
+    fn visit_binary(&mut self, left: &Expr, operator: &Token, right: &Expr) {
+        self.operation(left, operator, right);
+    }
+
+    fn visit_call(&mut self, callee: &Expr, _: &Token, arguments: &LinkedList<Box<Expr>>) {
+        callee.accept(self);
+        self.token("(");
+        for (i, argument) in arguments.iter().enumerate() {
+            if i > 0 {
+                self.token(",");
+                self.space();
+            }
+            argument.accept(self);
+        }
+        self.token(")");
+    }
+
+    fn visit_get(&mut self, object: &Expr, name: &Token) {
+        object.accept(self);
+        self.token(".");
+        self.name(name);
+    }
+
+    fn visit_grouping(&mut self, expression: &Expr, _: Span) {
+        self.token("(");
+        expression.accept(self);
+        self.token(")");
+    }
+
+    fn visit_literal(&mut self, value: &Value, _: Span) {
+        match value {
+            Value::String(s) => self.token(&format!("\"{}\"", s)),
+            Value::None => self.token("nil"),
+            val => self.token(&val.to_string()),
+        }
+    }
+
+    fn visit_logical(&mut self, left: &Expr, operator: &Token, right: &Expr) {
+        self.operation(left, operator, right);
+    }
+
+    fn visit_set(&mut self, object: &Expr, name: &Token, value: &Expr) {
+        object.accept(self);
+        self.token(".");
+        self.name(name);
+        self.space();
+        self.token("=");
+        self.space();
+        value.accept(self);
+    }
+
+    fn visit_super(&mut self, _: &Token, method: &Token, _: u64) {
+        self.token("super");
+        self.token(".");
+        self.name(method);
+    }
+
+    fn visit_this(&mut self, _: &Token, _: u64) {
+        self.token("this");
+    }
+
+    fn visit_unary(&mut self, operator: &Token, right: &Expr) {
+        self.token(symbol(operator.ttype));
+        right.accept(self);
+    }
+
+    fn visit_variable(&mut self, name: &Token, _: u64) {
+        self.name(name);
+    }
+
+    fn visit_assign(&mut self, name: &Token, value: &Expr, _: u64) {
+        self.name(name);
+        self.space();
+        self.token("=");
+        self.space();
+        value.accept(self);
+    }
+}
+
+pub(crate) fn symbol(ttype: TokenType) -> &'static str {
+    match ttype {
+        TokenType::Minus => "-",
+        TokenType::Plus => "+",
+        TokenType::Slash => "/",
+        TokenType::Star => "*",
+        TokenType::Bang => "!",
+        TokenType::BangEqual => "!=",
+        TokenType::EqualEqual => "==",
+        TokenType::Greater => ">",
+        TokenType::GreaterEqual => ">=",
+        TokenType::Less => "<",
+        TokenType::LessEqual => "<=",
+        TokenType::And => "and",
+        TokenType::Or => "or",
+        ttype => unreachable!("{:?} is not an operator", ttype),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn format(source: &str) -> String {
+        format_source(source, &mut std::io::sink()).unwrap()
+    }
+
+    #[test]
+    fn test_golden() {
+        let source = include_str!("../tests/input/format.lox");
+        let expected = include_str!("../tests/input/format.out");
+        assert_eq!(format(source), expected);
+    }
+
+    #[test]
+    fn test_idempotent() {
+        // Every test script the tree-walk parser accepts formats the same
+        // way a second time.
+        for entry in fs::read_dir("tests/input").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "lox") {
+                continue;
+            }
+            let source = fs::read_to_string(&path).unwrap();
+            if let Ok(once) = format_source(&source, &mut std::io::sink()) {
+                assert_eq!(format(&once), once, "{}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_eq!(
+            format("fun f() { // why\n}\nfun g() {} // empty\n"),
+            "fun f() { // why\n}\nfun g() {} // empty\n"
+        );
+        assert_eq!(
+            format("{print 1;}// after\n\n\n// alone"),
+            "{\n    print 1;\n} // after\n\n// alone\n"
+        );
+        assert!(format_source("print 1 +;", &mut std::io::sink()).is_err());
+    }
+}
@@ -0,0 +1,361 @@
+use crate::object::{Class, Closure, Instance, Upvalue};
+use crate::shared::{Shared, SharedCell, WeakShared};
+use crate::value::{MapKey, Value};
+use std::collections::{HashMap, HashSet};
+
+type MapCell = SharedCell<HashMap<MapKey, Value>>;
+
+// Tracked objects before the first collection; afterwards a collection runs
+// whenever the tracked count doubles from what survived the last one.
+const GC_INITIAL: usize = 1024;
+
+// Reference counting frees acyclic garbage by itself. Every cycle has to run
+// through a RefCell, so the heap only tracks the objects behind one and, on
+// collection, empties those that are unreachable to break their cycles.
+enum HeapRef {
+    Instance(WeakShared<SharedCell<Instance>>),
+    Class(WeakShared<SharedCell<Class>>),
+    Array(WeakShared<SharedCell<Vec<Value>>>),
+    Map(WeakShared<MapCell>),
+    Upvalue(WeakShared<SharedCell<Upvalue>>),
+}
+
+// A tracked object held for the length of a collection.
+enum Object {
+    Instance(Shared<SharedCell<Instance>>),
+    Class(Shared<SharedCell<Class>>),
+    Array(Shared<SharedCell<Vec<Value>>>),
+    Map(Shared<MapCell>),
+    Upvalue(Shared<SharedCell<Upvalue>>),
+}
+
+fn address<T: ?Sized>(rc: &Shared<T>) -> usize {
+    Shared::as_ptr(rc) as *const () as usize
+}
+
+impl Object {
+    fn address(&self) -> usize {
+        match self {
+            Object::Instance(rc) => address(rc),
+            Object::Class(rc) => address(rc),
+            Object::Array(rc) => address(rc),
+            Object::Map(rc) => address(rc),
+            Object::Upvalue(rc) => address(rc),
+        }
+    }
+
+    // Less the reference this holds.
+    fn strong_count(&self) -> usize {
+        let count = match self {
+            Object::Instance(rc) => Shared::strong_count(rc),
+            Object::Class(rc) => Shared::strong_count(rc),
+            Object::Array(rc) => Shared::strong_count(rc),
+            Object::Map(rc) => Shared::strong_count(rc),
+            Object::Upvalue(rc) => Shared::strong_count(rc),
+        };
+        count - 1
+    }
+
+    // Calls found with the address of each object this one refers to.
+    fn references(&self, found: &mut impl FnMut(usize)) {
+        match self {
+            Object::Instance(rc) => {
+                let instance = rc.borrow();
+                found(address(&instance.klass));
+                instance
+                    .fields()
+                    .for_each(|val| value_references(val, found));
+            }
+            Object::Class(rc) => {
+                for method in rc.borrow().methods() {
+                    method.upvalues.iter().for_each(|u| found(address(u)));
+                }
+            }
+            Object::Array(rc) => rc
+                .borrow()
+                .iter()
+                .for_each(|val| value_references(val, found)),
+            Object::Map(rc) => rc
+                .borrow()
+                .values()
+                .for_each(|val| value_references(val, found)),
+            Object::Upvalue(rc) => {
+                if let Upvalue::Out(val) = &*rc.borrow() {
+                    value_references(val, found);
+                }
+            }
+        }
+    }
+
+    fn mark(&self, marker: &mut Marker) {
+        match self {
+            Object::Instance(rc) => marker.mark_value(&Value::Instance(rc.clone())),
+            Object::Class(rc) => marker.mark_value(&Value::Class(rc.clone())),
+            Object::Array(rc) => marker.mark_value(&Value::Array(rc.clone())),
+            Object::Map(rc) => marker.mark_value(&Value::Map(rc.clone())),
+            Object::Upvalue(rc) => marker.mark_upvalue(rc),
+        }
+    }
+}
+
+// The tracked objects a value refers to, through closures and bound methods
+// it holds inline.
+fn value_references(val: &Value, found: &mut impl FnMut(usize)) {
+    match val {
+        Value::Instance(rc) => found(address(rc)),
+        Value::Class(rc) => found(address(rc)),
+        Value::Array(rc) => found(address(rc)),
+        Value::Map(rc) => found(address(rc)),
+        Value::Closure(closure) => closure.upvalues.iter().for_each(|u| found(address(u))),
+        Value::BoundMethod(bound) => {
+            found(address(&bound.receiver));
+            bound.method.upvalues.iter().for_each(|u| found(address(u)));
+        }
+        _ => {}
+    }
+}
+
+impl HeapRef {
+    fn upgrade(&self) -> Option<Object> {
+        Some(match self {
+            HeapRef::Instance(w) => Object::Instance(w.upgrade()?),
+            HeapRef::Class(w) => Object::Class(w.upgrade()?),
+            HeapRef::Array(w) => Object::Array(w.upgrade()?),
+            HeapRef::Map(w) => Object::Map(w.upgrade()?),
+            HeapRef::Upvalue(w) => Object::Upvalue(w.upgrade()?),
+        })
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            HeapRef::Instance(w) => w.strong_count() > 0,
+            HeapRef::Class(w) => w.strong_count() > 0,
+            HeapRef::Array(w) => w.strong_count() > 0,
+            HeapRef::Map(w) => w.strong_count() > 0,
+            HeapRef::Upvalue(w) => w.strong_count() > 0,
+        }
+    }
+
+    fn address(&self) -> usize {
+        match self {
+            HeapRef::Instance(w) => w.as_ptr() as *const () as usize,
+            HeapRef::Class(w) => w.as_ptr() as *const () as usize,
+            HeapRef::Array(w) => w.as_ptr() as *const () as usize,
+            HeapRef::Map(w) => w.as_ptr() as *const () as usize,
+            HeapRef::Upvalue(w) => w.as_ptr() as *const () as usize,
+        }
+    }
+
+    // The taken contents are dropped only after the borrow is released.
+    fn clear(&self) {
+        match self {
+            HeapRef::Instance(w) => {
+                if let Some(rc) = w.upgrade() {
+                    let fields = rc.borrow_mut().take_fields();
+                    drop(fields);
+                }
+            }
+            HeapRef::Class(w) => {
+                if let Some(rc) = w.upgrade() {
+                    let methods = rc.borrow_mut().take_methods();
+                    drop(methods);
+                }
+            }
+            HeapRef::Array(w) => {
+                if let Some(rc) = w.upgrade() {
+                    let elements = std::mem::take(&mut *rc.borrow_mut());
+                    drop(elements);
+                }
+            }
+            HeapRef::Map(w) => {
+                if let Some(rc) = w.upgrade() {
+                    let entries = std::mem::take(&mut *rc.borrow_mut());
+                    drop(entries);
+                }
+            }
+            HeapRef::Upvalue(w) => {
+                if let Some(rc) = w.upgrade() {
+                    let val = rc.replace(Upvalue::Out(Value::None));
+                    drop(val);
+                }
+            }
+        }
+    }
+}
+
+pub struct Heap {
+    objects: Vec<HeapRef>,
+    next_gc: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            next_gc: GC_INITIAL,
+        }
+    }
+
+    pub fn instance(&mut self, instance: Instance) -> Shared<SharedCell<Instance>> {
+        let rc = Shared::new(SharedCell::new(instance));
+        self.objects.push(HeapRef::Instance(Shared::downgrade(&rc)));
+        rc
+    }
+
+    pub fn class(&mut self, class: Class) -> Shared<SharedCell<Class>> {
+        let rc = Shared::new(SharedCell::new(class));
+        self.objects.push(HeapRef::Class(Shared::downgrade(&rc)));
+        rc
+    }
+
+    pub fn array(&mut self, elements: Vec<Value>) -> Shared<SharedCell<Vec<Value>>> {
+        let rc = Shared::new(SharedCell::new(elements));
+        self.objects.push(HeapRef::Array(Shared::downgrade(&rc)));
+        rc
+    }
+
+    pub fn map(&mut self, entries: HashMap<MapKey, Value>) -> Shared<MapCell> {
+        let rc = Shared::new(SharedCell::new(entries));
+        self.objects.push(HeapRef::Map(Shared::downgrade(&rc)));
+        rc
+    }
+
+    pub fn upvalue(&mut self, upvalue: Upvalue) -> Shared<SharedCell<Upvalue>> {
+        let rc = Shared::new(SharedCell::new(upvalue));
+        self.objects.push(HeapRef::Upvalue(Shared::downgrade(&rc)));
+        rc
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.objects.len() >= self.next_gc
+    }
+
+    #[cfg(test)]
+    pub fn live_objects(&self) -> usize {
+        self.objects.iter().filter(|obj| obj.is_alive()).count()
+    }
+
+    // Anything else holding an object, such as a host keeping a value a run
+    // returned, makes it a root too. Those are the objects with more
+    // references than the tracked objects account for.
+    fn mark_held(&self, marker: &mut Marker) {
+        let objects: Vec<Object> = self.objects.iter().filter_map(HeapRef::upgrade).collect();
+        let mut unexplained: HashMap<usize, usize> = objects
+            .iter()
+            .map(|obj| (obj.address(), obj.strong_count()))
+            .collect();
+        for obj in &objects {
+            obj.references(&mut |address| {
+                if let Some(count) = unexplained.get_mut(&address) {
+                    *count = count.saturating_sub(1);
+                }
+            });
+        }
+        for obj in &objects {
+            if unexplained[&obj.address()] > 0 {
+                obj.mark(marker);
+            }
+        }
+    }
+
+    pub fn sweep(&mut self, mut marker: Marker) {
+        self.mark_held(&mut marker);
+        marker.trace();
+        for obj in &self.objects {
+            if obj.is_alive() && !marker.marked.contains(&obj.address()) {
+                obj.clear();
+            }
+        }
+        self.objects.retain(HeapRef::is_alive);
+        self.next_gc = GC_INITIAL.max(self.objects.len() * 2);
+    }
+}
+
+enum Gray {
+    Value(Value),
+    Upvalue(Shared<SharedCell<Upvalue>>),
+}
+
+// Marks everything reachable from the roots it is given. Tracing uses an
+// explicit worklist so long chains of objects cannot overflow the stack.
+#[derive(Default)]
+pub struct Marker {
+    marked: HashSet<usize>,
+    gray: Vec<Gray>,
+}
+
+impl Marker {
+    pub fn mark_value(&mut self, val: &Value) {
+        self.gray.push(Gray::Value(val.clone()));
+    }
+
+    pub fn mark_closure(&mut self, closure: &Closure) {
+        for upvalue in &closure.upvalues {
+            self.mark_upvalue(upvalue);
+        }
+    }
+
+    pub fn mark_upvalue(&mut self, upvalue: &Shared<SharedCell<Upvalue>>) {
+        self.gray.push(Gray::Upvalue(upvalue.clone()));
+    }
+
+    fn visit<T>(&mut self, rc: &Shared<T>) -> bool {
+        self.marked.insert(Shared::as_ptr(rc) as *const () as usize)
+    }
+
+    fn trace(&mut self) {
+        while let Some(gray) = self.gray.pop() {
+            match gray {
+                Gray::Upvalue(upvalue) => {
+                    if self.visit(&upvalue) {
+                        if let Upvalue::Out(val) = &*upvalue.borrow() {
+                            self.mark_value(val);
+                        }
+                    }
+                }
+                Gray::Value(Value::Closure(closure)) => self.mark_closure(&closure),
+                Gray::Value(Value::BoundMethod(bound)) => {
+                    self.mark_value(&Value::Instance(bound.receiver.clone()));
+                    self.mark_closure(&bound.method);
+                }
+                Gray::Value(Value::Class(class)) => {
+                    if self.visit(&class) {
+                        for method in class.borrow().methods() {
+                            self.mark_closure(method);
+                        }
+                    }
+                }
+                Gray::Value(Value::Instance(instance)) => {
+                    if self.visit(&instance) {
+                        let instance = instance.borrow();
+                        self.mark_value(&Value::Class(instance.klass.clone()));
+                        for val in instance.fields() {
+                            self.mark_value(val);
+                        }
+                    }
+                }
+                Gray::Value(Value::Array(array)) => {
+                    if self.visit(&array) {
+                        for val in array.borrow().iter() {
+                            self.mark_value(val);
+                        }
+                    }
+                }
+                Gray::Value(Value::Map(map)) => {
+                    if self.visit(&map) {
+                        for val in map.borrow().values() {
+                            self.mark_value(val);
+                        }
+                    }
+                }
+                Gray::Value(_) => {}
+            }
+        }
+    }
+}
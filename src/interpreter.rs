@@ -1,32 +1,378 @@
 use crate::callable::{Callable, LoxClass, LoxFunction};
-use crate::error::RuntimeError;
+use crate::coverage::stmt_line;
+use crate::error::{ErrorKind, LoxError, RuntimeError};
 use crate::expr::Expr;
+use crate::output::Io;
+use crate::parser::parse_ast_to;
+use crate::profile::Timings;
+use crate::resolver::resolve;
+use crate::scanner::scan_source;
+use crate::shared::{Shared, SharedCell};
+use crate::stdlib;
 use crate::stmt::{Environment, Stmt};
-use crate::token::{BasicType, Token, TokenType};
-use std::cell::RefCell;
-use std::collections::{HashMap, LinkedList};
-use std::rc::Rc;
+use crate::token::{Token, TokenType};
+use crate::value::{Native, NativeFn, Value};
+use crate::vm::VmOptions;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, LinkedList};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+// Loop iterations and function calls left before the tree-walk interpreter
+// gives up, and the instant after which it stops regardless.
+struct Budget {
+    steps: Option<u64>,
+    deadline: Option<Instant>,
+}
+
+/// Nested calls allowed before the tree-walk interpreter reports a stack
+/// overflow by default, well before the native stack runs out.
+pub const MAX_CALL_DEPTH: usize = 256;
+
+/// What a run passes down to every statement it executes: where printed
+/// values go, the budget and call depth it is held to, and the statements
+/// it has executed so far.
+pub struct Context<'a> {
+    out: &'a mut dyn Write,
+    budget: Budget,
+    depth: usize,
+    max_depth: usize,
+    statements: u64,
+    // The lines of executed statements, while recording them.
+    covered: Option<&'a mut BTreeSet<i32>>,
+}
+
+impl<'a> Context<'a> {
+    /// A run printing to out, held to config's instruction, time and call
+    /// depth limits from now on.
+    pub fn new(out: &'a mut dyn Write, config: &VmOptions) -> Context<'a> {
+        Context {
+            out,
+            budget: Budget {
+                steps: config.max_instructions,
+                deadline: config.timeout.map(|t| Instant::now() + t),
+            },
+            depth: 0,
+            max_depth: config.max_call_depth,
+            statements: 0,
+            covered: None,
+        }
+    }
+
+    pub(crate) fn tick(&mut self, line: i32) -> Result<(), RuntimeError> {
+        if crate::is_interrupted() {
+            return Err(RuntimeError::of(line, ErrorKind::Interrupted));
+        }
+        let budget = &mut self.budget;
+        let out_of_steps = match &mut budget.steps {
+            Some(0) => true,
+            Some(n) => {
+                *n -= 1;
+                false
+            }
+            None => false,
+        };
+        if out_of_steps || budget.deadline.is_some_and(|d| Instant::now() >= d) {
+            Err(RuntimeError::new(
+                line,
+                "Execution budget exceeded.".to_string(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Counts a function call until the matching leave_call.
+    pub(crate) fn enter_call(&mut self, line: i32) -> Result<(), RuntimeError> {
+        if self.depth >= self.max_depth {
+            return Err(RuntimeError::of(line, ErrorKind::StackOverflow));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub(crate) fn leave_call(&mut self) {
+        self.depth -= 1;
+    }
+
+    fn in_function(&self) -> bool {
+        self.depth > 0
+    }
+
+    // Counts a statement about to run, and records its line when covering.
+    fn count(&mut self, stmt: &Stmt) {
+        self.statements += 1;
+        if let (Some(lines), Some(line)) = (self.covered.as_mut(), stmt_line(stmt)) {
+            lines.insert(line);
+        }
+    }
+}
+
+// What a run can change in a session: the globals and what the resolver
+// knows of them.
+pub(crate) struct Snapshot {
+    globals: HashMap<String, Value>,
+    scopes: LinkedList<HashMap<String, bool>>,
+    table: HashMap<u64, i32>,
+}
+
+/// The tree-walk backend's state between runs: the globals, the resolver's
+/// scopes and table of variable depths, its limits and where output goes.
+/// Each run sees the globals earlier runs defined.
+///
+/// ```
+/// use lox::interpreter::Interpreter;
+/// use lox::vm::VmOptions;
+/// use lox::Io;
+///
+/// let (io, out, _) = Io::capture();
+/// let mut interpreter = Interpreter::new(VmOptions::default(), io);
+/// interpreter.run("var a = 1;").unwrap();
+/// interpreter.run("print a + 1;").unwrap();
+/// assert_eq!(out.contents(), "2\n");
+/// ```
+pub struct Interpreter {
+    env: Shared<SharedCell<Environment>>,
+    scopes: LinkedList<HashMap<String, bool>>,
+    table: HashMap<u64, i32>,
+    config: VmOptions,
+    io: Io,
+    covered: Option<BTreeSet<i32>>,
+}
+
+impl Interpreter {
+    /// An interpreter with only the natives defined, held to config's
+    /// instruction, time and call depth limits.
+    pub fn new(config: VmOptions, io: Io) -> Interpreter {
+        let mut interpreter = Interpreter {
+            env: Shared::new(SharedCell::new(Environment::new())),
+            scopes: LinkedList::from([HashMap::new()]),
+            table: HashMap::new(),
+            config,
+            io,
+            covered: None,
+        };
+        stdlib::register_ast(&mut interpreter);
+        interpreter
+    }
+
+    /// Runs source, numbering its lines from 1.
+    pub fn run(&mut self, source: &str) -> Result<(), LoxError> {
+        self.run_from(source, 1, false, None).map(|_| ())
+    }
+
+    /// Runs source like run, returning the value of its last statement if
+    /// that is an expression, and nil otherwise.
+    pub fn eval(&mut self, source: &str) -> Result<Value, LoxError> {
+        self.run_from(source, 1, true, None)
+    }
+
+    /// Runs the script at path.
+    pub fn run_file(&mut self, path: impl AsRef<Path>) -> Result<(), LoxError> {
+        let source = fs::read_to_string(path)?;
+        self.run(&source)
+    }
+
+    pub fn io(&mut self) -> &mut Io {
+        &mut self.io
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.env.borrow().get(name, 0)
+    }
+
+    /// Every global's name and value, by name.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        self.env.borrow().flattened()
+    }
+
+    // Defines a global holding a native.
+    pub fn define_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+        let native = Native {
+            name: name.to_string(),
+            arity,
+            function,
+        };
+        self.env
+            .borrow_mut()
+            .define(name.to_string(), Value::Native(Shared::new(native)));
+        // Scripts resolve it as a global they declared.
+        if let Some(globals) = self.scopes.back_mut() {
+            globals.insert(name.to_string(), true);
+        }
+    }
+
+    // Starts or stops recording the lines of executed statements.
+    pub(crate) fn record_lines(&mut self, on: bool) {
+        self.covered = on.then(BTreeSet::new);
+    }
+
+    // The lines recorded since the last call, while recording.
+    pub(crate) fn recorded_lines(&mut self) -> BTreeSet<i32> {
+        self.covered
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            globals: self.env.borrow().snapshot(),
+            scopes: self.scopes.clone(),
+            table: self.table.clone(),
+        }
+    }
+
+    pub(crate) fn restore(&mut self, snapshot: Snapshot) {
+        self.env.borrow_mut().restore(snapshot.globals);
+        self.scopes = snapshot.scopes;
+        self.table = snapshot.table;
+    }
+
+    // Runs code the user didn't write, such as the prelude, without the
+    // limits.
+    pub(crate) fn run_quietly(&mut self, source: &str) -> Result<(), LoxError> {
+        let config = std::mem::take(&mut self.config);
+        let result = self.run_from(source, 1, false, None);
+        self.config = config;
+        result.map(|_| ())
+    }
+
+    // Runs source numbered from line_number, timing its phases if asked.
+    // Returns the value of a trailing expression statement, or of a
+    // top-level return. With eval, that statement may lack its semicolon.
+    pub(crate) fn run_from(
+        &mut self,
+        source: &str,
+        line_number: i32,
+        eval: bool,
+        mut timings: Option<&mut Timings>,
+    ) -> Result<Value, LoxError> {
+        let stmts = self.parse(source, line_number, eval, timings.as_deref_mut())?;
+        let start = timings.is_some().then(Instant::now);
+        let mut ctx = Context::new(&mut *self.io.out, &self.config);
+        ctx.covered = self.covered.as_mut();
+        let result = interpret_last(stmts, self.env.clone(), &self.table, &mut ctx);
+        if let (Some(timings), Some(start)) = (timings, start) {
+            timings.phase("execute", start.elapsed());
+            timings.count("statements", ctx.statements);
+        }
+        match result {
+            // A top-level return ends the script, as on the VM.
+            Ok(value) | Err(RuntimeError::ReturnValue(value)) => Ok(value),
+            Err(e) => {
+                let _ = writeln!(self.io.err, "{}", e);
+                Err(e.into())
+            }
+        }
+    }
+
+    // Scans, parses and resolves source, reporting errors to err.
+    pub(crate) fn parse(
+        &mut self,
+        source: &str,
+        line_number: i32,
+        eval: bool,
+        mut timings: Option<&mut Timings>,
+    ) -> Result<LinkedList<Box<Stmt>>, LoxError> {
+        let mut start = timings.is_some().then(Instant::now);
+        let mut lap = |name, timings: &mut Option<&mut Timings>| {
+            if let (Some(timings), Some(start)) = (timings, &mut start) {
+                timings.phase(name, start.elapsed());
+                *start = Instant::now();
+            }
+        };
+        let tokens = scan_source(source, line_number);
+        lap("scan", &mut timings);
+        // Report every line that fails to scan, but don't parse what's left.
+        let mut tokens = match tokens {
+            Ok(tokens) => tokens,
+            Err(errors) => {
+                for e in &errors {
+                    let _ = writeln!(self.io.err, "{}", e);
+                }
+                return Err(errors.into_iter().next().unwrap().into());
+            }
+        };
+        let ast = parse_ast_to(&mut tokens, &mut self.io.err, eval);
+        lap("parse", &mut timings);
+        let ast = ast?;
+        let resolved = resolve(&ast, &mut self.scopes, &mut self.table);
+        lap("resolve", &mut timings);
+        if let Err(e) = resolved {
+            let _ = writeln!(self.io.err, "{}", e);
+            // Close the scopes the failed resolve left open.
+            while self.scopes.len() > 1 {
+                self.scopes.pop_front();
+            }
+            return Err(e.into());
+        }
+        Ok(ast.to_tree())
+    }
+
+    pub(crate) fn call(
+        &mut self,
+        function: &LoxFunction,
+        args: &[Value],
+    ) -> Result<Value, LoxError> {
+        let mut arguments: LinkedList<Value> = args.iter().cloned().collect();
+        let mut ctx = Context::new(&mut *self.io.out, &self.config);
+        let result = function.call(&mut arguments, 0, &mut ctx);
+        result.map_err(|e| {
+            let _ = writeln!(self.io.err, "{}", e);
+            e.into()
+        })
+    }
+}
 
 pub fn interpret(
     stmts: LinkedList<Box<Stmt>>,
-    env: Rc<RefCell<Environment>>,
+    env: Shared<SharedCell<Environment>>,
     table: &HashMap<u64, i32>,
+    ctx: &mut Context,
 ) -> Result<(), RuntimeError> {
     for stmt in stmts {
-        execute(*stmt, env.clone(), table)?
+        execute(*stmt, env.clone(), table, ctx)?
     }
     Ok(())
 }
 
+// Like interpret, but evaluates a trailing expression statement for its
+// value. Anything else leaves nil.
+pub fn interpret_last(
+    mut stmts: LinkedList<Box<Stmt>>,
+    env: Shared<SharedCell<Environment>>,
+    table: &HashMap<u64, i32>,
+    ctx: &mut Context,
+) -> Result<Value, RuntimeError> {
+    let last = match stmts.back() {
+        Some(stmt) if matches!(**stmt, Stmt::Expression { .. }) => stmts.pop_back(),
+        _ => None,
+    };
+    interpret(stmts, env.clone(), table, ctx)?;
+    let Some(stmt) = last else {
+        return Ok(Value::None);
+    };
+    ctx.count(&stmt);
+    match *stmt {
+        Stmt::Expression { expression } => evaluate(*expression, env, table, ctx),
+        _ => Ok(Value::None),
+    }
+}
+
 pub fn execute(
     stmt: Stmt,
-    env: Rc<RefCell<Environment>>,
+    env: Shared<SharedCell<Environment>>,
     table: &HashMap<u64, i32>,
+    ctx: &mut Context,
 ) -> Result<(), RuntimeError> {
+    ctx.count(&stmt);
     match stmt {
         Stmt::Block { statements } => {
-            let new_env = Rc::new(RefCell::new(Environment::from(env.clone())));
-            match interpret(statements, new_env.clone(), table) {
+            let new_env = Shared::new(SharedCell::new(Environment::from(env.clone())));
+            match interpret(statements, new_env.clone(), table, ctx) {
                 Ok(()) => {}
                 Err(s) => return Err(s),
             }
@@ -37,26 +383,29 @@ pub fn execute(
             superclass,
             methods,
         } => {
-            let mut sp: Option<Rc<LoxClass>> = None;
+            let mut sp: Option<Shared<LoxClass>> = None;
             let mut local_env = env.clone();
             if let Some(expr) = superclass {
-                if let Some(val) = evaluate(*expr.clone(), env.clone(), table)
-                    .expect("Non empty")
-                    .as_class()
+                if let Some(val) = evaluate(*expr.clone(), env.clone(), table, ctx)?.as_lox_class()
                 {
                     sp = Some(val.clone());
-                    local_env = Rc::new(RefCell::new(Environment::from(env.clone())));
+                    local_env = Shared::new(SharedCell::new(Environment::from(env.clone())));
                     local_env
                         .borrow_mut()
-                        .define("super".to_string(), BasicType::Class(val));
+                        .define("super".to_string(), Value::LoxClass(val));
                 } else {
+                    // The parser only takes a name for the superclass.
+                    let superclass = match &*expr {
+                        Expr::Variable { name, .. } => name.lexeme.clone(),
+                        expr => expr.to_string(),
+                    };
                     return Err(RuntimeError::new(
                         expr.line_number(),
-                        format!("{} is not a class name.", expr),
+                        format!("{} is not a class name.", superclass),
                     ));
                 }
             }
-            local_env = Rc::new(RefCell::new(Environment::from(local_env.clone())));
+            local_env = Shared::new(SharedCell::new(Environment::from(local_env.clone())));
 
             let mut kmethods: HashMap<String, LoxFunction> = HashMap::new();
             for method in methods {
@@ -66,48 +415,29 @@ pub fn execute(
                     body,
                 } = *method
                 {
-                    let st = new_name
-                        .lexeme
-                        .clone()
-                        .unwrap()
-                        .as_string()
-                        .expect("Must be a identifier.")
-                        .clone();
+                    let st = new_name.lexeme.clone();
                     kmethods.insert(
                         st,
                         LoxFunction::new(new_name, params, body, local_env.clone(), table.clone()),
                     );
                 }
             }
-            let klass = BasicType::Class(Rc::new(LoxClass::new(name.clone(), sp, kmethods)));
-            let st = name
-                .lexeme
-                .unwrap()
-                .as_string()
-                .expect("Must be a identifier.")
-                .clone();
+            let klass = Value::LoxClass(Shared::new(LoxClass::new(name.clone(), sp, kmethods)));
+            let st = name.lexeme.clone();
             env.borrow_mut().define(st, klass);
             Ok(())
         }
-        Stmt::Expression { expression } => match evaluate(*expression, env.clone(), table) {
+        Stmt::Expression { expression } => match evaluate(*expression, env.clone(), table, ctx) {
             Err(e) => Err(e),
             _ => Ok(()),
         },
         Stmt::Function { name, params, body } => {
-            let fun = Rc::new(LoxFunction::new(
-                name.clone(),
-                params,
-                body,
-                env.clone(),
-                table.clone(),
-            ));
-            let st = name
-                .lexeme
-                .unwrap()
-                .as_string()
-                .expect("Must be a identifier.")
-                .clone();
-            env.borrow_mut().define(st, BasicType::Function(fun));
+            let fun = Shared::new(
+                LoxFunction::new(name.clone(), params, body, env.clone(), table.clone())
+                    .downgrade(),
+            );
+            let st = name.lexeme.clone();
+            env.borrow_mut().define(st, Value::LoxFunction(fun));
             Ok(())
         }
         Stmt::If {
@@ -115,98 +445,82 @@ pub fn execute(
             then_branch,
             else_branch,
         } => {
-            let is_true: bool;
-            let line_number = condition.line_number();
-            match evaluate(*condition, env.clone(), table) {
-                Err(e) => return Err(e),
-                Ok(val) => {
-                    if let Some(value) = val.as_bool() {
-                        is_true = value;
-                    } else {
-                        return Err(RuntimeError::new(
-                            line_number,
-                            "Statement in condition is not of bool type.".to_string(),
-                        ));
-                    }
-                }
-            }
-            if is_true {
-                return execute(*then_branch, env.clone(), table);
+            if evaluate(*condition, env.clone(), table, ctx)?.is_truthy() {
+                return execute(*then_branch, env.clone(), table, ctx);
             } else if let Some(branch) = else_branch {
-                return execute(*branch, env.clone(), table);
+                return execute(*branch, env.clone(), table, ctx);
             }
             Ok(())
         }
-        Stmt::Print { expression } => match evaluate(*expression, env.clone(), table) {
+        Stmt::Print { expression } => match evaluate(*expression, env.clone(), table, ctx) {
             Ok(value) => {
-                println!("{}", value);
+                let _ = writeln!(ctx.out, "{}", value);
                 Ok(())
             }
             Err(e) => Err(e),
         },
         Stmt::Return { value } => match value {
-            None => Err(RuntimeError::ReturnValue(BasicType::None)),
-            Some(expr) => match evaluate(*expr, env.clone(), table) {
-                Ok(val) => Err(RuntimeError::ReturnValue(val)),
-                Err(e) => Err(e),
-            },
-        },
-        Stmt::Var { name, initializer } => {
-            if let Some(key) = name.lexeme.unwrap().as_string() {
-                if env.borrow().is_defined(key.to_string()) {
-                    return Err(RuntimeError::new(
-                        name.line,
-                        format!("Multiple definition of some variable {}.", key),
-                    ));
-                }
-                match initializer {
-                    None => env.borrow_mut().define(key.clone(), BasicType::None),
-                    Some(val) => {
-                        let result = evaluate(*val, env.clone(), table);
-                        match result {
-                            Ok(val) => env.borrow_mut().define(key.clone(), val),
-                            Err(e) => return Err(e),
+            None => Err(RuntimeError::ReturnValue(Value::None)),
+            Some(expr) => {
+                let line = expr.line_number();
+                match *expr {
+                    // Calls in tail position are made by the calling
+                    // function's loop, so tail recursion doesn't nest.
+                    Expr::Call {
+                        callee,
+                        paren: _,
+                        arguments,
+                    } if ctx.in_function() => {
+                        let (callee, arguments) =
+                            evaluate_call(*callee, arguments, env.clone(), table, ctx)?;
+                        match callee {
+                            Value::LoxFunction(_) => Err(RuntimeError::TailCall {
+                                callee,
+                                arguments,
+                                line,
+                            }),
+                            _ => Err(RuntimeError::ReturnValue(call(
+                                callee, arguments, line, ctx,
+                            )?)),
                         }
                     }
-                };
-                Ok(())
-            } else {
-                Err(RuntimeError::new(
-                    name.line,
-                    "Invalid Variable Name".to_string(),
-                ))
-            }
-        }
-        Stmt::While { condition, body } => {
-            let mut is_true: bool;
-            match evaluate(*condition.clone(), env.clone(), table) {
-                Err(e) => return Err(e),
-                Ok(val) => {
-                    if let Some(value) = val.as_bool() {
-                        is_true = value;
-                    } else {
-                        return Err(RuntimeError::new(
-                            condition.line_number(),
-                            "Statement in condition is not of bool type.".to_string(),
-                        ));
-                    }
+                    expr => Err(RuntimeError::ReturnValue(evaluate(
+                        expr,
+                        env.clone(),
+                        table,
+                        ctx,
+                    )?)),
                 }
             }
-            while is_true {
-                execute(*body.clone(), env.clone(), table)?;
-                match evaluate(*condition.clone(), env.clone(), table) {
-                    Err(e) => return Err(e),
-                    Ok(val) => {
-                        if let Some(value) = val.as_bool() {
-                            is_true = value;
-                        } else {
-                            return Err(RuntimeError::new(
-                                condition.line_number(),
-                                "Statement in condition is not of bool type.".to_string(),
-                            ));
-                        }
+        },
+        Stmt::Var { name, initializer } => {
+            let key = name.lexeme;
+            if env.borrow().is_defined(key.clone()) {
+                return Err(RuntimeError::new(
+                    name.line,
+                    format!("Multiple definition of some variable {}.", key),
+                ));
+            }
+            match initializer {
+                None => env.borrow_mut().define(key, Value::None),
+                Some(val) => {
+                    let result = evaluate(*val, env.clone(), table, ctx);
+                    match result {
+                        Ok(val) => env.borrow_mut().define(key, val),
+                        Err(e) => return Err(e),
                     }
                 }
+            };
+            Ok(())
+        }
+        Stmt::While {
+            keyword,
+            condition,
+            body,
+        } => {
+            while evaluate(*condition.clone(), env.clone(), table, ctx)?.is_truthy() {
+                ctx.tick(keyword.line)?;
+                execute(*body.clone(), env.clone(), table, ctx)?;
             }
             Ok(())
         }
@@ -215,44 +529,29 @@ pub fn execute(
 
 pub fn evaluate(
     expr: Expr,
-    env: Rc<RefCell<Environment>>,
+    env: Shared<SharedCell<Environment>>,
     table: &HashMap<u64, i32>,
-) -> Result<BasicType, RuntimeError> {
+    ctx: &mut Context,
+) -> Result<Value, RuntimeError> {
     let line_number = expr.line_number();
     match expr {
         Expr::Binary {
             left,
             operator,
             right,
-        } => binary_eval(*left, operator, *right, env, table),
+        } => binary_eval(*left, operator, *right, env, table, ctx),
         Expr::Call {
             callee,
             paren: _,
             arguments,
         } => {
-            let callee_evaluated = evaluate(*callee, env.clone(), table)?;
-            let mut args: LinkedList<BasicType> = LinkedList::new();
-            for expr in arguments {
-                match evaluate(*expr, env.clone(), table) {
-                    Err(e) => return Err(e),
-                    Ok(val) => args.push_back(val),
-                }
-            }
-            if let BasicType::Function(val) = callee_evaluated {
-                val.call(&mut args, line_number)
-            } else if let BasicType::Class(val) = callee_evaluated {
-                val.call(&mut args, line_number)
-            } else {
-                Err(RuntimeError::new(
-                    line_number,
-                    format!("Callee {} is not a function.", callee_evaluated),
-                ))
-            }
+            let (callee, arguments) = evaluate_call(*callee, arguments, env, table, ctx)?;
+            call(callee, arguments, line_number, ctx)
         }
         Expr::Get { object, name } => {
-            let ob = evaluate(*object, env, table)?;
-            if let BasicType::Instance(val) = ob.clone() {
-                let st = name.lexeme.unwrap().as_string().unwrap();
+            let ob = evaluate(*object, env, table, ctx)?;
+            if let Value::LoxInstance(val) = ob.clone() {
+                let st = name.lexeme;
                 if val.borrow_mut().fields.contains_key(&st) {
                     return Ok(val
                         .borrow_mut()
@@ -261,19 +560,15 @@ pub fn evaluate(
                         .cloned()
                         .expect("Key is found."));
                 }
-                let mut klass = val.borrow_mut().clone().klass.clone();
-                loop {
-                    if let Some(method) = klass.find_method(st.clone()) {
-                        return Ok(BasicType::Function(Rc::new(method.bind(val))));
-                    }
-                    match klass.superclass() {
-                        None => {
-                            return Err(RuntimeError::new(
-                                line_number,
-                                "Undefined property.".to_string(),
-                            ));
-                        }
-                        Some(val) => klass = val,
+                let method = val.borrow().klass.find_method(&st);
+                match method {
+                    Some(method) => Ok(Value::LoxFunction(Shared::new(method.bind(val)))),
+                    None => {
+                        let on = Some(Box::new(val.borrow().members()));
+                        Err(RuntimeError::of(
+                            line_number,
+                            ErrorKind::UndefinedProperty { name: st, on },
+                        ))
                     }
                 }
             } else {
@@ -283,44 +578,28 @@ pub fn evaluate(
                 ))
             }
         }
-        Expr::Grouping { expression } => evaluate(*expression, env, table),
-        Expr::Literal { value } => Ok(value),
+        Expr::Grouping { expression, .. } => evaluate(*expression, env, table, ctx),
+        Expr::Literal { value, .. } => Ok(value),
         Expr::Logical {
             left,
             operator,
             right,
         } => {
-            let is_true: bool;
-            match evaluate(*left, env.clone(), table) {
-                Err(e) => return Err(e),
-                Ok(val) => {
-                    if let Some(value) = val.as_bool() {
-                        is_true = value;
-                    } else {
-                        return Err(RuntimeError::new(
-                            line_number,
-                            "Statement in condition is not of bool type.".to_string(),
-                        ));
-                    }
-                }
-            }
-            if operator.ttype == TokenType::Or {
-                if is_true {
-                    return Ok(BasicType::Bool(is_true));
-                }
-            } else if !is_true {
-                return Ok(BasicType::Bool(is_true));
+            // Short-circuiting yields the left operand itself.
+            let left = evaluate(*left, env.clone(), table, ctx)?;
+            if left.is_truthy() == (operator.ttype == TokenType::Or) {
+                return Ok(left);
             }
-            evaluate(*right, env.clone(), table)
+            evaluate(*right, env.clone(), table, ctx)
         }
         Expr::Set {
             object,
             name,
             value,
         } => {
-            let ob = evaluate(*object, env.clone(), table)?;
-            if let BasicType::Instance(val) = ob.clone() {
-                let v = evaluate(*value, env.clone(), table)?;
+            let ob = evaluate(*object, env.clone(), table, ctx)?;
+            if let Value::LoxInstance(val) = ob.clone() {
+                let v = evaluate(*value, env.clone(), table, ctx)?;
                 val.borrow_mut().set(name, v.clone());
                 Ok(v)
             } else {
@@ -336,44 +615,36 @@ pub fn evaluate(
             id,
         } => {
             let depth = table.get(&id).expect("ID automatically generated.");
-            let superclass = match env.borrow_mut().get(&"super".to_string(), *depth) {
+            let superclass = match env.borrow_mut().get("super", *depth) {
                 None => {
                     return Err(RuntimeError::new(
                         line_number,
                         "Don't know what \"super\" referred to.".to_string(),
                     ));
                 }
-                Some(val) => val.as_class().expect("Lox Class"),
+                Some(val) => val.as_lox_class().expect("Lox Class"),
             };
-            let object = match env.borrow_mut().get(&"this".to_string(), *depth - 1) {
+            let object = match env.borrow_mut().get("this", *depth - 1) {
                 None => {
                     return Err(RuntimeError::new(
                         line_number,
                         "Don't know what \"this\" referred to.".to_string(),
                     ));
                 }
-                Some(val) => val.as_instance().expect("Lox Instance"),
+                Some(val) => val.as_lox_instance().expect("Lox Instance"),
             };
-            let st = method.lexeme.unwrap().as_string().unwrap();
-            let mut klass = superclass.clone();
-            loop {
-                if let Some(method) = klass.find_method(st.clone()) {
-                    return Ok(BasicType::Function(Rc::new(method.bind(object))));
-                }
-                match klass.superclass() {
-                    None => {
-                        return Err(RuntimeError::new(
-                            line_number,
-                            "Undefined property.".to_string(),
-                        ));
-                    }
-                    Some(val) => klass = val,
-                }
+            let st = method.lexeme;
+            match superclass.find_method(&st) {
+                Some(method) => Ok(Value::LoxFunction(Shared::new(method.bind(object)))),
+                None => Err(RuntimeError::of(
+                    line_number,
+                    ErrorKind::UndefinedProperty { name: st, on: None },
+                )),
             }
         }
         Expr::This { keyword: _, id } => {
             let depth = table.get(&id).expect("ID automatically generated.");
-            match env.borrow_mut().get(&"this".to_string(), *depth) {
+            match env.borrow_mut().get("this", *depth) {
                 None => Err(RuntimeError::new(
                     line_number,
                     "Don't know what \"this\" referred to.".to_string(),
@@ -381,63 +652,100 @@ pub fn evaluate(
                 Some(val) => Ok(val),
             }
         }
-        Expr::Unary { operator, right } => unitary_eval(operator, *right, env, table),
+        Expr::Unary { operator, right } => unitary_eval(operator, *right, env, table, ctx),
         Expr::Variable { name, id } => {
-            if let Some(key) = name.lexeme.unwrap().as_string() {
-                let depth = table.get(&id).expect("ID automatically generated.");
-                return match env.borrow_mut().get(&key, *depth) {
-                    None => Err(RuntimeError::new(
-                        line_number,
-                        format!("Undefined Variable {}.", key),
-                    )),
-                    Some(val) => Ok(val),
-                };
-            } else {
-                Err(RuntimeError::new(
+            let depth = global_depth(table, id, &env);
+            let value = env.borrow_mut().get(&name.lexeme, depth);
+            match value {
+                None => Err(RuntimeError::of(
                     line_number,
-                    "Invalid identifier.".to_string(),
-                ))
+                    ErrorKind::UndefinedVariable { name: name.lexeme },
+                )),
+                Some(val) => Ok(val),
             }
         }
         Expr::Assign { name, value, id } => {
-            if let Some(key) = name.lexeme.unwrap().as_string() {
-                let depth = table.get(&id).expect("ID automatically generated.");
-                let val: BasicType = evaluate(*value, env.clone(), table)?;
-                return Ok(env
-                    .borrow_mut()
-                    .assign(key.clone(), val, *depth)
-                    .expect("Always initialized."));
-            } else {
-                Err(RuntimeError::new(
+            let depth = global_depth(table, id, &env);
+            let val: Value = evaluate(*value, env.clone(), table, ctx)?;
+            match env
+                .borrow_mut()
+                .assign(name.lexeme.clone(), val.clone(), depth)
+            {
+                Some(_) => Ok(val),
+                None => Err(RuntimeError::of(
                     line_number,
-                    "Invalid identifier.".to_string(),
-                ))
+                    ErrorKind::UndefinedVariable { name: name.lexeme },
+                )),
+            }
+        }
+    }
+}
+
+// How many scopes out a variable is. The resolver leaves out names it
+// found in no scope, which can only be globals defined later or never.
+fn global_depth(table: &HashMap<u64, i32>, id: u64, env: &Shared<SharedCell<Environment>>) -> i32 {
+    match table.get(&id) {
+        Some(&depth) => depth,
+        None => env.borrow().depth() as i32,
+    }
+}
+
+fn evaluate_call(
+    callee: Expr,
+    arguments: LinkedList<Box<Expr>>,
+    env: Shared<SharedCell<Environment>>,
+    table: &HashMap<u64, i32>,
+    ctx: &mut Context,
+) -> Result<(Value, LinkedList<Value>), RuntimeError> {
+    let callee = evaluate(callee, env.clone(), table, ctx)?;
+    let mut args: LinkedList<Value> = LinkedList::new();
+    for expr in arguments {
+        args.push_back(evaluate(*expr, env.clone(), table, ctx)?);
+    }
+    Ok((callee, args))
+}
+
+fn call(
+    callee: Value,
+    mut arguments: LinkedList<Value>,
+    line_number: i32,
+    ctx: &mut Context,
+) -> Result<Value, RuntimeError> {
+    match callee {
+        Value::LoxFunction(val) => val.call(&mut arguments, line_number, ctx),
+        Value::LoxClass(val) => val.call(&mut arguments, line_number, ctx),
+        Value::Native(val) => val.call(&mut arguments, line_number, ctx),
+        // An instance is called through its class's call method.
+        Value::LoxInstance(val) => {
+            let method = val.borrow().klass.find_method("call");
+            match method {
+                Some(method) => method.bind(val).call(&mut arguments, line_number, ctx),
+                None => Err(RuntimeError::of(line_number, ErrorKind::NotCallable)),
             }
         }
+        _ => Err(RuntimeError::of(line_number, ErrorKind::NotCallable)),
     }
 }
 
 fn unitary_eval(
     token: Token,
     expr: Expr,
-    env: Rc<RefCell<Environment>>,
+    env: Shared<SharedCell<Environment>>,
     table: &HashMap<u64, i32>,
-) -> Result<BasicType, RuntimeError> {
-    let line_number = expr.line_number();
-    let right = evaluate(expr, env.clone(), table)?;
+    ctx: &mut Context,
+) -> Result<Value, RuntimeError> {
+    let line_number = token.line;
+    let right = evaluate(expr, env.clone(), table, ctx)?;
 
     match token.ttype {
         TokenType::Minus => match right.as_number() {
-            Some(x) => Ok(BasicType::Number(-x)),
-            _ => Err(RuntimeError::new(line_number, "Type mismatch.".to_string())),
+            Some(x) => Ok(Value::Number(-x)),
+            _ => Err(RuntimeError::of(
+                line_number,
+                ErrorKind::type_mismatch("a number", right),
+            )),
         },
-        TokenType::Bang => {
-            if let Some(x) = right.as_bool() {
-                Ok(BasicType::Bool(!x))
-            } else {
-                Err(RuntimeError::new(line_number, "Type mismatch.".to_string()))
-            }
-        }
+        TokenType::Bang => Ok(Value::Bool(!right.is_truthy())),
         _ => Err(RuntimeError::new(
             line_number,
             "Unknown operator.".to_string(),
@@ -445,69 +753,385 @@ fn unitary_eval(
     }
 }
 
+// Operands of an arithmetic operator that are not both numbers.
+fn not_numbers(token: &Token, left: &Value, right: &Value) -> RuntimeError {
+    RuntimeError::of(
+        token.line,
+        ErrorKind::TypeMismatch {
+            expected: format!("numbers for {}", token.lexeme),
+            found: format!("{} and {}", left, right),
+        },
+    )
+}
+
 fn binary_eval(
     expr1: Expr,
     token: Token,
     expr2: Expr,
-    env: Rc<RefCell<Environment>>,
+    env: Shared<SharedCell<Environment>>,
     table: &HashMap<u64, i32>,
-) -> Result<BasicType, RuntimeError> {
-    let left = evaluate(expr1, env.clone(), table)?;
-    let right = evaluate(expr2, env.clone(), table)?;
+    ctx: &mut Context,
+) -> Result<Value, RuntimeError> {
+    let left = evaluate(expr1, env.clone(), table, ctx)?;
+    let right = evaluate(expr2, env.clone(), table, ctx)?;
 
     match token.ttype {
         TokenType::Minus => match (left.as_number(), right.as_number()) {
-            (Some(x), Some(y)) => Ok(BasicType::Number(x - y)),
-            _ => Err(RuntimeError::new(token.line, "Type mismatch.".to_string())),
+            (Some(x), Some(y)) => Ok(Value::Number(x - y)),
+            _ => Err(not_numbers(&token, &left, &right)),
         },
         TokenType::Slash => match (left.as_number(), right.as_number()) {
             (Some(x), Some(y)) => {
                 if y == 0.0 {
-                    Err(RuntimeError::new(token.line, "Divide by 0.".to_string()))
+                    Err(RuntimeError::of(token.line, ErrorKind::DivisionByZero))
                 } else {
-                    Ok(BasicType::Number(x / y))
+                    Ok(Value::Number(x / y))
                 }
             }
-            _ => Err(RuntimeError::new(token.line, "Type mismatch.".to_string())),
+            _ => Err(not_numbers(&token, &left, &right)),
         },
         TokenType::Star => match (left.as_number(), right.as_number()) {
-            (Some(x), Some(y)) => Ok(BasicType::Number(x * y)),
-            _ => Err(RuntimeError::new(token.line, "Type mismatch.".to_string())),
+            (Some(x), Some(y)) => Ok(Value::Number(x * y)),
+            _ => Err(not_numbers(&token, &left, &right)),
         },
         TokenType::Plus => {
             if let (Some(x), Some(y)) = (left.as_number(), right.as_number()) {
-                return Ok(BasicType::Number(x + y));
+                return Ok(Value::Number(x + y));
             }
 
             if let (Some(x), Some(y)) = (left.as_string(), right.as_string()) {
-                return Ok(BasicType::String(x.clone() + &*y));
+                return Ok(Value::String(format!("{}{}", x, y).into()));
             }
-            Err(RuntimeError::new(token.line, "Type mismatch.".to_string()))
+            Err(RuntimeError::of(
+                token.line,
+                ErrorKind::type_mismatch(
+                    "two numbers or two strings",
+                    format!("{} and {}", left, right),
+                ),
+            ))
         }
 
-        TokenType::Greater => match (left.as_number(), right.as_number()) {
-            (Some(x), Some(y)) => Ok(BasicType::Bool(x > y)),
-            _ => Err(RuntimeError::new(token.line, "Type mismatch.".to_string())),
-        },
-
-        TokenType::GreaterEqual => match (left.as_number(), right.as_number()) {
-            (Some(x), Some(y)) => Ok(BasicType::Bool(x >= y)),
-            _ => Err(RuntimeError::new(token.line, "Type mismatch.".to_string())),
-        },
-
-        TokenType::Less => match (left.as_number(), right.as_number()) {
-            (Some(x), Some(y)) => Ok(BasicType::Bool(x < y)),
-            _ => Err(RuntimeError::new(token.line, "Type mismatch.".to_string())),
-        },
-        TokenType::LessEqual => match (left.as_number(), right.as_number()) {
-            (Some(x), Some(y)) => Ok(BasicType::Bool(x <= y)),
-            _ => Err(RuntimeError::new(token.line, "Type mismatch.".to_string())),
-        },
-        TokenType::BangEqual => Ok(BasicType::Bool(!(left == right))),
-        TokenType::EqualEqual => Ok(BasicType::Bool(left == right)),
+        TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {
+            let ord = left
+                .compare(&right, &token.lexeme)
+                .map_err(|kind| RuntimeError::of(token.line, kind))?;
+            Ok(Value::Bool(match token.ttype {
+                TokenType::Greater => ord == Some(Ordering::Greater),
+                TokenType::GreaterEqual => matches!(ord, Some(Ordering::Greater | Ordering::Equal)),
+                TokenType::Less => ord == Some(Ordering::Less),
+                _ => matches!(ord, Some(Ordering::Less | Ordering::Equal)),
+            }))
+        }
+        TokenType::BangEqual => Ok(Value::Bool(!(left == right))),
+        TokenType::EqualEqual => Ok(Value::Bool(left == right)),
         _ => Err(RuntimeError::new(
             token.line,
             "Unknown operator.".to_string(),
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_ast;
+    use crate::resolver::resolve;
+
+    fn run(source: &str) -> Result<(), RuntimeError> {
+        run_with(source, &VmOptions::default())
+    }
+
+    fn run_with(source: &str, config: &VmOptions) -> Result<(), RuntimeError> {
+        let mut tokens = scan_source(source, 1).expect("Scan error");
+        let ast = parse_ast(&mut tokens).expect("Parse error");
+        let mut table: HashMap<u64, i32> = HashMap::new();
+        let mut scopes: LinkedList<HashMap<String, bool>> = LinkedList::new();
+        scopes.push_front(HashMap::<String, bool>::new());
+        resolve(&ast, &mut scopes, &mut table).expect("Resolve error");
+        let env = Shared::new(SharedCell::new(Environment::new()));
+        interpret(
+            ast.to_tree(),
+            env,
+            &table,
+            &mut Context::new(&mut std::io::stdout(), config),
+        )
+    }
+
+    #[test]
+    fn test_operator_line() {
+        match run(include_str!("../tests/input/line_error.lox")) {
+            Err(RuntimeError::Reason { line, .. }) => assert_eq!(line, 3),
+            _ => panic!("Expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_truthiness_matches_vm() {
+        use crate::callable::LoxInstance;
+        use crate::chunk::Chunk;
+        use crate::object::{BoundMethod, Class, Closure, Function, Instance};
+        use crate::value::Native;
+
+        let token = Token::new(TokenType::Identifier, "A", None, 1, 1);
+        let env = Shared::new(SharedCell::new(Environment::new()));
+        let function = LoxFunction::new(
+            token.clone(),
+            LinkedList::new(),
+            LinkedList::new(),
+            env,
+            HashMap::new(),
+        );
+        let class = Shared::new(LoxClass::new(token, None, HashMap::new()));
+        let tree_walk = [
+            Value::None,
+            Value::Bool(false),
+            Value::Bool(true),
+            Value::Number(0.0),
+            Value::String("".into()),
+            Value::LoxFunction(Shared::new(function)),
+            Value::LoxClass(class.clone()),
+            Value::LoxInstance(Shared::new(SharedCell::new(LoxInstance::new(class)))),
+        ];
+
+        let closure = Closure::new(Shared::new(Function {
+            arity: 0,
+            upvalue: 0,
+            chunk: Box::new(Chunk::new()),
+            name: "f".to_string(),
+            frame_size: 1,
+        }));
+        let klass = Shared::new(SharedCell::new(Class::new("A".into())));
+        let instance = Shared::new(SharedCell::new(Instance::new(klass.clone())));
+        let vm = [
+            Value::None,
+            Value::Bool(false),
+            Value::Bool(true),
+            Value::Number(0.0),
+            Value::String("".into()),
+            Value::Function(closure.function.clone()),
+            Value::Closure(closure.clone()),
+            Value::Class(klass),
+            Value::Instance(instance.clone()),
+            Value::BoundMethod(Box::new(BoundMethod {
+                receiver: instance,
+                method: closure,
+            })),
+            Value::Array(Shared::new(SharedCell::new(Vec::new()))),
+            Value::Native(Shared::new(Native {
+                name: "clock".to_string(),
+                arity: 0,
+                function: Shared::new(|_| Ok(Value::None)),
+            })),
+        ];
+
+        // Only nil and false, the first two entries of each list, are falsey.
+        for (i, val) in tree_walk.iter().enumerate() {
+            assert_eq!(val.is_truthy(), i >= 2, "{:?}", val);
+        }
+        for (i, val) in vm.iter().enumerate() {
+            assert_eq!(val.is_truthy(), i >= 2, "{:?}", val);
+        }
+    }
+
+    #[test]
+    fn test_function_environments_freed() {
+        use crate::stmt::LIVE_ENVIRONMENTS;
+
+        let before = LIVE_ENVIRONMENTS.with(|n| n.get());
+        run("var i = 0;\nwhile (i < 100) {\nfun f() { return i; }\nf();\ni = i + 1;\n}\n")
+            .expect("Runtime error");
+        assert_eq!(LIVE_ENVIRONMENTS.with(|n| n.get()), before);
+    }
+
+    #[test]
+    fn test_declared_functions_outlive_scope() {
+        run("fun make() {\nvar x = 1;\nfun g() { return x; }\nreturn g;\n}\nvar h = make();\nh();\n")
+            .expect("Returned closure lost its environment");
+        run("fun fib(n) {\nif (n < 2) return n;\nreturn fib(n - 1) + fib(n - 2);\n}\nfib(5);\n")
+            .expect("Recursive call failed");
+    }
+
+    #[test]
+    fn test_budget_stops_infinite_loop() {
+        let config = VmOptions {
+            max_instructions: Some(1000),
+            ..VmOptions::default()
+        };
+        let result = run_with("var i = 0;\nwhile (i >= 0) {\ni = i + 1;\n}\n", &config);
+        match result {
+            Err(RuntimeError::Reason { line, kind, .. }) => {
+                assert_eq!(line, 2);
+                assert_eq!(kind.to_string(), "Execution budget exceeded.");
+            }
+            _ => panic!("Expected the budget to run out"),
+        }
+    }
+
+    #[test]
+    fn test_tail_calls_run_in_constant_stack() {
+        run("fun count(n) {\nif (n < 1) return 0;\nreturn count(n - 1);\n}\ncount(1000000);\n")
+            .expect("Tail recursion overflowed");
+    }
+
+    #[test]
+    fn test_deep_recursion_overflows() {
+        // Each level takes several native frames, so give the interpreter
+        // room to reach its own limit.
+        let result = std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(|| {
+                match run("fun f(n) {\nif (n < 1) return 0;\nreturn f(n - 1) + 1;\n}\nf(100000);\n")
+                {
+                    Err(RuntimeError::Reason {
+                        line,
+                        kind,
+                        functions,
+                    }) => Some((line, kind, functions.len())),
+                    _ => None,
+                }
+            })
+            .expect("Failed to spawn")
+            .join()
+            .expect("Interpreter panicked");
+        let (line, kind, functions) = result.expect("Expected a stack overflow");
+        assert_eq!((line, kind), (3, ErrorKind::StackOverflow));
+        assert!(functions > 0);
+    }
+
+    #[test]
+    fn test_interpreter_keeps_state() {
+        let (io, out, err) = Io::capture();
+        let config = VmOptions {
+            max_instructions: Some(100),
+            ..VmOptions::default()
+        };
+        let mut interpreter = Interpreter::new(config, io);
+        interpreter
+            .run("var count = 0;\nfun bump() { count = count + 1; }")
+            .unwrap();
+        interpreter.run("bump();").unwrap();
+        interpreter.run_file("tests/input/math1.lox").unwrap();
+        interpreter.run("bump();\nprint count;").unwrap();
+        assert!(interpreter.run("print 1 +;").is_err());
+        // Each run gets the whole budget.
+        assert!(interpreter.run("while (true) {}").is_err());
+        interpreter.run("print count;").unwrap();
+        assert!(out.contents().ends_with("2\n2\n"));
+        assert!(err.contents().contains("Execution budget exceeded."));
+        assert!(matches!(
+            interpreter.run_file("tests/input/missing.lox"),
+            Err(LoxError::Io(_))
+        ));
+        assert_eq!(
+            f64::try_from(interpreter.get_global("count").unwrap()),
+            Ok(2.0)
+        );
+    }
+
+    #[test]
+    fn test_sessions_keep_their_own_limits() {
+        let shallow = VmOptions {
+            max_call_depth: 3,
+            ..VmOptions::default()
+        };
+        let source = "fun f(n) {\nif (n < 1) return 0;\nreturn f(n - 1) + 1;\n}\n";
+        let (io, _, _) = Io::capture();
+        let mut a = Interpreter::new(shallow, io);
+        let (io, out, _) = Io::capture();
+        let mut b = Interpreter::new(VmOptions::default(), io);
+        a.run(source).unwrap();
+        b.run(source).unwrap();
+        assert!(a.run("f(10);").is_err());
+        let Some(Value::LoxFunction(f)) = b.get_global("f") else {
+            panic!("Expected a function");
+        };
+        assert_eq!(
+            f64::try_from(b.call(&f, &[Value::Number(10.0)]).unwrap()),
+            Ok(10.0)
+        );
+        b.run("print f(10);").unwrap();
+        assert_eq!(out.contents(), "10\n");
+        assert!(a.run("f(10);").is_err());
+    }
+
+    #[test]
+    fn test_bad_superclass() {
+        let (io, _, err) = Io::capture();
+        let mut interpreter = Interpreter::new(VmOptions::default(), io);
+        let kind = |err: LoxError| match err {
+            LoxError::Runtime(e) => e.kind().clone(),
+            e => panic!("Expected a runtime error but got {}", e),
+        };
+        assert_eq!(
+            kind(interpreter.run("class A < Missing {}").unwrap_err()),
+            ErrorKind::UndefinedVariable {
+                name: "Missing".to_string()
+            }
+        );
+        assert!(interpreter
+            .run("var Number = 1;\nclass B < Number {}")
+            .is_err());
+        assert!(err.contents().ends_with("Number is not a class name.\n"));
+    }
+
+    #[test]
+    fn test_inherited_methods() {
+        let (io, out, _) = Io::capture();
+        let mut interpreter = Interpreter::new(VmOptions::default(), io);
+        interpreter
+            .run(
+                "class A {\nname() { return \"A\"; }\nonlyA() { return \"a\"; }\nwho() { return this.name(); }\n}\n\
+                 class B < A {\nname() { return \"B\"; }\nonlyB() { return \"b\"; }\n}\n\
+                 class C < B {\nname() { return \"C and \" + super.name(); }\n}\n\
+                 var c = C();\nprint c.name();\nprint c.who();\nprint c.onlyA() + c.onlyB();\n\
+                 print B().who();\nprint A().who();",
+            )
+            .unwrap();
+        // The nearest class's method wins, also when an inherited method
+        // calls it, and super still starts from the superclass.
+        assert_eq!(out.contents(), "C and B\nC and B\nab\nB\nA\n");
+        let err = interpreter.run("A().onlyB();").unwrap_err();
+        assert!(
+            matches!(&err, LoxError::Runtime(e) if e.kind() == &ErrorKind::UndefinedProperty {
+                name: "onlyB".to_string(),
+                on: Some(Box::new(crate::error::Members::new(
+                    "A".to_string(),
+                    Vec::new(),
+                    vec!["name".to_string(), "onlyA".to_string(), "who".to_string()],
+                ))),
+            }),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_callable_instances() {
+        let (io, out, _) = Io::capture();
+        let mut interpreter = Interpreter::new(VmOptions::default(), io);
+        interpreter
+            .run(
+                "class Counter {\ncall(by) { this.n = this.n + by; return this.n; }\n}\n\
+                 class Tally < Counter {}\nclass Plain {}\n\
+                 var tally = Tally();\ntally.n = 0;\ntally(1);\nprint tally(2);",
+            )
+            .unwrap();
+        assert_eq!(out.contents(), "3\n");
+        let kind = |err: LoxError| match err {
+            LoxError::Runtime(e) => e.kind().clone(),
+            e => panic!("Expected a runtime error but got {}", e),
+        };
+        assert_eq!(
+            kind(interpreter.run("tally();").unwrap_err()),
+            ErrorKind::ArityMismatch {
+                callee: "call".to_string(),
+                expected: 1,
+                got: 0
+            }
+        );
+        assert_eq!(
+            kind(interpreter.run("Plain()(1);").unwrap_err()),
+            ErrorKind::NotCallable
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use crate::shared::{Shared, SharedCell};
+use crate::stdlib::Builtin;
+use crate::value::{MapKey, Value};
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, Serialize, SerializeMap, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+
+// Arrays and maps nested deeper than this, which include any that contain
+// themselves, don't serialize.
+const MAX_DEPTH: usize = 128;
+
+// Integers fit in an f64 up to here, and are written without a fraction.
+const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+
+/// Nil, booleans, numbers, strings, arrays and maps with string keys
+/// serialize as JSON would have them, maps as objects with their keys in
+/// order; functions, classes, instances and other map keys are an error.
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Nested(self, 0).serialize(serializer)
+    }
+}
+
+struct Nested<'a>(&'a Value, usize);
+
+impl Serialize for Nested<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Nested(val, depth) = *self;
+        match val {
+            Value::None => serializer.serialize_unit(),
+            Value::Bool(b) => serializer.serialize_bool(*b),
+            Value::Number(n) if n.fract() == 0.0 && n.abs() <= MAX_SAFE_INTEGER => {
+                serializer.serialize_i64(*n as i64)
+            }
+            Value::Number(n) => serializer.serialize_f64(*n),
+            Value::String(s) => serializer.serialize_str(s),
+            Value::Array(_) if depth >= MAX_DEPTH => Err(ser::Error::custom(
+                "Can't serialize arrays nested this deeply.",
+            )),
+            Value::Array(a) => {
+                serializer.collect_seq(a.borrow().iter().map(|val| Nested(val, depth + 1)))
+            }
+            Value::Map(_) if depth >= MAX_DEPTH => Err(ser::Error::custom(
+                "Can't serialize maps nested this deeply.",
+            )),
+            Value::Map(m) => {
+                let m = m.borrow();
+                let mut entries: Vec<_> = m.iter().collect();
+                entries.sort_by_key(|(key, _)| *key);
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, val) in entries {
+                    let MapKey::String(key) = key else {
+                        return Err(ser::Error::custom(format!(
+                            "Can't serialize the number key {}.",
+                            key.to_value()
+                        )));
+                    };
+                    map.serialize_entry(&**key, &Nested(val, depth + 1))?;
+                }
+                map.end()
+            }
+            val => Err(ser::Error::custom(format!("Can't serialize {}.", val))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "nil, a boolean, a number, a string, an array or a map")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::None)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_bool<E: de::Error>(self, b: bool) -> Result<Value, E> {
+        Ok(Value::Bool(b))
+    }
+
+    fn visit_i64<E: de::Error>(self, n: i64) -> Result<Value, E> {
+        Ok(Value::Number(n as f64))
+    }
+
+    fn visit_u64<E: de::Error>(self, n: u64) -> Result<Value, E> {
+        Ok(Value::Number(n as f64))
+    }
+
+    fn visit_f64<E: de::Error>(self, n: f64) -> Result<Value, E> {
+        Ok(Value::Number(n))
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Value, E> {
+        Ok(Value::String(s.into()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut elements = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(val) = seq.next_element()? {
+            elements.push(val);
+        }
+        Ok(Value::Array(Shared::new(SharedCell::new(elements))))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut entries = HashMap::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some((key, val)) = map.next_entry::<String, Value>()? {
+            entries.insert(MapKey::String(key.into()), val);
+        }
+        Ok(Value::Map(Shared::new(SharedCell::new(entries))))
+    }
+}
+
+/// Parses JSON into a Lox value. Objects become maps with string keys.
+///
+/// ```
+/// use lox::json::{value_from_json, value_to_json};
+///
+/// let val = value_from_json("[1, \"two\", [true, null]]").unwrap();
+/// assert_eq!(val.to_string(), "[1, \"two\", [true, nil]]");
+/// assert_eq!(value_to_json(&val).unwrap(), "[1,\"two\",[true,null]]");
+/// let val = value_from_json("{\"b\": [1], \"a\": {}}").unwrap();
+/// assert_eq!(val.to_string(), "{a: {}, b: [1]}");
+/// assert_eq!(value_to_json(&val).unwrap(), "{\"a\":{},\"b\":[1]}");
+/// ```
+pub fn value_from_json(json: &str) -> Result<Value, serde_json::Error> {
+    serde_json::from_str(json)
+}
+
+pub fn value_to_json(val: &Value) -> Result<String, serde_json::Error> {
+    serde_json::to_string(val)
+}
+
+pub(crate) const BUILTINS: [Builtin; 2] = [
+    Builtin {
+        name: "jsonParse",
+        arity: 1,
+        function: json_parse,
+    },
+    Builtin {
+        name: "jsonEncode",
+        arity: 1,
+        function: json_encode,
+    },
+];
+
+fn json_parse(args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::String(s) => value_from_json(s).map_err(|e| e.to_string()),
+        val => Err(format!("Expect a string but got {}.", val)),
+    }
+}
+
+fn json_encode(args: &[Value]) -> Result<Value, String> {
+    value_to_json(&args[0])
+        .map(Value::from)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Backend, Io, Lox, RunOptions};
+
+    #[test]
+    fn test_round_trip() {
+        for json in [
+            "null",
+            "true",
+            "-3",
+            "2.5",
+            "\"a \\\"quoted\\\" string\"",
+            "[]",
+            "[1,[2,[3,[]]],\"x\",false,null,0.125]",
+            "{}",
+            "{\"a\":1,\"b\":{\"c\":[null]},\"d e\":\"f\"}",
+        ] {
+            let val = value_from_json(json).unwrap();
+            assert_eq!(value_to_json(&val).unwrap(), json);
+        }
+        let val = value_from_json("[[1, 2], [3]]").unwrap();
+        let Value::Array(rows) = &val else {
+            panic!("Expected an array");
+        };
+        assert_eq!(rows.borrow().len(), 2);
+        assert_eq!(val.to_string(), "[[1, 2], [3]]");
+    }
+
+    #[test]
+    fn test_unsupported_values() {
+        let mut lox = Lox::with_io(RunOptions::default(), Io::capture().0);
+        lox.run("fun f() {}\nvar a = [1];\na[0] = a;\nvar m = {1: 2};\nvar n = {};\nn[\"n\"] = n;")
+            .unwrap();
+        let err = value_to_json(&lox.get_global("m").unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "Can't serialize the number key 1.");
+        let err = value_to_json(&lox.get_global("n").unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "Can't serialize maps nested this deeply.");
+        let err = value_to_json(&lox.get_global("f").unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "Can't serialize <fn f>.");
+        let err = value_to_json(&lox.get_global("a").unwrap()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't serialize arrays nested this deeply."
+        );
+    }
+
+    #[test]
+    fn test_natives() {
+        for backend in [Backend::Vm, Backend::Ast] {
+            let options = RunOptions {
+                backend,
+                ..RunOptions::default()
+            };
+            let (io, out, err) = Io::capture();
+            let mut lox = Lox::with_io(options, io);
+            lox.run("print jsonEncode(jsonParse(\"[1, [true, null], 0.5]\"));")
+                .unwrap();
+            assert_eq!(out.contents(), "[1,[true,null],0.5]\n");
+            assert!(lox.run("jsonParse(\"{\");").is_err());
+            assert!(err.contents().contains("jsonParse: "), "{}", err.contents());
+        }
+
+        // Map literals are VM syntax.
+        let (io, out, _) = Io::capture();
+        let mut lox = Lox::with_io(RunOptions::default(), io);
+        lox.run("var json = jsonEncode({\"a\": 1, \"b\": [nil]});\nprint json;\nprint jsonParse(json)[\"b\"];")
+            .unwrap();
+        assert_eq!(out.contents(), "{\"a\":1,\"b\":[null]}\n[nil]\n");
+    }
+}
@@ -0,0 +1,1105 @@
+//! A Lox interpreter with two backends: a tree-walk interpreter and a
+//! bytecode VM.
+//!
+//! [`run_source`] runs a whole program. [`Lox`] keeps its globals between
+//! runs, the way the REPL does.
+extern crate lazy_static;
+use std::collections::{BTreeSet, LinkedList};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub mod ast;
+pub mod callable;
+pub mod chunk;
+pub mod compile;
+pub mod completion;
+pub mod coverage;
+pub mod debugger;
+pub mod dot;
+pub mod error;
+pub mod expr;
+pub mod formatter;
+pub mod gc;
+pub mod interpreter;
+#[cfg(feature = "serde")]
+pub mod json;
+pub mod loxc;
+pub mod object;
+pub mod output;
+pub mod parser;
+pub mod profile;
+pub mod resolver;
+pub mod scanner;
+pub mod shared;
+pub mod stdlib;
+pub mod stmt;
+pub mod test_runner;
+pub mod token;
+pub mod value;
+pub mod vm;
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
+use crate::callable::LoxFunction;
+use crate::coverage::{compiled_lines, executable_lines, Coverage};
+pub use crate::error::{ErrorKind, LoxError};
+use crate::interpreter::Interpreter;
+use crate::object::{Closure, Function};
+pub use crate::output::Io;
+use crate::parser::parse_all;
+use crate::profile::{Profile, Timings};
+use crate::scanner::scan_source;
+use crate::shared::{SendSync, Shared};
+use crate::stmt::Stmt;
+pub use crate::value::Value;
+use crate::vm::{VmOptions, VM};
+use std::io::Write;
+use std::time::Instant;
+
+const USIZE: usize = std::mem::size_of::<usize>();
+
+/// Lox helpers every session with `RunOptions::prelude` starts with. The
+/// file lists what it defines.
+pub const PRELUDE: &str = include_str!("prelude.lox");
+
+// Set by interrupt and cleared when the next run starts.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Stops the running program with an "Interrupted." runtime error that try
+/// blocks can't catch. Only sets a flag, so it is safe in a signal handler.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+/// Whether the last run was interrupted.
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Which interpreter runs programs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Vm,
+    Ast,
+}
+
+/// How to run programs. The instruction, time and call depth limits in `vm`
+/// apply to both backends; the rest only to the VM.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RunOptions {
+    pub backend: Backend,
+    pub vm: VmOptions,
+    /// Print each compiled chunk to stderr before running it.
+    pub dump_bytecode: bool,
+    /// Time each phase of every run, for `Lox::timings`.
+    pub time: bool,
+    /// Record which lines run, for `Lox::take_coverage`.
+    pub coverage: bool,
+    /// Run the [`PRELUDE`] into the globals when a session starts. Scripts
+    /// may redefine its names.
+    pub prelude: bool,
+    /// Warn, after compiling, of globals the code uses that are neither
+    /// defined in it nor yet in the session.
+    pub check_globals: bool,
+}
+
+/// Runs a whole program. Errors are reported on stderr as they happen, and
+/// the first is returned.
+///
+/// ```
+/// use lox::{run_source, RunOptions};
+///
+/// run_source("print \"Hello, world!\";", &RunOptions::default()).unwrap();
+/// ```
+///
+/// ```
+/// use lox::{run_source, Backend, ErrorKind, LoxError, RunOptions};
+///
+/// let options = RunOptions {
+///     backend: Backend::Ast,
+///     ..RunOptions::default()
+/// };
+/// let err = run_source("print 1 +;", &options).unwrap_err();
+/// assert!(matches!(err, LoxError::Parse(e) if e.line == 1));
+/// let err = run_source("print nil + 1;", &options).unwrap_err();
+/// assert!(matches!(
+///     err,
+///     LoxError::Runtime(e) if matches!(e.kind(), ErrorKind::TypeMismatch { .. })
+/// ));
+/// ```
+pub fn run_source(source: &str, options: &RunOptions) -> Result<(), LoxError> {
+    Lox::new(*options).run(source)
+}
+
+/// Scans and parses source into the tree-walk backend's syntax tree without
+/// resolving or running it. Every error is returned and none are reported.
+/// Malformed source is an error, never a panic.
+///
+/// ```
+/// use lox::{parse_source, LoxError};
+///
+/// assert_eq!(parse_source("var a = 1; print a;").unwrap().len(), 2);
+/// let Err(errors) = parse_source("print 1 +;\nvar = 2;") else {
+///     panic!("Expect errors.");
+/// };
+/// assert_eq!(errors.len(), 2);
+/// assert!(matches!(&errors[1], LoxError::Parse(e) if e.line == 2));
+/// ```
+pub fn parse_source(source: &str) -> Result<LinkedList<Box<Stmt>>, Vec<LoxError>> {
+    let mut tokens = scan_source(source, 1)
+        .map_err(|errors| errors.into_iter().map(LoxError::from).collect::<Vec<_>>())?;
+    parse_all(&mut tokens).map_err(|errors| errors.into_iter().map(LoxError::from).collect())
+}
+
+enum State {
+    Vm(Box<VM>),
+    Ast(Interpreter),
+}
+
+// The state a run_atomically puts back on failure.
+enum Snapshot {
+    Vm(Vec<Option<Value>>),
+    Ast(interpreter::Snapshot),
+}
+
+/// A Lox function the host keeps to call after the run that defined it.
+///
+/// ```
+/// use lox::{Lox, RunOptions, Value};
+///
+/// let mut lox = Lox::new(RunOptions::default());
+/// lox.run("fun twice(x) { return x * 2; }").unwrap();
+/// let twice = lox.get_function("twice").unwrap();
+/// let result = twice.call(&mut lox, &[Value::Number(21.0)]).unwrap();
+/// assert_eq!(f64::try_from(result), Ok(42.0));
+/// ```
+#[derive(Clone)]
+pub struct LoxCallable(Callee);
+
+#[derive(Clone)]
+enum Callee {
+    Vm(Closure),
+    Ast(Shared<LoxFunction>),
+}
+
+impl LoxCallable {
+    /// Calls the function in the session it came from, which may have run
+    /// other code since. Errors are reported as a run's would be.
+    ///
+    /// Panics if `lox` uses the other backend.
+    pub fn call(&self, lox: &mut Lox, args: &[Value]) -> Result<Value, LoxError> {
+        INTERRUPTED.store(false, Ordering::Relaxed);
+        match (&self.0, &mut lox.state) {
+            (Callee::Vm(closure), State::Vm(vm)) => vm
+                .call_closure(closure.clone(), args)
+                .map_err(|e| report(vm, e, lox.options.vm.backtrace)),
+            (Callee::Ast(function), State::Ast(ast)) => ast.call(function, args),
+            _ => panic!("The function belongs to a session on the other backend"),
+        }
+    }
+}
+
+// Reports a failed VM run on its err, with the backtrace if asked for.
+fn report(vm: &mut VM, e: vm::RuntimeError, backtrace: bool) -> LoxError {
+    let trace = vm.backtrace().to_vec();
+    let err = &mut vm.io().err;
+    if backtrace && !trace.is_empty() {
+        let _ = writeln!(err, "Backtrace:");
+        for frame in trace {
+            let _ = writeln!(err, "{}", frame);
+        }
+    }
+    let _ = writeln!(err, "{}", e);
+    LoxError::from(e)
+}
+
+/// An interpreter session. Each run sees the globals earlier runs defined.
+///
+/// ```
+/// use lox::{Io, Lox, RunOptions};
+///
+/// let (io, out, _) = Io::capture();
+/// let mut lox = Lox::with_io(RunOptions::default(), io);
+/// lox.run("var a = 1;").unwrap();
+/// lox.run("print a + 1;").unwrap();
+/// assert_eq!(out.contents(), "2\n");
+/// ```
+pub struct Lox {
+    options: RunOptions,
+    state: State,
+    // Where the next run's source starts, so errors carry session lines.
+    line: i32,
+    timings: Option<Timings>,
+    coverage: Option<Coverage>,
+}
+
+impl Lox {
+    /// A session writing to stdout and stderr.
+    pub fn new(options: RunOptions) -> Lox {
+        Lox::with_io(options, Io::stdio())
+    }
+
+    pub fn with_io(options: RunOptions, io: Io) -> Lox {
+        let state = match options.backend {
+            Backend::Vm => {
+                let mut vm = VM::init(options.vm);
+                vm.set_io(io);
+                State::Vm(Box::new(vm))
+            }
+            Backend::Ast => State::Ast(Interpreter::new(options.vm, io)),
+        };
+        let mut lox = Lox {
+            options,
+            state,
+            line: 1,
+            timings: None,
+            coverage: None,
+        };
+        if options.prelude {
+            lox.load_prelude();
+        }
+        // The prelude is neither timed nor covered.
+        lox.timings = options.time.then(Timings::new);
+        lox.coverage = options.coverage.then(Coverage::default);
+        lox
+    }
+
+    // The prelude's tests run it on both backends, so it can't fail here.
+    fn load_prelude(&mut self) {
+        let result = match &mut self.state {
+            State::Vm(vm) => match vm.compile(PRELUDE, 1) {
+                Ok(function) => vm
+                    .interpret_quietly(function)
+                    .map(|_| ())
+                    .map_err(LoxError::from),
+                Err(e) => Err(e.into()),
+            },
+            State::Ast(ast) => ast.run_quietly(PRELUDE),
+        };
+        if let Err(e) = result {
+            panic!("The prelude failed: {}", e);
+        }
+    }
+
+    /// The VM, when it is the backend.
+    pub fn vm(&mut self) -> Option<&mut VM> {
+        match &mut self.state {
+            State::Vm(vm) => Some(vm),
+            State::Ast(_) => None,
+        }
+    }
+
+    /// Sends the VM's trace to out instead of the session's err. The
+    /// tree-walk backend has no trace.
+    pub fn set_trace_output(&mut self, out: Box<dyn Write>) {
+        if let State::Vm(vm) = &mut self.state {
+            vm.set_trace_output(out);
+        }
+    }
+
+    /// The VM's profile, when profiling is on.
+    pub fn profile(&self) -> Option<&Profile> {
+        match &self.state {
+            State::Vm(vm) => vm.profile(),
+            State::Ast(_) => None,
+        }
+    }
+
+    /// How long the phases of the runs so far took, when timing is on.
+    ///
+    /// ```
+    /// use lox::{Backend, Io, Lox, RunOptions};
+    ///
+    /// let options = RunOptions {
+    ///     backend: Backend::Ast,
+    ///     time: true,
+    ///     ..RunOptions::default()
+    /// };
+    /// let mut lox = Lox::with_io(options, Io::capture().0);
+    /// lox.run("var a = 1; print a;").unwrap();
+    /// let report = lox.timings().unwrap().to_string();
+    /// assert!(report.starts_with("scan: "));
+    /// assert!(report.ends_with("statements: 2\n"));
+    /// ```
+    pub fn timings(&self) -> Option<&Timings> {
+        self.timings.as_ref()
+    }
+
+    /// The lines the runs since the last call executed, out of the lines
+    /// of their sources that hold statements, when coverage is on.
+    ///
+    /// ```
+    /// use lox::{Io, Lox, RunOptions};
+    ///
+    /// let options = RunOptions {
+    ///     coverage: true,
+    ///     ..RunOptions::default()
+    /// };
+    /// let mut lox = Lox::with_io(options, Io::capture().0);
+    /// lox.run("var a = 1;\nif (a > 1) {\n  a = 0;\n}").unwrap();
+    /// let coverage = lox.take_coverage().unwrap();
+    /// assert_eq!(coverage.missed().collect::<Vec<_>>(), [3]);
+    /// ```
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.as_mut().map(std::mem::take)
+    }
+
+    /// Defines a global function implemented in Rust. An `Err` from it
+    /// becomes a runtime error at the call site, prefixed with its name.
+    ///
+    /// ```
+    /// use lox::{Io, Lox, RunOptions, Value};
+    ///
+    /// let (io, out, _) = Io::capture();
+    /// let mut lox = Lox::with_io(RunOptions::default(), io);
+    /// lox.register("square", 1, |args| match args[0] {
+    ///     Value::Number(n) => Ok(Value::Number(n * n)),
+    ///     _ => Err("Expect a number.".to_string()),
+    /// });
+    /// lox.run("print square(12);").unwrap();
+    /// assert_eq!(out.contents(), "144\n");
+    /// ```
+    pub fn register(
+        &mut self,
+        name: &str,
+        arity: u8,
+        function: impl Fn(&[Value]) -> Result<Value, String> + SendSync + 'static,
+    ) {
+        match &mut self.state {
+            State::Vm(vm) => vm.define_native(name, arity, function),
+            State::Ast(ast) => ast.define_native(name, arity, Shared::new(function)),
+        }
+    }
+
+    /// Reads a global after a run. Values convert to Rust types with
+    /// `try_into`, and instances read their fields with `get`.
+    ///
+    /// ```
+    /// use lox::{Lox, RunOptions};
+    ///
+    /// let mut lox = Lox::new(RunOptions::default());
+    /// lox.run("var total = 1 + 2; var name = \"lox\";").unwrap();
+    /// let total: f64 = lox.get_global("total").unwrap().try_into().unwrap();
+    /// assert_eq!(total, 3.0);
+    /// let name: String = lox.get_global("name").unwrap().try_into().unwrap();
+    /// assert_eq!(name, "lox");
+    /// ```
+    ///
+    /// A value of the wrong type is a `ConversionError`:
+    ///
+    /// ```
+    /// use lox::error::ConversionError;
+    /// use lox::{Lox, RunOptions};
+    ///
+    /// let mut lox = Lox::new(RunOptions::default());
+    /// lox.run("var name = \"lox\"; var missing;").unwrap();
+    /// let err = f64::try_from(lox.get_global("name").unwrap()).unwrap_err();
+    /// assert_eq!(err, ConversionError { expected: "a number", found: "lox".to_string() });
+    /// assert_eq!(err.to_string(), "Expected a number but got lox.");
+    /// let missing: Option<f64> = lox.get_global("missing").unwrap().try_into().unwrap();
+    /// assert_eq!(missing, None);
+    /// ```
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        match &self.state {
+            State::Vm(vm) => vm.get_global(name),
+            State::Ast(ast) => ast.get_global(name),
+        }
+    }
+
+    /// The function a global holds, to call from Rust.
+    pub fn get_function(&self, name: &str) -> Option<LoxCallable> {
+        match &self.state {
+            State::Vm(vm) => match vm.get_global(name)? {
+                Value::Closure(closure) => Some(LoxCallable(Callee::Vm(closure))),
+                _ => None,
+            },
+            State::Ast(ast) => match ast.get_global(name)? {
+                Value::LoxFunction(function) => Some(LoxCallable(Callee::Ast(function))),
+                _ => None,
+            },
+        }
+    }
+
+    /// Every defined global's name and printed value.
+    pub fn globals(&self) -> Vec<(String, String)> {
+        match &self.state {
+            State::Vm(vm) => vm
+                .globals()
+                .map(|(name, val)| (name.to_string(), val.to_string()))
+                .collect(),
+            State::Ast(ast) => ast
+                .globals()
+                .into_iter()
+                .map(|(name, val)| (name, val.to_string()))
+                .collect(),
+        }
+    }
+
+    /// Forgets everything earlier runs defined, keeping the options and io.
+    pub fn reset(&mut self) {
+        let io = match &mut self.state {
+            State::Vm(vm) => std::mem::take(vm.io()),
+            State::Ast(ast) => std::mem::take(ast.io()),
+        };
+        *self = Lox::with_io(self.options, io);
+    }
+
+    /// Reports the errors a run of source would find before running it,
+    /// without running anything. On the VM that is compiling it; on the
+    /// tree-walk backend, scanning, parsing and resolving it.
+    ///
+    /// ```
+    /// use lox::{Io, Lox, LoxError, RunOptions};
+    ///
+    /// let (io, out, err) = Io::capture();
+    /// let mut lox = Lox::with_io(RunOptions::default(), io);
+    /// lox.check("print \"unreached\";").unwrap();
+    /// assert!(matches!(lox.check("print 1 +;"), Err(LoxError::Parse(_))));
+    /// assert_eq!(out.contents(), "");
+    /// assert!(err.contents().starts_with("[line 2]"));
+    /// ```
+    pub fn check(&mut self, source: &str) -> Result<(), LoxError> {
+        let line = self.line;
+        self.line += source.lines().count().max(1) as i32;
+        match &mut self.state {
+            State::Vm(vm) => {
+                let function = vm.compile(source, line)?;
+                if self.options.check_globals {
+                    vm.warn_undefined_globals(&function);
+                }
+                Ok(())
+            }
+            State::Ast(ast) => ast.parse(source, line, false, None).map(|_| ()),
+        }
+    }
+
+    /// Numbers the next run's lines from line. Each run otherwise carries on
+    /// from where the last ended, as a REPL session does; a host running
+    /// several files starts each at 1.
+    pub fn set_line(&mut self, line: i32) {
+        self.line = line;
+    }
+
+    pub fn run(&mut self, source: &str) -> Result<(), LoxError> {
+        self.execute(source, false).map(|_| ())
+    }
+
+    /// Runs source like run, but if it fails puts the globals back as they
+    /// were, so later runs go on as if it never ran. The REPL runs each
+    /// entry this way.
+    ///
+    /// Only the bindings are put back: changes the source made to arrays,
+    /// maps and instances before failing stay.
+    ///
+    /// ```
+    /// use lox::{Io, Lox, RunOptions};
+    ///
+    /// let mut lox = Lox::with_io(RunOptions::default(), Io::capture().0);
+    /// assert!(lox.run_atomically("var a = 1; var b = missing();").is_err());
+    /// assert!(lox.get_global("a").is_none());
+    /// ```
+    pub fn run_atomically(&mut self, source: &str) -> Result<(), LoxError> {
+        let snapshot = match &self.state {
+            State::Vm(vm) => Snapshot::Vm(vm.snapshot_globals()),
+            State::Ast(ast) => Snapshot::Ast(ast.snapshot()),
+        };
+        let result = self.run(source);
+        if result.is_err() {
+            match (&mut self.state, snapshot) {
+                (State::Vm(vm), Snapshot::Vm(globals)) => vm.restore_globals(globals),
+                (State::Ast(ast), Snapshot::Ast(snapshot)) => ast.restore(snapshot),
+                _ => unreachable!("The backend changed during a run"),
+            }
+        }
+        result
+    }
+
+    /// Runs source like run, returning the value of its last statement if
+    /// that is an expression, and nil otherwise. That expression may leave
+    /// off its semicolon.
+    ///
+    /// ```
+    /// use lox::{Lox, RunOptions, Value};
+    ///
+    /// let mut lox = Lox::new(RunOptions::default());
+    /// assert!(matches!(lox.eval("var x = 1;"), Ok(Value::None)));
+    /// let sum = lox.eval("x + 2").unwrap();
+    /// assert_eq!(f64::try_from(sum), Ok(3.0));
+    /// ```
+    pub fn eval(&mut self, source: &str) -> Result<Value, LoxError> {
+        self.execute(source, true)
+    }
+
+    fn execute(&mut self, source: &str, eval: bool) -> Result<Value, LoxError> {
+        INTERRUPTED.store(false, Ordering::Relaxed);
+        let line = self.line;
+        self.line += source.lines().count().max(1) as i32;
+        match &mut self.state {
+            State::Vm(vm) => {
+                // Clocks are only read when timing: wasm32 has none.
+                let start = self.timings.is_some().then(Instant::now);
+                let function = if eval {
+                    vm.compile_eval(source, line)
+                } else {
+                    vm.compile(source, line)
+                };
+                if let (Some(timings), Some(start)) = (&mut self.timings, start) {
+                    timings.phase("compile", start.elapsed());
+                }
+                if let (Ok(function), true) = (&function, self.options.check_globals) {
+                    vm.warn_undefined_globals(function);
+                }
+                let executable = self
+                    .coverage
+                    .is_some()
+                    .then(|| executable_lines(source, line))
+                    .flatten();
+                self.run_compiled(function?, executable)
+            }
+            State::Ast(ast) => {
+                ast.record_lines(self.coverage.is_some());
+                let result = ast.run_from(source, line, eval, self.timings.as_mut());
+                if let Some(coverage) = &mut self.coverage {
+                    coverage
+                        .executable
+                        .extend(executable_lines(source, line).unwrap_or_default());
+                    coverage.executed.extend(ast.recorded_lines());
+                    ast.record_lines(false);
+                }
+                result
+            }
+        }
+    }
+
+    /// Runs a function compiled against this session's globals. Only the VM
+    /// backend runs compiled code.
+    pub fn run_function(&mut self, function: Shared<Function>) -> Result<(), LoxError> {
+        self.run_compiled(function, None).map(|_| ())
+    }
+
+    // Runs a compiled function. For coverage, the lines that hold
+    // statements default to the lines it has code for.
+    fn run_compiled(
+        &mut self,
+        function: Shared<Function>,
+        executable: Option<BTreeSet<i32>>,
+    ) -> Result<Value, LoxError> {
+        let State::Vm(vm) = &mut self.state else {
+            panic!("The tree-walk backend can't run compiled functions");
+        };
+        INTERRUPTED.store(false, Ordering::Relaxed);
+        if self.options.dump_bytecode {
+            let err = &mut vm.io().err;
+            if let Err(e) = function.chunk.disassemble_chunk_to(err) {
+                let _ = writeln!(err, "{}", e);
+            }
+        }
+        let start = self.timings.is_some().then(Instant::now);
+        if let Some(coverage) = &mut self.coverage {
+            let executable = executable.unwrap_or_else(|| compiled_lines(&function));
+            coverage.executable.extend(executable);
+        }
+        vm.record_lines(self.coverage.is_some());
+        let result = vm.interpret(function);
+        if let Some(coverage) = &mut self.coverage {
+            coverage.executed.extend(vm.recorded_lines());
+        }
+        if let (Some(timings), Some(start)) = (&mut self.timings, start) {
+            timings.phase("execute", start.elapsed());
+            timings.count("instructions", vm.executed());
+        }
+        result.map_err(|e| report(vm, e, self.options.vm.backtrace))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Members;
+    use crate::token::Span;
+    use std::error::Error;
+
+    fn run(source: &str, backend: Backend) -> Result<(), LoxError> {
+        run_source(
+            source,
+            &RunOptions {
+                backend,
+                ..RunOptions::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_error_phases() {
+        assert!(
+            matches!(run("print \"a\" @ 1;", Backend::Ast), Err(LoxError::Scan(e)) if e.line == 1)
+        );
+        assert!(matches!(
+            run("{ var a = a; }", Backend::Ast),
+            Err(LoxError::Resolve(_))
+        ));
+        for backend in [Backend::Vm, Backend::Ast] {
+            assert!(matches!(run("\nprint 1 +;", backend), Err(LoxError::Parse(e)) if e.line == 2));
+            let err = run("print -nil;", backend).unwrap_err();
+            assert!(matches!(&err, LoxError::Runtime(e) if e.line == 1));
+            assert!(err.source().is_some());
+        }
+    }
+
+    #[test]
+    fn test_undefined_globals() {
+        let warnings = |source| {
+            let (io, _, err) = Io::capture();
+            let options = RunOptions {
+                check_globals: true,
+                ..RunOptions::default()
+            };
+            Lox::with_io(options, io).run(source).unwrap();
+            err.contents()
+        };
+        assert_eq!(
+            warnings("fun never() {\npritn(\"x\");\n}\nprint 1;"),
+            "[line 2] at pritn: Warning: Global 'pritn' is never defined.\n"
+        );
+        assert_eq!(
+            warnings("fun f() { return a + clock(); }\nvar a = 1;\nprint f() > 0;"),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_arity_errors() {
+        let source = "fun greet(a, b) {\n  print a + b;\n}\n\ngreet(1, 2, 3);";
+        for backend in [Backend::Vm, Backend::Ast] {
+            let Err(LoxError::Runtime(e)) = run(source, backend) else {
+                panic!("Expected a runtime error on {:?}", backend);
+            };
+            assert_eq!(
+                e.kind().to_string(),
+                "Expected 2 arguments to 'greet' but got 3."
+            );
+            // The call's line, not the declaration's.
+            assert_eq!(e.line, 5, "{:?}", backend);
+            let Err(LoxError::Runtime(e)) = run("fun one(x) {}\none();", backend) else {
+                panic!("Expected a runtime error on {:?}", backend);
+            };
+            assert_eq!(
+                e.kind().to_string(),
+                "Expected 1 argument to 'one' but got 0."
+            );
+        }
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let kind = |source, backend| match run(source, backend) {
+            Err(LoxError::Runtime(e)) => e.kind().clone(),
+            result => panic!("Expected a runtime error but got {:?}", result),
+        };
+        assert_eq!(
+            kind("print x;", Backend::Vm),
+            ErrorKind::UndefinedVariable {
+                name: "x".to_string()
+            }
+        );
+        for backend in [Backend::Vm, Backend::Ast] {
+            assert_eq!(
+                kind("class A {}\nprint A().x;", backend),
+                ErrorKind::UndefinedProperty {
+                    name: "x".to_string(),
+                    on: Some(Box::new(Members::new("A", Vec::new(), Vec::new())))
+                }
+            );
+            assert_eq!(
+                kind("fun f(a) {}\nf();", backend),
+                ErrorKind::ArityMismatch {
+                    callee: "f".to_string(),
+                    expected: 1,
+                    got: 0
+                }
+            );
+            assert_eq!(kind("1();", backend), ErrorKind::NotCallable);
+            // Errors keep their kind on the way out of functions.
+            assert_eq!(
+                kind("fun f() { return -nil; }\nf();", backend),
+                ErrorKind::TypeMismatch {
+                    expected: "a number".to_string(),
+                    found: "Nil".to_string()
+                }
+            );
+        }
+        assert_eq!(
+            kind("print 1 / 0;", Backend::Ast),
+            ErrorKind::DivisionByZero
+        );
+    }
+
+    #[test]
+    fn test_undefined_property_names() {
+        for backend in [Backend::Vm, Backend::Ast] {
+            let message = |source| match run(source, backend) {
+                Err(LoxError::Runtime(e)) => e.reason,
+                result => panic!("Expected a runtime error but got {:?}", result),
+            };
+            assert_eq!(
+                message("class Bike {}\nvar b = Bike();\nb.wheels = 2;\nb.gear = 1;\nb.speed;"),
+                "Undefined property 'speed' on Bike instance. Known fields: gear, wheels."
+            );
+            assert_eq!(
+                message("class A { ride() {} }\nclass B < A { stop() {} ride() {} }\nB().speed;"),
+                "Undefined property 'speed' on B instance. Known methods: ride, stop."
+            );
+            assert_eq!(
+                message("class Empty {}\nEmpty().speed;"),
+                "Undefined property 'speed' on Empty instance."
+            );
+        }
+    }
+
+    #[test]
+    fn test_error_positions() {
+        let Err(errors) = parse_source("var a = 1;\nprint a +;") else {
+            panic!("Expect an error.");
+        };
+        let LoxError::Parse(e) = &errors[0] else {
+            panic!("Expect a parse error.");
+        };
+        assert_eq!((e.line(), e.lexeme()), (2, Some(";")));
+        let Err(errors) = parse_source("print  \"abc") else {
+            panic!("Expect an error.");
+        };
+        let LoxError::Scan(e) = &errors[0] else {
+            panic!("Expect a scan error.");
+        };
+        assert_eq!((e.column(), e.lexeme()), (Some(8), Some("\"abc")));
+        let mut lox = Lox::new(RunOptions::default());
+        let Err(LoxError::Parse(e)) = lox.check("var a = 1;\n  print a +;") else {
+            panic!("Expect a parse error.");
+        };
+        assert_eq!((e.line(), e.column(), e.lexeme()), (2, Some(12), Some(";")));
+
+        // Both backends point at all of the token an error is at.
+        let source = "var a = 1;\nprint a + while;";
+        let Err(errors) = parse_source(source) else {
+            panic!("Expect an error.");
+        };
+        let LoxError::Parse(e) = &errors[0] else {
+            panic!("Expect a parse error.");
+        };
+        assert_eq!(e.span(), Some(Span::new(21, 26)));
+        let Err(LoxError::Parse(e)) = lox.check(source) else {
+            panic!("Expect a parse error.");
+        };
+        assert_eq!(e.span(), Some(Span::new(21, 26)));
+        assert_eq!(&source[21..26], "while");
+    }
+
+    #[test]
+    fn test_adversarial_sources() {
+        let deep = |open: &str, close: &str| open.repeat(10_000) + "1" + &close.repeat(10_000);
+        let sources = [
+            String::new(),
+            " ".to_string(),
+            "/".to_string(),
+            "1.".to_string(),
+            "\"abc".to_string(),
+            "\"é".to_string(),
+            "print é;".to_string(),
+            "var nan = 0 / 0for;".to_string(),
+            "class A < ".to_string(),
+            "fun f(".to_string(),
+            "super.".to_string(),
+            "-".to_string(),
+            "print (1 + ;".to_string(),
+            deep("(", ")"),
+            deep("{", "}"),
+            deep("-", ""),
+            deep("if (true) ", ""),
+            deep("fun f() { ", "}"),
+        ];
+        // Each must be parsed or rejected, not panic or overflow the stack.
+        for source in &sources {
+            let _ = parse_source(source);
+            let mut globals = chunk::GlobalNames::default();
+            let _ = compile::compile_to(source, 1, &mut globals, &mut std::io::sink(), false);
+        }
+        assert!(matches!(parse_source(" "), Ok(stmts) if stmts.is_empty()));
+        let Err(errors) = parse_source(&deep("(", ")")) else {
+            panic!("Expect an error.");
+        };
+        assert!(matches!(&errors[0], LoxError::Parse(e) if e.reason == "Too much nesting."));
+    }
+
+    #[test]
+    fn test_captured_output() {
+        for backend in [Backend::Vm, Backend::Ast] {
+            let (io, out, err) = Io::capture();
+            let options = RunOptions {
+                backend,
+                ..RunOptions::default()
+            };
+            let mut lox = Lox::with_io(options, io);
+            lox.run("print \"a\";\nprint 1 + 2;").unwrap();
+            assert!(lox.run("print nil + 1;").is_err());
+            assert_eq!(out.contents(), "a\n3\n");
+            assert!(err.contents().contains("Runtime Error"));
+        }
+    }
+
+    #[test]
+    fn test_host_functions() {
+        for backend in [Backend::Vm, Backend::Ast] {
+            let (io, out, err) = Io::capture();
+            let options = RunOptions {
+                backend,
+                ..RunOptions::default()
+            };
+            let mut lox = Lox::with_io(options, io);
+            lox.register("greet", 1, |args| match &args[0] {
+                Value::String(name) => Ok(Value::String(format!("Hello, {}!", name).into())),
+                _ => Err("Expect a name.".to_string()),
+            });
+            lox.run("print greet(\"Lox\");\nprint greet;").unwrap();
+            assert_eq!(out.contents(), "Hello, Lox!\n<native fn>\n");
+            let e = lox.run("greet(1);").unwrap_err();
+            assert!(matches!(e, LoxError::Runtime(e) if e.reason == "greet: Expect a name."));
+            assert!(lox.run("greet();").is_err());
+            assert!(err.contents().contains("greet: Expect a name."));
+        }
+    }
+
+    #[test]
+    fn test_get_global() {
+        for backend in [Backend::Vm, Backend::Ast] {
+            let options = RunOptions {
+                backend,
+                ..RunOptions::default()
+            };
+            let mut lox = Lox::with_io(options, Io::capture().0);
+            lox.run("var n = 2.5; var b = !nil; var s = \"x\" + \"y\"; var none;")
+                .unwrap();
+            assert_eq!(f64::try_from(lox.get_global("n").unwrap()), Ok(2.5));
+            assert_eq!(bool::try_from(lox.get_global("b").unwrap()), Ok(true));
+            assert_eq!(
+                String::try_from(lox.get_global("s").unwrap()),
+                Ok("xy".to_string())
+            );
+            assert_eq!(
+                Option::<bool>::try_from(lox.get_global("none").unwrap()),
+                Ok(None)
+            );
+            assert!(bool::try_from(lox.get_global("n").unwrap()).is_err());
+            assert!(lox.get_global("undefined").is_none());
+        }
+    }
+
+    #[test]
+    fn test_instance_fields() {
+        let mut lox = Lox::with_io(RunOptions::default(), Io::capture().0);
+        lox.run("class Point {}\nvar p = Point();\np.x = 3;")
+            .unwrap();
+        let Some(Value::Instance(point)) = lox.get_global("p") else {
+            panic!("p is not an instance");
+        };
+        assert_eq!(f64::try_from(point.borrow().get("x").unwrap()), Ok(3.0));
+        assert!(point.borrow().get("y").is_none());
+    }
+
+    #[test]
+    fn test_host_values_survive_collection() {
+        let mut lox = Lox::with_io(RunOptions::default(), Io::capture().0);
+        let Ok(Value::Instance(point)) =
+            lox.eval("class Point {}\nvar p = Point();\np.x = 42;\np.self = p;\np;")
+        else {
+            panic!("eval did not return an instance");
+        };
+        let Ok(Value::Array(list)) = lox.eval("var a = [p, [1]];\na;") else {
+            panic!("eval did not return an array");
+        };
+        lox.run("p = nil;\na = nil;\nvar i = 0;\nwhile (i < 5000) {\n  var o = Point();\n  o.o = o;\n  i = i + 1;\n}")
+            .unwrap();
+        lox.vm().unwrap().collect_garbage();
+        assert_eq!(f64::try_from(point.borrow().get("x").unwrap()), Ok(42.0));
+        assert!(matches!(
+            point.borrow().get("self"),
+            Some(Value::Instance(_))
+        ));
+        assert_eq!(list.borrow().len(), 2);
+        assert!(matches!(&list.borrow()[1], Value::Array(inner) if inner.borrow().len() == 1));
+        // Once the host lets go, only the class is left.
+        drop((point, list));
+        lox.vm().unwrap().collect_garbage();
+        assert_eq!(lox.vm().unwrap().live_objects(), 1);
+    }
+
+    #[test]
+    fn test_call_functions() {
+        for backend in [Backend::Vm, Backend::Ast] {
+            let (io, out, err) = Io::capture();
+            let options = RunOptions {
+                backend,
+                ..RunOptions::default()
+            };
+            let mut lox = Lox::with_io(options, io);
+            lox.run("var scale = 2;\nfun on_event(x) {\n  print x;\n  return x * scale;\n}\n")
+                .unwrap();
+            let on_event = lox.get_function("on_event").unwrap();
+            let result = on_event.call(&mut lox, &[Value::Number(1.0)]).unwrap();
+            assert_eq!(f64::try_from(result), Ok(2.0));
+            // Later runs are visible to the function.
+            lox.run("scale = 10;").unwrap();
+            let result = on_event.call(&mut lox, &[Value::Number(3.0)]).unwrap();
+            assert_eq!(f64::try_from(result), Ok(30.0));
+            assert_eq!(out.contents(), "1\n3\n");
+
+            assert!(on_event.call(&mut lox, &[]).is_err());
+            // A bad call from the host has no line or frames to show.
+            assert_eq!(
+                err.contents(),
+                "Runtime Error: Expected 1 argument to 'on_event' but got 0.\n",
+                "{:?}",
+                backend
+            );
+            let e = on_event.call(&mut lox, &["a".into()]).unwrap_err();
+            assert!(matches!(e, LoxError::Runtime(_)));
+            assert!(err.contents().contains("Runtime Error"));
+            assert!(lox.get_function("scale").is_none());
+            assert!(lox.get_function("missing").is_none());
+        }
+    }
+
+    #[test]
+    fn test_call_survives_collection() {
+        for backend in [Backend::Vm, Backend::Ast] {
+            let options = RunOptions {
+                backend,
+                ..RunOptions::default()
+            };
+            let mut lox = Lox::with_io(options, Io::capture().0);
+            lox.run(
+                "var c = 1;\nvar h;\n{\n  var d = c;\n  fun f(x) { return x + d; }\n  h = f;\n}",
+            )
+            .unwrap();
+            let h = lox.get_function("h").unwrap();
+            let result = h.call(&mut lox, &[Value::Number(1.0)]).unwrap();
+            assert_eq!(f64::try_from(result), Ok(2.0));
+            lox.run("h = nil;\nclass O {}\nvar i = 0;\nwhile (i < 5000) {\n  var o = O();\n  o.o = o;\n  i = i + 1;\n}")
+                .unwrap();
+            if let Some(vm) = lox.vm() {
+                vm.collect_garbage();
+            }
+            let result = h.call(&mut lox, &[Value::Number(2.0)]).unwrap();
+            assert_eq!(f64::try_from(result), Ok(3.0), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn test_eval() {
+        for backend in [Backend::Vm, Backend::Ast] {
+            let (io, out, _) = Io::capture();
+            let options = RunOptions {
+                backend,
+                ..RunOptions::default()
+            };
+            let mut lox = Lox::with_io(options, io);
+            let value = lox.eval("1+2*3").unwrap();
+            assert_eq!(f64::try_from(value), Ok(7.0));
+            let value = lox.eval("1 + 2 * 3;").unwrap();
+            assert_eq!(f64::try_from(value), Ok(7.0));
+            // Only a last expression may go without its semicolon.
+            let value = lox.eval("var y = 2;\ny * 3").unwrap();
+            assert_eq!(f64::try_from(value), Ok(6.0));
+            assert!(lox.eval("var z = 1").is_err());
+            assert!(lox.eval("print 1").is_err());
+            assert!(lox.eval("1 2").is_err());
+            assert!(lox.run("1 + 2").is_err());
+            assert!(matches!(lox.eval("var x = 1;"), Ok(Value::None)));
+            assert!(matches!(lox.eval("{ x; }"), Ok(Value::None)));
+            let value = lox
+                .eval("fun f() { return x; }\nprint f();\n\"a\" + \"b\";")
+                .unwrap();
+            assert_eq!(String::try_from(value), Ok("ab".to_string()));
+            assert!(lox.eval("-nil;").is_err());
+            // Plain runs still drop the value.
+            lox.run("x + 1;").unwrap();
+            lox.run("print x;").unwrap();
+            assert_eq!(out.contents(), "1\n1\n");
+        }
+    }
+
+    #[test]
+    fn test_prelude() {
+        for backend in [Backend::Vm, Backend::Ast] {
+            let (io, out, err) = Io::capture();
+            let options = RunOptions {
+                backend,
+                prelude: true,
+                ..RunOptions::default()
+            };
+            let mut lox = Lox::with_io(options, io);
+            lox.run("print abs(-2);\nprint max(1, 3);\nprint min(1, 3);\nprint repeat(\"ab\", 3);")
+                .unwrap();
+            // Scripts may redefine what it defines.
+            lox.run("fun abs(x) { return \"mine\"; }\nprint abs(-2);")
+                .unwrap();
+            assert_eq!(out.contents(), "2\n3\n1\nababab\nmine\n");
+            assert_eq!(err.contents(), "");
+            assert!(Lox::new(RunOptions {
+                backend,
+                ..RunOptions::default()
+            })
+            .get_global("abs")
+            .is_none());
+        }
+    }
+
+    #[test]
+    fn test_run_atomically() {
+        for backend in [Backend::Vm, Backend::Ast] {
+            let (io, out, _) = Io::capture();
+            let options = RunOptions {
+                backend,
+                ..RunOptions::default()
+            };
+            let mut lox = Lox::with_io(options, io);
+            lox.run_atomically("var kept = 0;").unwrap();
+            assert!(lox
+                .run_atomically("var a = 1; kept = 1; var b = undefinedFn();")
+                .is_err());
+            let err = lox.run_atomically("print a;").unwrap_err();
+            assert!(
+                matches!(&err, LoxError::Runtime(e) if e.kind() == &ErrorKind::UndefinedVariable { name: "a".to_string() }),
+                "{:?}",
+                err
+            );
+            lox.run_atomically("print kept;").unwrap();
+
+            // A line that fails to resolve partway through a nested scope
+            // leaves nothing declared or open.
+            assert!(lox
+                .run_atomically("var x = 0; { var y = 1; { var z = z; } }")
+                .is_err());
+            lox.run_atomically(
+                "var x = \"global\";\nfun show() { var x = \"local\"; fun inner() { return x; } return inner(); }\nprint show(); print x;",
+            )
+            .unwrap();
+            assert_eq!(out.contents(), "0\nlocal\nglobal\n", "{:?}", backend);
+        }
+
+        // The snapshot keeps what it restores alive through collections.
+        let (io, out, _) = Io::capture();
+        let mut lox = Lox::with_io(RunOptions::default(), io);
+        lox.run_atomically("var a = [1, 2, 3];").unwrap();
+        assert!(lox
+            .run_atomically("a = nil;\nclass O {}\nvar i = 0;\nwhile (i < 20000) {\n  var o = O();\n  o.o = o;\n  i = i + 1;\n}\nprint zz;")
+            .is_err());
+        lox.run_atomically("print a;").unwrap();
+        assert_eq!(out.contents(), "[1, 2, 3]\n");
+    }
+
+    #[test]
+    fn test_io_error() {
+        let err: LoxError = std::io::Error::new(std::io::ErrorKind::NotFound, "gone").into();
+        assert!(matches!(err, LoxError::Io(_)));
+        assert_eq!(err.to_string(), "gone");
+    }
+}
@@ -0,0 +1,344 @@
+use crate::chunk::{Chunk, ChunkError, GlobalNames};
+use crate::object::Function;
+use crate::shared::Shared;
+use crate::value::Value;
+use crate::USIZE;
+use std::fmt;
+
+// A compiled script: a header, the global names the code refers to by
+// slot, then the top-level function. Integers are little-endian, operands
+// inside code included, but jump operands are as wide as a usize, so the
+// header records the width. Code is verified before it is loaded.
+const MAGIC: &[u8; 4] = b"LOXC";
+const VERSION: u16 = 6;
+
+const TAG_NUMBER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    Magic,
+    Version(u16),
+    Platform,
+    Truncated,
+    Constant(u8),
+    String,
+    Lines,
+    Code(ChunkError),
+    Global { name: String, slot: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Magic => write!(f, "Not a compiled Lox file."),
+            LoadError::Version(found) => write!(
+                f,
+                "Compiled file has format version {} but {} is supported.",
+                found, VERSION
+            ),
+            LoadError::Platform => write!(f, "Compiled file is for another platform."),
+            LoadError::Truncated => write!(f, "Compiled file is truncated."),
+            LoadError::Constant(tag) => write!(f, "Unknown constant tag {}.", tag),
+            LoadError::String => write!(f, "Compiled file holds an invalid string."),
+            LoadError::Lines => write!(f, "Line table does not match the code."),
+            LoadError::Code(e) => write!(f, "Compiled file has invalid code: {}.", e),
+            LoadError::Global { name, slot } => {
+                write!(f, "Global {} must be in slot {}.", name, slot)
+            }
+        }
+    }
+}
+impl std::error::Error for LoadError {}
+
+pub fn is_loxc(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn save(function: &Function, globals: &GlobalNames) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.push(USIZE as u8);
+    write_u32(&mut out, globals.names().len());
+    for name in globals.names() {
+        write_string(&mut out, name);
+    }
+    write_function(&mut out, function);
+    out
+}
+
+// The globals of the file must take the same slots in the VM's table, which
+// holds when it only has the natives defined before compiling.
+pub fn load(bytes: &[u8], globals: &mut GlobalNames) -> Result<Shared<Function>, LoadError> {
+    let mut reader = Reader {
+        bytes,
+        pos: 0,
+        globals: 0,
+    };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(LoadError::Magic);
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(LoadError::Version(version));
+    }
+    if reader.u8()? as usize != USIZE {
+        return Err(LoadError::Platform);
+    }
+    for slot in 0..reader.u32()? {
+        let name = reader.string()?;
+        if globals.slot(&name) != slot {
+            return Err(LoadError::Global { name, slot });
+        }
+    }
+    reader.globals = globals.names().len();
+    Ok(Shared::new(reader.function()?))
+}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) {
+    out.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+fn write_string(out: &mut Vec<u8>, string: &str) {
+    write_u32(out, string.len());
+    out.extend_from_slice(string.as_bytes());
+}
+
+fn write_function(out: &mut Vec<u8>, function: &Function) {
+    write_string(out, &function.name);
+    out.push(function.arity);
+    out.push(function.upvalue);
+    let chunk = &function.chunk;
+    write_u32(out, chunk.len());
+    out.extend_from_slice(chunk.code());
+    let runs: Vec<(usize, i32)> = chunk.line_runs().collect();
+    write_u32(out, runs.len());
+    for (count, line) in runs {
+        write_u32(out, count);
+        out.extend_from_slice(&line.to_le_bytes());
+    }
+    write_u32(out, chunk.constants().len());
+    for constant in chunk.constants() {
+        match constant {
+            Value::Number(n) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::String(s) => {
+                out.push(TAG_STRING);
+                write_string(out, s);
+            }
+            Value::Function(f) => {
+                out.push(TAG_FUNCTION);
+                write_function(out, f);
+            }
+            _ => unreachable!("The compiler only makes number, string and function constants"),
+        }
+    }
+    out.extend_from_slice(&(chunk.cache_count() as u16).to_le_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // Global slots the code may refer to.
+    globals: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        let end = self.pos.checked_add(n).ok_or(LoadError::Truncated)?;
+        let bytes = self.bytes.get(self.pos..end).ok_or(LoadError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn i32(&mut self) -> Result<i32, LoadError> {
+        Ok(self.u32()? as u32 as i32)
+    }
+
+    fn f64(&mut self) -> Result<f64, LoadError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(f64::from_le_bytes(bytes))
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| LoadError::String)
+    }
+
+    fn function(&mut self) -> Result<Function, LoadError> {
+        let name = self.string()?;
+        let arity = self.u8()?;
+        let upvalue = self.u8()?;
+        let len = self.u32()?;
+        let code = self.take(len)?;
+        let mut chunk = Chunk::new();
+        for _ in 0..self.u32()? {
+            let count = self.u32()?;
+            let line = self.i32()?;
+            let start = chunk.len();
+            for byte in code.get(start..start + count).ok_or(LoadError::Lines)? {
+                chunk.write_chunk(*byte, line);
+            }
+        }
+        if chunk.len() != len {
+            return Err(LoadError::Lines);
+        }
+        for _ in 0..self.u32()? {
+            let constant = match self.u8()? {
+                TAG_NUMBER => Value::Number(self.f64()?),
+                TAG_STRING => Value::String(self.string()?.into()),
+                TAG_FUNCTION => Value::Function(Shared::new(self.function()?)),
+                tag => return Err(LoadError::Constant(tag)),
+            };
+            chunk.add_constant(constant);
+        }
+        for _ in 0..self.u16()? {
+            chunk.add_cache();
+        }
+        let frame_size = chunk
+            .verify(arity as usize + 1, upvalue as usize, self.globals)
+            .map_err(LoadError::Code)?;
+        Ok(Function {
+            arity,
+            upvalue,
+            chunk: Box::new(chunk),
+            name,
+            frame_size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::compile;
+
+    fn compiled() -> (Vec<u8>, GlobalNames) {
+        let mut globals = GlobalNames::default();
+        let function = compile(
+            "var greeting = \"hi\";\nfun f(a) {\n  return a + 1.5;\n}\nprint f(1);\n",
+            &mut globals,
+        )
+        .expect("Compile error");
+        (save(&function, &globals), globals)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (bytes, _) = compiled();
+        let mut globals = GlobalNames::default();
+        let function = load(&bytes, &mut globals).expect("Load error");
+        assert_eq!(save(&function, &globals), bytes);
+        let f = function
+            .chunk
+            .constants()
+            .iter()
+            .find_map(|val| val.as_function())
+            .expect("Lost nested function");
+        assert_eq!((f.name.as_str(), f.arity), ("f", 1));
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        let (mut bytes, _) = compiled();
+        for len in 0..bytes.len() {
+            let err = load(&bytes[..len], &mut GlobalNames::default());
+            if len < MAGIC.len() {
+                assert!(err.is_err());
+            } else {
+                assert_eq!(err.err(), Some(LoadError::Truncated), "at {}", len);
+            }
+        }
+        bytes[4] = 9;
+        assert_eq!(
+            load(&bytes, &mut GlobalNames::default()).err(),
+            Some(LoadError::Version(9))
+        );
+        assert_eq!(
+            load(b"print 1;", &mut GlobalNames::default()).err(),
+            Some(LoadError::Magic)
+        );
+    }
+
+    #[test]
+    fn test_global_slots_must_match() {
+        let (bytes, _) = compiled();
+        let mut globals = GlobalNames::default();
+        globals.slot("clock");
+        assert_eq!(
+            load(&bytes, &mut globals).err(),
+            Some(LoadError::Global {
+                name: "greeting".to_string(),
+                slot: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_corrupt_code_never_panics() {
+        use crate::output::Io;
+        use crate::vm::{VmOptions, VM};
+
+        let source = r#"class A {
+  init(n) { this.n = n; }
+  get() { return this.n; }
+}
+class B < A {
+  get() { return super.get() + 1; }
+}
+fun counter() {
+  var i = 0;
+  fun next() { i = i + 1; return i; }
+  return next;
+}
+var c = counter();
+var list = [B(1).get(), c(), {"k": c()}];
+var j = 0;
+while (j < 3) {
+  if (j == 1 or j == 2) print list[j];
+  j = j + 1;
+}
+try { throw list[0]; } catch (e) { print e; }
+"#;
+        let options = VmOptions {
+            max_instructions: Some(10_000),
+            ..VmOptions::default()
+        };
+        let mut vm = VM::init(options);
+        let function = vm.compile(source, 1).expect("Compile error");
+        let bytes = save(&function, vm.global_names());
+        // Every byte changed a few ways either fails to load or runs to an
+        // error or the end.
+        for pos in MAGIC.len()..bytes.len() {
+            for flip in [0x01, 0x80, 0xff] {
+                let mut corrupt = bytes.clone();
+                corrupt[pos] ^= flip;
+                let mut vm = VM::init(options);
+                vm.set_io(Io::capture().0);
+                if let Ok(function) = load(&corrupt, vm.global_names()) {
+                    let _ = vm.interpret(function);
+                }
+            }
+        }
+    }
+}
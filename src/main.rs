@@ -1,173 +1,798 @@
-extern crate lazy_static;
-use std::cell::RefCell;
-use std::collections::{HashMap, LinkedList};
+use lox::compile::compile;
+#[cfg(not(target_arch = "wasm32"))]
+use lox::completion::Names;
+use lox::coverage::Coverage;
+use lox::dot::write_dot;
+use lox::formatter::format_source;
+use lox::output::{bold, paint, Colored};
+use lox::test_runner::run_suite;
+use lox::vm::TraceFormat;
+use lox::{loxc, Backend, Io, Lox, LoxError, RunOptions};
+#[cfg(not(target_arch = "wasm32"))]
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, Editor, Helper,
+};
 use std::env;
-use std::fs;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
-use std::io::{BufRead, BufReader, Error};
+use std::io::{BufWriter, Error, ErrorKind, IsTerminal, Read, Write};
+use std::path::Path;
 use std::process;
-use std::rc::Rc;
-
-mod callable;
-mod chunk;
-mod compile;
-mod error;
-mod expr;
-mod interpreter;
-mod object;
-mod parser;
-mod resolver;
-mod scanner;
-mod stmt;
-mod token;
-mod vm;
-use crate::compile::compile;
-use crate::error::RuntimeError;
-use crate::interpreter::interpret;
-use crate::parser::parser;
-use crate::resolver::resolve;
-use crate::scanner::scan_tokens;
-use crate::stmt::Environment;
-use crate::token::Token;
-use crate::vm::VM;
-
-const USIZE: usize = std::mem::size_of::<usize>();
-const BACKTRACE: bool = true;
-const DEBUG: bool = false;
-const NEW: bool = true;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
+// Exit statuses from sysexits.h, as the book uses them.
+// What lox fmt --check and lox test exit with when a file fails.
+const EXIT_FAILED: i32 = 1;
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
+const EX_IOERR: i32 = 74;
+// 128 + SIGINT, as shells report a process killed by Ctrl-C.
+const EXIT_INTERRUPTED: i32 = 130;
+
+// How often lox watch looks at its scripts, and how long they must stay
+// unchanged after a change before it runs them again.
+const WATCH_POLL: Duration = Duration::from_millis(100);
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+// The tree-walk backend recurses on the native stack, several frames to a
+// Lox call, so lox runs on a thread with room for its call depth limit to
+// be reached first.
+const STACK_SIZE: usize = 64 << 20;
+
+#[cfg(target_arch = "wasm32")]
+fn main() {
+    cli();
+}
+
+#[cfg(not(target_arch = "wasm32"))]
 fn main() {
+    let cli = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(cli)
+        .expect("Could not start the interpreter thread");
+    if let Err(panic) = cli.join() {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+fn cli() {
     let args: Vec<String> = env::args().collect();
-    if args.len() > 2 {
-        println!("Usage: lox [script]");
-        process::exit(0x0040);
-    } else if args.len() == 2 {
-        if NEW {
-            let _ = run_file(&args[1]);
+    let mut options = RunOptions {
+        prelude: true,
+        ..RunOptions::default()
+    };
+    options.vm.trace = env::var_os("LOX_TRACE").is_some();
+    options.vm.backtrace = env::var_os("LOX_BACKTRACE").is_none_or(|v| v != "0");
+    let mut disassemble = false;
+    let mut ast_dot = false;
+    let mut check = false;
+    let mut no_color = false;
+    let mut command: Option<&str> = None;
+    let mut output: Option<&String> = None;
+    let mut coverage_out: Option<&String> = None;
+    let mut trace_file: Option<&String> = None;
+    let mut scripts: Vec<&String> = Vec::new();
+    let mut files: Vec<&String> = Vec::new();
+    let mut evals: Vec<&String> = Vec::new();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--max-stack" => match iter.next().and_then(|n| n.parse().ok()) {
+                Some(n) => options.vm.max_stack = n,
+                None => usage(),
+            },
+            "--max-frames" => match iter.next().and_then(|n| n.parse().ok()) {
+                Some(n) => options.vm.max_frames = n,
+                None => usage(),
+            },
+            "--max-call-depth" => match iter.next().and_then(|n| n.parse().ok()) {
+                Some(n) => options.vm.max_call_depth = n,
+                None => usage(),
+            },
+            "--max-instructions" => match iter.next().and_then(|n| n.parse().ok()) {
+                Some(n) => options.vm.max_instructions = Some(n),
+                None => usage(),
+            },
+            "--timeout-ms" => match iter.next().and_then(|n| n.parse().ok()) {
+                Some(n) => options.vm.timeout = Some(Duration::from_millis(n)),
+                None => usage(),
+            },
+            "--backend" => match iter.next().map(String::as_str) {
+                Some("vm") => options.backend = Backend::Vm,
+                Some("ast") => options.backend = Backend::Ast,
+                _ => usage(),
+            },
+            "--trace" => options.vm.trace = true,
+            "--trace-file" => match iter.next() {
+                Some(path) => {
+                    options.vm.trace = true;
+                    trace_file = Some(path);
+                }
+                None => usage(),
+            },
+            "--trace-format" => match iter.next().map(String::as_str) {
+                Some("full") => options.vm.trace_format = TraceFormat::Full,
+                Some("compact") => options.vm.trace_format = TraceFormat::Compact,
+                _ => usage(),
+            },
+            "--no-backtrace" => options.vm.backtrace = false,
+            "--dump-bytecode" => options.dump_bytecode = true,
+            "--disassemble" => disassemble = true,
+            "--ast-dot" => ast_dot = true,
+            "--profile" => options.vm.profile = true,
+            "--time" => options.time = true,
+            "--coverage" => options.coverage = true,
+            "--coverage-out" => match iter.next() {
+                Some(path) => {
+                    options.coverage = true;
+                    coverage_out = Some(path);
+                }
+                None => usage(),
+            },
+            "--debug" => options.vm.debug = true,
+            "--no-color" => no_color = true,
+            "--no-prelude" => options.prelude = false,
+            "--check-globals" => options.check_globals = true,
+            "-e" | "--eval" => match iter.next() {
+                Some(source) => evals.push(source),
+                None => usage(),
+            },
+            "-o" => match iter.next() {
+                Some(path) => output = Some(path),
+                None => usage(),
+            },
+            "--check" if command == Some("fmt") => check = true,
+            "compile" | "run" | "check" | "fmt" | "watch" | "test"
+                if command.is_none() && scripts.is_empty() =>
+            {
+                command = Some(arg.as_str())
+            }
+            _ if matches!(command, Some("check" | "fmt" | "test")) => files.push(arg),
+            _ => scripts.push(arg),
+        }
+    }
+    // NO_COLOR turns color off when set to anything but the empty string.
+    let color = !no_color
+        && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        && io::stderr().is_terminal();
+    if !evals.is_empty() {
+        if !scripts.is_empty() || command.is_some() || disassemble || ast_dot {
+            usage();
+        }
+        catch_interrupts();
+        run_evals(&evals, options, color, trace_file);
+    } else if command == Some("check") {
+        if files.is_empty() || disassemble || ast_dot {
+            usage();
+        }
+        let options = RunOptions {
+            check_globals: true,
+            ..options
+        };
+        process::exit(check_files(&files, options, color));
+    } else if command == Some("fmt") {
+        if files.is_empty() || disassemble || ast_dot {
+            usage();
+        }
+        process::exit(fmt_files(&files, check, color));
+    } else if command == Some("compile") || disassemble || ast_dot {
+        let [path] = scripts[..] else {
+            usage();
+        };
+        let result = if disassemble {
+            disassemble_file(path, options.prelude)
+        } else if ast_dot {
+            dot_file(path)
         } else {
-            let _ = run_file_old(&args[1]);
+            compile_file(path, output, options.prelude)
+        };
+        if let Err(e) = result {
+            fail(path, &e, false);
+        }
+    } else if command == Some("test") {
+        if files.is_empty() || disassemble || ast_dot {
+            usage();
+        }
+        process::exit(test_dirs(&files, options));
+    } else if command == Some("watch") {
+        if scripts.is_empty() || scripts.iter().any(|path| *path == "-") {
+            usage();
         }
-    } else if NEW {
-        let _ = run_prompt();
+        watch_files(&scripts, options, color);
+    } else if !scripts.is_empty() {
+        catch_interrupts();
+        run_files(&scripts, options, color, coverage_out, trace_file);
+    } else if command.is_some() {
+        usage();
     } else {
-        let _ = run_prompt_old();
+        catch_interrupts();
+        let _ = run_prompt(options, color, trace_file);
     }
 }
 
-fn run_file(path: &String) -> Result<(), Error> {
-    let content = fs::read_to_string(path)?;
-    let mut vm = VM::init();
-    if let Some(function) = compile(&content) {
-        vm.interpret(function);
+// Ctrl-C stops the running program instead of the process. The REPL then
+// carries on at the next line.
+#[cfg(not(target_arch = "wasm32"))]
+fn catch_interrupts() {
+    if let Err(e) = ctrlc::set_handler(lox::interrupt) {
+        eprintln!("Could not handle Ctrl-C: {}", e);
     }
-    Ok(())
 }
 
-fn run_prompt() -> Result<(), Error> {
-    let mut vm = VM::init();
-    let lines = io::stdin().lines();
-    for line in lines {
-        let s = line?;
-        if let Some(function) = compile(&s) {
-            vm.interpret(function);
+#[cfg(target_arch = "wasm32")]
+fn catch_interrupts() {}
+
+fn usage() -> ! {
+    println!("Usage: lox [--backend vm|ast] [--max-stack N] [--max-frames N] [--max-call-depth N] [--max-instructions N] [--timeout-ms N] [--trace] [--trace-file file] [--trace-format full|compact] [--no-backtrace] [--dump-bytecode] [--disassemble] [--ast-dot] [--profile] [--time] [--coverage] [--coverage-out file] [--debug] [--no-color] [--no-prelude] [--check-globals] [script... | - | -e source]");
+    println!("       lox compile script.lox [-o script.loxc]");
+    println!("       lox run script.loxc");
+    println!("       lox check script.lox...");
+    println!("       lox fmt [--check] script.lox...");
+    println!("       lox watch script.lox...");
+    println!("       lox test dir...");
+    process::exit(EX_USAGE);
+}
+
+// "-" names standard input, read to the end as one script.
+fn read_script(path: &str) -> Result<Vec<u8>, Error> {
+    if path == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes)?;
+        Ok(bytes)
+    } else {
+        fs::read(path)
+    }
+}
+
+fn read_script_string(path: &str) -> Result<String, Error> {
+    decode(read_script(path)?)
+}
+
+// Scripts must be UTF-8; the error gives the offset of the first bad byte.
+fn decode(bytes: Vec<u8>) -> Result<String, Error> {
+    String::from_utf8(bytes).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid UTF-8 at byte {}", e.utf8_error().valid_up_to()),
+        )
+    })
+}
+
+// An I/O error's message without the " (os error N)" the OS adds.
+fn io_message(e: &Error) -> String {
+    let message = e.to_string();
+    match e.raw_os_error() {
+        Some(code) => message
+            .trim_end_matches(&format!(" (os error {})", code))
+            .to_string(),
+        None => message,
+    }
+}
+
+// Runs each script in turn in one session, so later scripts see the
+// globals earlier ones defined. The first failure stops the rest.
+fn run_files(
+    paths: &[&String],
+    options: RunOptions,
+    color: bool,
+    coverage_out: Option<&String>,
+    trace_file: Option<&String>,
+) {
+    let mut lox = session(options, color, trace_file);
+    let mut coverage = Vec::new();
+    let mut result = Ok(());
+    for path in paths {
+        let outcome = run_file(&mut lox, path);
+        coverage.extend(lox.take_coverage().map(|lines| (*path, lines)));
+        if let Err(e) = outcome {
+            result = Err((path, e));
+            break;
         }
     }
-    Ok(())
+    if let Some(profile) = lox.profile() {
+        eprint!("{}", profile);
+    }
+    if let Some(timings) = lox.timings() {
+        eprint!("{}", timings);
+    }
+    if options.coverage {
+        report_coverage(&coverage, coverage_out);
+    }
+    // Flushes the trace file before exiting.
+    drop(lox);
+    if let Err((path, e)) = result {
+        fail(path, &e, paths.len() > 1);
+    }
 }
 
-fn run_file_old(path: &String) -> Result<(), Error> {
-    let input = File::open(path)?;
-    let buffered = BufReader::new(input);
-    let mut l: i32 = 1;
-    let env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new()));
-    let mut tokens: LinkedList<Token> = LinkedList::new();
-    for line in buffered.lines() {
-        tokens.pop_back();
-        match scan_tokens(&line?, &mut l) {
+// Writes the scripts' coverage to path as an lcov tracefile, or else
+// summarizes it on stderr.
+fn report_coverage(coverage: &[(&String, Coverage)], path: Option<&String>) {
+    if let Some(path) = path {
+        let lcov: String = coverage
+            .iter()
+            .map(|(script, lines)| lines.lcov(script))
+            .collect();
+        if let Err(e) = fs::write(path, lcov) {
+            eprintln!("Could not write {}: {}", path, e);
+            process::exit(EX_IOERR);
+        }
+        return;
+    }
+    for (script, lines) in coverage {
+        eprintln!(
+            "{}: {} of {} lines executed",
+            script,
+            lines.hit().count(),
+            lines.executable.len()
+        );
+        let missed: Vec<String> = lines.missed().map(|line| line.to_string()).collect();
+        if !missed.is_empty() {
+            eprintln!("{}: never executed: {}", script, missed.join(", "));
+        }
+    }
+}
+
+// Exits for a failed script.
+fn fail(path: &str, e: &LoxError, name_script: bool) -> ! {
+    report_failure(path, e, name_script);
+    process::exit(exit_code(e));
+}
+
+// Everything but failing to read a script was reported already; with
+// several scripts, the one that failed is named.
+fn report_failure(path: &str, e: &LoxError, name_script: bool) {
+    let name = if path == "-" { "<stdin>" } else { path };
+    if let LoxError::Io(e) = e {
+        eprintln!("Could not read '{}': {}", name, io_message(e));
+    } else if name_script {
+        eprintln!("Error in {}.", name);
+    }
+}
+
+// Runs the test scripts under each directory, reporting each to stdout.
+fn test_dirs(dirs: &[&String], options: RunOptions) -> i32 {
+    let mut status = 0;
+    for dir in dirs {
+        match run_suite(Path::new(dir), options, &mut io::stdout()) {
+            Ok(summary) if summary.failed == 0 => {}
+            Ok(_) => status = status.max(EXIT_FAILED),
             Err(e) => {
-                eprintln!("{}", e);
+                eprintln!("{}: Could not read: {}", dir, e);
+                status = EX_IOERR;
             }
-            Ok(mut val) => {
-                tokens.append(&mut val);
+        }
+    }
+    status
+}
+
+// Runs the scripts, then again in a fresh session each time any of them
+// changes, until killed. A failing run only ends that run.
+fn watch_files(paths: &[&String], options: RunOptions, color: bool) -> ! {
+    let mut seen = modified(paths);
+    loop {
+        let mut lox = Lox::with_io(options, stdio(color));
+        for path in paths {
+            if let Err(e) = run_file(&mut lox, path) {
+                report_failure(path, &e, paths.len() > 1);
+                break;
             }
         }
-        l += 1;
-    }
-    let result = parser(&mut tokens);
-    match result {
-        Ok(stmts) => {
-            let mut table: HashMap<u64, i32> = HashMap::new();
-            let mut scopes: LinkedList<HashMap<String, bool>> = LinkedList::new();
-            scopes.push_front(HashMap::<String, bool>::new());
-            resolve(stmts.clone(), &mut scopes, &mut table);
-            match interpret(stmts, env, &table) {
-                Ok(_) => Ok(()),
-                Err(e) => match e {
-                    RuntimeError::ReturnValue(val) => match val.as_number() {
-                        Some(v) => process::exit(v as i32),
-                        None => process::exit(-1),
-                    },
-                    _ => {
-                        eprintln!("{}", e);
-                        process::exit(-1);
-                    }
-                },
+        seen = next_change(paths, seen, WATCH_POLL, WATCH_DEBOUNCE);
+        println!("--- changed, running again ---");
+    }
+}
+
+// When each script was last modified, if it can be read.
+fn modified(paths: &[&String]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+// Waits for the scripts to change from seen, then for them to stay as they
+// are for debounce, so an editor's several writes make one change.
+fn next_change(
+    paths: &[&String],
+    seen: Vec<Option<SystemTime>>,
+    poll: Duration,
+    debounce: Duration,
+) -> Vec<Option<SystemTime>> {
+    let mut current = seen.clone();
+    while current == seen {
+        thread::sleep(poll);
+        current = modified(paths);
+    }
+    loop {
+        thread::sleep(debounce);
+        let latest = modified(paths);
+        if latest == current {
+            return current;
+        }
+        current = latest;
+    }
+}
+
+// Runs a script, or a compiled script when the file starts with the .loxc
+// magic bytes. Its lines are numbered from 1, and its errors name it.
+fn run_file(lox: &mut Lox, path: &str) -> Result<(), LoxError> {
+    let bytes = read_script(path)?;
+    lox.set_line(1);
+    let name = if path == "-" { "<stdin>" } else { path };
+    let result = match lox.vm() {
+        Some(vm) if loxc::is_loxc(&bytes) => match loxc::load(&bytes, vm.global_names()) {
+            Ok(function) => lox.run_function(function),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(EX_DATAERR);
+            }
+        },
+        _ => lox.run(&decode(bytes)?),
+    };
+    result.map_err(|e| e.with_source_name(name))
+}
+
+fn compile_file(path: &String, output: Option<&String>, prelude: bool) -> Result<(), LoxError> {
+    let content = read_script_string(path)?;
+    let mut lox = numbering_globals(prelude);
+    let vm = lox.vm().expect("The VM is the default backend");
+    let function = compile(&content, vm.global_names())?;
+    let output = match output {
+        Some(output) => Path::new(output).to_path_buf(),
+        None => Path::new(path).with_extension("loxc"),
+    };
+    if let Err(e) = fs::write(&output, loxc::save(&function, vm.global_names())) {
+        eprintln!("Could not write {}: {}", output.display(), e);
+        process::exit(EX_IOERR);
+    }
+    Ok(())
+}
+
+// A VM session to compile against, numbering globals as a run would: after
+// the natives and, unless it is off, the prelude.
+fn numbering_globals(prelude: bool) -> Lox {
+    Lox::new(RunOptions {
+        prelude,
+        ..RunOptions::default()
+    })
+}
+
+// A session on the standard streams that traces to trace_file, if given.
+fn session(options: RunOptions, color: bool, trace_file: Option<&String>) -> Lox {
+    let mut lox = Lox::with_io(options, stdio(color));
+    if let Some(path) = trace_file {
+        match File::create(path) {
+            Ok(file) => lox.set_trace_output(Box::new(BufWriter::new(file))),
+            Err(e) => {
+                eprintln!("Could not create {}: {}", path, e);
+                process::exit(EX_IOERR);
             }
         }
-        Err(e) => {
-            eprintln!("{}", e);
-            process::exit(-1);
+    }
+    lox
+}
+
+// Standard streams, with diagnostics colored for a terminal.
+fn stdio(color: bool) -> Io {
+    if color {
+        Io {
+            out: Box::new(io::stdout()),
+            err: Box::new(Colored::new(io::stderr())),
         }
+    } else {
+        Io::stdio()
     }
 }
 
-fn run_prompt_old() -> Result<(), Error> {
-    let lines = io::stdin().lines();
-    let mut l: i32 = 1;
-    let env: Rc<RefCell<Environment>> = Rc::new(RefCell::new(Environment::new()));
-    let mut table: HashMap<u64, i32> = HashMap::new();
-    let mut scopes: LinkedList<HashMap<String, bool>> = LinkedList::new();
-    scopes.push_front(HashMap::<String, bool>::new());
-    for line in lines {
-        if run(line.unwrap(), l, env.clone(), &mut scopes, &mut table).is_err() {
-            eprintln!("Error in evaluation")
+// Prints a diagnostic from one of several files under the file's name.
+fn report(path: &str, line: &str, color: bool) {
+    if color {
+        eprintln!("{}: {}", bold(path), paint(line));
+    } else {
+        eprintln!("{}: {}", path, line);
+    }
+}
+
+// Checks each file without running it, printing its diagnostics under its
+// name, and returns the exit status for the lot.
+fn check_files(paths: &[&String], options: RunOptions, color: bool) -> i32 {
+    let mut status = 0;
+    for path in paths {
+        let (io, _, err) = Io::capture();
+        let result = read_script_string(path)
+            .map_err(LoxError::from)
+            .and_then(|source| Lox::with_io(options, io).check(&source));
+        if let Err(LoxError::Io(e)) = result {
+            eprintln!("{}: Could not read: {}", path, e);
+            status = EX_DATAERR;
+            continue;
+        }
+        // Warnings come with a successful check too.
+        for line in err.contents().lines().filter(|line| !line.is_empty()) {
+            report(path, line, color);
+        }
+        match result {
+            Ok(()) => println!("{}: ok", path),
+            Err(_) => status = EX_DATAERR,
         }
-        l += 1;
+    }
+    status
+}
+
+// Formats each file in place, or to stdout for "-". With check, only
+// reports the files that would change.
+fn fmt_files(paths: &[&String], check: bool, color: bool) -> i32 {
+    let mut status = 0;
+    for path in paths {
+        let source = match read_script_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("{}: Could not read: {}", path, e);
+                status = EX_DATAERR;
+                continue;
+            }
+        };
+        let mut err = Vec::new();
+        let formatted = match format_source(&source, &mut err) {
+            Ok(formatted) => formatted,
+            Err(_) => {
+                for line in String::from_utf8_lossy(&err)
+                    .lines()
+                    .filter(|line| !line.is_empty())
+                {
+                    report(path, line, color);
+                }
+                status = EX_DATAERR;
+                continue;
+            }
+        };
+        if check {
+            if formatted != source {
+                println!("{}: not formatted", path);
+                status = status.max(EXIT_FAILED);
+            }
+        } else if path.as_str() == "-" {
+            print!("{}", formatted);
+        } else if formatted != source {
+            if let Err(e) = fs::write(path, formatted) {
+                eprintln!("{}: Could not write: {}", path, e);
+                status = EX_IOERR;
+            }
+        }
+    }
+    status
+}
+
+// Lists the bytecode of a script and every function in it without running it.
+fn disassemble_file(path: &str, prelude: bool) -> Result<(), LoxError> {
+    let content = read_script_string(path)?;
+    let mut lox = numbering_globals(prelude);
+    let vm = lox.vm().expect("The VM is the default backend");
+    let function = compile(&content, vm.global_names())?;
+    if let Err(e) = function.disassemble_to(&mut io::stdout()) {
+        eprintln!("{}", e);
     }
     Ok(())
 }
 
-fn run(
-    source: String,
-    line_number: i32,
-    env: Rc<RefCell<Environment>>,
-    scopes: &mut LinkedList<HashMap<String, bool>>,
-    table: &mut HashMap<u64, i32>,
-) -> Result<(), ()> {
-    let mut line: i32 = line_number;
-    let mut tokens: LinkedList<Token> = match scan_tokens(&source, &mut line) {
-        Err(e) => {
-            eprintln!("{}", e);
-            return Err(());
-        }
-        Ok(val) => val,
+// Prints a script's syntax tree as a Graphviz digraph without running it.
+fn dot_file(path: &str) -> Result<(), LoxError> {
+    let content = read_script_string(path)?;
+    write_dot(&content, &mut io::stdout(), &mut io::stderr())
+}
+
+// Lines are buffered until their brackets and strings are closed. A blank
+// line drops an unfinished entry.
+fn run_prompt(options: RunOptions, color: bool, trace_file: Option<&String>) -> Result<(), Error> {
+    let mut lox = session(options, color, trace_file);
+    let mut input = Input::new();
+    let mut pending = String::new();
+    loop {
+        let prompt = if pending.is_empty() { "" } else { "... " };
+        let Some(line) = input.next_line(prompt) else {
+            break;
+        };
+        let line = line?;
+        if pending.is_empty() && line.trim_start().starts_with(':') {
+            if !run_command(&mut lox, line.trim()) {
+                break;
+            }
+            continue;
+        }
+        if !pending.is_empty() && line.trim().is_empty() {
+            pending.clear();
+            continue;
+        }
+        pending.push_str(&line);
+        pending.push('\n');
+        if is_complete(&pending) {
+            let _ = lox.run_atomically(&pending);
+            pending.clear();
+            input.update(&lox);
+        }
+    }
+    if let Some(profile) = lox.profile() {
+        eprint!("{}", profile);
+    }
+    if let Some(timings) = lox.timings() {
+        eprint!("{}", timings);
+    }
+    Ok(())
+}
+
+const HELP: &str = "\
+:help         List these commands.
+:quit         Leave the REPL.
+:env          Show the defined globals.
+:load <path>  Run a file in this session.
+:reset        Forget everything defined so far.
+
+A line that fails puts the globals back as they were. Changes it made to
+arrays, maps and instances stay.";
+
+// Runs a REPL line starting with ':'. Returns false on :quit.
+fn run_command(lox: &mut Lox, command: &str) -> bool {
+    let (name, arg) = match command.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (command, ""),
     };
-    let result = parser(&mut tokens);
-    match result {
-        Ok(stmts) => {
-            resolve(stmts.clone(), scopes, table);
-            match interpret(stmts, env, table) {
-                Ok(_) => Ok(()),
-                Err(e) => {
-                    eprintln!("Line {}: {}", line_number, e);
-                    Err(())
+    match name {
+        ":help" => println!("{}", HELP),
+        ":quit" => return false,
+        ":env" => {
+            for (name, val) in lox.globals() {
+                println!("{} = {}", name, val);
+            }
+        }
+        ":load" if arg.is_empty() => eprintln!("Usage: :load <path>"),
+        ":load" => match fs::read(arg).and_then(decode) {
+            Ok(content) => {
+                let _ = lox.run(&content);
+            }
+            Err(e) => eprintln!("Could not read '{}': {}", arg, io_message(&e)),
+        },
+        ":reset" => lox.reset(),
+        _ => eprintln!("Unknown command {}. Type :help for a list.", name),
+    }
+    true
+}
+
+// Whether every bracket and string opened in source is closed. Extra closing
+// brackets count as complete and are left for the parser to report.
+fn is_complete(source: &str) -> bool {
+    let mut depth: i32 = 0;
+    let mut in_string = false;
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '/' if chars.peek() == Some(&'/') => while chars.next_if(|&c| c != '\n').is_some() {},
+            '(' | '{' | '[' => depth += 1,
+            ')' | '}' | ']' => depth -= 1,
+            _ => {}
+        }
+    }
+    depth <= 0 && !in_string
+}
+
+// Where the REPL reads lines: a line editor with history and completion on
+// a terminal, or else stdin as it comes.
+enum Input {
+    #[cfg(not(target_arch = "wasm32"))]
+    Editor(Box<Editor<LoxHelper, DefaultHistory>>),
+    Lines(io::Lines<io::StdinLock<'static>>),
+}
+
+impl Input {
+    fn new() -> Input {
+        #[cfg(not(target_arch = "wasm32"))]
+        if io::stdin().is_terminal() {
+            if let Ok(mut editor) = Editor::new() {
+                editor.set_helper(Some(LoxHelper::default()));
+                return Input::Editor(Box::new(editor));
+            }
+        }
+        Input::Lines(io::stdin().lines())
+    }
+
+    // The next line, or None at the end of input. Ctrl-C at the prompt
+    // reads as a blank line, dropping an unfinished entry.
+    fn next_line(&mut self, prompt: &str) -> Option<Result<String, Error>> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Input::Editor(editor) => match editor.readline(prompt) {
+                Ok(line) => {
+                    let _ = editor.add_history_entry(line.as_str());
+                    Some(Ok(line))
                 }
+                Err(ReadlineError::Interrupted) => Some(Ok(String::new())),
+                Err(ReadlineError::Eof) => None,
+                Err(e) => Some(Err(Error::other(e))),
+            },
+            Input::Lines(lines) => {
+                if !prompt.is_empty() && io::stdin().is_terminal() {
+                    print!("{}", prompt);
+                    let _ = io::stdout().flush();
+                }
+                lines.next()
             }
         }
-        Err(e) => {
-            eprintln!("{}", e);
-            Err(())
+    }
+
+    // Lets completion see what the last line defined.
+    fn update(&mut self, lox: &Lox) {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Input::Editor(editor) = self {
+            if let Some(helper) = editor.helper_mut() {
+                helper.names = Names::of(lox);
+            }
         }
     }
 }
+
+// Completes keywords, globals and the fields of global instances.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Default)]
+struct LoxHelper {
+    names: Names,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Completer for LoxHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.names.complete(line, pos))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Hinter for LoxHelper {
+    type Hint = String;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Highlighter for LoxHelper {}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Validator for LoxHelper {}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Helper for LoxHelper {}
+
+// Runs each -e snippet in turn, sharing one set of globals.
+fn run_evals(evals: &[&String], options: RunOptions, color: bool, trace_file: Option<&String>) {
+    let mut lox = session(options, color, trace_file);
+    let result = evals.iter().try_for_each(|source| lox.run(source));
+    if let Some(profile) = lox.profile() {
+        eprint!("{}", profile);
+    }
+    if let Some(timings) = lox.timings() {
+        eprint!("{}", timings);
+    }
+    drop(lox);
+    if let Err(e) = result {
+        process::exit(exit_code(&e));
+    }
+}
+
+fn exit_code(e: &LoxError) -> i32 {
+    if lox::is_interrupted() {
+        return EXIT_INTERRUPTED;
+    }
+    match e {
+        LoxError::Scan(_) | LoxError::Parse(_) | LoxError::Resolve(_) => EX_DATAERR,
+        LoxError::Runtime(_) => EX_SOFTWARE,
+        LoxError::Io(_) => EX_IOERR,
+    }
+}
@@ -1,96 +1,57 @@
-use crate::chunk::Chunk;
-use std::cell::RefCell;
+use crate::chunk::{Chunk, ChunkError};
+use crate::error::Members;
+use crate::shared::{Shared, SharedCell, WeakShared};
+use crate::value::Value;
 use std::collections::HashMap;
 use std::fmt;
-use std::rc::Rc;
+use std::io::Write;
 
 #[derive(Clone)]
-pub enum LoxType {
-    None,
-    String(String),
-    Number(f64),
-    Bool(bool),
-    Function(Rc<Function>),
-    Closure(Closure),
-    Class(Rc<RefCell<Class>>),
-    Instance(Rc<RefCell<Instance>>),
-    BoundMethod(Box<BoundMethod>),
-}
-
-impl LoxType {
-    pub fn as_string(&self) -> Option<String> {
-        if let LoxType::String(s) = self {
-            Some(s.clone())
-        } else {
-            None
-        }
-    }
-
-    pub fn as_number(&self) -> Option<f64> {
-        if let LoxType::Number(n) = self {
-            Some(*n)
-        } else {
-            None
-        }
-    }
-
-    pub fn as_function(&self) -> Option<Rc<Function>> {
-        if let LoxType::Function(func) = self {
-            Some(func.clone())
-        } else {
-            None
-        }
-    }
+pub struct Function {
+    pub arity: u8,
+    pub upvalue: u8,
+    pub chunk: Box<Chunk>,
+    pub name: String,
+    // Stack slots a call may use, its callee and arguments included.
+    pub frame_size: usize,
 }
 
-impl fmt::Display for LoxType {
+impl fmt::Display for Function {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            LoxType::String(s) => write!(f, "{}", s),
-            LoxType::Number(n) => write!(f, "{}", n),
-            LoxType::Bool(b) => write!(f, "{}", b),
-            LoxType::Function(fun) => write!(f, "{}", fun.name),
-            LoxType::Closure(c) => write!(f, "{}", c.function.name),
-            LoxType::Class(k) => write!(f, "{}", k.borrow().name),
-            LoxType::Instance(i) => write!(f, "Instance of {}", i.borrow().klass.borrow().name),
-            LoxType::BoundMethod(m) => write!(f, "Bound method {}", m.method.function.name),
-            LoxType::None => write!(f, "Nil"),
+        if self.name.is_empty() {
+            write!(f, "<script>")
+        } else {
+            write!(f, "<fn {}>", self.name)
         }
     }
 }
 
-impl PartialEq for LoxType {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (LoxType::String(s1), LoxType::String(s2)) => s1 == s2,
-            (LoxType::Bool(b1), LoxType::Bool(b2)) => b1 == b2,
-            _ => false,
+impl Function {
+    // Lists this function's chunk under a header naming it, then those of
+    // the functions it defines.
+    pub fn disassemble_to(&self, out: &mut dyn Write) -> Result<(), ChunkError> {
+        let name = if self.name.is_empty() {
+            "script"
+        } else {
+            &self.name
+        };
+        let _ = writeln!(out, "== {} ==", name);
+        self.chunk.disassemble_chunk_to(out)?;
+        for function in self.chunk.functions() {
+            function.disassemble_to(out)?;
         }
+        Ok(())
     }
 }
 
-impl fmt::Debug for LoxType {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Display::fmt(self, f)
-    }
-}
-
-#[derive(Clone)]
-pub struct Function {
-    pub arity: u8,
-    pub upvalue: u8,
-    pub chunk: Box<Chunk>,
-    pub name: String,
-}
-
 #[derive(Clone)]
 pub struct Closure {
-    pub function: Rc<Function>,
-    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    pub function: Shared<Function>,
+    pub upvalues: Vec<Shared<SharedCell<Upvalue>>>,
 }
 
 impl Closure {
-    pub fn new(func: Rc<Function>) -> Closure {
+    pub fn new(func: Shared<Function>) -> Closure {
         Closure {
             function: func.clone(),
             upvalues: Vec::new(),
@@ -98,41 +59,187 @@ impl Closure {
     }
 }
 
+// Methods and fields live in slots so an inline cache can remember where
+// it found a name. A field name gets a slot on the class the first time
+// any of its instances sets it, and keeps it.
 #[derive(Clone)]
 pub struct Class {
-    pub name: String,
-    pub methods: HashMap<String, Closure>,
+    pub name: Shared<str>,
+    methods: Vec<Closure>,
+    method_slots: HashMap<Shared<str>, usize>,
+    field_slots: HashMap<Shared<str>, usize>,
 }
 
 impl Class {
-    pub fn bind_method(&self, name: &String) -> Option<&Closure> {
-        self.methods.get(name)
+    pub fn new(name: Shared<str>) -> Class {
+        Class {
+            name,
+            methods: Vec::new(),
+            method_slots: HashMap::new(),
+            field_slots: HashMap::new(),
+        }
+    }
+
+    pub fn bind_method(&self, name: &str) -> Option<&Closure> {
+        self.method(self.method_slot(name)?)
+    }
+
+    pub fn method_slot(&self, name: &str) -> Option<usize> {
+        self.method_slots.get(name).copied()
+    }
+
+    pub fn method(&self, slot: usize) -> Option<&Closure> {
+        self.methods.get(slot)
+    }
+
+    pub fn methods(&self) -> &[Closure] {
+        &self.methods
+    }
+
+    // Its methods' names, inherited ones included.
+    pub fn method_names(&self) -> impl Iterator<Item = &Shared<str>> {
+        self.method_slots.keys()
+    }
+
+    pub fn add_method(&mut self, name: Shared<str>, method: Closure) {
+        match self.method_slots.get(&name) {
+            Some(&slot) => self.methods[slot] = method,
+            None => {
+                self.method_slots.insert(name, self.methods.len());
+                self.methods.push(method);
+            }
+        }
+    }
+
+    // Copies the superclass's methods down, before any of the subclass's
+    // own are added.
+    pub fn inherit(&mut self, superclass: &Class) {
+        self.methods = superclass.methods.clone();
+        self.method_slots = superclass.method_slots.clone();
+    }
+
+    pub fn take_methods(&mut self) -> Vec<Closure> {
+        std::mem::take(&mut self.methods)
+    }
+
+    pub fn field_slot(&self, name: &str) -> Option<usize> {
+        self.field_slots.get(name).copied()
+    }
+
+    pub fn add_field(&mut self, name: Shared<str>) -> usize {
+        let next = self.field_slots.len();
+        *self.field_slots.entry(name).or_insert(next)
+    }
+
+    pub fn field_count(&self) -> usize {
+        self.field_slots.len()
     }
 }
 
 #[derive(Clone)]
 pub struct Instance {
-    pub klass: Rc<RefCell<Class>>,
-    pub fields: HashMap<String, LoxType>,
+    pub klass: Shared<SharedCell<Class>>,
+    fields: Vec<Option<Value>>,
 }
 
 #[derive(Clone)]
 pub enum Upvalue {
     Stack(usize),
-    Out(LoxType),
+    Out(Value),
 }
 
 impl Instance {
-    pub fn new(klass: Rc<RefCell<Class>>) -> Instance {
+    pub fn new(klass: Shared<SharedCell<Class>>) -> Instance {
         Instance {
             klass,
-            fields: HashMap::new(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn field(&self, slot: usize) -> Option<&Value> {
+        self.fields.get(slot)?.as_ref()
+    }
+
+    pub fn set_field(&mut self, slot: usize, val: Value) {
+        if slot >= self.fields.len() {
+            self.fields.resize(slot + 1, None);
         }
+        self.fields[slot] = Some(val);
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = &Value> {
+        self.fields.iter().flatten()
+    }
+
+    // Looks a field up by name, for hosts holding an instance.
+    pub fn get(&self, name: &str) -> Option<Value> {
+        let slot = self.klass.borrow().field_slot(name)?;
+        self.field(slot).cloned()
+    }
+
+    // The names of the fields set on it.
+    pub fn field_names(&self) -> Vec<Shared<str>> {
+        let klass = self.klass.borrow();
+        klass
+            .field_slots
+            .iter()
+            .filter(|(_, &slot)| self.field(slot).is_some())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    // Its class and the names of its fields and methods, for errors.
+    pub fn members(&self) -> Members {
+        let klass = self.klass.borrow();
+        Members::new(
+            klass.name.to_string(),
+            self.field_names()
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            klass.method_names().map(|name| name.to_string()).collect(),
+        )
+    }
+
+    pub fn take_fields(&mut self) -> Vec<Option<Value>> {
+        std::mem::take(&mut self.fields)
+    }
+}
+
+// Where an instruction last found its property, valid while the receiver
+// has the same class and that class has laid out no new fields, since a
+// new field may shadow a method.
+#[derive(Clone)]
+pub struct PropertyCache {
+    class: WeakShared<SharedCell<Class>>,
+    fields: usize,
+    pub member: Member,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Member {
+    Field(usize),
+    Method(usize),
+}
+
+impl PropertyCache {
+    // Held weakly so a cache neither keeps its class alive nor has its
+    // address reused by another class.
+    pub fn new(class: &Shared<SharedCell<Class>>, fields: usize, member: Member) -> PropertyCache {
+        PropertyCache {
+            class: Shared::downgrade(class),
+            fields,
+            member,
+        }
+    }
+
+    pub fn matches(&self, class: &Shared<SharedCell<Class>>, fields: usize) -> bool {
+        self.class.as_ptr() == Shared::as_ptr(class) && self.fields == fields
     }
 }
 
 #[derive(Clone)]
 pub struct BoundMethod {
-    pub receiver: Rc<RefCell<Instance>>,
+    pub receiver: Shared<SharedCell<Instance>>,
     pub method: Closure,
 }
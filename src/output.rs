@@ -0,0 +1,214 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// Where a program's output and diagnostics are written. Printed values go
+/// to `out`; errors, warnings and traces go to `err`. Write errors are
+/// ignored, as eprintln!'s would be.
+pub struct Io {
+    pub out: Box<dyn Write>,
+    pub err: Box<dyn Write>,
+}
+
+impl Default for Io {
+    fn default() -> Self {
+        Io::stdio()
+    }
+}
+
+impl Io {
+    pub fn stdio() -> Io {
+        Io {
+            out: Box::new(io::stdout()),
+            err: Box::new(io::stderr()),
+        }
+    }
+
+    /// Writes both streams to buffers the caller keeps a handle to.
+    pub fn capture() -> (Io, Buffer, Buffer) {
+        let out = Buffer::default();
+        let err = Buffer::default();
+        let io = Io {
+            out: Box::new(out.clone()),
+            err: Box::new(err.clone()),
+        };
+        (io, out, err)
+    }
+}
+
+/// An in-memory sink whose clones share their contents.
+#[derive(Clone, Default)]
+pub struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+impl Buffer {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+// The words that name a diagnostic's kind, as the backends print them.
+const KINDS: [&str; 5] = [
+    "Scanner Error",
+    "Parser Error",
+    "Resolver Error",
+    "Runtime Error",
+    "Warning",
+];
+
+/// Colors diagnostics for a terminal: the kind in red, or yellow for a
+/// warning, locations in bold, and everything else, such as backtrace
+/// frames, dimmed. A compiler error names no kind, so its message is red.
+///
+/// ```
+/// use lox::output::paint;
+///
+/// assert_eq!(
+///     paint("[line 2] at ;: Expect expression."),
+///     "\x1b[1m[line 2]\x1b[0m\x1b[31m at ;: Expect expression.\x1b[0m"
+/// );
+/// ```
+pub fn paint(line: &str) -> String {
+    if line.is_empty() {
+        return String::new();
+    }
+    let (location, rest) = match line.find(']') {
+        Some(end) if line.starts_with("[line ") || line.starts_with("[Line ") => {
+            line.split_at(end + 1)
+        }
+        _ => ("", line),
+    };
+    let mut out = String::new();
+    if !location.is_empty() {
+        out.push_str(&bold(location));
+    }
+    match KINDS
+        .iter()
+        .find_map(|kind| rest.find(kind).map(|at| (at, *kind)))
+    {
+        Some((at, kind)) => {
+            let color = if kind == "Warning" { YELLOW } else { RED };
+            out.push_str(&rest[..at]);
+            out.push_str(&format!("{}{}{}", color, kind, RESET));
+            let message = &rest[at + kind.len()..];
+            // The tree-walk backend puts the line after the kind.
+            match message
+                .strip_prefix(": Line ")
+                .and_then(|m| m.split_once(','))
+            {
+                Some((number, message)) => {
+                    out.push_str(": ");
+                    out.push_str(&bold(&format!("Line {}", number)));
+                    out.push(',');
+                    out.push_str(message);
+                }
+                None => out.push_str(message),
+            }
+        }
+        None if rest.starts_with(" at ") => out.push_str(&format!("{}{}{}", RED, rest, RESET)),
+        None => out.push_str(&format!("{}{}{}", DIM, rest, RESET)),
+    }
+    out
+}
+
+pub fn bold(text: &str) -> String {
+    format!("{}{}{}", BOLD, text, RESET)
+}
+
+/// Paints each line written through it on the way to inner. A line is held
+/// back until its newline, or until a flush.
+pub struct Colored<W: Write> {
+    inner: W,
+    line: Vec<u8>,
+}
+
+impl<W: Write> Colored<W> {
+    pub fn new(inner: W) -> Colored<W> {
+        Colored {
+            inner,
+            line: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for Colored<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte == b'\n' {
+                let line = String::from_utf8_lossy(&self.line).into_owned();
+                writeln!(self.inner, "{}", paint(&line))?;
+                self.line.clear();
+            } else {
+                self.line.push(byte);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.line)?;
+        self.line.clear();
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for Colored<W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paint() {
+        assert_eq!(paint(""), "");
+        assert_eq!(
+            paint("[Line 3] in script, Runtime Error: Type mismatch."),
+            "\x1b[1m[Line 3]\x1b[0m in script, \x1b[31mRuntime Error\x1b[0m: Type mismatch."
+        );
+        assert_eq!(
+            paint("Parser Error: Line 2, Expect an identifier."),
+            "\x1b[31mParser Error\x1b[0m: \x1b[1mLine 2\x1b[0m, Expect an identifier."
+        );
+        assert_eq!(
+            paint("[line 1] at print: Warning: Unreachable code."),
+            "\x1b[1m[line 1]\x1b[0m at print: \x1b[33mWarning\x1b[0m: Unreachable code."
+        );
+        assert_eq!(
+            paint("[line 1] in f()"),
+            "\x1b[1m[line 1]\x1b[0m\x1b[2m in f()\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_colored() {
+        let buffer = Buffer::default();
+        let mut colored = Colored::new(buffer.clone());
+        write!(colored, "Back").unwrap();
+        assert_eq!(buffer.contents(), "");
+        writeln!(colored, "trace:\n").unwrap();
+        assert_eq!(buffer.contents(), "\x1b[2mBacktrace:\x1b[0m\n\n");
+        write!(colored, "> ").unwrap();
+        colored.flush().unwrap();
+        assert_eq!(buffer.contents(), "\x1b[2mBacktrace:\x1b[0m\n\n> ");
+    }
+}
@@ -1,36 +1,148 @@
+use crate::ast::{Ast, ExprId, ExprNode, StmtId, StmtNode, TokenId};
 use crate::error::ParseError;
-use crate::expr::Expr;
 use crate::stmt::Stmt;
-use crate::token::{BasicType, Token, TokenType};
+use crate::token::{Token, TokenType};
+use crate::value::Value;
+use std::cell::Cell;
 use std::collections::LinkedList;
+use std::io::{self, Write};
 use std::sync::atomic::{AtomicU64, Ordering};
 
 static COUNTER: AtomicU64 = AtomicU64::new(0);
 
+/// How deeply statements and expressions may nest before parsing fails, so
+/// that deeply nested source is an error instead of a stack overflow.
+pub(crate) const MAX_NESTING: usize = 256;
+
+thread_local! {
+    static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+// A level of nesting, counted while it's alive.
+struct Nested;
+
+impl Nested {
+    fn enter(tokens: &LinkedList<Token>) -> Result<Nested, ParseError> {
+        let depth = NESTING.get() + 1;
+        if depth > MAX_NESTING {
+            return Err(error_at(tokens, "Too much nesting."));
+        }
+        NESTING.set(depth);
+        Ok(Nested)
+    }
+}
+
+impl Drop for Nested {
+    fn drop(&mut self) {
+        NESTING.set(NESTING.get() - 1);
+    }
+}
+
 fn get_count() -> u64 {
     COUNTER.fetch_add(1, Ordering::SeqCst)
 }
 
+// An error at the next token, which names it and where it is.
+fn error_at(tokens: &LinkedList<Token>, reason: &str) -> ParseError {
+    let token = tokens.front().unwrap();
+    let error = ParseError::new(token.line, reason.to_string())
+        .with_lexeme(token.to_string())
+        .with_span(token.span);
+    match token.column {
+        0 => error,
+        column => error.with_column(column),
+    }
+}
+
+// Every error is reported on stderr; the first is returned.
 pub fn parser(tokens: &mut LinkedList<Token>) -> Result<LinkedList<Box<Stmt>>, ParseError> {
-    let mut statements: LinkedList<Box<Stmt>> = LinkedList::new();
-    let mut has_fail: bool = false;
+    parser_to(tokens, &mut io::stderr())
+}
+
+// Like parser, but errors are reported to err.
+pub fn parser_to(
+    tokens: &mut LinkedList<Token>,
+    err: &mut dyn Write,
+) -> Result<LinkedList<Box<Stmt>>, ParseError> {
+    parse_ast_to(tokens, err, false).map(|ast| ast.to_tree())
+}
+
+// Like parser_to, but the program is left in its arena. With eval, it is
+// parsed by parse_eval_ast.
+pub fn parse_ast_to(
+    tokens: &mut LinkedList<Token>,
+    err: &mut dyn Write,
+    eval: bool,
+) -> Result<Ast, ParseError> {
+    let ast = if eval {
+        parse_eval_ast(tokens)
+    } else {
+        parse_ast(tokens)
+    };
+    ast.map_err(|errors| {
+        for e in &errors {
+            let _ = writeln!(err, "{}", e);
+        }
+        errors.into_iter().next().unwrap()
+    })
+}
+
+/// Parses every declaration in tokens, which must end with Eof, returning
+/// every error instead of reporting them.
+pub fn parse_all(tokens: &mut LinkedList<Token>) -> Result<LinkedList<Box<Stmt>>, Vec<ParseError>> {
+    parse_ast(tokens).map(|ast| ast.to_tree())
+}
+
+/// Like parse_all, but the program is left in its arena.
+pub fn parse_ast(tokens: &mut LinkedList<Token>) -> Result<Ast, Vec<ParseError>> {
+    let mut ast = Ast::new();
+    let mut errors = Vec::new();
     while !match_head(tokens, &[TokenType::Eof]) {
-        match declaration(tokens) {
-            Ok(stmt) => statements.push_back(stmt),
+        match declaration(tokens, &mut ast) {
+            Ok(stmt) => ast.push_statement(stmt),
             Err(e) => {
-                has_fail = true;
-                println!("{}", e);
+                errors.push(e);
                 synchronize(tokens);
             }
         }
     }
-    if has_fail {
-        Err(ParseError::new(0, "Interpretation stopped.".to_string()))
+    if errors.is_empty() {
+        Ok(ast)
     } else {
-        Ok(statements)
+        Err(errors)
     }
 }
 
+/// Like parse_ast, but the program may end with an expression statement
+/// missing its semicolon, as `Lox::eval` takes it.
+pub fn parse_eval_ast(tokens: &mut LinkedList<Token>) -> Result<Ast, Vec<ParseError>> {
+    // Try the source with the semicolon put in, keeping it only if that
+    // makes the last statement an expression.
+    let last = tokens.iter().rev().nth(1).map(|token| token.ttype);
+    if !matches!(
+        last,
+        None | Some(TokenType::Semicolon) | Some(TokenType::RightBrace)
+    ) {
+        let mut bare = tokens.clone();
+        let eof = bare.pop_back().unwrap();
+        let mut semicolon = Token::new(TokenType::Semicolon, ";", None, eof.line, eof.column);
+        semicolon.span = eof.span;
+        bare.push_back(semicolon);
+        bare.push_back(eof);
+        if let Ok(ast) = parse_ast(&mut bare) {
+            let ends_in_expression = ast
+                .statements()
+                .last()
+                .is_some_and(|&id| matches!(ast[id], StmtNode::Expression { .. }));
+            if ends_in_expression {
+                *tokens = bare;
+                return Ok(ast);
+            }
+        }
+    }
+    parse_ast(tokens)
+}
+
 fn match_head(tokens: &LinkedList<Token>, slice: &[TokenType]) -> bool {
     let head = &tokens.front().unwrap().ttype;
     for t in slice.iter() {
@@ -41,113 +153,91 @@ fn match_head(tokens: &LinkedList<Token>, slice: &[TokenType]) -> bool {
     false
 }
 
-fn declaration(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, ParseError> {
+fn declaration(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<StmtId, ParseError> {
+    let _nested = Nested::enter(tokens)?;
     if match_head(tokens, &[TokenType::Class]) {
-        return class_declaration(tokens);
+        return class_declaration(tokens, ast);
     }
     if match_head(tokens, &[TokenType::Fun]) {
-        return function_declaration(tokens);
+        return function_declaration(tokens, ast);
     }
     if match_head(tokens, &[TokenType::Var]) {
-        var_declaration(tokens)
+        var_declaration(tokens, ast)
     } else {
-        statement(tokens)
+        statement(tokens, ast)
     }
 }
 
-fn class_declaration(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, ParseError> {
+fn class_declaration(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<StmtId, ParseError> {
     tokens.pop_front();
-    let mut superclass: Option<Box<Expr>> = None;
+    let mut superclass: Option<ExprId> = None;
     if !match_head(tokens, &[TokenType::Identifier]) {
-        return Err(ParseError::new(
-            tokens.front().unwrap().line,
-            "Invalid token for class name".to_string(),
-        ));
+        return Err(error_at(tokens, "Invalid token for class name"));
     }
-    let name = tokens.pop_front().expect("Must be an identifier.");
+    let name = ast.push_token(tokens.pop_front().expect("Must be an identifier."));
     if match_head(tokens, &[TokenType::Less]) {
         tokens.pop_front();
         if match_head(tokens, &[TokenType::Identifier]) {
-            superclass = Some(Box::new(Expr::Variable {
-                name: tokens.pop_front().expect("Must be an identifier."),
+            let name = ast.push_token(tokens.pop_front().expect("Must be an identifier."));
+            superclass = Some(ast.push_expr(ExprNode::Variable {
+                name,
                 id: get_count(),
             }));
         } else {
-            return Err(ParseError::new(
-                tokens.front().unwrap().line,
-                "Invalid superclass name".to_string(),
-            ));
+            return Err(error_at(tokens, "Invalid superclass name"));
         }
     }
     if !match_head(tokens, &[TokenType::LeftBrace]) {
-        return Err(ParseError::new(
-            tokens.front().unwrap().line,
-            "Expect '{{' before class body".to_string(),
-        ));
+        return Err(error_at(tokens, "Expect '{{' before class body"));
     }
     tokens.pop_front();
-    let mut methods: LinkedList<Box<Stmt>> = LinkedList::new();
+    let mut methods: Vec<StmtId> = Vec::new();
     while !match_head(tokens, &[TokenType::RightBrace]) {
-        methods.push_back(function(tokens)?);
+        methods.push(function(tokens, ast)?);
     }
     if !match_head(tokens, &[TokenType::RightBrace]) {
-        return Err(ParseError::new(
-            tokens.front().unwrap().line,
-            "Expect '}}' before class body".to_string(),
-        ));
+        return Err(error_at(tokens, "Expect '}}' before class body"));
     }
     tokens.pop_front();
-    Ok(Box::new(Stmt::Class {
+    Ok(ast.push_stmt(StmtNode::Class {
         name,
         superclass,
         methods,
     }))
 }
 
-fn function_declaration(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, ParseError> {
+fn function_declaration(
+    tokens: &mut LinkedList<Token>,
+    ast: &mut Ast,
+) -> Result<StmtId, ParseError> {
     tokens.pop_front();
-    function(tokens)
+    function(tokens, ast)
 }
 
-fn function(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, ParseError> {
+fn function(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<StmtId, ParseError> {
     if !match_head(tokens, &[TokenType::Identifier]) {
-        return Err(ParseError::new(
-            tokens.front().unwrap().line,
-            "Invalid token for function name.".to_string(),
-        ));
+        return Err(error_at(tokens, "Invalid token for function name."));
     }
-    let nm = tokens.pop_front().expect("Must be an identifier");
+    let nm = ast.push_token(tokens.pop_front().expect("Must be an identifier"));
 
     if !match_head(tokens, &[TokenType::LeftParen]) {
-        return Err(ParseError::new(
-            tokens.front().unwrap().line,
-            "Expect ( for function arguments.".to_string(),
-        ));
+        return Err(error_at(tokens, "Expect ( for function arguments."));
     }
     tokens.pop_front();
 
-    let mut ps: LinkedList<Token> = LinkedList::new();
+    let mut ps: Vec<TokenId> = Vec::new();
     if !match_head(tokens, &[TokenType::RightParen]) {
         loop {
             if ps.len() >= 255 {
-                return Err(ParseError::new(
-                    tokens.front().unwrap().line,
-                    "Arguments of function exceed 255.".to_string(),
-                ));
+                return Err(error_at(tokens, "Arguments of function exceed 255."));
             }
             if !match_head(tokens, &[TokenType::Identifier]) {
-                return Err(ParseError::new(
-                    tokens.front().unwrap().line,
-                    "Invalid name for arguments.".to_string(),
-                ));
+                return Err(error_at(tokens, "Invalid name for arguments."));
             } else {
-                ps.push_back(tokens.pop_front().expect("Must be an identifier."));
+                ps.push(ast.push_token(tokens.pop_front().expect("Must be an identifier.")));
             }
             if !match_head(tokens, &[TokenType::RightParen, TokenType::Comma]) {
-                return Err(ParseError::new(
-                    tokens.front().unwrap().line,
-                    "Invalid function definition".to_string(),
-                ));
+                return Err(error_at(tokens, "Invalid function definition"));
             } else if match_head(tokens, &[TokenType::RightParen]) {
                 break;
             } else {
@@ -158,197 +248,176 @@ fn function(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, ParseError> {
     tokens.pop_front();
 
     if !match_head(tokens, &[TokenType::LeftBrace]) {
-        return Err(ParseError::new(
-            tokens.front().unwrap().line,
-            "Expect '{{' for function body".to_string(),
-        ));
+        return Err(error_at(tokens, "Expect '{{' for function body"));
     }
-    let b: LinkedList<Box<Stmt>> = block(tokens)?;
-    Ok(Box::new(Stmt::Function {
+    let b: Vec<StmtId> = block(tokens, ast)?;
+    Ok(ast.push_stmt(StmtNode::Function {
         name: nm,
         params: ps,
         body: b,
     }))
 }
 
-fn var_declaration(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, ParseError> {
+fn var_declaration(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<StmtId, ParseError> {
     tokens.pop_front();
     if match_head(tokens, &[TokenType::Identifier]) {
-        let name = tokens.pop_front().expect("Identifier Token.");
-        let mut initializer: Option<Box<Expr>> = None;
+        let name = ast.push_token(tokens.pop_front().expect("Identifier Token."));
+        let mut initializer: Option<ExprId> = None;
         if match_head(tokens, &[TokenType::Equal]) {
             tokens.pop_front();
-            match expression(tokens) {
+            match expression(tokens, ast) {
                 Ok(val) => initializer = Some(val),
                 Err(e) => return Err(e),
             }
         }
         if match_head(tokens, &[TokenType::Semicolon]) {
             tokens.pop_front();
-            Ok(Box::new(Stmt::Var { name, initializer }))
+            Ok(ast.push_stmt(StmtNode::Var { name, initializer }))
         } else {
-            Err(ParseError::new(
-                tokens.front().unwrap().line,
-                "Expect ';' after expression : Declaration.".to_string(),
+            Err(error_at(
+                tokens,
+                "Expect ';' after expression : Declaration.",
             ))
         }
     } else {
-        Err(ParseError::new(
-            tokens.front().unwrap().line,
-            "Expect an identifier.".to_string(),
-        ))
+        Err(error_at(tokens, "Expect an identifier."))
     }
 }
 
-fn statement(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, ParseError> {
+fn statement(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<StmtId, ParseError> {
+    let _nested = Nested::enter(tokens)?;
     if match_head(tokens, &[TokenType::If]) {
-        return if_statement(tokens);
+        return if_statement(tokens, ast);
     }
     if match_head(tokens, &[TokenType::Print]) {
-        return print_statement(tokens);
+        return print_statement(tokens, ast);
     }
     if match_head(tokens, &[TokenType::Return]) {
-        return return_statement(tokens);
+        return return_statement(tokens, ast);
     }
     if match_head(tokens, &[TokenType::While]) {
-        return while_statement(tokens);
+        return while_statement(tokens, ast);
     }
     if match_head(tokens, &[TokenType::LeftBrace]) {
-        return block_statement(tokens);
+        return block_statement(tokens, ast);
     }
-    expression_statement(tokens)
+    expression_statement(tokens, ast)
 }
 
-fn block_statement(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, ParseError> {
-    block(tokens).map(|val| Box::new(Stmt::Block { statements: val }))
+fn block_statement(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<StmtId, ParseError> {
+    let statements = block(tokens, ast)?;
+    Ok(ast.push_stmt(StmtNode::Block { statements }))
 }
 
-fn block(tokens: &mut LinkedList<Token>) -> Result<LinkedList<Box<Stmt>>, ParseError> {
-    let mut stmts: LinkedList<Box<Stmt>> = LinkedList::new();
+fn block(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<Vec<StmtId>, ParseError> {
+    let mut stmts: Vec<StmtId> = Vec::new();
     tokens.pop_front();
     while !match_head(tokens, &[TokenType::RightBrace, TokenType::Eof]) {
-        match declaration(tokens) {
-            Ok(val) => stmts.push_back(val),
+        match declaration(tokens, ast) {
+            Ok(val) => stmts.push(val),
             Err(e) => return Err(e),
         }
     }
     if match_head(tokens, &[TokenType::RightBrace]) {
         tokens.pop_front();
     } else {
-        return Err(ParseError::new(
-            tokens.front().unwrap().line,
-            "No matching } for block.".to_string(),
-        ));
+        return Err(error_at(tokens, "No matching } for block."));
     }
     Ok(stmts)
 }
 
-fn if_statement(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, ParseError> {
+fn if_statement(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<StmtId, ParseError> {
     tokens.pop_front();
     if !match_head(tokens, &[TokenType::LeftParen]) {
-        return Err(ParseError::new(
-            tokens.front().unwrap().line,
-            "No ( after if.".to_string(),
-        ));
+        return Err(error_at(tokens, "No ( after if."));
     } else {
         tokens.pop_front();
     }
-    let cond: Box<Expr> = expression(tokens)?;
+    let cond: ExprId = expression(tokens, ast)?;
     if !match_head(tokens, &[TokenType::RightParen]) {
-        return Err(ParseError::new(
-            tokens.front().unwrap().line,
-            "No ) after if.".to_string(),
-        ));
+        return Err(error_at(tokens, "No ) after if."));
     } else {
         tokens.pop_front();
     }
-    let then_b: Box<Stmt> = statement(tokens)?;
-    let mut else_b: Option<Box<Stmt>> = None;
+    let then_b: StmtId = statement(tokens, ast)?;
+    let mut else_b: Option<StmtId> = None;
     if match_head(tokens, &[TokenType::Else]) {
         tokens.pop_front();
-        match statement(tokens) {
+        match statement(tokens, ast) {
             Ok(val) => else_b = Some(val),
             Err(e) => return Err(e),
         }
     }
-    Ok(Box::new(Stmt::If {
+    Ok(ast.push_stmt(StmtNode::If {
         condition: cond,
         then_branch: then_b,
         else_branch: else_b,
     }))
 }
 
-fn return_statement(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, ParseError> {
+fn return_statement(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<StmtId, ParseError> {
     tokens.pop_front().expect("Must be keyword return.");
-    let mut value: Option<Box<Expr>> = None;
+    let mut value: Option<ExprId> = None;
     if !match_head(tokens, &[TokenType::Semicolon]) {
-        value = Some(expression(tokens)?);
+        value = Some(expression(tokens, ast)?);
     }
     if !match_head(tokens, &[TokenType::Semicolon]) {
-        return Err(ParseError::new(
-            tokens.front().unwrap().line,
-            "Expect ';' after return.".to_string(),
-        ));
+        return Err(error_at(tokens, "Expect ';' after return."));
     }
     tokens.pop_front();
-    Ok(Box::new(Stmt::Return { value }))
+    Ok(ast.push_stmt(StmtNode::Return { value }))
 }
 
-fn while_statement(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, ParseError> {
-    tokens.pop_front();
+fn while_statement(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<StmtId, ParseError> {
+    let keyword = ast.push_token(tokens.pop_front().unwrap());
     if !match_head(tokens, &[TokenType::LeftParen]) {
-        return Err(ParseError::new(
-            tokens.front().unwrap().line,
-            "No ( after while.".to_string(),
-        ));
+        return Err(error_at(tokens, "No ( after while."));
     } else {
         tokens.pop_front();
     }
-    let cond: Box<Expr> = expression(tokens)?;
+    let cond: ExprId = expression(tokens, ast)?;
     if !match_head(tokens, &[TokenType::RightParen]) {
-        return Err(ParseError::new(
-            tokens.front().unwrap().line,
-            "No ) after while.".to_string(),
-        ));
+        return Err(error_at(tokens, "No ) after while."));
     } else {
         tokens.pop_front();
     }
 
-    let stmt: Box<Stmt> = statement(tokens)?;
-    Ok(Box::new(Stmt::While {
+    let stmt: StmtId = statement(tokens, ast)?;
+    Ok(ast.push_stmt(StmtNode::While {
+        keyword,
         condition: cond,
         body: stmt,
     }))
 }
 
-fn print_statement(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, ParseError> {
+fn print_statement(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<StmtId, ParseError> {
     tokens.pop_front();
-    match expression(tokens) {
+    match expression(tokens, ast) {
         Ok(value) => {
             if match_head(tokens, &[TokenType::Semicolon]) {
                 tokens.pop_front();
-                Ok(Box::new(Stmt::Print { expression: value }))
+                Ok(ast.push_stmt(StmtNode::Print { expression: value }))
             } else {
-                Err(ParseError::new(
-                    tokens.front().unwrap().line,
-                    "Expect ';' after expression.".to_string(),
-                ))
+                Err(error_at(tokens, "Expect ';' after expression."))
             }
         }
         Err(e) => Err(e),
     }
 }
 
-fn expression_statement(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, ParseError> {
-    match expression(tokens) {
+fn expression_statement(
+    tokens: &mut LinkedList<Token>,
+    ast: &mut Ast,
+) -> Result<StmtId, ParseError> {
+    match expression(tokens, ast) {
         Ok(value) => {
             if match_head(tokens, &[TokenType::Semicolon]) {
                 tokens.pop_front();
-                Ok(Box::new(Stmt::Expression { expression: value }))
+                Ok(ast.push_stmt(StmtNode::Expression { expression: value }))
             } else {
-                Err(ParseError::new(
-                    tokens.front().unwrap().line,
-                    "Expect ';' after expression : Expression.".to_string(),
+                Err(error_at(
+                    tokens,
+                    "Expect ';' after expression : Expression.",
                 ))
             }
         }
@@ -356,48 +425,60 @@ fn expression_statement(tokens: &mut LinkedList<Token>) -> Result<Box<Stmt>, Par
     }
 }
 
-fn expression(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
-    assignment(tokens)
+fn expression(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<ExprId, ParseError> {
+    let _nested = Nested::enter(tokens)?;
+    assignment(tokens, ast)
 }
 
-fn assignment(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
-    let expr: Box<Expr> = or(tokens)?;
+fn assignment(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<ExprId, ParseError> {
+    let expr: ExprId = or(tokens, ast)?;
     if match_head(tokens, &[TokenType::Equal]) {
         tokens.pop_front();
-        match *expr {
-            Expr::Variable { name, id: _ } => {
-                let val: Box<Expr> = assignment(tokens)?;
-                return Ok(Box::new(Expr::Assign {
-                    name,
-                    value: val,
-                    id: get_count(),
-                }));
-            }
-            Expr::Get { object, name } => {
-                let val = assignment(tokens)?;
-                return Ok(Box::new(Expr::Set {
-                    object,
-                    name,
-                    value: val,
-                }));
-            }
-            _ => {
-                return Err(ParseError::new(
-                    tokens.front().unwrap().line,
-                    "Assign to something not assignable.".to_string(),
-                ))
-            }
-        }
+        return assign(tokens, ast, expr);
     }
     Ok(expr)
 }
 
-fn or(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
-    let mut expr: Box<Expr> = and(tokens)?;
+// The target of an assignment, parsed apart from assignment to keep its stack
+// frame small. The target was the last expression parsed, and is replaced.
+fn assign(
+    tokens: &mut LinkedList<Token>,
+    ast: &mut Ast,
+    target: ExprId,
+) -> Result<ExprId, ParseError> {
+    if !matches!(
+        ast[target],
+        ExprNode::Variable { .. } | ExprNode::Get { .. }
+    ) {
+        return Err(error_at(tokens, "Assign to something not assignable."));
+    }
+    match ast.pop_expr(target) {
+        ExprNode::Variable { name, id: _ } => {
+            let val: ExprId = assignment(tokens, ast)?;
+            Ok(ast.push_expr(ExprNode::Assign {
+                name,
+                value: val,
+                id: get_count(),
+            }))
+        }
+        ExprNode::Get { object, name } => {
+            let val = assignment(tokens, ast)?;
+            Ok(ast.push_expr(ExprNode::Set {
+                object,
+                name,
+                value: val,
+            }))
+        }
+        _ => unreachable!("Checked above."),
+    }
+}
+
+fn or(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<ExprId, ParseError> {
+    let mut expr: ExprId = and(tokens, ast)?;
     while match_head(tokens, &[TokenType::Or]) {
-        let op = tokens.pop_front().expect("Must be or.");
-        let rexpr: Box<Expr> = and(tokens)?;
-        expr = Box::new(Expr::Logical {
+        let op = ast.push_token(tokens.pop_front().expect("Must be or."));
+        let rexpr: ExprId = and(tokens, ast)?;
+        expr = ast.push_expr(ExprNode::Logical {
             left: expr,
             operator: op,
             right: rexpr,
@@ -406,12 +487,12 @@ fn or(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
     Ok(expr)
 }
 
-fn and(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
-    let mut expr: Box<Expr> = equality(tokens)?;
+fn and(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<ExprId, ParseError> {
+    let mut expr: ExprId = equality(tokens, ast)?;
     while match_head(tokens, &[TokenType::And]) {
-        let op = tokens.pop_front().expect("Must be and.");
-        let rexpr: Box<Expr> = equality(tokens)?;
-        expr = Box::new(Expr::Logical {
+        let op = ast.push_token(tokens.pop_front().expect("Must be and."));
+        let rexpr: ExprId = equality(tokens, ast)?;
+        expr = ast.push_expr(ExprNode::Logical {
             left: expr,
             operator: op,
             right: rexpr,
@@ -420,13 +501,13 @@ fn and(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
     Ok(expr)
 }
 
-fn equality(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
-    let mut expr: Box<Expr> = comparison(tokens)?;
+fn equality(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<ExprId, ParseError> {
+    let mut expr: ExprId = comparison(tokens, ast)?;
     while match_head(tokens, &[TokenType::BangEqual, TokenType::EqualEqual]) {
-        let operator = tokens.pop_front().unwrap();
-        match comparison(tokens) {
+        let operator = ast.push_token(tokens.pop_front().unwrap());
+        match comparison(tokens, ast) {
             Ok(x) => {
-                expr = Box::new(Expr::Binary {
+                expr = ast.push_expr(ExprNode::Binary {
                     left: expr,
                     operator,
                     right: x,
@@ -439,8 +520,8 @@ fn equality(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
     Ok(expr)
 }
 
-fn comparison(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
-    let mut expr: Box<Expr> = term(tokens)?;
+fn comparison(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<ExprId, ParseError> {
+    let mut expr: ExprId = term(tokens, ast)?;
     while match_head(
         tokens,
         &[
@@ -450,10 +531,10 @@ fn comparison(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
             TokenType::LessEqual,
         ],
     ) {
-        let operator = tokens.pop_front().unwrap();
-        match term(tokens) {
+        let operator = ast.push_token(tokens.pop_front().unwrap());
+        match term(tokens, ast) {
             Ok(x) => {
-                expr = Box::new(Expr::Binary {
+                expr = ast.push_expr(ExprNode::Binary {
                     left: expr,
                     operator,
                     right: x,
@@ -465,13 +546,13 @@ fn comparison(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
     Ok(expr)
 }
 
-fn term(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
-    let mut expr: Box<Expr> = factor(tokens)?;
+fn term(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<ExprId, ParseError> {
+    let mut expr: ExprId = factor(tokens, ast)?;
     while match_head(tokens, &[TokenType::Plus, TokenType::Minus]) {
-        let operator = tokens.pop_front().unwrap();
-        match factor(tokens) {
+        let operator = ast.push_token(tokens.pop_front().unwrap());
+        match factor(tokens, ast) {
             Ok(x) => {
-                expr = Box::new(Expr::Binary {
+                expr = ast.push_expr(ExprNode::Binary {
                     left: expr,
                     operator,
                     right: x,
@@ -484,13 +565,13 @@ fn term(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
     Ok(expr)
 }
 
-fn factor(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
-    let mut expr: Box<Expr> = unary(tokens)?;
+fn factor(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<ExprId, ParseError> {
+    let mut expr: ExprId = unary(tokens, ast)?;
     while match_head(tokens, &[TokenType::Slash, TokenType::Star]) {
-        let operator = tokens.pop_front().unwrap();
-        match unary(tokens) {
+        let operator = ast.push_token(tokens.pop_front().unwrap());
+        match unary(tokens, ast) {
             Ok(x) => {
-                expr = Box::new(Expr::Binary {
+                expr = ast.push_expr(ExprNode::Binary {
                     left: expr,
                     operator,
                     right: x,
@@ -502,32 +583,30 @@ fn factor(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
     Ok(expr)
 }
 
-fn unary(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
+fn unary(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<ExprId, ParseError> {
+    let _nested = Nested::enter(tokens)?;
     if match_head(tokens, &[TokenType::Bang, TokenType::Minus]) {
-        let operator = tokens.pop_front().unwrap();
-        match unary(tokens) {
-            Ok(x) => return Ok(Box::new(Expr::Unary { operator, right: x })),
+        let operator = ast.push_token(tokens.pop_front().unwrap());
+        match unary(tokens, ast) {
+            Ok(x) => return Ok(ast.push_expr(ExprNode::Unary { operator, right: x })),
             Err(e) => return Err(e),
         };
     }
-    call(tokens)
+    call(tokens, ast)
 }
 
-fn call(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
-    let mut expr: Box<Expr> = primary(tokens)?;
+fn call(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<ExprId, ParseError> {
+    let mut expr: ExprId = primary(tokens, ast)?;
     loop {
         if match_head(tokens, &[TokenType::LeftParen]) {
-            expr = finish_call(tokens, expr)?;
+            expr = finish_call(tokens, ast, expr)?;
         } else if match_head(tokens, &[TokenType::Dot]) {
             tokens.pop_front();
             if !match_head(tokens, &[TokenType::Identifier]) {
-                return Err(ParseError::new(
-                    tokens.front().unwrap().line,
-                    "Invalid class method.".to_string(),
-                ));
+                return Err(error_at(tokens, "Invalid class method."));
             }
-            let name = tokens.pop_front().expect("Must be identifier");
-            expr = Box::new(Expr::Get { object: expr, name });
+            let name = ast.push_token(tokens.pop_front().expect("Must be identifier"));
+            expr = ast.push_expr(ExprNode::Get { object: expr, name });
         } else {
             break;
         }
@@ -535,151 +614,134 @@ fn call(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
     Ok(expr)
 }
 
-fn finish_call(tokens: &mut LinkedList<Token>, expr: Box<Expr>) -> Result<Box<Expr>, ParseError> {
+fn finish_call(
+    tokens: &mut LinkedList<Token>,
+    ast: &mut Ast,
+    expr: ExprId,
+) -> Result<ExprId, ParseError> {
     tokens.pop_front();
-    let mut args = LinkedList::<Box<Expr>>::new();
+    let mut args = Vec::<ExprId>::new();
     if !match_head(tokens, &[TokenType::RightParen]) {
         loop {
-            match expression(tokens) {
-                Ok(val) => args.push_back(val),
+            match expression(tokens, ast) {
+                Ok(val) => args.push(val),
                 Err(e) => return Err(e),
             }
             if args.len() >= 255 {
-                return Err(ParseError::new(
-                    tokens.front().unwrap().line,
-                    "Function can't have more than 255 arguments.".to_string(),
+                return Err(error_at(
+                    tokens,
+                    "Function can't have more than 255 arguments.",
                 ));
             }
             if !match_head(tokens, &[TokenType::RightParen, TokenType::Comma]) {
-                return Err(ParseError::new(
-                    tokens.front().unwrap().line,
-                    "Invalid expression call.".to_string(),
-                ));
+                return Err(error_at(tokens, "Invalid expression call."));
             } else if match_head(tokens, &[TokenType::RightParen]) {
                 break;
             }
             tokens.pop_front();
         }
     }
-    let p = tokens.pop_front().expect("Must be right paren.");
-    Ok(Box::new(Expr::Call {
+    let p = ast.push_token(tokens.pop_front().expect("Must be right paren."));
+    Ok(ast.push_expr(ExprNode::Call {
         callee: expr,
         paren: p,
         arguments: args,
     }))
 }
 
-fn primary(tokens: &mut LinkedList<Token>) -> Result<Box<Expr>, ParseError> {
-    if match_head(tokens, &[TokenType::False]) {
-        tokens.pop_front();
-        return Ok(Box::new(Expr::Literal {
-            value: BasicType::Bool(false),
-        }));
-    }
-    if match_head(tokens, &[TokenType::True]) {
-        tokens.pop_front();
-        return Ok(Box::new(Expr::Literal {
-            value: BasicType::Bool(true),
-        }));
-    }
-    if match_head(tokens, &[TokenType::Nil]) {
-        tokens.pop_front();
-        return Ok(Box::new(Expr::Literal {
-            value: BasicType::None,
-        }));
-    }
-    if match_head(tokens, &[TokenType::Number, TokenType::String]) {
-        let token = tokens.pop_front().expect("Must be number or string");
-        return Ok(Box::new(Expr::Literal {
-            value: token
-                .lexeme
-                .clone()
-                .expect("Number or string must have conent."),
+fn primary(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<ExprId, ParseError> {
+    let literals = [
+        TokenType::False,
+        TokenType::True,
+        TokenType::Nil,
+        TokenType::Number,
+        TokenType::String,
+    ];
+    if match_head(tokens, &literals) {
+        let token = tokens.pop_front().unwrap();
+        let value = match token.ttype {
+            TokenType::False => Value::Bool(false),
+            TokenType::True => Value::Bool(true),
+            TokenType::Nil => Value::None,
+            _ => token.literal.expect("Number or string must have conent."),
+        };
+        return Ok(ast.push_expr(ExprNode::Literal {
+            value,
+            span: token.span,
         }));
     }
     if match_head(tokens, &[TokenType::LeftParen]) {
-        tokens.pop_front();
-        let opt = expression(tokens);
-        let expr: Box<Expr> = opt?;
-        if !match_head(tokens, &[TokenType::RightParen]) {
-            return Err(ParseError::new(
-                tokens.front().unwrap().line,
-                "Expect ')' after expression.".to_string(),
-            ));
-        }
-        tokens.pop_front();
-        return Ok(Box::new(Expr::Grouping { expression: expr }));
+        return grouping(tokens, ast);
     }
     if match_head(tokens, &[TokenType::This]) {
-        let token = tokens.pop_front().ok_or(ParseError::new(
-            tokens.front().unwrap().line,
-            "Invalid method or property name.".to_string(),
-        ));
-        return Ok(Box::new(Expr::This {
-            keyword: token?,
+        let keyword = ast.push_token(tokens.pop_front().unwrap());
+        return Ok(ast.push_expr(ExprNode::This {
+            keyword,
             id: get_count(),
         }));
     }
     if match_head(tokens, &[TokenType::Super]) {
-        let keyword = tokens.pop_front().ok_or(ParseError::new(
-            tokens.front().unwrap().line,
-            "Invalid super class name.".to_string(),
-        ));
-        if !match_head(tokens, &[TokenType::Dot]) {
-            return Err(ParseError::new(
-                tokens.front().unwrap().line,
-                "Expect . after super.".to_string(),
-            ));
-        }
-        tokens.pop_front();
-        if match_head(tokens, &[TokenType::Identifier]) {
-            let method = tokens.pop_front().ok_or(ParseError::new(
-                tokens.front().unwrap().line,
-                "Invalid method name.".to_string(),
-            ));
-            return Ok(Box::new(Expr::Super {
-                keyword: keyword?,
-                method: method?,
-                id: get_count(),
-            }));
-        } else {
-            return Err(ParseError::new(
-                tokens.front().unwrap().line,
-                "Not an identifier after super.".to_string(),
-            ));
-        }
+        return super_expr(tokens, ast);
     }
     if match_head(tokens, &[TokenType::Identifier]) {
-        let token = tokens.pop_front().unwrap();
-        return Ok(Box::new(Expr::Variable {
-            name: token,
+        let name = ast.push_token(tokens.pop_front().unwrap());
+        return Ok(ast.push_expr(ExprNode::Variable {
+            name,
             id: get_count(),
         }));
     }
-    Err(ParseError::new(
-        tokens.front().unwrap().line,
-        "Uncorrected matching.".to_string(),
-    ))
+    Err(error_at(tokens, "Uncorrected matching."))
+}
+
+// Grouping and super expressions are parsed apart from primary, which every
+// level of nesting passes through, to keep its stack frame small.
+fn grouping(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<ExprId, ParseError> {
+    let open = tokens.pop_front().unwrap();
+    let expr = expression(tokens, ast)?;
+    if !match_head(tokens, &[TokenType::RightParen]) {
+        return Err(error_at(tokens, "Expect ')' after expression."));
+    }
+    let close = tokens.pop_front().unwrap();
+    Ok(ast.push_expr(ExprNode::Grouping {
+        expression: expr,
+        span: open.span.merge(close.span),
+    }))
+}
+
+fn super_expr(tokens: &mut LinkedList<Token>, ast: &mut Ast) -> Result<ExprId, ParseError> {
+    let keyword = ast.push_token(tokens.pop_front().unwrap());
+    if !match_head(tokens, &[TokenType::Dot]) {
+        return Err(error_at(tokens, "Expect . after super."));
+    }
+    tokens.pop_front();
+    if !match_head(tokens, &[TokenType::Identifier]) {
+        return Err(error_at(tokens, "Not an identifier after super."));
+    }
+    let method = ast.push_token(tokens.pop_front().unwrap());
+    Ok(ast.push_expr(ExprNode::Super {
+        keyword,
+        method,
+        id: get_count(),
+    }))
 }
 
 fn synchronize(tokens: &mut LinkedList<Token>) {
+    // Skip the token the error is at first: it may start a statement the
+    // parser can't handle, and stopping at it again would never end.
     while !match_head(tokens, &[TokenType::Eof]) {
+        if tokens.pop_front().unwrap().ttype == TokenType::Semicolon {
+            return;
+        }
         match tokens.front().unwrap().ttype {
-            TokenType::Semicolon => {
-                tokens.pop_front();
-                return;
-            }
-            TokenType::Class => return,
-            TokenType::Fun => return,
-            TokenType::Var => return,
-            TokenType::For => return,
-            TokenType::If => return,
-            TokenType::While => return,
-            TokenType::Print => return,
-            TokenType::Return => return,
-            _ => {
-                tokens.pop_front();
-            }
+            TokenType::Class
+            | TokenType::Fun
+            | TokenType::Var
+            | TokenType::For
+            | TokenType::If
+            | TokenType::While
+            | TokenType::Print
+            | TokenType::Return => return,
+            _ => {}
         }
     }
 }
@@ -0,0 +1,125 @@
+use crate::chunk::OpCode;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Call targets listed in the report.
+const TOP_CALLS: usize = 10;
+
+// Per-opcode counts and time, and call counts per function. The time of an
+// instruction runs until the next one starts, so it includes any dispatch
+// overhead.
+pub struct Profile {
+    counts: [u64; 256],
+    times: [Duration; 256],
+    calls: HashMap<String, u64>,
+    last: Option<(OpCode, Instant)>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile {
+            counts: [0; 256],
+            times: [Duration::ZERO; 256],
+            calls: HashMap::new(),
+            last: None,
+        }
+    }
+
+    pub fn instruction(&mut self, op: OpCode) {
+        let now = Instant::now();
+        if let Some((prev, start)) = self.last {
+            self.times[prev as usize] += now - start;
+        }
+        self.counts[op as usize] += 1;
+        self.last = Some((op, now));
+    }
+
+    pub fn call(&mut self, name: &str) {
+        let name = if name.is_empty() { "script" } else { name };
+        *self.calls.entry(name.to_string()).or_insert(0) += 1;
+    }
+
+    // Closes the timing of the last instruction when execution stops.
+    pub fn stop(&mut self) {
+        if let Some((prev, start)) = self.last.take() {
+            self.times[prev as usize] += start.elapsed();
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut ops: Vec<usize> = (0..256).filter(|&op| self.counts[op] > 0).collect();
+        ops.sort_by(|a, b| self.counts[*b].cmp(&self.counts[*a]).then(a.cmp(b)));
+        writeln!(
+            f,
+            "Instructions executed: {}",
+            self.counts.iter().sum::<u64>()
+        )?;
+        writeln!(f, "{:<20} {:>12} {:>12}", "Opcode", "Count", "Time (ms)")?;
+        for op in ops {
+            writeln!(
+                f,
+                "{:<20} {:>12} {:>12.3}",
+                OpCode::try_from(op as u8).map_or("OP_UNKNOWN", OpCode::name),
+                self.counts[op],
+                self.times[op].as_secs_f64() * 1000.0
+            )?;
+        }
+        let mut calls: Vec<(&String, &u64)> = self.calls.iter().collect();
+        calls.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        writeln!(f, "{:<20} {:>12}", "Function", "Calls")?;
+        for (name, count) in calls.into_iter().take(TOP_CALLS) {
+            writeln!(f, "{:<20} {:>12}", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// How long each phase of a session's runs took, summed over the runs, and
+/// how much work execution did: statements on the tree-walk backend,
+/// instructions on the VM. Prints one "name: value" line per entry.
+#[derive(Default)]
+pub struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+    counts: Vec<(&'static str, u64)>,
+}
+
+impl Timings {
+    pub fn new() -> Timings {
+        Timings::default()
+    }
+
+    pub fn phase(&mut self, name: &'static str, time: Duration) {
+        match self.phases.iter_mut().find(|(phase, _)| *phase == name) {
+            Some((_, total)) => *total += time,
+            None => self.phases.push((name, time)),
+        }
+    }
+
+    pub fn count(&mut self, name: &'static str, n: u64) {
+        match self.counts.iter_mut().find(|(count, _)| *count == name) {
+            Some((_, total)) => *total += n,
+            None => self.counts.push((name, n)),
+        }
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, time) in &self.phases {
+            writeln!(f, "{}: {:.3} ms", name, time.as_secs_f64() * 1000.0)?;
+        }
+        for (name, n) in &self.counts {
+            writeln!(f, "{}: {}", name, n)?;
+        }
+        Ok(())
+    }
+}
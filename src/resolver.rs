@@ -1,192 +1,182 @@
-use crate::expr::Expr;
-use crate::stmt::Stmt;
-use crate::token::Token;
+use crate::ast::{Ast, ExprId, ExprNode, StmtId, StmtNode, TokenId};
+use crate::error::ResolveError;
 use std::collections::{HashMap, LinkedList};
 
 pub fn resolve(
-    statements: LinkedList<Box<Stmt>>,
+    ast: &Ast,
     scopes: &mut LinkedList<HashMap<String, bool>>,
     table: &mut HashMap<u64, i32>,
-) {
-    for stmt in statements {
-        resolve_stmt(*stmt, scopes, table);
+) -> Result<(), ResolveError> {
+    let mut resolver = Resolver { ast, scopes, table };
+    for &stmt in ast.statements() {
+        resolver.stmt(stmt)?;
     }
+    Ok(())
 }
-fn resolve_stmt(
-    stmt: Stmt,
-    scopes: &mut LinkedList<HashMap<String, bool>>,
-    table: &mut HashMap<u64, i32>,
-) {
-    match stmt {
-        Stmt::Block { statements } => {
-            begin_scope(scopes);
-            for stmt in statements {
-                resolve_stmt(*stmt, scopes, table);
+
+struct Resolver<'a> {
+    ast: &'a Ast,
+    scopes: &'a mut LinkedList<HashMap<String, bool>>,
+    table: &'a mut HashMap<u64, i32>,
+}
+
+impl Resolver<'_> {
+    fn stmt(&mut self, stmt: StmtId) -> Result<(), ResolveError> {
+        let ast = self.ast;
+        match &ast[stmt] {
+            StmtNode::Block { statements } => {
+                begin_scope(self.scopes);
+                for &stmt in statements {
+                    self.stmt(stmt)?;
+                }
+                end_scope(self.scopes);
             }
-            end_scope(scopes);
-        }
-        Stmt::Class {
-            name,
-            superclass,
-            methods,
-        } => {
-            if let Some(key) = name.lexeme.unwrap().as_string() {
-                declare(key.to_string(), scopes);
-                define(key.to_string(), scopes);
-            }
-            let mut has_superclass = false;
-            if let Some(c) = superclass {
-                resolve_expr(c, scopes, table);
-                has_superclass = true;
-            }
-            if has_superclass {
-                begin_scope(scopes);
-                let s = "super".to_string();
-                declare(s.clone(), scopes);
-                define(s, scopes);
-            }
-            begin_scope(scopes);
-            let t = "this".to_string();
-            declare(t.clone(), scopes);
-            define(t, scopes);
-            for method in methods {
-                if let Stmt::Function {
-                    name: _,
-                    params,
-                    body,
-                } = *method
-                {
-                    resolve_function(params, body, scopes, table)
+            StmtNode::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                declare(ast[*name].lexeme.clone(), self.scopes);
+                define(ast[*name].lexeme.clone(), self.scopes);
+                if let Some(c) = superclass {
+                    self.expr(*c)?;
+                    begin_scope(self.scopes);
+                    let s = "super".to_string();
+                    declare(s.clone(), self.scopes);
+                    define(s, self.scopes);
+                }
+                begin_scope(self.scopes);
+                let t = "this".to_string();
+                declare(t.clone(), self.scopes);
+                define(t, self.scopes);
+                for &method in methods {
+                    if let StmtNode::Function {
+                        name: _,
+                        params,
+                        body,
+                    } = &ast[method]
+                    {
+                        self.function(params, body)?;
+                    }
+                }
+                end_scope(self.scopes);
+                if superclass.is_some() {
+                    end_scope(self.scopes);
                 }
             }
-            end_scope(scopes);
-            if has_superclass {
-                end_scope(scopes);
+            StmtNode::Expression { expression } | StmtNode::Print { expression } => {
+                self.expr(*expression)?;
             }
-        }
-        Stmt::Expression { expression } => {
-            resolve_expr(expression, scopes, table);
-        }
-        Stmt::Function { name, params, body } => {
-            if let Some(key) = name.lexeme.unwrap().as_string() {
-                declare(key.to_string(), scopes);
-                define(key.to_string(), scopes);
-                resolve_function(params, body, scopes, table);
+            StmtNode::Function { name, params, body } => {
+                declare(ast[*name].lexeme.clone(), self.scopes);
+                define(ast[*name].lexeme.clone(), self.scopes);
+                self.function(params, body)?;
             }
-        }
-        Stmt::If {
-            condition,
-            then_branch,
-            else_branch,
-        } => {
-            resolve_expr(condition, scopes, table);
-            resolve_stmt(*then_branch, scopes, table);
-            if let Some(stmt) = else_branch {
-                resolve_stmt(*stmt, scopes, table);
+            StmtNode::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expr(*condition)?;
+                self.stmt(*then_branch)?;
+                if let Some(stmt) = else_branch {
+                    self.stmt(*stmt)?;
+                }
             }
-        }
-        Stmt::Print { expression } => {
-            resolve_expr(expression, scopes, table);
-        }
-        Stmt::Return { value } => {
-            if let Some(expr) = value {
-                resolve_expr(expr, scopes, table);
+            StmtNode::Return { value } => {
+                if let Some(expr) = value {
+                    self.expr(*expr)?;
+                }
             }
-        }
-        Stmt::Var { name, initializer } => {
-            if let Some(key) = name.lexeme.unwrap().as_string() {
-                declare(key.to_string(), scopes);
+            StmtNode::Var { name, initializer } => {
+                declare(ast[*name].lexeme.clone(), self.scopes);
                 if let Some(expr) = initializer {
-                    resolve_expr(expr, scopes, table);
+                    self.expr(*expr)?;
                 }
-                define(key.to_string(), scopes);
-            } else {
-                eprintln!("Invalid identifier.");
+                define(ast[*name].lexeme.clone(), self.scopes);
+            }
+            StmtNode::While {
+                condition, body, ..
+            } => {
+                self.expr(*condition)?;
+                self.stmt(*body)?;
             }
         }
-        Stmt::While { condition, body } => {
-            resolve_expr(condition, scopes, table);
-            resolve_stmt(*body, scopes, table);
-        }
+        Ok(())
     }
-}
 
-fn resolve_expr(
-    expr: Box<Expr>,
-    scopes: &mut LinkedList<HashMap<String, bool>>,
-    table: &mut HashMap<u64, i32>,
-) {
-    match *expr.clone() {
-        Expr::Binary {
-            left,
-            operator: _,
-            right,
-        } => {
-            resolve_expr(left, scopes, table);
-            resolve_expr(right, scopes, table);
-        }
-        Expr::Call {
-            callee,
-            paren: _,
-            arguments,
-        } => {
-            resolve_expr(callee, scopes, table);
-            for arg in arguments {
-                resolve_expr(arg, scopes, table);
+    fn expr(&mut self, expr: ExprId) -> Result<(), ResolveError> {
+        let ast = self.ast;
+        match &ast[expr] {
+            ExprNode::Binary { left, right, .. } | ExprNode::Logical { left, right, .. } => {
+                self.expr(*left)?;
+                self.expr(*right)?;
             }
-        }
-        Expr::Get { object, name: _ } => {
-            resolve_expr(object, scopes, table);
-        }
-        Expr::Grouping { expression } => {
-            resolve_expr(expression, scopes, table);
-        }
-        Expr::Literal { .. } => {}
-        Expr::Logical {
-            left,
-            operator: _,
-            right,
-        } => {
-            resolve_expr(left, scopes, table);
-            resolve_expr(right, scopes, table);
-        }
-        Expr::Set {
-            object,
-            name: _,
-            value,
-        } => {
-            resolve_expr(value, scopes, table);
-            resolve_expr(object, scopes, table);
-        }
-        Expr::Super {
-            keyword: _,
-            method: _,
-            id,
-        } => {
-            resolve_local(id, &"super".to_string(), scopes, table);
-        }
-        Expr::This { keyword: _, id } => {
-            resolve_local(id, &"this".to_string(), scopes, table);
-        }
-        Expr::Unary { operator: _, right } => {
-            resolve_expr(right, scopes, table);
-        }
-        Expr::Variable { name, id } => {
-            if let Some(key) = name.lexeme.unwrap().as_string() {
-                if !scopes.is_empty()
-                    && scopes.front_mut().expect("Non empty").get(&key) == Some(&false)
+            ExprNode::Call {
+                callee, arguments, ..
+            } => {
+                self.expr(*callee)?;
+                for &arg in arguments {
+                    self.expr(arg)?;
+                }
+            }
+            ExprNode::Get { object, .. } => {
+                self.expr(*object)?;
+            }
+            ExprNode::Grouping { expression, .. } => {
+                self.expr(*expression)?;
+            }
+            ExprNode::Literal { .. } => {}
+            ExprNode::Set { object, value, .. } => {
+                self.expr(*value)?;
+                self.expr(*object)?;
+            }
+            ExprNode::Super { id, .. } => {
+                resolve_local(*id, "super", self.scopes, self.table);
+            }
+            ExprNode::This { id, .. } => {
+                resolve_local(*id, "this", self.scopes, self.table);
+            }
+            ExprNode::Unary { right, .. } => {
+                self.expr(*right)?;
+            }
+            ExprNode::Variable { name, id } => {
+                let name = &ast[*name];
+                if !self.scopes.is_empty()
+                    && self
+                        .scopes
+                        .front_mut()
+                        .expect("Non empty")
+                        .get(&name.lexeme)
+                        == Some(&false)
                 {
-                    eprintln!("Can't read local variable in its own initializer.")
+                    return Err(ResolveError::new(
+                        name.line,
+                        "Can't read local variable in its own initializer.".to_string(),
+                    ));
                 }
-                resolve_local(id, &key, scopes, table);
+                resolve_local(*id, &name.lexeme, self.scopes, self.table);
             }
-        }
-        Expr::Assign { name, value, id } => {
-            resolve_expr(value, scopes, table);
-            if let Some(key) = name.lexeme.unwrap().as_string() {
-                resolve_local(id, &key, scopes, table);
+            ExprNode::Assign { name, value, id } => {
+                self.expr(*value)?;
+                resolve_local(*id, &ast[*name].lexeme, self.scopes, self.table);
             }
         }
+        Ok(())
+    }
+
+    fn function(&mut self, params: &[TokenId], body: &[StmtId]) -> Result<(), ResolveError> {
+        begin_scope(self.scopes);
+        for &param in params {
+            let name = &self.ast[param].lexeme;
+            declare(name.clone(), self.scopes);
+            define(name.clone(), self.scopes);
+        }
+        for &stmt in body {
+            self.stmt(stmt)?;
+        }
+        end_scope(self.scopes);
+        Ok(())
     }
 }
 
@@ -218,7 +208,7 @@ fn define(var: String, scopes: &mut LinkedList<HashMap<String, bool>>) {
 
 fn resolve_local(
     id: u64,
-    var: &String,
+    var: &str,
     scopes: &mut LinkedList<HashMap<String, bool>>,
     table: &mut HashMap<u64, i32>,
 ) {
@@ -229,22 +219,3 @@ fn resolve_local(
         }
     }
 }
-
-fn resolve_function(
-    params: LinkedList<Token>,
-    body: LinkedList<Box<Stmt>>,
-    scopes: &mut LinkedList<HashMap<String, bool>>,
-    table: &mut HashMap<u64, i32>,
-) {
-    begin_scope(scopes);
-    for token in params {
-        if let Some(key) = token.lexeme.unwrap().as_string() {
-            declare(key.to_string(), scopes);
-            define(key.to_string(), scopes);
-        }
-    }
-    for stmt in body {
-        resolve_stmt(*stmt, scopes, table);
-    }
-    end_scope(scopes);
-}
@@ -1,11 +1,13 @@
 use crate::error::ScanError;
-use crate::token::{BasicType, Token, TokenType};
+use crate::token::{Span, Token, TokenType};
+use crate::value::Value;
 use lazy_static::lazy_static;
 use std::collections::{HashMap, LinkedList};
 
 lazy_static! {
     pub static ref keywords: HashMap<String, TokenType> = HashMap::from([
         ("and".to_string(), TokenType::And),
+        ("catch".to_string(), TokenType::Catch),
         ("class".to_string(), TokenType::Class),
         ("else".to_string(), TokenType::Else),
         ("false".to_string(), TokenType::False),
@@ -18,246 +20,192 @@ lazy_static! {
         ("return".to_string(), TokenType::Return),
         ("super".to_string(), TokenType::Super),
         ("this".to_string(), TokenType::This),
+        ("throw".to_string(), TokenType::Throw),
         ("true".to_string(), TokenType::True),
+        ("try".to_string(), TokenType::Try),
         ("var".to_string(), TokenType::Var),
         ("while".to_string(), TokenType::While),
     ]);
 }
 
-pub fn scan_tokens(string: &str, line: &mut i32) -> Result<LinkedList<Token>, ScanError> {
+/// Scans source line by line, numbering its lines from line. Every line
+/// that fails to scan is an error.
+pub fn scan_source(source: &str, line: i32) -> Result<LinkedList<Token>, Vec<ScanError>> {
+    let mut l = line;
+    let mut offset = 0;
+    let mut tokens: LinkedList<Token> = LinkedList::new();
+    let mut errors = Vec::new();
+    for text in source.split_inclusive('\n') {
+        tokens.pop_back();
+        let content = text.strip_suffix('\n').unwrap_or(text);
+        let content = content.strip_suffix('\r').unwrap_or(content);
+        match scan_tokens(content, &mut l, offset) {
+            Ok(mut line_tokens) => tokens.append(&mut line_tokens),
+            Err(e) => errors.push(e),
+        }
+        l += 1;
+        offset += text.len();
+    }
+    if tokens.is_empty() {
+        tokens.push_back(Token::new(TokenType::Eof, "", None, l, 0));
+    }
+    if errors.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Scans a line of source that starts offset bytes into it.
+pub fn scan_tokens(
+    string: &str,
+    line: &mut i32,
+    offset: usize,
+) -> Result<LinkedList<Token>, ScanError> {
     let mut start: usize;
     let mut current: usize = 0;
     let mut tokens: LinkedList<Token> = LinkedList::new();
     while current < string.len() {
-        while current < string.len()
-            && is_blank(string.chars().nth(current).expect("Not at end of string"))
-        {
+        while current < string.len() && is_blank(byte_at(string, current)) {
             current += 1
         }
+        if current == string.len() {
+            break;
+        }
         start = current;
         match scan_token(string, start, line) {
-            Err(e) => return Err(e),
-            Ok((token, c)) => {
+            Err(e) => {
+                let end = start + e.lexeme.as_ref().map_or(0, String::len);
+                return Err(e
+                    .with_column(string[..start].chars().count() + 1)
+                    .with_span(Span::new(offset + start, offset + end)));
+            }
+            Ok((mut token, c)) => {
+                token.span = Span::new(offset + token.span.start, offset + token.span.end);
                 tokens.push_back(token);
                 current = c;
             }
         };
     }
     if tokens.is_empty() || tokens.back().expect("Not empty").ttype != TokenType::Eof {
-        tokens.push_back(Token {
-            ttype: TokenType::Eof,
-            lexeme: None,
-            line: *line,
-        });
+        let mut eof = Token::new(TokenType::Eof, "", None, *line, 0);
+        eof.span = Span::new(offset + string.len(), offset + string.len());
+        tokens.push_back(eof);
     }
     Ok(tokens)
 }
 
+// Indexing by byte keeps positions valid for slicing string: every byte the
+// scanner looks for is ASCII, and the bytes of other characters never are.
+fn byte_at(string: &str, i: usize) -> char {
+    string.as_bytes()[i] as char
+}
+
 fn scan_token(string: &str, pos: usize, line: &mut i32) -> Result<(Token, usize), ScanError> {
-    let c: char = string.chars().nth(pos).expect("End of string.");
+    let c: char = byte_at(string, pos);
     let mut end: usize = pos;
-    let token: Token = match c {
-        '(' => Token {
-            ttype: TokenType::LeftParen,
-            lexeme: None,
-            line: *line,
-        },
-        ')' => Token {
-            ttype: TokenType::RightParen,
-            lexeme: None,
-            line: *line,
-        },
-        '{' => Token {
-            ttype: TokenType::LeftBrace,
-            lexeme: None,
-            line: *line,
-        },
-        '}' => Token {
-            ttype: TokenType::RightBrace,
-            lexeme: None,
-            line: *line,
-        },
-        ',' => Token {
-            ttype: TokenType::Comma,
-            lexeme: None,
-            line: *line,
-        },
-        '.' => Token {
-            ttype: TokenType::Dot,
-            lexeme: None,
-            line: *line,
-        },
-        '-' => Token {
-            ttype: TokenType::Minus,
-            lexeme: None,
-            line: *line,
-        },
-        '+' => Token {
-            ttype: TokenType::Plus,
-            lexeme: None,
-            line: *line,
-        },
-        ';' => Token {
-            ttype: TokenType::Semicolon,
-            lexeme: None,
-            line: *line,
-        },
-        '*' => Token {
-            ttype: TokenType::Star,
-            lexeme: None,
-            line: *line,
-        },
-        '!' => {
-            if pos + 1 < string.len() && string.chars().nth(pos + 1).expect("End of string") == '='
-            {
-                end = pos + 1;
-                Token {
-                    ttype: TokenType::BangEqual,
-                    lexeme: None,
-                    line: *line,
-                }
-            } else {
-                Token {
-                    ttype: TokenType::Bang,
-                    lexeme: None,
-                    line: *line,
-                }
-            }
-        }
-        '=' => {
-            if pos + 1 < string.len() && string.chars().nth(pos + 1).expect("End of string") == '='
-            {
-                end = pos + 1;
-                Token {
-                    ttype: TokenType::EqualEqual,
-                    lexeme: None,
-                    line: *line,
-                }
-            } else {
-                Token {
-                    ttype: TokenType::Equal,
-                    lexeme: None,
-                    line: *line,
-                }
-            }
-        }
-        '<' => {
-            if pos + 1 < string.len() && string.chars().nth(pos + 1).expect("End of string") == '='
-            {
-                end = pos + 1;
-                Token {
-                    ttype: TokenType::LessEqual,
-                    lexeme: None,
-                    line: *line,
-                }
-            } else {
-                Token {
-                    ttype: TokenType::Less,
-                    lexeme: None,
-                    line: *line,
-                }
-            }
-        }
-        '>' => {
-            if pos + 1 < string.len() && string.chars().nth(pos + 1).expect("End of string") == '='
-            {
-                end = pos + 1;
-                Token {
-                    ttype: TokenType::GreaterEqual,
-                    lexeme: None,
-                    line: *line,
-                }
-            } else {
-                Token {
-                    ttype: TokenType::Greater,
-                    lexeme: None,
-                    line: *line,
-                }
-            }
-        }
+    let mut literal = None;
+    let ttype = match c {
+        '(' => TokenType::LeftParen,
+        ')' => TokenType::RightParen,
+        '{' => TokenType::LeftBrace,
+        '}' => TokenType::RightBrace,
+        ',' => TokenType::Comma,
+        '.' => TokenType::Dot,
+        '-' => TokenType::Minus,
+        '+' => TokenType::Plus,
+        ';' => TokenType::Semicolon,
+        '*' => TokenType::Star,
+        '!' => or_equal(string, &mut end, TokenType::BangEqual, TokenType::Bang),
+        '=' => or_equal(string, &mut end, TokenType::EqualEqual, TokenType::Equal),
+        '<' => or_equal(string, &mut end, TokenType::LessEqual, TokenType::Less),
+        '>' => or_equal(
+            string,
+            &mut end,
+            TokenType::GreaterEqual,
+            TokenType::Greater,
+        ),
         '/' => {
-            if pos + 1 < string.len() && string.chars().nth(pos + 1).expect("End of string") == '/'
-            {
-                end = string.len();
-                Token {
-                    ttype: TokenType::Eof,
-                    lexeme: None,
-                    line: *line,
-                }
-            } else {
-                Token {
-                    ttype: TokenType::Slash,
-                    lexeme: None,
-                    line: *line,
-                }
+            if pos + 1 < string.len() && byte_at(string, pos + 1) == '/' {
+                // The rest of the line is a comment.
+                let mut token = Token::new(TokenType::Eof, "", None, *line, 0);
+                token.span = Span::new(string.len(), string.len());
+                return Ok((token, string.len() + 1));
             }
+            TokenType::Slash
         }
         '"' => {
             end = pos + 1;
-            while end < string.len() && string.chars().nth(end).expect("End of string") != '"' {
+            while end < string.len() && byte_at(string, end) != '"' {
                 end += 1
             }
             if end == string.len() {
-                return Err(ScanError::new(*line, "Unterminated string.".to_string()));
-            } else {
-                Token {
-                    ttype: TokenType::String,
-                    lexeme: Some(BasicType::String(string[pos + 1..end].to_string())),
-                    line: *line,
-                }
+                return Err(ScanError::new(*line, "Unterminated string.".to_string())
+                    .with_lexeme(&string[pos..]));
             }
+            literal = Some(Value::String(string[pos + 1..end].into()));
+            TokenType::String
         }
         '0'..='9' => {
             end = pos;
-            while end + 1 < string.len()
-                && is_digit(string.chars().nth(end + 1).expect("End of string"))
-            {
+            while end + 1 < string.len() && is_digit(byte_at(string, end + 1)) {
                 end += 1;
             }
             if end + 2 < string.len()
-                && string.chars().nth(end + 1).expect("End of string") == '.'
-                && is_digit(string.chars().nth(end + 2).expect("End of string"))
+                && byte_at(string, end + 1) == '.'
+                && is_digit(byte_at(string, end + 2))
             {
                 end += 2;
-                while end + 1 < string.len()
-                    && is_digit(string.chars().nth(end + 1).expect("End of string"))
-                {
+                while end + 1 < string.len() && is_digit(byte_at(string, end + 1)) {
                     end += 1;
                 }
             }
-            Token {
-                ttype: TokenType::Number,
-                lexeme: Some(BasicType::Number(
-                    string[pos..end + 1].parse::<f64>().unwrap(),
-                )),
-                line: *line,
-            }
+            literal = Some(parse_number(&string[pos..end + 1], *line)?);
+            TokenType::Number
         }
         'a'..='z' | 'A'..='Z' => {
             end = pos;
-            while end + 1 < string.len()
-                && is_alpha_numeric(string.chars().nth(end + 1).expect("End of string"))
-            {
+            while end + 1 < string.len() && is_alpha_numeric(byte_at(string, end + 1)) {
                 end += 1;
             }
-            let text = string[pos..end + 1].to_string();
-            let ttype: TokenType = match keywords.get(&text) {
+            match keywords.get(&string[pos..end + 1]) {
                 Some(i) => *i,
                 None => TokenType::Identifier,
-            };
-            Token {
-                ttype,
-                lexeme: Some(BasicType::String(text)),
-                line: *line,
             }
         }
         _ => {
-            return Err(ScanError::new(*line, "Unterminated string.".to_string()));
+            let lexeme: String = string[pos..].chars().take(1).collect();
+            let reason = format!("Unexpected character '{}'.", lexeme);
+            return Err(ScanError::new(*line, reason).with_lexeme(lexeme));
         }
     };
+    let column = string[..pos].chars().count() + 1;
+    let mut token = Token::new(ttype, &string[pos..end + 1], literal, *line, column);
+    token.span = Span::new(pos, end + 1);
     Ok((token, end + 1))
 }
 
+// The two-character token if the character after end is '=', which end
+// then moves to, and the one-character token if not.
+fn or_equal(string: &str, end: &mut usize, two: TokenType, one: TokenType) -> TokenType {
+    if *end + 1 < string.len() && byte_at(string, *end + 1) == '=' {
+        *end += 1;
+        two
+    } else {
+        one
+    }
+}
+
+// Literals too large for an f64 parse to inf rather than failing.
+fn parse_number(text: &str, line: i32) -> Result<Value, ScanError> {
+    match text.parse::<f64>() {
+        Ok(value) => Ok(Value::Number(value)),
+        Err(_) => {
+            Err(ScanError::new(line, format!("Invalid number literal {}.", text)).with_lexeme(text))
+        }
+    }
+}
+
 fn is_digit(c: char) -> bool {
     c.is_ascii_digit()
 }
@@ -269,3 +217,52 @@ fn is_alpha_numeric(c: char) -> bool {
 fn is_blank(c: char) -> bool {
     matches!(c, '\r' | '\n' | ' ' | '\t')
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_text() {
+        let mut line = 1;
+        let tokens: Vec<Token> = scan_tokens("var s = \"a b\" >= 1.50; // c", &mut line, 0)
+            .unwrap()
+            .into_iter()
+            .collect();
+        let lexemes: Vec<&str> = tokens.iter().map(|t| t.lexeme.as_str()).collect();
+        assert_eq!(lexemes, ["var", "s", "=", "\"a b\"", ">=", "1.50", ";", ""]);
+        let columns: Vec<usize> = tokens.iter().map(|t| t.column).collect();
+        assert_eq!(columns, [1, 5, 7, 9, 15, 18, 22, 0]);
+        assert_eq!(tokens[3].literal, Some(Value::String("a b".into())));
+        assert_eq!(tokens[5].literal, Some(Value::Number(1.5)));
+        assert_eq!(tokens[1].literal, None);
+    }
+
+    #[test]
+    fn test_malformed_number() {
+        let err = parse_number("1.2.3", 4).expect_err("Malformed number accepted");
+        assert_eq!(
+            err.to_string(),
+            "Scanner Error: Line 4, Invalid number literal 1.2.3."
+        );
+    }
+
+    #[test]
+    fn test_unexpected_character() {
+        for (source, lexeme) in [("print 1; @", "@"), ("print [1];", "["), ("é", "é")] {
+            let errors = scan_source(source, 1).expect_err("Unexpected character accepted");
+            assert_eq!(
+                errors[0].to_string(),
+                format!("Scanner Error: Line 1, Unexpected character '{}'.", lexeme)
+            );
+            assert_eq!(errors[0].lexeme(), Some(lexeme));
+        }
+    }
+
+    #[test]
+    fn test_number_overflow() {
+        let text = format!("1{}", "0".repeat(400));
+        let value = parse_number(&text, 1).expect("Number rejected");
+        assert_eq!(value.as_number(), Some(f64::INFINITY));
+    }
+}
@@ -0,0 +1,113 @@
+//! The pointers values share their parts through. By default they are
+//! `Rc` and `RefCell`; with the `sync` feature they are `Arc` and a
+//! `RwLock`, so values can move between threads.
+//!
+//! ```
+//! # #[cfg(feature = "sync")]
+//! # {
+//! fn send_sync<T: Send + Sync>() {}
+//! send_sync::<lox::Value>();
+//! # }
+//! ```
+
+#[cfg(not(feature = "sync"))]
+pub use std::cell::RefCell as SharedCell;
+#[cfg(not(feature = "sync"))]
+pub use std::rc::{Rc as Shared, Weak as WeakShared};
+#[cfg(feature = "sync")]
+pub use std::sync::{Arc as Shared, Weak as WeakShared};
+
+/// Bounds natives must meet: `Send + Sync` under the `sync` feature, and
+/// none otherwise.
+#[cfg(not(feature = "sync"))]
+pub trait SendSync {}
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> SendSync for T {}
+
+#[cfg(feature = "sync")]
+pub trait SendSync: Send + Sync {}
+#[cfg(feature = "sync")]
+impl<T: ?Sized + Send + Sync> SendSync for T {}
+
+/// A `RefCell` that threads can share. Borrowing locks it, so a borrow that
+/// would panic in a `RefCell` blocks instead.
+#[cfg(feature = "sync")]
+#[derive(Debug, Default)]
+pub struct SharedCell<T: ?Sized>(std::sync::RwLock<T>);
+
+#[cfg(feature = "sync")]
+impl<T> SharedCell<T> {
+    pub fn new(value: T) -> SharedCell<T> {
+        SharedCell(std::sync::RwLock::new(value))
+    }
+
+    pub fn replace(&self, value: T) -> T {
+        std::mem::replace(&mut *self.borrow_mut(), value)
+    }
+}
+
+#[cfg(feature = "sync")]
+impl<T: ?Sized> SharedCell<T> {
+    // A panic while borrowed leaves nothing half-written that later
+    // borrows can't read, so poisoning is ignored.
+    pub fn borrow(&self) -> std::sync::RwLockReadGuard<'_, T> {
+        self.0.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn borrow_mut(&self) -> std::sync::RwLockWriteGuard<'_, T> {
+        self.0.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(feature = "sync")]
+impl<T: Clone> Clone for SharedCell<T> {
+    fn clone(&self) -> SharedCell<T> {
+        SharedCell::new(self.borrow().clone())
+    }
+}
+
+// Values are what hosts hand between threads.
+#[cfg(feature = "sync")]
+const _: fn() = || {
+    fn send_sync<T: Send + Sync>() {}
+    send_sync::<crate::Value>();
+};
+
+#[cfg(all(test, feature = "sync"))]
+mod tests {
+    use crate::{Backend, Io, Lox, RunOptions, Value};
+    use std::thread;
+
+    #[test]
+    fn test_threads() {
+        let threads: Vec<_> = [Backend::Vm, Backend::Ast]
+            .into_iter()
+            .enumerate()
+            .map(|(i, backend)| {
+                thread::spawn(move || {
+                    let (io, out, _) = Io::capture();
+                    let options = RunOptions {
+                        backend,
+                        ..RunOptions::default()
+                    };
+                    let mut lox = Lox::with_io(options, io);
+                    lox.run(&format!("var n = {};\nfun f() {{ return n * 10; }}", i))
+                        .unwrap();
+                    lox.run("print f();").unwrap();
+                    (out.contents(), lox.eval("f() + n;").unwrap())
+                })
+            })
+            .collect();
+        let results: Vec<(String, Value)> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        assert_eq!(results[0].0, "0\n");
+        assert_eq!(results[1].0, "10\n");
+        assert_eq!(results[1].1.to_string(), "11");
+    }
+}
@@ -0,0 +1,95 @@
+//! The natives every session starts with. Each is written once over
+//! [`Value`], and registered into whichever backend runs.
+use crate::interpreter::Interpreter;
+use crate::shared::Shared;
+use crate::value::Value;
+use crate::vm::VM;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A native as both backends define it.
+#[derive(Clone, Copy)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub arity: u8,
+    pub function: fn(&[Value]) -> Result<Value, String>,
+}
+
+const CORE: [Builtin; 1] = [Builtin {
+    name: "clock",
+    arity: 0,
+    function: clock,
+}];
+
+#[cfg(feature = "serde")]
+const JSON: &[Builtin] = &crate::json::BUILTINS;
+#[cfg(not(feature = "serde"))]
+const JSON: &[Builtin] = &[];
+
+/// Every builtin, in the order sessions define them.
+pub fn builtins() -> impl Iterator<Item = Builtin> {
+    CORE.iter().chain(JSON).copied()
+}
+
+pub fn register_vm(vm: &mut VM) {
+    for builtin in builtins() {
+        vm.define_native(builtin.name, builtin.arity, builtin.function);
+    }
+}
+
+pub fn register_ast(interpreter: &mut Interpreter) {
+    for builtin in builtins() {
+        interpreter.define_native(builtin.name, builtin.arity, Shared::new(builtin.function));
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn clock(_args: &[Value]) -> Result<Value, String> {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => Ok(Value::Number(time.as_secs_f64())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// The system clock isn't reachable through std in the browser.
+#[cfg(target_arch = "wasm32")]
+fn clock(_args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(js_sys::Date::now() / 1000.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Backend, Io, Lox, RunOptions};
+
+    // A line of Lox calling the builtin, which prints the same on every
+    // backend.
+    fn uses(name: &str) -> &'static str {
+        match name {
+            "clock" => "print clock() > 0;",
+            "jsonParse" => "print jsonParse(\"[1, true, null]\");",
+            "jsonEncode" => "print jsonEncode(\"a\");",
+            _ => panic!("No line uses {}", name),
+        }
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        let source: Vec<&str> = builtins().map(|b| uses(b.name)).collect();
+        let source = source.join("\n");
+        let outputs: Vec<String> = [Backend::Vm, Backend::Ast]
+            .into_iter()
+            .map(|backend| {
+                let (io, out, _) = Io::capture();
+                let options = RunOptions {
+                    backend,
+                    ..RunOptions::default()
+                };
+                Lox::with_io(options, io).run(&source).unwrap();
+                out.contents()
+            })
+            .collect();
+        assert_eq!(outputs[0].lines().count(), builtins().count());
+        assert_eq!(outputs[0], outputs[1]);
+    }
+}
@@ -1,10 +1,10 @@
 use crate::expr::Expr;
-use crate::token::{BasicType, Token};
-use std::cell::RefCell;
+use crate::shared::{Shared, SharedCell};
+use crate::token::{Span, Token};
+use crate::value::Value;
 use std::collections::{HashMap, LinkedList};
-use std::rc::Rc;
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Stmt {
     Block {
         statements: LinkedList<Box<Stmt>>,
@@ -38,32 +38,212 @@ pub enum Stmt {
         initializer: Option<Box<Expr>>,
     },
     While {
+        keyword: Token,
         condition: Box<Expr>,
         body: Box<Stmt>,
     },
 }
 
+/// A pass over statements, with a method for each kind that takes its fields.
+/// Passes over whole programs implement it alongside ExprVisitor.
+pub trait StmtVisitor<R> {
+    fn visit_block(&mut self, statements: &LinkedList<Box<Stmt>>) -> R;
+    fn visit_class(
+        &mut self,
+        name: &Token,
+        superclass: Option<&Expr>,
+        methods: &LinkedList<Box<Stmt>>,
+    ) -> R;
+    fn visit_expression(&mut self, expression: &Expr) -> R;
+    fn visit_function(
+        &mut self,
+        name: &Token,
+        params: &LinkedList<Token>,
+        body: &LinkedList<Box<Stmt>>,
+    ) -> R;
+    fn visit_if(&mut self, condition: &Expr, then_branch: &Stmt, else_branch: Option<&Stmt>) -> R;
+    fn visit_print(&mut self, expression: &Expr) -> R;
+    fn visit_return(&mut self, value: Option<&Expr>) -> R;
+    fn visit_var(&mut self, name: &Token, initializer: Option<&Expr>) -> R;
+    fn visit_while(&mut self, condition: &Expr, body: &Stmt) -> R;
+}
+
+impl Stmt {
+    /// Calls the visitor's method for this kind of statement.
+    pub fn accept<R, V: StmtVisitor<R> + ?Sized>(&self, visitor: &mut V) -> R {
+        match self {
+            Stmt::Block { statements } => visitor.visit_block(statements),
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => visitor.visit_class(name, superclass.as_deref(), methods),
+            Stmt::Expression { expression } => visitor.visit_expression(expression),
+            Stmt::Function { name, params, body } => visitor.visit_function(name, params, body),
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => visitor.visit_if(condition, then_branch, else_branch.as_deref()),
+            Stmt::Print { expression } => visitor.visit_print(expression),
+            Stmt::Return { value } => visitor.visit_return(value.as_deref()),
+            Stmt::Var { name, initializer } => visitor.visit_var(name, initializer.as_deref()),
+            Stmt::While {
+                condition, body, ..
+            } => visitor.visit_while(condition, body),
+        }
+    }
+
+    /// The bytes of source from the first token the statement keeps to the
+    /// last, which leave out keywords, braces and semicolons. A block with no
+    /// statements and a bare return keep none.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Stmt::Block { statements } => cover(statements.iter().filter_map(|s| s.span())),
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => cover(
+                std::iter::once(name.span)
+                    .chain(superclass.iter().map(|s| s.span()))
+                    .chain(methods.iter().filter_map(|m| m.span())),
+            ),
+            Stmt::Expression { expression } | Stmt::Print { expression } => Some(expression.span()),
+            Stmt::Function { name, params, body } => cover(
+                std::iter::once(name.span)
+                    .chain(params.iter().map(|p| p.span))
+                    .chain(body.iter().filter_map(|s| s.span())),
+            ),
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => cover(
+                std::iter::once(condition.span())
+                    .chain(then_branch.span())
+                    .chain(else_branch.as_ref().and_then(|s| s.span())),
+            ),
+            Stmt::Return { value } => value.as_ref().map(|v| v.span()),
+            Stmt::Var { name, initializer } => {
+                cover(std::iter::once(name.span).chain(initializer.as_ref().map(|i| i.span())))
+            }
+            Stmt::While {
+                condition, body, ..
+            } => cover(std::iter::once(condition.span()).chain(body.span())),
+        }
+    }
+}
+
+fn cover(spans: impl Iterator<Item = Span>) -> Option<Span> {
+    spans.reduce(Span::merge)
+}
+
 pub struct Environment {
-    values: HashMap<String, BasicType>,
-    enclosing: Option<Rc<RefCell<Environment>>>,
+    values: HashMap<String, Value>,
+    enclosing: Option<Shared<SharedCell<Environment>>>,
+}
+
+// Environments currently alive on this thread, for leak tests.
+#[cfg(test)]
+thread_local! {
+    pub static LIVE_ENVIRONMENTS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(test)]
+impl Drop for Environment {
+    fn drop(&mut self) {
+        LIVE_ENVIRONMENTS.with(|n| n.set(n.get() - 1));
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment::new()
+    }
 }
 
 impl Environment {
     pub fn new() -> Environment {
+        #[cfg(test)]
+        LIVE_ENVIRONMENTS.with(|n| n.set(n.get() + 1));
         Environment {
             values: HashMap::new(),
             enclosing: None,
         }
     }
 
-    pub fn from(env: Rc<RefCell<Environment>>) -> Environment {
+    pub fn from(env: Shared<SharedCell<Environment>>) -> Environment {
+        #[cfg(test)]
+        LIVE_ENVIRONMENTS.with(|n| n.set(n.get() + 1));
         Environment {
             values: HashMap::new(),
             enclosing: Some(env.clone()),
         }
     }
 
-    pub fn define(&mut self, key: String, value: BasicType) -> Option<BasicType> {
+    pub fn enclosing(&self) -> Option<Shared<SharedCell<Environment>>> {
+        self.enclosing.clone()
+    }
+
+    // A copy of the variables defined in this scope, to put back with
+    // restore.
+    pub fn snapshot(&self) -> HashMap<String, Value> {
+        self.values.clone()
+    }
+
+    pub fn restore(&mut self, values: HashMap<String, Value>) {
+        self.values = values;
+    }
+
+    /// The variables defined in this scope alone, in no particular order,
+    /// read as get reads them.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, Value)> {
+        self.values.iter().map(|(name, val)| (name, readable(val)))
+    }
+
+    /// The names defined in this scope alone, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.values.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// How many scopes enclose this one, which is 0 for the globals.
+    pub fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut enclosing = self.enclosing.clone();
+        while let Some(env) = enclosing {
+            depth += 1;
+            enclosing = env.borrow().enclosing.clone();
+        }
+        depth
+    }
+
+    /// Every variable visible from this scope, sorted by name, with those in
+    /// inner scopes shadowing those of the same name further out.
+    pub fn flattened(&self) -> Vec<(String, Value)> {
+        let mut visible: HashMap<String, Value> = HashMap::new();
+        let mut add = |values: &HashMap<String, Value>| {
+            for (name, val) in values {
+                if !visible.contains_key(name) {
+                    visible.insert(name.clone(), readable(val));
+                }
+            }
+        };
+        add(&self.values);
+        let mut enclosing = self.enclosing.clone();
+        while let Some(env) = enclosing {
+            let env = env.borrow();
+            add(&env.values);
+            enclosing = env.enclosing.clone();
+        }
+        let mut visible: Vec<_> = visible.into_iter().collect();
+        visible.sort_by(|(a, _), (b, _)| a.cmp(b));
+        visible
+    }
+
+    pub fn define(&mut self, key: String, value: Value) -> Option<Value> {
         self.values.insert(key, value)
     }
 
@@ -71,9 +251,13 @@ impl Environment {
         self.values.contains_key(&key)
     }
 
-    pub fn assign(&mut self, key: String, value: BasicType, depth: i32) -> Option<BasicType> {
+    /// Sets a variable defined depth scopes out, returning its old value, or
+    /// None if it isn't defined there.
+    pub fn assign(&mut self, key: String, value: Value, depth: i32) -> Option<Value> {
         if depth == 0 {
-            self.values.insert(key, value)
+            self.values
+                .get_mut(&key)
+                .map(|old| std::mem::replace(old, value))
         } else {
             (*self.enclosing.clone()?)
                 .borrow_mut()
@@ -81,11 +265,244 @@ impl Environment {
         }
     }
 
-    pub fn get(&self, key: &String, depth: i32) -> Option<BasicType> {
+    pub fn get(&self, key: &str, depth: i32) -> Option<Value> {
         if depth == 0 {
-            self.values.get(key).cloned()
+            self.values.get(key).map(readable)
         } else {
             return (*self.enclosing.clone()?).borrow().get(key, depth - 1);
         }
     }
 }
+
+// A value as read out of a scope, with functions that scope holds weakly
+// made strong again.
+fn readable(val: &Value) -> Value {
+    match val {
+        Value::LoxFunction(fun) => Value::LoxFunction(fun.upgrade()),
+        val => val.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::ExprVisitor;
+    use crate::parse_source;
+
+    // Counts the statements and expressions in a tree: the least a pass
+    // written as a visitor has to do, which is to visit every child.
+    #[derive(Default)]
+    struct NodeCounter {
+        stmts: usize,
+        exprs: usize,
+    }
+
+    impl NodeCounter {
+        fn stmts<'a>(&mut self, stmts: impl IntoIterator<Item = &'a Box<Stmt>>) {
+            for stmt in stmts {
+                stmt.accept(self);
+            }
+        }
+    }
+
+    impl StmtVisitor<()> for NodeCounter {
+        fn visit_block(&mut self, statements: &LinkedList<Box<Stmt>>) {
+            self.stmts += 1;
+            self.stmts(statements);
+        }
+
+        fn visit_class(
+            &mut self,
+            _: &Token,
+            superclass: Option<&Expr>,
+            methods: &LinkedList<Box<Stmt>>,
+        ) {
+            self.stmts += 1;
+            if let Some(superclass) = superclass {
+                superclass.accept(self);
+            }
+            self.stmts(methods);
+        }
+
+        fn visit_expression(&mut self, expression: &Expr) {
+            self.stmts += 1;
+            expression.accept(self);
+        }
+
+        fn visit_function(
+            &mut self,
+            _: &Token,
+            _: &LinkedList<Token>,
+            body: &LinkedList<Box<Stmt>>,
+        ) {
+            self.stmts += 1;
+            self.stmts(body);
+        }
+
+        fn visit_if(&mut self, condition: &Expr, then_branch: &Stmt, else_branch: Option<&Stmt>) {
+            self.stmts += 1;
+            condition.accept(self);
+            then_branch.accept(self);
+            if let Some(else_branch) = else_branch {
+                else_branch.accept(self);
+            }
+        }
+
+        fn visit_print(&mut self, expression: &Expr) {
+            self.stmts += 1;
+            expression.accept(self);
+        }
+
+        fn visit_return(&mut self, value: Option<&Expr>) {
+            self.stmts += 1;
+            if let Some(value) = value {
+                value.accept(self);
+            }
+        }
+
+        fn visit_var(&mut self, _: &Token, initializer: Option<&Expr>) {
+            self.stmts += 1;
+            if let Some(initializer) = initializer {
+                initializer.accept(self);
+            }
+        }
+
+        fn visit_while(&mut self, condition: &Expr, body: &Stmt) {
+            self.stmts += 1;
+            condition.accept(self);
+            body.accept(self);
+        }
+    }
+
+    impl ExprVisitor<()> for NodeCounter {
+        fn visit_binary(&mut self, left: &Expr, _: &Token, right: &Expr) {
+            self.exprs += 1;
+            left.accept(self);
+            right.accept(self);
+        }
+
+        fn visit_call(&mut self, callee: &Expr, _: &Token, arguments: &LinkedList<Box<Expr>>) {
+            self.exprs += 1;
+            callee.accept(self);
+            for argument in arguments {
+                argument.accept(self);
+            }
+        }
+
+        fn visit_get(&mut self, object: &Expr, _: &Token) {
+            self.exprs += 1;
+            object.accept(self);
+        }
+
+        fn visit_grouping(&mut self, expression: &Expr, _: Span) {
+            self.exprs += 1;
+            expression.accept(self);
+        }
+
+        fn visit_literal(&mut self, _: &Value, _: Span) {
+            self.exprs += 1;
+        }
+
+        fn visit_logical(&mut self, left: &Expr, _: &Token, right: &Expr) {
+            self.exprs += 1;
+            left.accept(self);
+            right.accept(self);
+        }
+
+        fn visit_set(&mut self, object: &Expr, _: &Token, value: &Expr) {
+            self.exprs += 1;
+            object.accept(self);
+            value.accept(self);
+        }
+
+        fn visit_super(&mut self, _: &Token, _: &Token, _: u64) {
+            self.exprs += 1;
+        }
+
+        fn visit_this(&mut self, _: &Token, _: u64) {
+            self.exprs += 1;
+        }
+
+        fn visit_unary(&mut self, _: &Token, right: &Expr) {
+            self.exprs += 1;
+            right.accept(self);
+        }
+
+        fn visit_variable(&mut self, _: &Token, _: u64) {
+            self.exprs += 1;
+        }
+
+        fn visit_assign(&mut self, _: &Token, value: &Expr, _: u64) {
+            self.exprs += 1;
+            value.accept(self);
+        }
+    }
+
+    fn count(source: &str) -> (usize, usize) {
+        let mut counter = NodeCounter::default();
+        counter.stmts(&parse_source(source).unwrap());
+        (counter.stmts, counter.exprs)
+    }
+
+    #[test]
+    fn test_node_counter() {
+        assert_eq!(count(""), (0, 0));
+        assert_eq!(count("print -(1 + 2);"), (1, 5));
+        assert_eq!(count("var a; a = a or f(1);"), (2, 6));
+        let source =
+            "class A < B { m() { return this.x; } }\nwhile (true) { if (!x) print 1; else {} }";
+        assert_eq!(count(source), (8, 7));
+    }
+
+    #[test]
+    fn test_flattened() {
+        let globals = Shared::new(SharedCell::new(Environment::new()));
+        globals
+            .borrow_mut()
+            .define("a".to_string(), Value::Number(1.0));
+        globals
+            .borrow_mut()
+            .define("b".to_string(), Value::Number(2.0));
+        let outer = Shared::new(SharedCell::new(Environment::from(globals.clone())));
+        outer
+            .borrow_mut()
+            .define("a".to_string(), Value::Number(3.0));
+        outer
+            .borrow_mut()
+            .define("c".to_string(), Value::Number(4.0));
+        let mut inner = Environment::from(outer);
+        inner.define("b".to_string(), Value::Number(5.0));
+
+        assert_eq!(
+            inner.flattened(),
+            [
+                ("a".to_string(), Value::Number(3.0)),
+                ("b".to_string(), Value::Number(5.0)),
+                ("c".to_string(), Value::Number(4.0)),
+            ]
+        );
+        assert_eq!(inner.names(), ["b"]);
+        assert_eq!(
+            inner.iter().collect::<Vec<_>>(),
+            [(&"b".to_string(), Value::Number(5.0))]
+        );
+        assert_eq!((inner.depth(), globals.borrow().depth()), (2, 0));
+        assert_eq!(globals.borrow().flattened().len(), 2);
+    }
+
+    #[test]
+    fn test_assign_undefined() {
+        let globals = Shared::new(SharedCell::new(Environment::new()));
+        globals
+            .borrow_mut()
+            .define("a".to_string(), Value::Number(1.0));
+        let mut inner = Environment::from(globals.clone());
+        assert_eq!(inner.assign("b".to_string(), Value::None, 1), None);
+        assert_eq!(inner.assign("a".to_string(), Value::None, 2), None);
+        assert_eq!(
+            inner.assign("a".to_string(), Value::Number(2.0), 1),
+            Some(Value::Number(1.0))
+        );
+        assert_eq!(globals.borrow().names(), ["a"]);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::error::LoxError;
+use crate::output::Io;
+use crate::{Lox, RunOptions};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// What a test script says it does, in comments:
+///
+/// - `// expect: value` after a statement that prints value, in order;
+/// - `// expect runtime error: message` where the run stops with that
+///   error, or `// expect runtime error` for any runtime error;
+/// - `// expect compile error` if it doesn't scan, parse or resolve.
+#[derive(Debug, Default, PartialEq)]
+pub struct Expectations {
+    pub output: Vec<String>,
+    pub error: Option<ExpectedError>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExpectedError {
+    Compile,
+    Runtime(Option<String>),
+}
+
+/// Reads the expectation comments in source. "//" inside a string doesn't
+/// start one.
+///
+/// ```
+/// use lox::test_runner::{expectations, ExpectedError};
+///
+/// let expected = expectations("print 1; // expect: 1\nprint -nil; // expect runtime error");
+/// assert_eq!(expected.output, ["1"]);
+/// assert_eq!(expected.error, Some(ExpectedError::Runtime(None)));
+/// ```
+pub fn expectations(source: &str) -> Expectations {
+    let mut expected = Expectations::default();
+    for line in source.lines() {
+        let Some(comment) = comment(line) else {
+            continue;
+        };
+        if let Some(value) = comment.strip_prefix("expect: ") {
+            expected.output.push(value.to_string());
+        } else if let Some(message) = comment.strip_prefix("expect runtime error") {
+            let message = message.strip_prefix(": ").map(str::to_string);
+            expected.error = Some(ExpectedError::Runtime(message));
+        } else if comment.starts_with("expect compile error") {
+            expected.error = Some(ExpectedError::Compile);
+        }
+    }
+    expected
+}
+
+// The text of a line's comment after the "//".
+fn comment(line: &str) -> Option<&str> {
+    let mut in_string = false;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => in_string = !in_string,
+            '/' if !in_string && chars.peek().is_some_and(|&(_, next)| next == '/') => {
+                return Some(line[i + 2..].trim());
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Runs source in a fresh session and compares what it printed and how it
+/// ended with its expectation comments. An Err says what differed.
+///
+/// ```
+/// use lox::test_runner::run_test;
+/// use lox::RunOptions;
+///
+/// let options = RunOptions::default();
+/// assert!(run_test("print 1 + 2; // expect: 3", options).is_ok());
+/// let err = run_test("print 1; // expect: 2", options).unwrap_err();
+/// assert_eq!(err, "Expected output 2 but got 1.");
+/// ```
+pub fn run_test(source: &str, options: RunOptions) -> Result<(), String> {
+    let expected = expectations(source);
+    let (io, out, _) = Io::capture();
+    let result = Lox::with_io(options, io).run(source);
+    let output = out.contents();
+    let output: Vec<&str> = output.lines().collect();
+    for (i, value) in expected.output.iter().enumerate() {
+        match output.get(i) {
+            Some(line) if line == value => {}
+            Some(line) => return Err(format!("Expected output {} but got {}.", value, line)),
+            None => return Err(format!("Missing expected output {}.", value)),
+        }
+    }
+    if let Some(line) = output.get(expected.output.len()) {
+        return Err(format!("Unexpected output {}.", line));
+    }
+    match (&expected.error, result) {
+        (None, Ok(())) => Ok(()),
+        (Some(ExpectedError::Compile), Err(LoxError::Scan(_)))
+        | (Some(ExpectedError::Compile), Err(LoxError::Parse(_)))
+        | (Some(ExpectedError::Compile), Err(LoxError::Resolve(_))) => Ok(()),
+        (Some(ExpectedError::Runtime(message)), Err(LoxError::Runtime(e))) => {
+            // The tree-walk backend puts the functions an error passed
+            // through before its reason.
+            let reason = e.reason.lines().last().unwrap_or_default();
+            match message {
+                Some(message) if message != reason => Err(format!(
+                    "Expected runtime error {} but got {}",
+                    message, reason
+                )),
+                _ => Ok(()),
+            }
+        }
+        (None, Err(e)) => Err(format!("Unexpected error: {}", e)),
+        (Some(_), Ok(())) => Err("Expected an error but the script succeeded.".to_string()),
+        (Some(ExpectedError::Compile), Err(e)) => {
+            Err(format!("Expected a compile error but got: {}", e))
+        }
+        (Some(ExpectedError::Runtime(_)), Err(e)) => {
+            Err(format!("Expected a runtime error but got: {}", e))
+        }
+    }
+}
+
+/// How many test scripts passed and failed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+}
+
+/// Runs every .lox file under dir, in order of path, writing "PASS path"
+/// or "FAIL path: why" for each to report, then a summary line.
+pub fn run_suite(dir: &Path, options: RunOptions, report: &mut dyn Write) -> io::Result<Summary> {
+    let mut scripts = Vec::new();
+    find_scripts(dir, &mut scripts)?;
+    scripts.sort();
+    let mut summary = Summary::default();
+    for path in scripts {
+        let result = fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read: {}", e))
+            .and_then(|source| run_test(&source, options));
+        match result {
+            Ok(()) => {
+                summary.passed += 1;
+                writeln!(report, "PASS {}", path.display())?;
+            }
+            Err(why) => {
+                summary.failed += 1;
+                writeln!(report, "FAIL {}: {}", path.display(), why)?;
+            }
+        }
+    }
+    writeln!(
+        report,
+        "{} passed, {} failed.",
+        summary.passed, summary.failed
+    )?;
+    Ok(summary)
+}
+
+fn find_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_scripts(&path, scripts)?;
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            scripts.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Backend;
+
+    #[test]
+    fn test_expectations() {
+        let expected = expectations(
+            "print \"// expect: no\"; // expect: // expect: no\n\
+             print 1;  //   expect: 1\n\
+             var a = 1 +; // expect compile error",
+        );
+        assert_eq!(expected.output, ["// expect: no", "1"]);
+        assert_eq!(expected.error, Some(ExpectedError::Compile));
+        let expected =
+            expectations("print -nil; // expect runtime error: Operand must be a number");
+        assert_eq!(
+            expected.error,
+            Some(ExpectedError::Runtime(Some(
+                "Operand must be a number".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn test_outcomes() {
+        for backend in [Backend::Vm, Backend::Ast] {
+            let options = RunOptions {
+                backend,
+                ..RunOptions::default()
+            };
+            assert_eq!(
+                run_test("print 1; print 2; // expect: 1", options),
+                Err("Unexpected output 2.".to_string())
+            );
+            assert_eq!(
+                run_test("var a = 1; // expect: 1", options),
+                Err("Missing expected output 1.".to_string())
+            );
+            assert!(run_test("print 1 +; // expect compile error", options).is_ok());
+            assert!(run_test(
+                "print 1; print -nil; // expect: 1\n// expect runtime error",
+                options
+            )
+            .is_ok());
+            assert!(run_test("print -nil;", options)
+                .unwrap_err()
+                .starts_with("Unexpected error: "));
+            assert_eq!(
+                run_test("var a = 1; // expect runtime error", options),
+                Err("Expected an error but the script succeeded.".to_string())
+            );
+            assert!(run_test("print -nil; // expect compile error", options)
+                .unwrap_err()
+                .starts_with("Expected a compile error but got: "));
+        }
+        let vm = RunOptions::default();
+        assert!(run_test(
+            "print -nil; // expect runtime error: Expected a number but got Nil.",
+            vm
+        )
+        .is_ok());
+        assert_eq!(
+            run_test("print -nil; // expect runtime error: Stack overflow.", vm),
+            Err(
+                "Expected runtime error Stack overflow. but got Expected a number but got Nil."
+                    .to_string()
+            )
+        );
+    }
+}
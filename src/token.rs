@@ -1,7 +1,5 @@
-use crate::callable::{LoxClass, LoxFunction, LoxInstance};
-use std::cell::RefCell;
+use crate::value::Value;
 use std::fmt;
-use std::rc::Rc;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenType {
@@ -10,6 +8,9 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -47,106 +48,122 @@ pub enum TokenType {
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
+    Catch,
 
     Eof,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct Token {
     pub ttype: TokenType,
-    pub lexeme: Option<BasicType>,
+    /// The text the token was scanned from, with a string's quotes. Ends of
+    /// input have none.
+    pub lexeme: String,
+    /// The value of a number or string literal.
+    pub literal: Option<Value>,
     pub line: i32,
+    /// The 1-based column the token starts at, or 0 for an end of input.
+    pub column: usize,
+    pub span: Span,
 }
 
-impl fmt::Display for Token {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self.lexeme {
-            Some(x) => write!(f, "{}", x),
-            None => write!(f, "{:?}", self.ttype),
-        }
-    }
-}
-#[derive(Clone)]
-pub enum BasicType {
-    None,
-    String(String),
-    Number(f64),
-    Bool(bool),
-    Function(Rc<LoxFunction>),
-    Class(Rc<LoxClass>),
-    Instance(Rc<RefCell<LoxInstance>>),
+/// The bytes of source from start up to end.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
-impl BasicType {
-    pub fn as_string(&self) -> Option<String> {
-        if let BasicType::String(s) = self {
-            Some(s.clone())
-        } else {
-            None
-        }
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
     }
 
-    pub fn as_number(&self) -> Option<f64> {
-        if let BasicType::Number(n) = self {
-            Some(*n)
-        } else {
-            None
-        }
+    /// The smallest span covering both spans.
+    pub fn merge(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
     }
+}
 
-    pub fn as_bool(&self) -> Option<bool> {
-        if let BasicType::Bool(b) = self {
-            Some(*b)
-        } else {
-            None
+impl Token {
+    /// A token with an empty span, which scanners set.
+    pub fn new(
+        ttype: TokenType,
+        lexeme: &str,
+        literal: Option<Value>,
+        line: i32,
+        column: usize,
+    ) -> Token {
+        Token {
+            ttype,
+            lexeme: lexeme.to_string(),
+            literal,
+            line,
+            column,
+            span: Span::default(),
         }
     }
+}
 
-    pub fn as_class(&self) -> Option<Rc<LoxClass>> {
-        if let BasicType::Class(c) = self {
-            Some(c.clone())
-        } else {
-            None
-        }
-    }
-    pub fn as_instance(&self) -> Option<Rc<RefCell<LoxInstance>>> {
-        if let BasicType::Instance(i) = self {
-            Some(i.clone())
-        } else {
-            None
-        }
+// Tokens are the same token wherever they are, so positions aren't
+// compared.
+impl PartialEq for Token {
+    fn eq(&self, other: &Token) -> bool {
+        self.ttype == other.ttype && self.lexeme == other.lexeme && self.literal == other.literal
     }
 }
 
-impl fmt::Display for BasicType {
+// The token as it was written, or "end" for an end of input.
+impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            BasicType::String(s) => write!(f, "{}", s),
-            BasicType::Number(n) => write!(f, "{}", n),
-            BasicType::Bool(b) => write!(f, "{}", b),
-            BasicType::Function(l) => write!(f, "{}", l.name.lexeme.clone().unwrap()),
-            BasicType::Class(c) => write!(f, "{}", c.name.lexeme.clone().unwrap()),
-            BasicType::Instance(_) => write!(f, ""),
-            BasicType::None => write!(f, "Nil"),
+        match self.ttype {
+            TokenType::Eof => write!(f, "end"),
+            _ => write!(f, "{}", self.lexeme),
         }
     }
 }
 
-impl PartialEq for BasicType {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (BasicType::String(s1), BasicType::String(s2)) => s1 == s2,
-            (BasicType::Bool(b1), BasicType::Bool(b2)) => b1 == b2,
-            _ => false,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let string = Token::new(
+            TokenType::String,
+            "\"a b\"",
+            Some(Value::String("a b".into())),
+            1,
+            5,
+        );
+        assert_eq!(string.to_string(), "\"a b\"");
+        let number = Token::new(TokenType::Number, "1.50", Some(Value::Number(1.5)), 1, 1);
+        assert_eq!(number.to_string(), "1.50");
+        assert_eq!(
+            Token::new(TokenType::BangEqual, "!=", None, 1, 3).to_string(),
+            "!="
+        );
+        assert_eq!(
+            Token::new(TokenType::Eof, "", None, 2, 0).to_string(),
+            "end"
+        );
     }
-}
 
-impl fmt::Debug for BasicType {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Display::fmt(self, f)
+    #[test]
+    fn test_merge() {
+        assert_eq!(Span::new(4, 6).merge(Span::new(0, 2)), Span::new(0, 6));
+        assert_eq!(Span::new(0, 9).merge(Span::new(3, 5)), Span::new(0, 9));
+    }
+
+    #[test]
+    fn test_positions_not_compared() {
+        let a = Token::new(TokenType::Identifier, "a", None, 1, 1);
+        assert_eq!(a, Token::new(TokenType::Identifier, "a", None, 3, 7));
+        assert_ne!(a, Token::new(TokenType::Identifier, "b", None, 1, 1));
     }
 }
@@ -0,0 +1,435 @@
+use crate::callable::{LoxClass, LoxFunction, LoxInstance};
+use crate::error::{ConversionError, ErrorKind};
+use crate::object::{BoundMethod, Class, Closure, Function, Instance};
+use crate::shared::{Shared, SharedCell};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A Lox value on either backend. Nil, booleans, numbers, strings, arrays,
+/// maps and host functions are shared; each backend has its own functions,
+/// classes and instances, and only ever meets its own.
+#[derive(Clone)]
+pub enum Value {
+    None,
+    String(Shared<str>),
+    Number(f64),
+    Bool(bool),
+    Array(Shared<SharedCell<Vec<Value>>>),
+    Map(Shared<SharedCell<HashMap<MapKey, Value>>>),
+    Native(Shared<Native>),
+    // The VM's objects.
+    Function(Shared<Function>),
+    Closure(Closure),
+    Class(Shared<SharedCell<Class>>),
+    Instance(Shared<SharedCell<Instance>>),
+    BoundMethod(Box<BoundMethod>),
+    // The tree-walk interpreter's objects.
+    LoxFunction(Shared<LoxFunction>),
+    LoxClass(Shared<LoxClass>),
+    LoxInstance(Shared<SharedCell<LoxInstance>>),
+}
+
+// Natives are closures so hosts can register functions that capture state.
+// An Err becomes a runtime error at the call site.
+#[cfg(not(feature = "sync"))]
+pub type NativeFn = Shared<dyn Fn(&[Value]) -> Result<Value, String>>;
+#[cfg(feature = "sync")]
+pub type NativeFn = Shared<dyn Fn(&[Value]) -> Result<Value, String> + Send + Sync>;
+
+/// A map key. Only strings and numbers can be keys; numbers are kept as
+/// their bits, with -0 folded into 0 and NaN refused.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Number(u64),
+    String(Shared<str>),
+}
+
+impl MapKey {
+    pub fn new(val: &Value) -> Option<MapKey> {
+        match val {
+            Value::Number(n) if !n.is_nan() => Some(MapKey::Number((n + 0.0).to_bits())),
+            Value::String(s) => Some(MapKey::String(s.clone())),
+            _ => None,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            MapKey::Number(bits) => Value::Number(f64::from_bits(*bits)),
+            MapKey::String(s) => Value::String(s.clone()),
+        }
+    }
+}
+
+// Numbers in numeric order come before strings, so printed maps are stable.
+impl Ord for MapKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (MapKey::Number(a), MapKey::Number(b)) => {
+                f64::from_bits(*a).total_cmp(&f64::from_bits(*b))
+            }
+            (MapKey::Number(_), MapKey::String(_)) => Ordering::Less,
+            (MapKey::String(_), MapKey::Number(_)) => Ordering::Greater,
+            (MapKey::String(a), MapKey::String(b)) => a.cmp(b),
+        }
+    }
+}
+
+impl PartialOrd for MapKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+pub struct Native {
+    pub name: String,
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+impl Value {
+    pub fn as_string(&self) -> Option<Shared<str>> {
+        if let Value::String(s) = self {
+            Some(s.clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        if let Value::Number(n) = self {
+            Some(*n)
+        } else {
+            None
+        }
+    }
+
+    /// The kind of value this is, with its article, for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::None => "nil",
+            Value::String(_) => "a string",
+            Value::Number(_) => "a number",
+            Value::Bool(_) => "a boolean",
+            Value::Array(_) => "an array",
+            Value::Map(_) => "a map",
+            Value::Native(_)
+            | Value::Function(_)
+            | Value::Closure(_)
+            | Value::BoundMethod(_)
+            | Value::LoxFunction(_) => "a function",
+            Value::Class(_) | Value::LoxClass(_) => "a class",
+            Value::Instance(_) | Value::LoxInstance(_) => "an instance",
+        }
+    }
+
+    // How two numbers, or two strings by their characters, order for the
+    // comparison operator op. A NaN leaves numbers unordered.
+    pub(crate) fn compare(&self, other: &Value, op: &str) -> Result<Option<Ordering>, ErrorKind> {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => Ok(a.partial_cmp(b)),
+            (Value::String(a), Value::String(b)) => Ok(Some(a.cmp(b))),
+            _ => Err(ErrorKind::type_mismatch(
+                &format!("two numbers or two strings for {}", op),
+                format!("{} and {}", self.type_name(), other.type_name()),
+            )),
+        }
+    }
+
+    // nil and false are falsey, everything else is truthy.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::None | Value::Bool(false))
+    }
+
+    pub fn as_function(&self) -> Option<Shared<Function>> {
+        if let Value::Function(func) = self {
+            Some(func.clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn as_lox_class(&self) -> Option<Shared<LoxClass>> {
+        if let Value::LoxClass(c) = self {
+            Some(c.clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn as_lox_instance(&self) -> Option<Shared<SharedCell<LoxInstance>>> {
+        if let Value::LoxInstance(i) = self {
+            Some(i.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{}", s),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Array(_) | Value::Map(_) => write_element(f, self, &mut Vec::new()),
+            Value::Native(_) => write!(f, "<native fn>"),
+            Value::Function(fun) => write!(f, "{}", fun),
+            Value::Closure(c) => write!(f, "{}", c.function),
+            Value::Class(k) => write!(f, "<class {}>", k.borrow().name),
+            Value::Instance(i) => write!(f, "{} instance", i.borrow().klass.borrow().name),
+            Value::BoundMethod(m) => write!(f, "{}", m.method.function),
+            Value::LoxFunction(l) => write!(f, "<fn {}>", l.name),
+            Value::LoxClass(c) => write!(f, "<class {}>", c.name),
+            Value::LoxInstance(i) => write!(f, "{} instance", i.borrow().klass.name),
+            Value::None => write!(f, "Nil"),
+        }
+    }
+}
+
+// Collections nested deeper than this print as if they were cycles.
+const MAX_PRINT_DEPTH: usize = 64;
+
+// Writes a value inside a collection as it would be written in source,
+// with strings quoted, and map entries in key order. open holds the
+// collections being written, so one inside itself prints as [...] or {...}.
+fn write_element(f: &mut fmt::Formatter, val: &Value, open: &mut Vec<usize>) -> fmt::Result {
+    let address = match val {
+        Value::Array(a) => Shared::as_ptr(a) as *const () as usize,
+        Value::Map(m) => Shared::as_ptr(m) as *const () as usize,
+        Value::String(s) => return write!(f, "\"{}\"", s),
+        Value::None => return write!(f, "nil"),
+        val => return write!(f, "{}", val),
+    };
+    if open.contains(&address) || open.len() >= MAX_PRINT_DEPTH {
+        return match val {
+            Value::Array(_) => write!(f, "[...]"),
+            _ => write!(f, "{{...}}"),
+        };
+    }
+    open.push(address);
+    match val {
+        Value::Array(a) => {
+            write!(f, "[")?;
+            for (i, val) in a.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_element(f, val, open)?;
+            }
+            write!(f, "]")?;
+        }
+        Value::Map(m) => {
+            let m = m.borrow();
+            let mut entries: Vec<_> = m.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            write!(f, "{{")?;
+            for (i, (key, val)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}: ", key.to_value())?;
+                write_element(f, val, open)?;
+            }
+            write!(f, "}}")?;
+        }
+        _ => unreachable!("Only collections are opened"),
+    }
+    open.pop();
+    Ok(())
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::String(s1), Value::String(s2)) => s1 == s2,
+            (Value::Bool(b1), Value::Bool(b2)) => b1 == b2,
+            // IEEE comparison, so NaN is not equal to itself.
+            (Value::Number(n1), Value::Number(n2)) => n1 == n2,
+            (Value::None, Value::None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+// Conversions to and from f64, bool and String, with nil standing for None.
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s.into())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(val: Option<T>) -> Self {
+        val.map_or(Value::None, Into::into)
+    }
+}
+
+macro_rules! try_from_value {
+    ($rust:ty, $expected:literal, $pattern:pat => $result:expr) => {
+        impl TryFrom<Value> for $rust {
+            type Error = ConversionError;
+            fn try_from(val: Value) -> Result<Self, Self::Error> {
+                match val {
+                    $pattern => Ok($result),
+                    val => Err(ConversionError::new($expected, &val)),
+                }
+            }
+        }
+
+        impl TryFrom<Value> for Option<$rust> {
+            type Error = ConversionError;
+            fn try_from(val: Value) -> Result<Self, Self::Error> {
+                match val {
+                    Value::None => Ok(None),
+                    val => val.try_into().map(Some),
+                }
+            }
+        }
+    };
+}
+
+try_from_value!(f64, "a number", Value::Number(n) => n);
+try_from_value!(bool, "a boolean", Value::Bool(b) => b);
+try_from_value!(String, "a string", Value::String(s) => s.to_string());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::stmt::Environment;
+    use crate::token::{Token, TokenType};
+    use std::collections::LinkedList;
+
+    // One value of every variant, each with how it printed before the two
+    // backends' value types were merged.
+    fn every_variant() -> Vec<(Value, &'static str)> {
+        let closure = Closure::new(Shared::new(Function {
+            arity: 0,
+            upvalue: 0,
+            chunk: Box::new(Chunk::new()),
+            name: "f".to_string(),
+            frame_size: 1,
+        }));
+        let klass = Shared::new(SharedCell::new(Class::new("A".into())));
+        let instance = Shared::new(SharedCell::new(Instance::new(klass.clone())));
+
+        let token = |name: &str| Token::new(TokenType::Identifier, name, None, 1, 1);
+        let function = LoxFunction::new(
+            token("g"),
+            LinkedList::new(),
+            LinkedList::new(),
+            Shared::new(SharedCell::new(Environment::new())),
+            HashMap::new(),
+        );
+        let lox_class = Shared::new(LoxClass::new(token("B"), None, HashMap::new()));
+
+        vec![
+            (Value::None, "Nil"),
+            (Value::String("hi".into()), "hi"),
+            (Value::Number(2.5), "2.5"),
+            (Value::Bool(true), "true"),
+            (
+                Value::Array(Shared::new(SharedCell::new(vec![
+                    Value::Number(1.0),
+                    Value::String("two".into()),
+                ]))),
+                "[1, \"two\"]",
+            ),
+            (
+                Value::Map(Shared::new(SharedCell::new(HashMap::from([
+                    (MapKey::new(&"b".into()).unwrap(), Value::Number(2.0)),
+                    (MapKey::new(&Value::Number(-0.0)).unwrap(), Value::None),
+                    (MapKey::new(&"a".into()).unwrap(), Value::Bool(true)),
+                ])))),
+                "{0: nil, a: true, b: 2}",
+            ),
+            (
+                Value::Native(Shared::new(Native {
+                    name: "clock".to_string(),
+                    arity: 0,
+                    function: Shared::new(|_| Ok(Value::None)),
+                })),
+                "<native fn>",
+            ),
+            (Value::Function(closure.function.clone()), "<fn f>"),
+            (Value::Closure(closure.clone()), "<fn f>"),
+            (Value::Class(klass), "<class A>"),
+            (Value::Instance(instance.clone()), "A instance"),
+            (
+                Value::BoundMethod(Box::new(BoundMethod {
+                    receiver: instance,
+                    method: closure,
+                })),
+                "<fn f>",
+            ),
+            (Value::LoxFunction(Shared::new(function)), "<fn g>"),
+            (Value::LoxClass(lox_class.clone()), "<class B>"),
+            (
+                Value::LoxInstance(Shared::new(SharedCell::new(LoxInstance::new(lox_class)))),
+                "B instance",
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_display() {
+        for (val, printed) in every_variant() {
+            assert_eq!(val.to_string(), printed);
+            assert_eq!(format!("{:?}", val), printed);
+        }
+        let script = Function {
+            arity: 0,
+            upvalue: 0,
+            chunk: Box::new(Chunk::new()),
+            name: String::new(),
+            frame_size: 1,
+        };
+        assert_eq!(Value::Function(Shared::new(script)).to_string(), "<script>");
+    }
+
+    #[test]
+    fn test_equality() {
+        // Nil, strings, booleans and numbers compare by value; every other
+        // value, even compared with itself, is unequal.
+        let values = every_variant();
+        for (i, (left, _)) in values.iter().enumerate() {
+            for (j, (right, _)) in values.iter().enumerate() {
+                assert_eq!(left == right, i == j && i < 4, "{:?} == {:?}", left, right);
+            }
+        }
+        assert_eq!(Value::String("a".into()), Value::String("a".into()));
+        assert_ne!(Value::String("a".into()), Value::String("b".into()));
+        assert_ne!(Value::Number(1.0), Value::Number(2.0));
+        assert_ne!(Value::Number(f64::NAN), Value::Number(f64::NAN));
+        assert_ne!(Value::Bool(true), Value::Bool(false));
+        assert_ne!(Value::Bool(false), Value::None);
+        assert_ne!(Value::Number(0.0), Value::Bool(false));
+        assert_ne!(Value::String("1".into()), Value::Number(1.0));
+    }
+}
@@ -1,623 +1,1475 @@
-use crate::chunk;
-use crate::chunk::Value;
-use crate::object::{BoundMethod, Class, Closure, Function, Instance, Upvalue};
-use crate::{BACKTRACE, DEBUG, USIZE};
-
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
+use crate::chunk::{ChunkError, GlobalNames, OpCode};
+use crate::compile::{compile_to, undefined_globals, ParseError};
+use crate::debugger::{function_name, Command, Debugger};
+use crate::error::{error_fields, ErrorKind};
+use crate::gc::{Heap, Marker};
+use crate::interpreter::MAX_CALL_DEPTH;
+use crate::object::{
+    BoundMethod, Class, Closure, Function, Instance, Member, PropertyCache, Upvalue,
+};
+use crate::output::Io;
+use crate::profile::Profile;
+use crate::shared::{SendSync, Shared, SharedCell};
+use crate::stdlib;
+use crate::token::Span;
+use crate::value::{MapKey, Native, Value};
+use crate::USIZE;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct RuntimeError {
+    /// The message, which is the kind's unless a backend says more.
     pub reason: String,
+    // Boxed to keep errors, and the results that carry them, small.
+    kind: Box<ErrorKind>,
     pub line: i32,
+    pub column: Option<usize>,
+    pub span: Option<Span>,
+    pub lexeme: Option<String>,
+    pub source_name: Option<Box<str>>,
 }
 
 impl std::fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "[Line {}] in script, Runtime Error: {}",
-            self.line, self.reason
-        )
+        // Lines start at 1; a failure outside any line, such as a host
+        // calling with the wrong arguments, has none to show.
+        if self.line > 0 {
+            write!(f, "[Line {}] in script, ", self.line)?;
+        }
+        write!(f, "Runtime Error: {}", self.reason)
     }
 }
 impl std::error::Error for RuntimeError {}
 
+impl RuntimeError {
+    pub fn new(line: i32, reason: String) -> RuntimeError {
+        RuntimeError::of(line, ErrorKind::Other(reason))
+    }
+
+    pub fn of(line: i32, kind: ErrorKind) -> RuntimeError {
+        RuntimeError {
+            reason: kind.to_string(),
+            kind: Box::new(kind),
+            line,
+            column: None,
+            span: None,
+            lexeme: None,
+            source_name: None,
+        }
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+}
+
+error_fields!(RuntimeError);
+
+impl From<ChunkError> for RuntimeError {
+    fn from(e: ChunkError) -> Self {
+        RuntimeError::new(-1, format!("{}.", e))
+    }
+}
+
+pub const FRAMES_MAX: usize = 1024;
+pub const STACK_MAX: usize = 64 * 256;
+// Frames kept at each end of a long backtrace.
+const BACKTRACE_EDGE: usize = 5;
+// Instructions run between checks of the wall-clock deadline.
+const CLOCK_INTERVAL: u64 = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct VmOptions {
+    pub max_stack: usize,
+    pub max_frames: usize,
+    // Limits on a single interpret call; exceeding either aborts it.
+    pub max_instructions: Option<u64>,
+    pub timeout: Option<Duration>,
+    // Dump the stack and each instruction to stderr as it runs.
+    pub trace: bool,
+    pub trace_format: TraceFormat,
+    // Count executed opcodes and calls for a report after the run.
+    pub profile: bool,
+    // Stop before each instruction and take commands from stdin.
+    pub debug: bool,
+    // Print the calls that led to a runtime error above it.
+    pub backtrace: bool,
+    // Nested calls the tree-walk interpreter allows, as max_frames is for
+    // the VM.
+    pub max_call_depth: usize,
+}
+
+impl Default for VmOptions {
+    fn default() -> Self {
+        VmOptions {
+            max_stack: STACK_MAX,
+            max_frames: FRAMES_MAX,
+            max_instructions: None,
+            timeout: None,
+            trace: false,
+            trace_format: TraceFormat::Full,
+            profile: false,
+            debug: false,
+            backtrace: true,
+            max_call_depth: MAX_CALL_DEPTH,
+        }
+    }
+}
+
+/// How a trace shows each instruction: the stack then the disassembly, or
+/// one line with the offset, opcode and top of the stack.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    #[default]
+    Full,
+    Compact,
+}
+
 pub struct VM {
-    stack: Vec<Value>,
-    globals: HashMap<String, Value>,
-    frames: Vec<Rc<RefCell<CallFrame>>>,
-    captures: HashMap<usize, Rc<RefCell<Upvalue>>>,
+    options: VmOptions,
+    // Allocated once at max_stack slots; calls check the callee's whole
+    // frame fits, so pushes inside it need no check.
+    stack: Box<[Value]>,
+    stack_top: usize,
+    globals: Vec<Option<Value>>,
+    global_names: GlobalNames,
+    frames: Vec<CallFrame>,
+    // Open upvalues sorted by stack slot, so closing a range of slots only
+    // touches the captured tail.
+    captures: Vec<(usize, Shared<SharedCell<Upvalue>>)>,
+    handlers: Vec<Handler>,
+    thrown: Option<Value>,
+    heap: Heap,
+    backtrace: Vec<String>,
+    executed: u64,
+    deadline: Option<Instant>,
+    // Set when the run must stop without reaching try handlers.
+    aborted: bool,
+    profile: Option<Profile>,
+    // Where traces go instead of io's err.
+    trace_out: Option<Box<dyn Write>>,
+    // Lines of dispatched instructions, while recording them.
+    covered: Option<BTreeSet<i32>>,
+    debugger: Option<Debugger>,
+    io: Io,
+}
+
+// An active try block: where to resume and how much of the frame and value
+// stacks survive when an exception is caught.
+struct Handler {
+    frames: usize,
+    depth: usize,
+    ip: usize,
 }
 
 macro_rules! binary_op {
-    ($stack:expr, $op:tt, $frame: expr) => {{
+    ($stack:expr, $op:tt) => {{
         if let (Some(a), Some(b)) = ($stack.peek(0).as_number(), $stack.peek(1).as_number()) {
             $stack.pop();
             $stack.pop();
             $stack.push(Value::Number(b $op a));
         }
         else {
-            return Err(RuntimeError {
-                line: $frame.read_line()?,
-                reason: "Operands must be numbers.".to_string()
-            }
-            )
+            return Err(RuntimeError::of(
+                $stack.read_line()?,
+                ErrorKind::type_mismatch(
+                    concat!("numbers for ", stringify!($op)),
+                    format!("{} and {}", $stack.peek(1), $stack.peek(0)),
+                ),
+            ))
         }
     }};
 }
 
-macro_rules! binary_op_bool {
-    ($stack:expr, $op:tt, $frame: expr) => {{
-        if let (Some(a), Some(b)) = ($stack.peek(0).as_number(), $stack.peek(1).as_number()) {
-            $stack.pop();
-            $stack.pop();
-            $stack.push(Value::Bool(b $op a));
-        }
-        else {
-            return Err(RuntimeError {
-                line: $frame.read_line()?,
-                reason: "Operands must be numbers.".to_string()
+// Orders two numbers or two strings, and pushes whether $test holds of the
+// ordering.
+macro_rules! comparison {
+    ($stack:expr, $op:tt, $test:expr) => {{
+        match $stack.peek(1).compare($stack.peek(0), stringify!($op)) {
+            Ok(ord) => {
+                $stack.pop();
+                $stack.pop();
+                $stack.push(Value::Bool(($test)(ord)));
             }
-            )
+            Err(kind) => return Err(RuntimeError::of($stack.read_line()?, kind)),
         }
     }};
 }
 
 impl VM {
-    pub fn init() -> VM {
-        VM {
-            stack: Vec::new(),
-            globals: HashMap::new(),
+    pub fn init(options: VmOptions) -> VM {
+        let mut vm = VM {
+            options,
+            // The script's closure is pushed before its call is checked.
+            stack: vec![Value::None; options.max_stack.max(1)].into_boxed_slice(),
+            stack_top: 0,
+            globals: Vec::new(),
+            global_names: GlobalNames::default(),
             frames: Vec::new(),
-            captures: HashMap::new(),
+            captures: Vec::new(),
+            handlers: Vec::new(),
+            thrown: None,
+            heap: Heap::new(),
+            backtrace: Vec::new(),
+            executed: 0,
+            deadline: None,
+            aborted: false,
+            profile: options.profile.then(Profile::new),
+            covered: None,
+            trace_out: None,
+            debugger: options.debug.then(Debugger::new),
+            io: Io::stdio(),
+        };
+        stdlib::register_vm(&mut vm);
+        vm
+    }
+
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: u8,
+        function: impl Fn(&[Value]) -> Result<Value, String> + SendSync + 'static,
+    ) {
+        let slot = self.global_names.slot(name);
+        self.define_global(
+            slot,
+            Value::Native(Shared::new(Native {
+                name: name.to_string(),
+                arity,
+                function: Shared::new(function),
+            })),
+        );
+    }
+
+    // Where printed values, traces and debugger output go.
+    pub fn io(&mut self) -> &mut Io {
+        &mut self.io
+    }
+
+    pub fn set_io(&mut self, io: Io) {
+        self.io = io;
+    }
+
+    // Compiles against this VM's globals, numbering lines from line and
+    // reporting errors to its io.
+    pub fn compile(&mut self, source: &str, line: i32) -> Result<Shared<Function>, ParseError> {
+        compile_to(
+            source,
+            line,
+            &mut self.global_names,
+            &mut self.io.err,
+            false,
+        )
+    }
+
+    // Warns, on its io, of the globals function uses that neither it nor
+    // this VM defines. Later lines and scripts may yet define them, so
+    // these are only warnings.
+    pub fn warn_undefined_globals(&mut self, function: &Shared<Function>) {
+        let undefined = undefined_globals(function, |slot| {
+            self.globals.get(slot).is_some_and(Option::is_some)
+        });
+        for (slot, line) in undefined {
+            let name = self.global_names.name(slot);
+            let _ = writeln!(
+                self.io.err,
+                "[line {}] at {}: Warning: Global '{}' is never defined.",
+                line, name, name
+            );
+        }
+    }
+
+    // Compiles source to return the value of its trailing expression
+    // statement, if it has one.
+    pub fn compile_eval(
+        &mut self,
+        source: &str,
+        line: i32,
+    ) -> Result<Shared<Function>, ParseError> {
+        compile_to(source, line, &mut self.global_names, &mut self.io.err, true)
+    }
+
+    // Defined globals with their names, in the order their slots were given.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.globals
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, val)| Some((self.global_names.name(slot), val.as_ref()?)))
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let slot = self.global_names.lookup(name)?;
+        self.globals.get(slot)?.clone()
+    }
+
+    // The globals' values, to put back with restore_globals. Slots the
+    // compiler has given out since stay given, but undefined.
+    pub(crate) fn snapshot_globals(&self) -> Vec<Option<Value>> {
+        self.globals.clone()
+    }
+
+    pub(crate) fn restore_globals(&mut self, globals: Vec<Option<Value>>) {
+        self.globals = globals;
+    }
+
+    pub fn global_names(&mut self) -> &mut GlobalNames {
+        &mut self.global_names
+    }
+
+    fn define_global(&mut self, slot: usize, val: Value) {
+        if slot >= self.globals.len() {
+            self.globals.resize(slot + 1, None);
         }
+        self.globals[slot] = Some(val);
+    }
+
+    fn frame(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("Frame is empty")
+    }
+
+    fn read_chunk(&mut self) -> Result<u8, RuntimeError> {
+        self.frame().read_chunk()
+    }
+
+    fn read_jump(&mut self) -> Result<usize, RuntimeError> {
+        self.frame().read_jump()
+    }
+
+    fn read_short(&mut self) -> Result<usize, RuntimeError> {
+        self.frame().read_short().map(usize::from)
+    }
+
+    fn read_constant(&mut self) -> Result<Value, RuntimeError> {
+        let offset = self.read_chunk()?;
+        self.frame().read_constant(offset as usize)
     }
 
-    fn current(&self) -> Rc<RefCell<CallFrame>> {
-        self.frames.last().expect("Frame is empty").clone()
+    fn read_line(&self) -> Result<i32, RuntimeError> {
+        self.frames.last().expect("Frame is empty").read_line()
     }
 
     pub fn reset_stack(&mut self) {
-        self.stack.clear();
+        // Closures that outlive a failed run keep what they captured rather
+        // than reading slots of whatever runs next.
+        for (slot, upvalue) in self.captures.drain(..) {
+            *upvalue.borrow_mut() = Upvalue::Out(self.stack[slot].clone());
+        }
+        self.truncate_stack(0);
+        self.frames.clear();
+        self.captures.clear();
+        self.handlers.clear();
+        self.thrown = None;
     }
 
     pub fn push(&mut self, val: Value) {
-        self.stack.push(val);
+        debug_assert!(self.stack_top < self.stack.len(), "Push past the frame");
+        self.stack[self.stack_top] = val;
+        self.stack_top += 1;
     }
 
     pub fn pop(&mut self) -> Value {
-        self.stack.pop().expect("Pop from empty stack")
+        debug_assert!(self.stack_top > 0, "Pop from empty stack");
+        self.stack_top -= 1;
+        std::mem::replace(&mut self.stack[self.stack_top], Value::None)
     }
 
     pub fn peek(&self, distance: usize) -> &Value {
-        &self.stack[self.stack.len() - 1 - distance]
+        debug_assert!(distance < self.stack_top, "Peek past the bottom");
+        &self.stack[self.stack_top - 1 - distance]
         // Hopefully remove clone in the future
     }
 
-    pub fn interpret(&mut self, func: Rc<Function>) {
-        let clos = Closure::new(func);
+    // Popped slots are cleared so they don't keep their values alive.
+    fn truncate_stack(&mut self, len: usize) {
+        debug_assert!(len <= self.stack_top, "Truncate above the top");
+        self.stack[len..self.stack_top].fill(Value::None);
+        self.stack_top = len;
+    }
+
+    // Runs a compiled script, returning what it returns: nil unless it was
+    // compiled to evaluate or ends in a top-level return.
+    pub fn interpret(&mut self, func: Shared<Function>) -> Result<Value, RuntimeError> {
+        self.call_closure(Closure::new(func), &[])
+    }
+
+    // Runs code the user didn't write, such as the prelude: untraced,
+    // unprofiled, outside the debugger and without the limits.
+    pub fn interpret_quietly(&mut self, func: Shared<Function>) -> Result<Value, RuntimeError> {
+        let options = self.options;
+        self.options = VmOptions {
+            trace: false,
+            profile: false,
+            debug: false,
+            max_instructions: None,
+            timeout: None,
+            ..options
+        };
+        let profile = self.profile.take();
+        let debugger = self.debugger.take();
+        let result = self.interpret(func);
+        self.options = options;
+        self.profile = profile;
+        self.debugger = debugger;
+        result
+    }
+
+    // Calls a closure from outside any run, such as one a host kept from an
+    // earlier run, and returns what it returns.
+    pub fn call_closure(&mut self, clos: Closure, args: &[Value]) -> Result<Value, RuntimeError> {
+        if args.len() >= self.stack.len() - self.stack_top || args.len() > u8::MAX as usize {
+            return Err(RuntimeError::of(0, ErrorKind::StackOverflow));
+        }
         self.push(Value::Closure(clos.clone()));
-        let _ = self.call(clos, 0);
-        if let Err(e) = self.run() {
-            if BACKTRACE {
-                eprintln!("Backtrace:");
-                for frame in self.frames.iter().rev() {
-                    let f = frame.borrow();
-                    let line = f.read_line().unwrap();
-                    eprintln!(
-                        "[Line {}] in {}",
-                        line,
-                        if f.closure.function.name.is_empty() {
-                            "Script"
-                        } else {
-                            &f.closure.function.name
-                        }
-                    );
-                    eprintln!();
+        for arg in args {
+            self.push(arg.clone());
+        }
+        self.executed = 0;
+        self.deadline = self.options.timeout.map(|t| Instant::now() + t);
+        self.aborted = false;
+        let result = self
+            .call(clos, args.len() as u8, 0)
+            .and_then(|_| self.run())
+            .map(|_| self.pop());
+        if let Some(profile) = &mut self.profile {
+            profile.stop();
+        }
+        self.backtrace.clear();
+        if let Err(e) = &result {
+            self.backtrace = self.trace_frames(e);
+            self.reset_stack();
+        }
+        result
+    }
+
+    // Starts or stops recording the lines of dispatched instructions.
+    pub fn record_lines(&mut self, on: bool) {
+        self.covered = on.then(BTreeSet::new);
+    }
+
+    // The lines recorded since the last call, while recording.
+    pub fn recorded_lines(&mut self) -> BTreeSet<i32> {
+        self.covered
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    // Instructions the last interpret call dispatched.
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    // Frames active when the last interpret call failed, innermost first.
+    pub fn backtrace(&self) -> &[String] {
+        &self.backtrace
+    }
+
+    fn trace_frames(&self, e: &RuntimeError) -> Vec<String> {
+        let mut trace = Vec::new();
+        // The innermost frame reports the faulting instruction, every
+        // outer frame the line of the call it is waiting on.
+        let mut line = match self.frames.last() {
+            Some(frame) => frame.read_line().unwrap_or(e.line),
+            None => e.line,
+        };
+        let depth = self.frames.len();
+        for (i, f) in self.frames.iter().rev().enumerate() {
+            if depth > 2 * BACKTRACE_EDGE && i == BACKTRACE_EDGE {
+                trace.push(format!("...{} more frames...", depth - 2 * BACKTRACE_EDGE));
+            }
+            if depth <= 2 * BACKTRACE_EDGE || i < BACKTRACE_EDGE || i >= depth - BACKTRACE_EDGE {
+                if f.closure.function.name.is_empty() {
+                    trace.push(format!("[line {}] in script", line));
+                } else {
+                    trace.push(format!("[line {}] in {}()", line, f.closure.function.name));
                 }
             }
-            self.reset_stack();
-            eprintln!("{}", e);
+            line = f.call_line;
         }
+        trace
     }
 
     pub fn run(&mut self) -> Result<(), RuntimeError> {
-        while !self.frames.is_empty() {
-            let binding = self.current();
-            let mut current = binding.borrow_mut();
-            while current.ip < current.closure.function.chunk.len() {
-                if DEBUG {
-                    eprintln!();
-                    for val in &self.stack {
-                        eprint!("[ {} ]", val);
+        loop {
+            match self.execute() {
+                Ok(()) => return Ok(()),
+                Err(e) if self.aborted => return Err(e),
+                Err(e) => {
+                    let thrown = self
+                        .thrown
+                        .take()
+                        .unwrap_or_else(|| Value::String(e.reason.as_str().into()));
+                    match self.handlers.pop() {
+                        Some(handler) => self.unwind(handler, thrown),
+                        None => return Err(e),
                     }
-                    eprintln!();
-                    current
-                        .closure
-                        .function
-                        .chunk
-                        .disassemble_instruction(current.ip);
-                }
-                if self.stack.len() > 16 * 256 {
-                    return Err(RuntimeError {
-                        reason: "Stack overflow".to_string(),
-                        line: -1,
-                    });
                 }
-                let op = current.read_chunk()?;
-                match op {
-                    chunk::OP_RETURN => {
-                        let ret = self.pop();
-                        for i in (current.slot..self.stack.len()).rev() {
-                            self.close_upvalues(i); // Expected to optimize in the future
-                        }
-                        self.frames.pop();
-                        if self.frames.is_empty() {
-                            self.pop();
-                            return Ok(());
-                        }
-                        let slot = current.slot;
-                        self.stack.truncate(slot);
-                        self.push(ret);
-                        break;
-                    }
-                    chunk::OP_CONSTANT => {
-                        let offset = current.read_chunk()?;
-                        let constant = current.read_constant(offset as usize)?;
-                        self.push(constant);
-                    }
-                    chunk::OP_NEGATE => {
-                        if let Some(x) = self.peek(0).as_number() {
-                            self.pop();
-                            let val = Value::Number(-x);
-                            self.push(val);
-                        } else {
-                            return Err(RuntimeError {
-                                line: current.read_line()?,
-                                reason: "Operand must be a number".to_string(),
-                            });
-                        }
-                    }
-                    chunk::OP_ADD => {
-                        if let (Some(a), Some(b)) =
-                            (self.peek(0).as_number(), self.peek(1).as_number())
-                        {
-                            self.pop();
-                            self.pop();
-                            self.push(Value::Number(b + a));
-                        } else if let (Some(a), Some(b)) =
-                            (self.peek(0).as_string(), self.peek(1).as_string())
-                        {
-                            self.pop();
-                            self.pop();
-                            self.push(Value::String(b + &a))
-                        } else {
-                            return Err(RuntimeError {
-                                line: current.read_line()?,
-                                reason: "Operands must be numbers.".to_string(),
-                            });
+            }
+        }
+    }
+
+    // Drop everything above the handler and resume at its catch clause with
+    // the thrown value in the catch variable's slot.
+    fn unwind(&mut self, handler: Handler, thrown: Value) {
+        self.frames.truncate(handler.frames);
+        while let Some((slot, _)) = self.captures.last() {
+            if *slot < handler.depth {
+                break;
+            }
+            let (slot, upvalue) = self.captures.pop().expect("Captures is empty");
+            *upvalue.borrow_mut() = Upvalue::Out(self.stack[slot].clone());
+        }
+        self.truncate_stack(handler.depth);
+        self.push(thrown);
+        self.frame().ip = handler.ip;
+    }
+
+    fn dump_stack(&mut self) {
+        write_stack(&self.stack[..self.stack_top], &mut self.io.err);
+    }
+
+    // Sends traces to out rather than io's err.
+    pub fn set_trace_output(&mut self, out: Box<dyn Write>) {
+        self.trace_out = Some(out);
+    }
+
+    fn trace(&mut self) -> Result<(), RuntimeError> {
+        let frame = self.frames.last().expect("Frame is empty");
+        let chunk = &frame.closure.function.chunk;
+        let stack = &self.stack[..self.stack_top];
+        let out: &mut dyn Write = match &mut self.trace_out {
+            Some(out) => out,
+            None => &mut self.io.err,
+        };
+        match self.options.trace_format {
+            TraceFormat::Full => {
+                let _ = writeln!(out);
+                write_stack(stack, out);
+                chunk.disassemble_instruction_to(frame.ip, out)?;
+            }
+            TraceFormat::Compact => {
+                let op = OpCode::try_from(chunk.read_chunk(frame.ip)?);
+                let top = stack.last().map_or("-".to_string(), Value::to_string);
+                let _ = writeln!(
+                    out,
+                    "{:04} {} {}",
+                    frame.ip,
+                    op.map_or("OP_UNKNOWN", OpCode::name),
+                    top
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<(), RuntimeError> {
+        let frame = self.frames.last().expect("Frame is empty");
+        let function = frame.closure.function.clone();
+        let ip = frame.ip;
+        let debugger = self.debugger.as_mut().expect("Debugger is off");
+        if !debugger.should_pause(&function.name, ip) {
+            return Ok(());
+        }
+        let _ = write!(self.io.err, "{} ", function_name(&function.name));
+        function
+            .chunk
+            .disassemble_instruction_to(ip, &mut self.io.err)?;
+        loop {
+            let debugger = self.debugger.as_mut().expect("Debugger is off");
+            match debugger.read_command() {
+                Command::Step => {
+                    debugger.set_stepping(true);
+                    return Ok(());
+                }
+                Command::Continue => {
+                    debugger.set_stepping(false);
+                    return Ok(());
+                }
+                Command::Break(name, offset) => {
+                    debugger.add_breakpoint(name.as_deref().unwrap_or(&function.name), offset)
+                }
+                Command::Print => self.dump_stack(),
+                Command::Global(name) => {
+                    let _ = match self
+                        .global_names
+                        .lookup(&name)
+                        .and_then(|slot| self.globals.get(slot).cloned().flatten())
+                    {
+                        Some(val) => writeln!(self.io.err, "{} = {}", name, val),
+                        None => writeln!(self.io.err, "Undefined global {}.", name),
+                    };
+                }
+                Command::Quit => {
+                    self.aborted = true;
+                    return Err(RuntimeError::new(
+                        function.chunk.read_line(ip)?,
+                        "Execution stopped by debugger.".to_string(),
+                    ));
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self) -> Result<(), RuntimeError> {
+        self.executed += 1;
+        let over_count = self
+            .options
+            .max_instructions
+            .is_some_and(|max| self.executed > max);
+        let checkpoint = self.executed.is_multiple_of(CLOCK_INTERVAL);
+        if checkpoint && crate::is_interrupted() {
+            self.aborted = true;
+            return Err(RuntimeError::of(self.read_line()?, ErrorKind::Interrupted));
+        }
+        let over_time = checkpoint && self.deadline.is_some_and(|d| Instant::now() >= d);
+        if over_count || over_time {
+            self.aborted = true;
+            return Err(RuntimeError::new(
+                self.read_line()?,
+                "Execution budget exceeded.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Result<(), RuntimeError> {
+        while !self.frames.is_empty() {
+            if self.debugger.is_some() {
+                self.pause()?;
+            }
+            if self.options.trace {
+                self.trace()?;
+            }
+            let op = match OpCode::try_from(self.read_chunk()?) {
+                Ok(op) => op,
+                Err(byte) => {
+                    return Err(RuntimeError::new(
+                        self.read_line()?,
+                        format!("Unknown opcode {}.", byte),
+                    ))
+                }
+            };
+            self.tick()?;
+            if let Some(profile) = &mut self.profile {
+                profile.instruction(op);
+            }
+            if let Some(lines) = &mut self.covered {
+                lines.insert(self.frames.last().expect("Frame is empty").read_line()?);
+            }
+            match op {
+                OpCode::Return => {
+                    let ret = self.pop();
+                    let slot = self.frame().slot;
+                    self.close_upvalues(slot);
+                    self.frames.pop();
+                    while let Some(handler) = self.handlers.last() {
+                        if handler.frames <= self.frames.len() {
+                            break;
                         }
+                        self.handlers.pop();
                     }
-                    chunk::OP_SUBTRACT => {
-                        binary_op!(self, -, current);
-                    }
-                    chunk::OP_MULTIPLY => {
-                        binary_op!(self, *, current);
+                    self.truncate_stack(slot);
+                    self.push(ret);
+                    // The outermost call's result is left for call_closure.
+                    if self.frames.is_empty() {
+                        return Ok(());
                     }
-                    chunk::OP_DIVIDE => {
-                        binary_op!(self, /, current);
-                    }
-                    chunk::OP_NIL => {
-                        self.push(Value::None);
-                    }
-                    chunk::OP_TRUE => {
-                        self.push(Value::Bool(true));
+                }
+                OpCode::Constant => {
+                    let constant = self.read_constant()?;
+                    self.push(constant);
+                }
+                OpCode::Negate => {
+                    if let Some(x) = self.peek(0).as_number() {
+                        self.pop();
+                        let val = Value::Number(-x);
+                        self.push(val);
+                    } else {
+                        return Err(RuntimeError::of(
+                            self.read_line()?,
+                            ErrorKind::type_mismatch("a number", self.peek(0)),
+                        ));
                     }
-                    chunk::OP_FALSE => {
-                        self.push(Value::Bool(false));
+                }
+                OpCode::Add => {
+                    if let (Some(a), Some(b)) = (self.peek(0).as_number(), self.peek(1).as_number())
+                    {
+                        self.pop();
+                        self.pop();
+                        self.push(Value::Number(b + a));
+                    } else if let (Some(a), Some(b)) =
+                        (self.peek(0).as_string(), self.peek(1).as_string())
+                    {
+                        self.pop();
+                        self.pop();
+                        self.push(Value::String([&*b, &*a].concat().into()))
+                    } else {
+                        return Err(RuntimeError::of(
+                            self.read_line()?,
+                            ErrorKind::type_mismatch(
+                                "two numbers or two strings",
+                                format!("{} and {}", self.peek(1), self.peek(0)),
+                            ),
+                        ));
                     }
-                    chunk::OP_NOT => {
-                        let logic = match self.pop() {
-                            Value::None => true,
-                            Value::Bool(x) => !x,
-                            _ => false,
-                        };
-                        // permissive NOT
-                        self.push(Value::Bool(logic))
+                }
+                OpCode::Subtract => {
+                    binary_op!(self, -);
+                }
+                OpCode::Multiply => {
+                    binary_op!(self, *);
+                }
+                OpCode::Divide => {
+                    binary_op!(self, /);
+                }
+                OpCode::Nil => {
+                    self.push(Value::None);
+                }
+                OpCode::True => {
+                    self.push(Value::Bool(true));
+                }
+                OpCode::False => {
+                    self.push(Value::Bool(false));
+                }
+                OpCode::Not => {
+                    let val = self.pop();
+                    self.push(Value::Bool(!val.is_truthy()))
+                }
+                OpCode::Equal => {
+                    let left = self.pop();
+                    let right = self.pop();
+                    self.push(Value::Bool(left == right))
+                }
+                OpCode::Greater => {
+                    comparison!(self, >, |ord| ord == Some(Ordering::Greater))
+                }
+                OpCode::Less => {
+                    comparison!(self, <, |ord| ord == Some(Ordering::Less))
+                }
+                // Negated like the OP_LESS/OP_GREATER and OP_NOT pairs these
+                // replace, so comparisons with NaN are true.
+                OpCode::GreaterEqual => {
+                    comparison!(self, >=, |ord| ord != Some(Ordering::Less))
+                }
+                OpCode::LessEqual => {
+                    comparison!(self, <=, |ord| ord != Some(Ordering::Greater))
+                }
+                OpCode::NotEqual => {
+                    let left = self.pop();
+                    let right = self.pop();
+                    self.push(Value::Bool(left != right))
+                }
+                OpCode::Dup => {
+                    let val = self.peek(0).clone();
+                    self.push(val);
+                }
+                OpCode::Print => {
+                    let val = self.pop();
+                    let _ = writeln!(self.io.out, "{}", val);
+                }
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::Array => {
+                    let cnt = self.read_chunk()? as usize;
+                    let start = self.stack_top - cnt;
+                    let elements = self.stack[start..self.stack_top]
+                        .iter_mut()
+                        .map(|val| std::mem::replace(val, Value::None))
+                        .collect();
+                    self.stack_top = start;
+                    let array = self.heap.array(elements);
+                    self.push(Value::Array(array));
+                    self.maybe_collect();
+                }
+                OpCode::Map => {
+                    let cnt = self.read_chunk()? as usize;
+                    let start = self.stack_top - 2 * cnt;
+                    let mut entries = HashMap::with_capacity(cnt);
+                    for i in (start..self.stack_top).step_by(2) {
+                        let key = VM::map_key(&self.stack[i], self.read_line()?)?;
+                        let val = std::mem::replace(&mut self.stack[i + 1], Value::None);
+                        entries.insert(key, val);
                     }
-                    chunk::OP_EQUAL => {
-                        let left = self.pop();
-                        let right = self.pop();
-                        self.push(Value::Bool(left == right))
+                    self.stack_top = start;
+                    let map = self.heap.map(entries);
+                    self.push(Value::Map(map));
+                    self.maybe_collect();
+                }
+                OpCode::IndexGet => {
+                    let index = self.pop();
+                    let target = self.pop();
+                    let line = self.read_line()?;
+                    // A missing key reads as nil, as an unset field would.
+                    let val = match &target {
+                        Value::Map(map) => map
+                            .borrow()
+                            .get(&VM::map_key(&index, line)?)
+                            .cloned()
+                            .unwrap_or(Value::None),
+                        _ => VM::index_array(&target, &index, line, |a, i| a[i].clone())?,
+                    };
+                    self.push(val);
+                }
+                OpCode::IndexSet => {
+                    let val = self.pop();
+                    let index = self.pop();
+                    let target = self.pop();
+                    let line = self.read_line()?;
+                    match &target {
+                        Value::Map(map) => {
+                            let key = VM::map_key(&index, line)?;
+                            map.borrow_mut().insert(key, val.clone());
+                        }
+                        _ => VM::index_array(&target, &index, line, |a, i| a[i] = val.clone())?,
                     }
-                    chunk::OP_GREATER => {
-                        binary_op_bool!(self, >, current)
+                    self.push(val);
+                }
+                OpCode::PushHandler => {
+                    let ip = self.read_jump()? + self.frame().ip;
+                    self.handlers.push(Handler {
+                        frames: self.frames.len(),
+                        depth: self.stack_top,
+                        ip,
+                    });
+                }
+                OpCode::PopHandler => {
+                    self.handlers.pop();
+                }
+                OpCode::Throw => {
+                    let val = self.pop();
+                    let reason = format!("Uncaught exception: {}", val);
+                    self.thrown = Some(val);
+                    return Err(RuntimeError::new(self.read_line()?, reason));
+                }
+                OpCode::PopN => {
+                    let cnt = self.read_chunk()? as usize;
+                    self.truncate_stack(self.stack_top - cnt);
+                }
+                OpCode::DefineGlobal => {
+                    let slot = self.read_short()?;
+                    let val = self.pop();
+                    self.define_global(slot, val);
+                }
+                OpCode::GetGlobal => {
+                    let slot = self.read_short()?;
+                    if let Some(Some(val)) = self.globals.get(slot) {
+                        self.push(val.clone());
+                    } else {
+                        return Err(RuntimeError::of(
+                            self.read_line()?,
+                            ErrorKind::UndefinedVariable {
+                                name: self.global_names.name(slot).to_string(),
+                            },
+                        ));
                     }
-                    chunk::OP_LESS => {
-                        binary_op_bool!(self, <, current)
+                }
+                OpCode::SetGlobal => {
+                    let slot = self.read_short()?;
+                    let val = self.peek(0).clone();
+                    if let Some(Some(global)) = self.globals.get_mut(slot) {
+                        *global = val;
+                    } else {
+                        return Err(RuntimeError::of(
+                            self.read_line()?,
+                            ErrorKind::UndefinedVariable {
+                                name: self.global_names.name(slot).to_string(),
+                            },
+                        ));
                     }
-                    chunk::OP_PRINT => {
-                        println!("{}", self.pop());
+                }
+                OpCode::GetLocal => {
+                    let offset = self.frame().slot + self.read_chunk()? as usize;
+                    self.push(self.stack[offset].clone());
+                }
+                OpCode::SetLocal => {
+                    let offset = self.frame().slot + self.read_chunk()? as usize;
+                    self.stack[offset] = self.peek(0).clone();
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_jump()?;
+                    if !self.peek(0).is_truthy() {
+                        self.frame().ip += offset;
                     }
-                    chunk::OP_POP => {
-                        self.pop();
+                }
+                OpCode::JumpIfTrue => {
+                    let offset = self.read_jump()?;
+                    if self.peek(0).is_truthy() {
+                        self.frame().ip += offset;
                     }
-                    chunk::OP_DEFINE_GLOBAL => {
-                        let offset = current.read_chunk()?;
-                        let constant = current.read_constant(offset as usize)?;
-                        if let Some(name) = constant.as_string() {
-                            let val = self.peek(0);
-                            self.globals.insert(name, val.clone());
-                            self.pop();
-                        } else {
-                            return Err(RuntimeError {
-                                reason: format!("{} is not a variable name.", constant),
-                                line: current.read_line()?,
-                            });
+                }
+                OpCode::Jump => {
+                    let offset = self.read_jump()?;
+                    self.frame().ip += offset;
+                }
+                OpCode::Loop => {
+                    let offset = self.read_jump()?;
+                    self.frame().ip -= offset;
+                }
+                OpCode::Call => {
+                    let cnt = self.read_chunk()?;
+                    let function = self.peek(cnt as usize).clone(); // Hopefully, remove this clone in the future.
+                    match function {
+                        Value::Closure(cls) => {
+                            self.call(cls, cnt, self.read_line()?)?;
                         }
-                    }
-                    chunk::OP_GET_GLOBAL => {
-                        let offset = current.read_chunk()?;
-                        let constant = current.read_constant(offset as usize)?;
-                        if let Some(name) = constant.as_string() {
-                            if let Some(val) = self.globals.get(&name) {
-                                self.push(val.clone());
-                            } else {
-                                return Err(RuntimeError {
-                                    reason: format!("Variable {} is not defined.", constant),
-                                    line: current.read_line()?,
-                                });
+                        Value::Class(klass) => {
+                            // Classes have no initializer yet, so they take no arguments.
+                            if cnt != 0 {
+                                return Err(RuntimeError::of(
+                                    self.read_line()?,
+                                    ErrorKind::ArityMismatch {
+                                        callee: klass.borrow().name.to_string(),
+                                        expected: 0,
+                                        got: cnt as usize,
+                                    },
+                                ));
                             }
-                        } else {
-                            return Err(RuntimeError {
-                                reason: format!("{} is not a variable name.", constant),
-                                line: current.read_line()?,
-                            });
+                            let slot = self.stack_top - 1;
+                            self.stack[slot] =
+                                Value::Instance(self.heap.instance(Instance::new(klass)));
+                            self.maybe_collect();
                         }
-                    }
-                    chunk::OP_SET_GLOBAL => {
-                        let offset = current.read_chunk()?;
-                        let constant = current.read_constant(offset as usize)?;
-                        if let Some(name) = constant.as_string() {
-                            let val = self.peek(0);
-                            if self.globals.insert(name.clone(), val.clone()).is_none() {
-                                self.globals.remove(&name);
-                                return Err(RuntimeError {
-                                    reason: format!(
-                                        "{} is not defined before assignment.",
-                                        constant
-                                    ),
-                                    line: current.read_line()?,
-                                });
+                        Value::Native(native) => {
+                            if cnt != native.arity {
+                                return Err(RuntimeError::of(
+                                    self.read_line()?,
+                                    ErrorKind::ArityMismatch {
+                                        callee: native.name.clone(),
+                                        expected: native.arity as usize,
+                                        got: cnt as usize,
+                                    },
+                                ));
+                            }
+                            let start = self.stack_top - cnt as usize;
+                            let result = (native.function)(&self.stack[start..self.stack_top]);
+                            match result {
+                                Ok(val) => {
+                                    self.truncate_stack(start - 1);
+                                    self.push(val);
+                                }
+                                Err(reason) => {
+                                    return Err(RuntimeError::new(
+                                        self.read_line()?,
+                                        format!("{}: {}", native.name, reason),
+                                    ))
+                                }
                             }
-                        } else {
-                            return Err(RuntimeError {
-                                reason: format!("{} is not a variable name.", constant),
-                                line: current.read_line()?,
-                            });
                         }
-                    }
-                    chunk::OP_GET_LOCAL => {
-                        let offset = current.slot + current.read_chunk()? as usize;
-                        self.push(self.stack[offset].clone());
-                    }
-                    chunk::OP_SET_LOCAL => {
-                        let offset = current.slot + current.read_chunk()? as usize;
-                        self.stack[offset] = self.peek(0).clone();
-                    }
-                    chunk::OP_JUMP_IF_FALSE => {
-                        let offset = current.read_jump()?;
-                        let is_false = match self.peek(0) {
-                            Value::None => true,
-                            Value::Bool(x) => !x,
-                            _ => false,
-                        };
-                        if is_false {
-                            current.ip += offset;
+                        Value::BoundMethod(bound) => {
+                            let slot = self.stack_top - 1 - cnt as usize;
+                            self.stack[slot] = Value::Instance(bound.receiver.clone());
+                            self.call(bound.method, cnt, self.read_line()?)?;
+                        }
+                        _ => {
+                            return Err(RuntimeError::of(self.read_line()?, ErrorKind::NotCallable))
                         }
                     }
-                    chunk::OP_JUMP => {
-                        let offset = current.read_jump()?;
-                        current.ip += offset;
-                    }
-                    chunk::OP_LOOP => {
-                        let offset = current.read_jump()?;
-                        current.ip -= offset;
+                }
+                OpCode::Class => {
+                    let constant = self.read_constant()?.as_string();
+                    if let Some(name) = constant {
+                        let class = self.heap.class(Class::new(name));
+                        self.push(Value::Class(class));
+                        self.maybe_collect();
+                    } else {
+                        return Err(RuntimeError::new(
+                            self.read_line()?,
+                            "Class name should be a string.".to_string(),
+                        ));
                     }
-                    chunk::OP_CALL => {
-                        let cnt = current.read_chunk()?;
-                        let function = self.peek(cnt as usize).clone(); // Hopefully, remove this clone in the future.
-                        match function {
-                            Value::Closure(cls) => {
-                                if let Err(mut e) = self.call(cls, cnt) {
-                                    e.line = current.read_line()?;
-                                    return Err(e);
-                                };
-                            }
-                            Value::Class(klass) => {
-                                self.pop();
-                                self.stack.push(Value::Instance(Rc::new(RefCell::new(
-                                    Instance::new(klass.clone()),
-                                ))));
-                            }
-                            Value::BoundMethod(bound) => {
-                                let size = self.stack.len();
-                                self.stack[size - 1 - cnt as usize] =
-                                    Value::Instance(bound.receiver.clone());
-                                self.call(bound.method, cnt)?;
-                            }
-                            _ => {
-                                return Err(RuntimeError {
-                                    reason: "Variable is not callable.".to_string(),
-                                    line: current.read_line()?,
-                                })
+                }
+                OpCode::GetProperty => {
+                    let constant = self.read_constant()?;
+                    let cache = self.read_short()?;
+                    let instance = self.pop();
+                    if let Value::Instance(ins) = instance {
+                        let val = self.get_property(&ins, &constant, cache)?;
+                        self.push(val);
+                    } else if let Value::String(string) = instance {
+                        let name = constant.as_string().unwrap_or_default();
+                        match string_property(&string, &name) {
+                            Some(val) => self.push(val),
+                            None => {
+                                return Err(RuntimeError::of(
+                                    self.read_line()?,
+                                    ErrorKind::UndefinedProperty {
+                                        name: name.to_string(),
+                                        on: None,
+                                    },
+                                ))
                             }
                         }
-                        break;
+                    } else {
+                        return Err(RuntimeError::new(
+                            self.read_line()?,
+                            format!("{} is not an instance.", instance),
+                        ));
                     }
-                    chunk::OP_CLASS => {
-                        let offset = current.read_chunk()?;
-                        let constant = current.read_constant(offset as usize)?.as_string();
-                        if let Some(name) = constant {
-                            self.push(Value::Class(Rc::new(RefCell::new(Class {
-                                name,
-                                methods: HashMap::new(),
-                            }))));
-                        } else {
-                            return Err(RuntimeError {
-                                reason: "Class name should be a string.".to_string(),
-                                line: current.read_line()?,
-                            });
-                        }
+                }
+                OpCode::SetProperty => {
+                    let constant = self.read_constant()?;
+                    let cache = self.read_short()?;
+                    let instance = self.peek(1);
+                    if let Value::Instance(ins) = instance {
+                        let val = self.peek(0).clone();
+                        self.set_property(ins, &constant, cache, val.clone())?;
+                        self.pop();
+                        self.pop();
+                        self.push(val);
+                    } else {
+                        return Err(RuntimeError::new(
+                            self.read_line()?,
+                            format!("{} is not an instance.", instance),
+                        ));
                     }
-                    chunk::OP_GET_PROPERTY => {
-                        let instance = self.pop();
-                        if let Value::Instance(ins) = instance {
-                            let offset = current.read_chunk()?;
-                            let constant = current.read_constant(offset as usize)?;
-
-                            if let Some(name) = constant.as_string() {
-                                let inst = ins.borrow();
-                                if let Some(val) = inst.fields.get(&name) {
-                                    self.push(val.clone());
-                                } else if let Some(method) = inst.klass.borrow().bind_method(&name)
-                                {
-                                    let bound = BoundMethod {
-                                        receiver: ins.clone(),
-                                        method: method.clone(),
-                                    };
-                                    self.push(Value::BoundMethod(Box::new(bound)));
-                                } else {
-                                    return Err(RuntimeError {
-                                        reason: format!("Property {} is not defined.", constant),
-                                        line: current.read_line()?,
-                                    });
-                                }
+                }
+                OpCode::Closure => {
+                    let constant = self.read_constant()?;
+                    if let Value::Function(func) = constant {
+                        let mut clos = Closure::new(func.clone());
+                        for _ in 0..clos.function.upvalue {
+                            let is_local = self.read_chunk()? == 1;
+                            let index = self.read_chunk()?;
+                            if is_local {
+                                let address = self.frame().slot + index as usize;
+                                clos.upvalues.push(self.capture_upvalue(address));
                             } else {
-                                return Err(RuntimeError {
-                                    reason: format!("{} is not a property name.", constant),
-                                    line: current.read_line()?,
-                                });
+                                clos.upvalues
+                                    .push(self.frame().closure.upvalues[index as usize].clone());
                             }
-                        } else {
-                            return Err(RuntimeError {
-                                reason: format!("{} is not an instance.", instance),
-                                line: current.read_line()?,
-                            });
                         }
+                        self.push(Value::Closure(clos));
+                        self.maybe_collect();
+                    } else {
+                        return Err(RuntimeError::new(
+                            self.read_line()?,
+                            format!("Expect a function but get {}", constant),
+                        ));
                     }
-                    chunk::OP_SET_PROPERTY => {
-                        let instance = self.peek(1);
-                        if let Value::Instance(ins) = instance {
-                            let offset = current.read_chunk()?;
-                            let constant = current.read_constant(offset as usize)?;
-                            if let Some(name) = constant.as_string() {
-                                let val = self.peek(0).clone();
-                                ins.borrow_mut().fields.insert(name.clone(), val.clone());
-                                self.pop();
-                                self.pop();
-                                self.push(val);
+                }
+                OpCode::GetUpvalue => {
+                    let offset = self.read_chunk()?;
+                    let upvalue = self.frame().closure.upvalues[offset as usize].clone();
+                    let val = match &*upvalue.borrow() {
+                        Upvalue::Stack(location) => self.stack[*location].clone(),
+                        Upvalue::Out(rc) => rc.clone(),
+                    };
+                    self.push(val);
+                }
+                OpCode::SetUpvalue => {
+                    let val = self.peek(0).clone();
+                    let offset = self.read_chunk()?;
+                    let upvalue = self.frame().closure.upvalues[offset as usize].clone();
+                    let mut borrow_mut = upvalue.borrow_mut();
+                    let loc = match *borrow_mut {
+                        Upvalue::Stack(location) => &mut self.stack[location],
+                        Upvalue::Out(ref mut rc) => rc,
+                    };
+                    *loc = val.clone();
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack_top - 1);
+                    self.pop();
+                }
+                OpCode::Method => {
+                    let constant = self.read_constant()?;
+                    match constant.as_string() {
+                        Some(string) => {
+                            let method = self.peek(0).clone();
+                            let klass = self.peek(1).clone();
+                            if let (Value::Class(klas), Value::Closure(clos)) = (klass, method) {
+                                klas.borrow_mut().add_method(string, clos.clone());
                             } else {
-                                return Err(RuntimeError {
-                                    reason: format!("{} is not a property name.", constant),
-                                    line: current.read_line()?,
-                                });
+                                return Err(RuntimeError::new(
+                                    self.read_line()?,
+                                    "Invalid class method definition.".to_string(),
+                                ));
                             }
-                        } else {
-                            return Err(RuntimeError {
-                                reason: format!("{} is not an instance.", instance),
-                                line: current.read_line()?,
-                            });
+                            self.pop();
                         }
-                    }
-                    chunk::OP_CLOSURE => {
-                        let offset = current.read_chunk()?;
-                        let constant = current.read_constant(offset as usize)?;
-                        if let Value::Function(func) = constant {
-                            let mut clos = Closure::new(func.clone());
-                            for _ in 0..clos.function.upvalue {
-                                let is_local = current.read_chunk()? == 1;
-                                let index = current.read_chunk()?;
-                                if is_local {
-                                    let address = current.slot + index as usize;
-                                    if let Some(upvalue) = self.captures.get(&address) {
-                                        clos.upvalues.push(upvalue.clone());
-                                    } else {
-                                        let upvalue =
-                                            Rc::new(RefCell::new(Upvalue::Stack(address)));
-                                        self.captures.insert(address, upvalue.clone());
-                                        clos.upvalues.push(upvalue.clone());
-                                    }
-                                } else {
-                                    clos.upvalues
-                                        .push(current.closure.upvalues[index as usize].clone());
-                                }
-                            }
-                            self.push(Value::Closure(clos));
-                        } else {
-                            return Err(RuntimeError {
-                                reason: format!("Expect a function but get {}", constant),
-                                line: current.read_line()?,
-                            });
+                        None => {
+                            return Err(RuntimeError::new(
+                                self.read_line()?,
+                                format!("Name of method should be a string but get {}.", constant),
+                            ))
                         }
                     }
-                    chunk::OP_GET_UPVALUE => {
-                        let offset = current.read_chunk()?;
-                        let val = match &*current.closure.upvalues[offset as usize].borrow() {
-                            Upvalue::Stack(location) => self.stack[*location].clone(),
-                            Upvalue::Out(rc) => rc.clone(),
-                        };
-                        self.push(val);
-                    }
-                    chunk::OP_SET_UPVALUE => {
-                        let val = self.peek(0).clone();
-                        let offset = current.read_chunk()?;
-                        let mut borrow_mut = current.closure.upvalues[offset as usize].borrow_mut();
-                        let loc = match *borrow_mut {
-                            Upvalue::Stack(location) => &mut self.stack[location],
-                            Upvalue::Out(ref mut rc) => rc,
-                        };
-                        *loc = val.clone();
-                    }
-                    chunk::OP_CLOSE_UPVALUE => {
-                        self.close_upvalues(self.stack.len() - 1);
+                }
+                OpCode::Inherit => {
+                    let superclass = self.peek(1).clone();
+                    let subclass = self.peek(0).clone();
+                    if let (Value::Class(supc), Value::Class(subc)) = (superclass, subclass) {
+                        subc.borrow_mut().inherit(&supc.borrow());
                         self.pop();
+                    } else {
+                        return Err(RuntimeError::new(
+                            self.read_line()?,
+                            "Inherit can only happen between classes.".to_string(),
+                        ));
                     }
-                    chunk::OP_METHOD => {
-                        let offset = current.read_chunk()?;
-                        let constant = current.read_constant(offset as usize)?;
-                        match constant.as_string() {
-                            Some(string) => {
-                                let method = self.peek(0).clone();
-                                let klass = self.peek(1).clone();
-                                if let (Value::Class(klas), Value::Closure(clos)) = (klass, method)
-                                {
-                                    klas.borrow_mut().methods.insert(string, clos.clone());
-                                } else {
-                                    return Err(RuntimeError {
-                                        reason: "Invalid class method definition.".to_string(),
-                                        line: current.read_line()?,
-                                    });
-                                }
-                                self.pop();
-                            }
-                            None => {
-                                return Err(RuntimeError {
-                                    reason: format!(
-                                        "Name of method should be a string but get {}.",
-                                        constant
-                                    ),
-                                    line: current.read_line()?,
-                                })
-                            }
-                        }
-                    }
-                    chunk::OP_INHERIT => {
-                        let superclass = self.peek(1).clone();
-                        let subclass = self.peek(0).clone();
-                        if let (Value::Class(supc), Value::Class(subc)) = (superclass, subclass) {
-                            subc.borrow_mut().methods = supc.borrow().methods.clone();
-                            self.pop();
-                        } else {
-                            return Err(RuntimeError {
-                                reason: "Inherit can only happen between classes.".to_string(),
-                                line: current.read_line()?,
-                            });
-                        }
-                    }
-                    chunk::OP_GET_SUPER => {
-                        let offset = current.read_chunk()?;
-                        let constant = current.read_constant(offset as usize)?;
-                        let superclass = self.pop();
-                        let receiver = self.pop();
-                        if let (Value::Class(supc), Value::Instance(r), Value::String(name)) =
-                            (superclass, receiver, constant)
-                        {
-                            if let Some(method) = supc.borrow().bind_method(&name) {
-                                let bound = BoundMethod {
-                                    receiver: r.clone(),
-                                    method: method.clone(),
-                                };
-                                self.push(Value::BoundMethod(Box::new(bound)));
-                            } else {
-                                return Err(RuntimeError {
-                                    reason: format!(
-                                        "Method {} is not defined in superclass.",
-                                        name
-                                    ),
-                                    line: current.read_line()?,
-                                });
-                            }
+                }
+                OpCode::GetSuper => {
+                    let constant = self.read_constant()?;
+                    let superclass = self.pop();
+                    let receiver = self.pop();
+                    if let (Value::Class(supc), Value::Instance(r), Value::String(name)) =
+                        (superclass, receiver, constant)
+                    {
+                        if let Some(method) = supc.borrow().bind_method(&name) {
+                            let bound = BoundMethod {
+                                receiver: r.clone(),
+                                method: method.clone(),
+                            };
+                            self.push(Value::BoundMethod(Box::new(bound)));
                         } else {
-                            return Err(RuntimeError {
-                                reason: "Invalid super class call.".to_string(),
-                                line: current.read_line()?,
-                            });
+                            return Err(RuntimeError::of(
+                                self.read_line()?,
+                                ErrorKind::UndefinedProperty {
+                                    name: name.to_string(),
+                                    on: None,
+                                },
+                            ));
                         }
+                    } else {
+                        return Err(RuntimeError::new(
+                            self.read_line()?,
+                            "Invalid super class call.".to_string(),
+                        ));
                     }
-                    _ => {
-                        return Err(RuntimeError {
-                            reason: "Unknown command.".to_string(),
-                            line: current.read_line()?,
-                        })
-                    }
                 }
             }
         }
         Ok(())
     }
 
+    fn index_array<T>(
+        array: &Value,
+        index: &Value,
+        line: i32,
+        access: impl FnOnce(&mut Vec<Value>, usize) -> T,
+    ) -> Result<T, RuntimeError> {
+        let Value::Array(elements) = array else {
+            return Err(RuntimeError::new(
+                line,
+                format!("{} is not an array or a map.", array),
+            ));
+        };
+        let i = match index.as_number() {
+            Some(n) if n.fract() == 0.0 && n >= 0.0 => n as usize,
+            _ => {
+                return Err(RuntimeError::new(
+                    line,
+                    format!(
+                        "Array index must be a non-negative integer but got {}.",
+                        index
+                    ),
+                ))
+            }
+        };
+        let mut elements = elements.borrow_mut();
+        if i >= elements.len() {
+            return Err(RuntimeError::new(
+                line,
+                format!(
+                    "Array index {} out of bounds for length {}.",
+                    i,
+                    elements.len()
+                ),
+            ));
+        }
+        Ok(access(&mut elements, i))
+    }
+
+    fn map_key(key: &Value, line: i32) -> Result<MapKey, RuntimeError> {
+        MapKey::new(key).ok_or_else(|| {
+            RuntimeError::of(
+                line,
+                ErrorKind::type_mismatch("a string or a number key", key),
+            )
+        })
+    }
+
+    // Only called once an instruction has put everything it allocated on
+    // the stack, so the roots below cover every live object.
+    fn maybe_collect(&mut self) {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+    }
+
+    pub fn collect_garbage(&mut self) {
+        let mut marker = Marker::default();
+        for val in self.stack[..self.stack_top]
+            .iter()
+            .chain(self.globals.iter().flatten())
+        {
+            marker.mark_value(val);
+        }
+        for frame in &self.frames {
+            marker.mark_closure(&frame.closure);
+        }
+        for (_, upvalue) in &self.captures {
+            marker.mark_upvalue(upvalue);
+        }
+        if let Some(val) = &self.thrown {
+            marker.mark_value(val);
+        }
+        self.heap.sweep(marker);
+    }
+
+    #[cfg(test)]
+    pub fn live_objects(&self) -> usize {
+        self.heap.live_objects()
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Shared<SharedCell<Upvalue>> {
+        match self.captures.binary_search_by_key(&slot, |(s, _)| *s) {
+            Ok(i) => self.captures[i].1.clone(),
+            Err(i) => {
+                let upvalue = self.heap.upvalue(Upvalue::Stack(slot));
+                self.captures.insert(i, (slot, upvalue.clone()));
+                upvalue
+            }
+        }
+    }
+
+    // Reads a property through the instruction's inline cache, filling the
+    // cache on a miss.
+    fn get_property(
+        &self,
+        ins: &Shared<SharedCell<Instance>>,
+        name: &Value,
+        cache: usize,
+    ) -> Result<Value, RuntimeError> {
+        let chunk = &self
+            .frames
+            .last()
+            .expect("Frame is empty")
+            .closure
+            .function
+            .chunk;
+        let inst = ins.borrow();
+        let klass = inst.klass.borrow();
+        let fields = klass.field_count();
+        let bind = |method: &Closure| {
+            Value::BoundMethod(Box::new(BoundMethod {
+                receiver: ins.clone(),
+                method: method.clone(),
+            }))
+        };
+        match chunk.cached(cache, &inst.klass, fields) {
+            Some(Member::Field(slot)) => {
+                if let Some(val) = inst.field(slot) {
+                    return Ok(val.clone());
+                }
+            }
+            Some(Member::Method(slot)) => {
+                if let Some(method) = klass.method(slot) {
+                    return Ok(bind(method));
+                }
+            }
+            None => {}
+        }
+
+        let Some(name) = name.as_string() else {
+            return Err(RuntimeError::new(
+                self.read_line()?,
+                format!("{} is not a property name.", name),
+            ));
+        };
+        let field = klass.field_slot(&name);
+        if let Some(slot) = field {
+            if let Some(val) = inst.field(slot) {
+                let member = Member::Field(slot);
+                chunk.fill_cache(cache, PropertyCache::new(&inst.klass, fields, member));
+                return Ok(val.clone());
+            }
+        }
+        if let Some(slot) = klass.method_slot(&name) {
+            if let Some(method) = klass.method(slot) {
+                // Once some instance has a field of this name, others may
+                // too, so the method can't be cached.
+                if field.is_none() {
+                    let member = Member::Method(slot);
+                    chunk.fill_cache(cache, PropertyCache::new(&inst.klass, fields, member));
+                }
+                return Ok(bind(method));
+            }
+        }
+        Err(RuntimeError::of(
+            self.read_line()?,
+            ErrorKind::UndefinedProperty {
+                name: name.to_string(),
+                on: Some(Box::new(inst.members())),
+            },
+        ))
+    }
+
+    fn set_property(
+        &self,
+        ins: &Shared<SharedCell<Instance>>,
+        name: &Value,
+        cache: usize,
+        val: Value,
+    ) -> Result<(), RuntimeError> {
+        let chunk = &self
+            .frames
+            .last()
+            .expect("Frame is empty")
+            .closure
+            .function
+            .chunk;
+        let mut inst = ins.borrow_mut();
+        let slot = {
+            let klass = &inst.klass;
+            let fields = klass.borrow().field_count();
+            match chunk.cached(cache, klass, fields) {
+                Some(Member::Field(slot)) => slot,
+                _ => {
+                    let Some(name) = name.as_string() else {
+                        return Err(RuntimeError::new(
+                            self.read_line()?,
+                            format!("{} is not a property name.", name),
+                        ));
+                    };
+                    let slot = klass.borrow_mut().add_field(name);
+                    let fields = klass.borrow().field_count();
+                    let member = Member::Field(slot);
+                    chunk.fill_cache(cache, PropertyCache::new(klass, fields, member));
+                    slot
+                }
+            }
+        };
+        inst.set_field(slot, val);
+        Ok(())
+    }
+
+    // Close every open upvalue at or above slot.
     fn close_upvalues(&mut self, slot: usize) {
-        if let Some(val) = self.captures.get(&slot) {
-            *val.borrow_mut() = Upvalue::Out(self.peek(0).clone());
-            self.captures.remove(&slot);
+        while let Some((top, _)) = self.captures.last() {
+            if *top < slot {
+                break;
+            }
+            let (captured, upvalue) = self.captures.pop().expect("Captures is empty");
+            *upvalue.borrow_mut() = Upvalue::Out(self.stack[captured].clone());
         }
     }
 
-    fn call(&mut self, clos: Closure, arg_cnt: u8) -> Result<(), RuntimeError> {
+    fn call(&mut self, clos: Closure, arg_cnt: u8, line: i32) -> Result<(), RuntimeError> {
         if arg_cnt != clos.function.arity {
-            return Err(RuntimeError {
-                reason: format!(
-                    "Expect {} arguments but got {}.",
-                    clos.function.arity, arg_cnt
-                ),
-                line: -1,
-            });
+            return Err(RuntimeError::of(
+                line,
+                ErrorKind::ArityMismatch {
+                    callee: clos.function.name.clone(),
+                    expected: clos.function.arity as usize,
+                    got: arg_cnt as usize,
+                },
+            ));
+        }
+        // The compiler bounds how deep the callee's frame gets, so checking
+        // it fits here keeps the check out of the dispatch loop.
+        let slot = self.stack_top - arg_cnt as usize - 1;
+        if self.frames.len() >= self.options.max_frames
+            || clos.function.frame_size > self.stack.len() - slot
+        {
+            return Err(RuntimeError::of(line, ErrorKind::StackOverflow));
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.call(&clos.function.name);
         }
-        self.frames.push(Rc::new(RefCell::new(CallFrame {
+        self.frames.push(CallFrame {
             closure: clos,
             ip: 0,
-            slot: self.stack.len() - arg_cnt as usize - 1,
-        })));
+            slot,
+            call_line: line,
+        });
         Ok(())
     }
 }
 
+// Read-only properties of string values.
+fn string_property(string: &str, name: &str) -> Option<Value> {
+    match name {
+        "length" => Some(Value::Number(string.chars().count() as f64)),
+        _ => None,
+    }
+}
+
+fn write_stack(stack: &[Value], out: &mut dyn Write) {
+    for val in stack {
+        let _ = write!(out, "[ {} ]", val);
+    }
+    let _ = writeln!(out);
+}
+
 struct CallFrame {
     closure: Closure,
     ip: usize,
     slot: usize,
+    call_line: i32,
 }
 
 impl CallFrame {
+    // Names the function whose chunk could not be read.
+    fn chunk_error(&self, e: ChunkError) -> RuntimeError {
+        RuntimeError::new(
+            -1,
+            format!("{} in {}.", e, function_name(&self.closure.function.name)),
+        )
+    }
+
     pub fn read_jump(&mut self) -> Result<usize, RuntimeError> {
-        let ret = self.closure.function.chunk.read_jump(self.ip)?;
+        let ret = self
+            .closure
+            .function
+            .chunk
+            .read_jump(self.ip)
+            .map_err(|e| self.chunk_error(e))?;
         self.ip += USIZE;
         Ok(ret)
     }
 
+    pub fn read_short(&mut self) -> Result<u16, RuntimeError> {
+        let ret = self
+            .closure
+            .function
+            .chunk
+            .read_short(self.ip)
+            .map_err(|e| self.chunk_error(e))?;
+        self.ip += 2;
+        Ok(ret)
+    }
+
     pub fn read_chunk(&mut self) -> Result<u8, RuntimeError> {
         self.ip += 1;
-        self.closure.function.chunk.read_chunk(self.ip - 1)
+        self.closure
+            .function
+            .chunk
+            .read_chunk(self.ip - 1)
+            .map_err(|e| self.chunk_error(e))
     }
 
     pub fn read_constant(&mut self, pos: usize) -> Result<Value, RuntimeError> {
-        self.closure.function.chunk.read_constant(pos)
+        self.closure
+            .function
+            .chunk
+            .read_constant(pos)
+            .map_err(|e| self.chunk_error(e))
     }
 
     pub fn read_line(&self) -> Result<i32, RuntimeError> {
-        self.closure.function.chunk.read_line(self.ip - 1)
+        self.closure
+            .function
+            .chunk
+            .read_line(self.ip.saturating_sub(1))
+            .map_err(|e| self.chunk_error(e))
     }
 }
@@ -0,0 +1,16 @@
+use crate::{Io, Lox, RunOptions};
+use js_sys::{Object, Reflect};
+use wasm_bindgen::prelude::*;
+
+/// Runs a program on the VM and returns `{ ok, output, diagnostics }`, with
+/// what it printed and the errors it reported as strings.
+#[wasm_bindgen]
+pub fn run(source: &str) -> JsValue {
+    let (io, out, err) = Io::capture();
+    let ok = Lox::with_io(RunOptions::default(), io).run(source).is_ok();
+    let result = Object::new();
+    let _ = Reflect::set(&result, &"ok".into(), &ok.into());
+    let _ = Reflect::set(&result, &"output".into(), &out.contents().into());
+    let _ = Reflect::set(&result, &"diagnostics".into(), &err.contents().into());
+    result.into()
+}
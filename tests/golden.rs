@@ -0,0 +1,174 @@
+#![cfg(not(target_arch = "wasm32"))]
+//! Runs every tests/input/*.lox through the binary once per backend and
+//! compares it with its golden files:
+//!
+//! - `name.expected`, the exact stdout;
+//! - `name.exit`, the exit status, 0 when it's missing;
+//! - `name.stderr`, the exact stderr, unchecked when it's missing.
+//!
+//! `name.vm.expected` and the like hold what only one backend does, and
+//! take the place of the shared file for it. A backend with no expected
+//! file for a script checks its expectation comments instead, as `lox test`
+//! does. Scripts with neither are inputs for the tests in lox.rs.
+
+use assert_cmd::Command;
+use lox::test_runner::{expectations, ExpectedError};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+const BACKENDS: [&str; 2] = ["vm", "ast"];
+
+struct Golden {
+    stdout: String,
+    code: i32,
+    stderr: Option<Stderr>,
+}
+
+enum Stderr {
+    Exact(String),
+    Contains(String),
+}
+
+impl Golden {
+    fn load(path: &Path, backend: &str) -> Option<Golden> {
+        if let Some(stdout) = sibling(path, backend, "expected") {
+            let code = sibling(path, backend, "exit").map_or(0, |code| {
+                code.trim()
+                    .parse()
+                    .unwrap_or_else(|_| panic!("{}: bad exit status {:?}", path.display(), code))
+            });
+            let stderr = sibling(path, backend, "stderr").map(Stderr::Exact);
+            return Some(Golden {
+                stdout,
+                code,
+                stderr,
+            });
+        }
+        let expected = expectations(&fs::read_to_string(path).unwrap());
+        if expected.output.is_empty() && expected.error.is_none() {
+            return None;
+        }
+        let (code, stderr) = match expected.error {
+            None => (0, None),
+            Some(ExpectedError::Compile) => (65, None),
+            Some(ExpectedError::Runtime(message)) => (70, message.map(Stderr::Contains)),
+        };
+        let stdout = expected
+            .output
+            .iter()
+            .map(|line| line.clone() + "\n")
+            .collect();
+        Some(Golden {
+            stdout,
+            code,
+            stderr,
+        })
+    }
+}
+
+// The backend's own file for a script, or the one its backends share.
+fn sibling(path: &Path, backend: &str, extension: &str) -> Option<String> {
+    fs::read_to_string(path.with_extension(format!("{}.{}", backend, extension)))
+        .or_else(|_| fs::read_to_string(path.with_extension(extension)))
+        .ok()
+}
+
+fn check(path: &Path, backend: &str, golden: &Golden) -> Result<(), String> {
+    let output = Command::cargo_bin("lox")
+        .unwrap()
+        .args(["--backend", backend])
+        .arg(path)
+        .timeout(Duration::from_secs(60))
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut why = String::new();
+    if output.status.code() != Some(golden.code) {
+        let _ = writeln!(
+            why,
+            "exit status {:?}, expected {}",
+            output.status.code(),
+            golden.code
+        );
+    }
+    if stdout != golden.stdout {
+        let _ = write!(why, "stdout differs:\n{}", diff(&golden.stdout, &stdout));
+    }
+    match &golden.stderr {
+        Some(Stderr::Exact(expected)) if stderr != *expected => {
+            let _ = write!(why, "stderr differs:\n{}", diff(expected, &stderr));
+        }
+        Some(Stderr::Contains(message)) if !stderr.contains(message.as_str()) => {
+            let _ = write!(why, "stderr lacks {:?}:\n{}", message, stderr);
+        }
+        _ => {}
+    }
+    if why.is_empty() {
+        Ok(())
+    } else {
+        Err(why)
+    }
+}
+
+// The lines of expected and actual side by side: "-" for an expected line
+// that's missing or different, "+" for what came instead.
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut out = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => {
+                let _ = writeln!(out, "  {}", e);
+            }
+            (e, a) => {
+                if let Some(e) = e {
+                    let _ = writeln!(out, "- {}", e);
+                }
+                if let Some(a) = a {
+                    let _ = writeln!(out, "+ {}", a);
+                }
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn golden() {
+    let mut paths: Vec<_> = fs::read_dir("tests/input")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+        .collect();
+    paths.sort();
+    let mut runs = 0;
+    let mut failures = Vec::new();
+    for path in &paths {
+        for backend in BACKENDS {
+            let Some(golden) = Golden::load(path, backend) else {
+                continue;
+            };
+            runs += 1;
+            if let Err(why) = check(path, backend, &golden) {
+                failures.push(format!("{} on {}: {}", path.display(), backend, why));
+            }
+        }
+    }
+    assert!(runs > 0, "no golden files in tests/input");
+    assert!(
+        failures.is_empty(),
+        "{} of {} golden runs failed:\n\n{}",
+        failures.len(),
+        runs,
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn diff_marks_changed_lines() {
+    assert_eq!(diff("a\nb\n", "a\nc\nd\n"), "  a\n- b\n+ c\n+ d\n");
+}
@@ -1,92 +1,829 @@
+#![cfg(not(target_arch = "wasm32"))]
+
 use assert_cmd::Command;
-// use predicates::prelude::*;
+use predicates::prelude::*;
+
+#[test]
+fn suite() {
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "test", "tests/suite"])
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("PASS tests/suite/scope.lox\n"))
+            .stdout(predicate::str::ends_with("10 passed, 0 failed.\n"));
+    }
+}
 
 #[test]
-fn math1() {
+fn suite_failures() {
+    let dir = std::env::temp_dir().join(format!("lox-suite-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("pass.lox"), "print 1; // expect: 1\n").unwrap();
+    std::fs::write(dir.join("wrong.lox"), "print 1; // expect: 2\n").unwrap();
     let mut cmd = Command::cargo_bin("lox").unwrap();
-    cmd.arg("tests/input/math1.lox")
+    cmd.arg("test")
+        .arg(&dir)
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains(
+            "wrong.lox: Expected output 2 but got 1.\n",
+        ))
+        .stdout(predicate::str::ends_with("1 passed, 1 failed.\n"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn upvalue_overflow() {
+    let mut source = String::from("fun a() {");
+    for i in 0..150 {
+        source += &format!("var x{} = {};", i, i);
+    }
+    source += "fun b() {";
+    for i in 0..150 {
+        source += &format!("var y{} = {};", i, i);
+    }
+    source += "fun c() {";
+    for i in 0..150 {
+        source += &format!("print x{}; print y{};", i, i);
+    }
+    source += "} } }\n";
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.write_stdin(source)
         .assert()
         .success()
-        .stdout("Yes\n");
+        .stderr(predicate::str::contains(
+            "Too many closure variables in function.",
+        ));
 }
 
 #[test]
-fn math2() {
+fn backtrace_off() {
+    let message = "[Line 2] in script, Runtime Error: Expected a number but got x.\n";
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--no-backtrace", "tests/input/backtrace.lox"])
+        .assert()
+        .code(70)
+        .stderr(message);
     let mut cmd = Command::cargo_bin("lox").unwrap();
-    cmd.arg("tests/input/math2.lox")
+    cmd.env("LOX_BACKTRACE", "0")
+        .arg("tests/input/backtrace.lox")
+        .assert()
+        .code(70)
+        .stderr(message);
+}
+
+#[test]
+fn call_depth_limits() {
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--max-frames", "4", "tests/input/recursion.lox"])
+        .assert()
+        .code(70)
+        .stderr(predicate::str::contains("Stack overflow."));
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--backend", "ast", "--max-call-depth", "4"])
+        .arg("tests/input/recursion.lox")
+        .assert()
+        .code(70)
+        .stderr(predicate::str::contains("Stack overflow."));
+    // The default limit is reached before the native stack runs out.
+    let recurse = "fun r(n) { if (n == 0) return 0; return 1 + r(n - 1); }";
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--backend", "ast", "-e", recurse, "-e", "print r(255);"])
         .assert()
         .success()
-        .stdout("Yes\n");
+        .stdout("255\n");
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--backend", "ast", "-e", recurse, "-e", "print r(100000);"])
+        .assert()
+        .code(70)
+        .stderr(predicate::str::contains("Stack overflow."));
 }
 
 #[test]
-fn math3() {
+fn raised_stack_limit() {
     let mut cmd = Command::cargo_bin("lox").unwrap();
-    cmd.arg("tests/input/math3.lox")
+    cmd.args(["--max-stack", "32768", "tests/input/deep_stack.lox"])
         .assert()
         .success()
-        .stdout("Yes\n");
+        .stdout("900\n");
+}
+
+#[test]
+fn lowered_stack_limit() {
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--max-stack", "4096", "tests/input/deep_stack.lox"])
+        .assert()
+        .code(70)
+        .stderr(predicate::str::ends_with(
+            "[Line 7] in script, Runtime Error: Stack overflow.\n",
+        ));
 }
 
 #[test]
-fn math4() {
+fn repl_globals() {
     let mut cmd = Command::cargo_bin("lox").unwrap();
-    cmd.arg("tests/input/math4.lox")
+    cmd.write_stdin("var a = 1;\nfun f() { return a + b; }\nvar b = 2;\nprint f();\nprint c;\n")
+        .assert()
+        .stdout("3\n")
+        .stderr(predicate::str::ends_with(
+            "Runtime Error: Undefined variable 'c'.\n",
+        ));
+}
+
+#[test]
+fn assign_undefined() {
+    for backend in ["vm", "ast"] {
+        for source in ["x = 1;", "fun f() { x = 1; }\nf();"] {
+            let mut cmd = Command::cargo_bin("lox").unwrap();
+            cmd.args(["--backend", backend, "-e", source])
+                .assert()
+                .code(70)
+                .stderr(predicate::str::ends_with("Undefined variable 'x'.\n"));
+        }
+    }
+}
+
+#[test]
+fn vm_repl_recovers_from_errors() {
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--backend", "vm"])
+        .write_stdin(
+            "class Box {}\nvar x = 1;\nvar box = Box();\nvar y = ;\n{ var a = x + 1; fun g() { return a; } box.g = g; x = g; nope(); }\nprint box.g();\nprint x;\n",
+        )
         .assert()
         .success()
-        .stdout("Yes\n");
+        .stdout("2\n1\n")
+        .stderr(predicate::str::contains(
+            "Runtime Error: Undefined variable 'nope'.\n",
+        ));
+}
+
+#[test]
+fn repl_multi_line_input() {
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend])
+            .write_stdin("fun add(a, b) {\n  return a + b;\n}\nprint add(1, 2);\nprint 1 +;\n")
+            .assert()
+            .success()
+            .stdout("3\n")
+            // Lines count on from the earlier entries.
+            .stderr(predicate::str::contains("[line 5]").or(predicate::str::contains("Line 5")));
+    }
+}
+
+#[test]
+fn repl_drops_unfinished_input() {
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend])
+            .write_stdin("fun f() {\n  print 1;\n\nprint 2;\n")
+            .assert()
+            .success()
+            .stdout("2\n")
+            .stderr("");
+    }
+}
+
+#[test]
+fn repl_commands() {
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend])
+            .write_stdin(":load tests/input/repl_lib.lox\nprint greet(\"Lox\");\n:env\n")
+            .assert()
+            .success()
+            .stdout(predicate::str::starts_with("Hello, Lox!\n"))
+            .stdout(predicate::str::contains("answer = 42\n"))
+            .stdout(predicate::str::contains("greet = <fn greet>\n"));
+    }
 }
 
 #[test]
-fn math5() {
+#[cfg_attr(feature = "serde", ignore = "the json natives change the globals")]
+fn repl_unknown_command_and_quit() {
     let mut cmd = Command::cargo_bin("lox").unwrap();
-    cmd.arg("tests/input/math5.lox")
+    cmd.arg("--no-prelude")
+        .write_stdin("var a = 1;\n:reset\n:env\n:nope\n:quit\nprint 1;\n")
         .assert()
         .success()
-        .stdout("Yes\n");
+        .stdout("clock = <native fn>\n")
+        .stderr("Unknown command :nope. Type :help for a list.\n");
+}
+
+// Starts lox with stdin piped in, and sends it SIGINT once it is running.
+#[cfg(unix)]
+fn interrupt(args: &[&str], stdin: &str) -> std::process::Output {
+    use std::io::Write;
+    use std::process::Stdio;
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_lox"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut pipe = child.stdin.take().unwrap();
+    pipe.write_all(stdin.as_bytes()).unwrap();
+    drop(pipe);
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    let kill = std::process::Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(kill.success());
+    child.wait_with_output().unwrap()
 }
 
+#[cfg(unix)]
 #[test]
-fn string() {
+fn interrupt_script() {
+    for backend in ["vm", "ast"] {
+        let output = interrupt(&["--backend", backend, "tests/input/infinite_loop.lox"], "");
+        assert_eq!(output.status.code(), Some(130));
+        assert!(String::from_utf8_lossy(&output.stderr).ends_with("Runtime Error: Interrupted.\n"));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupt_repl() {
+    for backend in ["vm", "ast"] {
+        let output = interrupt(
+            &["--backend", backend],
+            "var i = 0;\nwhile (true) { i = i + 1; }\nprint i;\n",
+        );
+        assert_eq!(output.status.code(), Some(0));
+        // The interrupted line's assignments are undone.
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "0\n");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Runtime Error: Interrupted.\n"));
+    }
+}
+
+#[test]
+fn instruction_budget() {
     let mut cmd = Command::cargo_bin("lox").unwrap();
-    cmd.arg("tests/input/string.lox")
+    cmd.args([
+        "--max-instructions",
+        "100000",
+        "tests/input/infinite_loop.lox",
+    ])
+    .assert()
+    .code(70)
+    .stdout("")
+    .stderr(predicate::str::ends_with(
+        "Runtime Error: Execution budget exceeded.\n",
+    ));
+    // A literal condition has no line, so the loop reports the while's.
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "--max-instructions", "1000"])
+            .args(["-e", "var i = 0;\nwhile (true) {}"])
+            .assert()
+            .code(70)
+            .stderr(predicate::str::ends_with(
+                "[Line 2] in script, Runtime Error: Execution budget exceeded.\n",
+            ));
+    }
+}
+
+#[test]
+fn timeout() {
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--timeout-ms", "50", "tests/input/infinite_loop.lox"])
+        .timeout(std::time::Duration::from_secs(10))
+        .assert()
+        .code(70)
+        .stdout("")
+        .stderr(predicate::str::ends_with(
+            "Runtime Error: Execution budget exceeded.\n",
+        ));
+}
+
+#[test]
+fn trace_keeps_stdout() {
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    let plain = cmd.arg("tests/input/while.lox").output().unwrap();
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--trace", "tests/input/while.lox"])
         .assert()
         .success()
-        .stdout("a1b2c3d4\n");
+        .stdout(String::from_utf8(plain.stdout).unwrap())
+        .stderr(predicate::str::contains("[0]    1 OP_CONSTANT 1\n"))
+        .stderr(predicate::str::contains("| OP_PRINT\n"));
 }
 
 #[test]
-fn while_test() {
+fn trace_from_env() {
     let mut cmd = Command::cargo_bin("lox").unwrap();
-    cmd.arg("tests/input/while.lox")
+    cmd.env("LOX_TRACE", "1")
+        .arg("tests/input/while.lox")
         .assert()
         .success()
-        .stdout("55\n");
+        .stdout("55\n")
+        .stderr(predicate::str::contains("OP_GET_GLOBAL"));
 }
 
 #[test]
-fn recursion() {
+fn dump_bytecode() {
     let mut cmd = Command::cargo_bin("lox").unwrap();
-    cmd.arg("tests/input/recursion.lox")
+    cmd.args(["--dump-bytecode", "tests/input/while.lox"])
         .assert()
         .success()
-        .stdout("8\n");
+        .stdout("55\n")
+        .stderr(predicate::str::starts_with("[0]    1 OP_CONSTANT 1\n"));
 }
 
 #[test]
-fn class() {
+fn profile_counts() {
     let mut cmd = Command::cargo_bin("lox").unwrap();
-    cmd.arg("tests/input/class.lox")
+    cmd.args(["--profile", "tests/input/profile.lox"])
+        .assert()
+        .success()
+        .stdout("100\n")
+        .stderr(predicate::str::is_match(r"\nOP_ADD +100 ").unwrap())
+        .stderr(predicate::str::is_match(r"\nstep +100\n").unwrap())
+        .stderr(predicate::str::starts_with("Instructions executed: 1513\n"));
+}
+
+#[test]
+fn scripts_share_globals() {
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend])
+            .args(["tests/input/module_lib.lox", "tests/input/module_main.lox"])
+            .assert()
+            .success()
+            .stdout("Hello, lox!\n2\n")
+            .stderr("");
+        // Lines count from the start of each file, and the scripts after a
+        // failing one don't run.
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "tests/input/module_lib.lox"])
+            .args(["tests/input/line_error.lox", "tests/input/module_main.lox"])
+            .assert()
+            .code(70)
+            .stdout("")
+            .stderr(predicate::str::contains("[Line 3] in script"))
+            .stderr(predicate::str::ends_with(
+                "Error in tests/input/line_error.lox.\n",
+            ));
+    }
+}
+
+#[test]
+fn watch_reruns_on_change() {
+    use std::io::{BufRead, BufReader};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let path = std::env::temp_dir().join(format!("lox-watch-{}.lox", std::process::id()));
+    std::fs::write(&path, "print 1;\n").unwrap();
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("lox"))
+        .arg("watch")
+        .arg(&path)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let lines = |stream: Box<dyn std::io::Read + Send>| {
+        let (send, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stream).lines() {
+                let _ = send.send(line.unwrap());
+            }
+        });
+        move || lines.recv_timeout(Duration::from_secs(10)).unwrap()
+    };
+    let next = lines(Box::new(child.stdout.take().unwrap()));
+    let next_error = lines(Box::new(child.stderr.take().unwrap()));
+    assert_eq!(next(), "1");
+    // A failing run doesn't stop the watcher.
+    std::fs::write(&path, "print nil + 1;\n").unwrap();
+    assert_eq!(next(), "--- changed, running again ---");
+    while !next_error().contains("Runtime Error") {}
+    std::fs::write(&path, "print 2;\n").unwrap();
+    assert_eq!(next(), "--- changed, running again ---");
+    assert_eq!(next(), "2");
+    child.kill().unwrap();
+    child.wait().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn coverage_report() {
+    let lcov = std::env::temp_dir().join(format!("lox-coverage-{}.info", std::process::id()));
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args([
+            "--backend",
+            backend,
+            "--coverage",
+            "tests/input/coverage.lox",
+        ])
+        .assert()
+        .success()
+        .stdout("5\n")
+        .stderr(
+            "tests/input/coverage.lox: 4 of 5 lines executed\n\
+                 tests/input/coverage.lox: never executed: 4\n",
+        );
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "--coverage-out"])
+            .arg(&lcov)
+            .arg("tests/input/coverage.lox")
+            .assert()
+            .success()
+            .stderr("");
+        assert_eq!(
+            std::fs::read_to_string(&lcov).unwrap(),
+            "SF:tests/input/coverage.lox\nDA:2,1\nDA:3,1\nDA:4,0\nDA:6,1\nDA:9,1\n\
+             LF:5\nLH:4\nend_of_record\n"
+        );
+    }
+    std::fs::remove_file(&lcov).unwrap();
+}
+
+#[test]
+fn prelude() {
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "tests/input/prelude.lox"])
+            .assert()
+            .success()
+            .stdout("3\n----\nshadowed\n")
+            .stderr("");
+    }
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--no-prelude", "tests/input/prelude.lox"])
+        .assert()
+        .code(70)
+        .stdout("")
+        .stderr(predicate::str::ends_with(
+            "Runtime Error: Undefined variable 'max'.\n",
+        ));
+}
+
+#[test]
+fn trace_file() {
+    let trace = std::env::temp_dir().join(format!("lox-trace-{}.txt", std::process::id()));
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.arg("--trace-file")
+        .arg(&trace)
+        .arg("tests/input/while.lox")
+        .assert()
+        .success()
+        .stdout("55\n")
+        .stderr("");
+    let full = std::fs::read_to_string(&trace).unwrap();
+    assert!(full.contains("[ <script> ]"));
+    assert!(full.contains("OP_ADD"));
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--trace-format", "compact", "--trace-file"])
+        .arg(&trace)
+        .arg("tests/input/while.lox")
+        .assert()
+        .success()
+        .stdout("55\n")
+        .stderr("");
+    let compact = std::fs::read_to_string(&trace).unwrap();
+    assert!(compact.starts_with("0000 OP_CONSTANT <script>\n"));
+    assert_eq!(
+        compact.lines().count(),
+        full.lines().filter(|line| line.contains("OP_")).count()
+    );
+    // The trace is flushed when a run fails too.
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--trace-format", "compact", "--trace-file"])
+        .arg(&trace)
+        .args(["-e", "print -nil;"])
+        .assert()
+        .code(70);
+    let failed = std::fs::read_to_string(&trace).unwrap();
+    assert!(failed.ends_with("OP_NEGATE Nil\n"));
+    std::fs::remove_file(&trace).unwrap();
+}
+
+#[test]
+fn time_phases() {
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--time", "tests/input/profile.lox"])
+        .assert()
+        .success()
+        .stdout("100\n")
+        .stderr(
+            predicate::str::is_match(r"^compile: \d+\.\d{3} ms\nexecute: \d+\.\d{3} ms\n").unwrap(),
+        )
+        .stderr(predicate::str::ends_with("\ninstructions: 1513\n"));
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--backend", "ast", "--time", "tests/input/profile.lox"])
+        .assert()
+        .success()
+        .stdout("100\n")
+        .stderr(
+            predicate::str::is_match(
+                r"^scan: \d+\.\d{3} ms\nparse: \d+\.\d{3} ms\nresolve: \d+\.\d{3} ms\nexecute: \d+\.\d{3} ms\nstatements: \d+\n$",
+            )
+            .unwrap(),
+        );
+}
+
+#[test]
+#[cfg_attr(feature = "serde", ignore = "the json natives change the globals")]
+fn debugger_session() {
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--no-prelude", "--debug", "tests/input/recursion.lox"])
+        .write_stdin("s\nb count 0\nc\np\ng count\nc\n")
+        .assert()
+        .success()
+        .stdout("8\n")
+        .stderr(predicate::str::starts_with(
+            "script [0]    1 OP_CLOSURE <fn count>\n(debug) script [2]    8 OP_DEFINE_GLOBAL 1\n",
+        ))
+        .stderr(predicate::str::contains(
+            "(debug) count [0]    2 OP_GET_LOCAL 1\n(debug) [ <script> ][ <fn count> ][ 5 ]\n(debug) count = <fn count>\n",
+        ));
+}
+
+#[test]
+fn debugger_quit() {
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--debug", "tests/input/recursion.lox"])
+        .write_stdin("q\n")
+        .assert()
+        .code(70)
+        .stdout("")
+        .stderr(predicate::str::ends_with(
+            "Runtime Error: Execution stopped by debugger.\n",
+        ));
+}
+
+#[test]
+#[cfg_attr(feature = "serde", ignore = "the json natives change the globals")]
+fn disassemble() {
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args([
+        "--no-prelude",
+        "--disassemble",
+        "tests/input/disassemble.lox",
+    ])
+    .assert()
+    .success()
+    .stdout(include_str!("input/disassemble.out"))
+    .stderr("");
+}
+
+#[test]
+fn ast_dot() {
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--ast-dot", "tests/input/while.lox"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("digraph ast {\n"))
+        .stdout(predicate::str::contains(
+            "[label=\"While\", shape=box, style=filled, fillcolor=lightblue];\n",
+        ))
+        .stdout(predicate::str::ends_with("}\n"))
+        .stderr("");
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--ast-dot", "tests/suite/compile_error.lox"])
+        .assert()
+        .code(65)
+        .stdout("");
+}
+
+#[test]
+fn compiled_scripts_match_source() {
+    let dir = std::env::temp_dir().join(format!("lox-loxc-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut scripts: Vec<_> = std::fs::read_dir("tests/input")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+        .collect();
+    scripts.sort();
+    for script in scripts {
+        let compiled = dir.join(script.with_extension("loxc").file_name().unwrap());
+        let status = std::process::Command::new(assert_cmd::cargo::cargo_bin("lox"))
+            .arg("compile")
+            .arg(&script)
+            .arg("-o")
+            .arg(&compiled)
+            .output()
+            .unwrap()
+            .status;
+        if !status.success() {
+            continue;
+        }
+        let run = |path: &std::path::Path| {
+            Command::cargo_bin("lox")
+                .unwrap()
+                .args(["--max-instructions", "100000"])
+                .arg(path)
+                .output()
+                .unwrap()
+        };
+        let direct = run(&script);
+        let loaded = run(&compiled);
+        assert_eq!(direct.stdout, loaded.stdout, "{}", script.display());
+        assert_eq!(
+            direct.status.code(),
+            loaded.status.code(),
+            "{}",
+            script.display()
+        );
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn exit_codes() {
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "tests/input/syntax_error.lox"])
+            .assert()
+            .code(65)
+            .stdout("");
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "tests/input/resolve_error.lox"])
+            .assert()
+            .code(65);
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "tests/input/line_error.lox"])
+            .assert()
+            .code(70);
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "tests/input/missing.lox"])
+            .assert()
+            .code(74)
+            .stdout("")
+            .stderr("Could not read 'tests/input/missing.lox': No such file or directory\n");
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "tests/fixtures/invalid_utf8.lox"])
+            .assert()
+            .code(74)
+            .stdout("")
+            .stderr("Could not read 'tests/fixtures/invalid_utf8.lox': Invalid UTF-8 at byte 12\n");
+        // A bad line doesn't end the REPL or its exit status.
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend])
+            .write_stdin("var = 1;\nprint 1 + nil;\nprint 2;\n")
+            .assert()
+            .success()
+            .stdout("2\n");
+    }
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["--backend", "js"]).assert().code(64);
+}
+
+#[test]
+fn script_from_stdin() {
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "-"])
+            .write_stdin("fun twice(n) {\n  return n * 2;\n}\nprint twice(21);\n")
+            .assert()
+            .success()
+            .stdout("42\n");
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "-"])
+            .write_stdin("print 1 +;\n")
+            .assert()
+            .code(65);
+    }
+}
+
+#[test]
+fn eval_snippets() {
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "-e", "print 6 * 7;"])
+            .assert()
+            .success()
+            .stdout("42\n");
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args([
+            "--backend",
+            backend,
+            "-e",
+            "var x = 2;",
+            "--eval",
+            "print x + 1;",
+        ])
         .assert()
         .success()
         .stdout("3\n");
+        // Snippets after a failing one don't run.
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "-e", "print 1 +;", "-e", "print 2;"])
+            .assert()
+            .code(65)
+            .stdout("");
+    }
+}
+
+#[test]
+fn check_reports_every_file() {
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "check"])
+            .args(["tests/input/syntax_error.lox", "tests/input/math1.lox"])
+            .args(["tests/input/resolve_error.lox"])
+            .assert()
+            .code(65)
+            .stdout("tests/input/math1.lox: ok\n")
+            .stderr(
+                predicate::str::contains("tests/input/syntax_error.lox: ")
+                    .and(predicate::str::contains("tests/input/resolve_error.lox: ")),
+            );
+    }
+}
+
+#[test]
+fn fmt_check_reports_unformatted_files() {
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["fmt", "--check", "tests/input/format.out"])
+        .assert()
+        .success()
+        .stdout("");
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["fmt", "--check", "tests/input/format.lox"])
+        .assert()
+        .code(1)
+        .stdout("tests/input/format.lox: not formatted\n");
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["fmt", "--check", "tests/input/syntax_error.lox"])
+        .assert()
+        .code(65)
+        .stderr(predicate::str::starts_with(
+            "tests/input/syntax_error.lox: ",
+        ));
+}
+
+#[test]
+fn fmt_stdin() {
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["fmt", "-"])
+        .write_stdin(std::fs::read("tests/input/format.lox").unwrap())
+        .assert()
+        .success()
+        .stdout(std::fs::read_to_string("tests/input/format.out").unwrap());
+}
+
+#[test]
+fn fmt_reports_vm_only_syntax() {
+    let mut cmd = Command::cargo_bin("lox").unwrap();
+    cmd.args(["fmt", "-"])
+        .write_stdin("var a = [1, 2];\nprint a[0];\n")
+        .assert()
+        .code(65)
+        .stdout("")
+        .stderr("-: Parser Error: Line 1, Unsupported syntax: only the VM backend parses this, and the formatter can't format it yet.\n");
+}
+
+#[test]
+fn no_color_output() {
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.env("NO_COLOR", "1")
+            .args(["--backend", backend, "tests/input/syntax_error.lox"])
+            .assert()
+            .code(65)
+            .stderr(predicate::str::contains("\x1b[").not());
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.env("NO_COLOR", "1")
+            .args([
+                "--backend",
+                backend,
+                "check",
+                "tests/input/resolve_error.lox",
+            ])
+            .assert()
+            .code(65)
+            .stderr(predicate::str::contains("\x1b[").not());
+    }
+}
+
+#[test]
+fn check_does_not_run() {
+    for backend in ["vm", "ast"] {
+        let mut cmd = Command::cargo_bin("lox").unwrap();
+        cmd.args(["--backend", backend, "check", "tests/input/line_error.lox"])
+            .assert()
+            .success()
+            .stdout("tests/input/line_error.lox: ok\n")
+            .stderr("");
+    }
 }
 
 #[test]
-fn superclass() {
+fn check_warns_of_undefined_globals() {
     let mut cmd = Command::cargo_bin("lox").unwrap();
-    cmd.arg("tests/input/superclass.lox")
+    cmd.args(["check", "-"])
+        .write_stdin("fun never() {\npritn(1);\n}\n")
         .assert()
         .success()
-        .stdout("22\n");
+        .stdout("-: ok\n")
+        .stderr("-: [line 2] at pritn: Warning: Global 'pritn' is never defined.\n");
 }
@@ -0,0 +1,282 @@
+#![cfg(not(target_arch = "wasm32"))]
+//! Random programs printed back to source and parsed again must give the
+//! same syntax tree. The generator only builds trees the parser can: an
+//! operand that binds looser than its operator is grouped, and an `else`
+//! never follows a branch that ends in an `if` without one.
+
+use lox::expr::Expr;
+use lox::formatter::{format_source, print_program};
+use lox::parse_source;
+use lox::stmt::Stmt;
+use lox::token::{Span, Token, TokenType};
+use lox::Value;
+use proptest::collection::vec;
+use proptest::option;
+use proptest::prelude::*;
+use proptest::sample::select;
+use std::collections::LinkedList;
+
+fn token(ttype: TokenType, text: &str) -> Token {
+    Token::new(ttype, text, None, 1, 1)
+}
+
+fn name() -> impl Strategy<Value = Token> {
+    select(vec!["a", "b", "foo", "Bar", "x1"]).prop_map(|text| token(TokenType::Identifier, text))
+}
+
+fn list<T>(items: Vec<T>) -> LinkedList<Box<T>> {
+    items.into_iter().map(Box::new).collect()
+}
+
+// How tightly an expression binds, from assignment up to primaries.
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Assign { .. } | Expr::Set { .. } => 1,
+        Expr::Logical { operator, .. } if operator.ttype == TokenType::Or => 2,
+        Expr::Logical { .. } => 3,
+        Expr::Binary { operator, .. } => operator_precedence(operator.ttype),
+        Expr::Unary { .. } => 8,
+        Expr::Call { .. } | Expr::Get { .. } => 9,
+        _ => 10,
+    }
+}
+
+fn operator_precedence(ttype: TokenType) -> u8 {
+    match ttype {
+        TokenType::BangEqual | TokenType::EqualEqual => 4,
+        TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => 5,
+        TokenType::Minus | TokenType::Plus => 6,
+        _ => 7,
+    }
+}
+
+// Groups expr if it binds looser than precedence.
+fn at_least(expr: Expr, precedence_needed: u8) -> Box<Expr> {
+    if precedence(&expr) >= precedence_needed {
+        Box::new(expr)
+    } else {
+        Box::new(Expr::Grouping {
+            expression: Box::new(expr),
+            span: Span::default(),
+        })
+    }
+}
+
+fn literal() -> impl Strategy<Value = Expr> {
+    prop_oneof![
+        (0u32..1000).prop_map(|n| Value::Number(n.into())),
+        (0u32..100).prop_map(|n| Value::Number(n as f64 + 0.5)),
+        "[a-z ]{0,6}".prop_map(|s| Value::String(s.as_str().into())),
+        any::<bool>().prop_map(Value::Bool),
+        Just(Value::None),
+    ]
+    .prop_map(|value| Expr::Literal {
+        value,
+        span: Span::default(),
+    })
+}
+
+fn expr() -> impl Strategy<Value = Expr> {
+    let leaf = prop_oneof![
+        4 => literal(),
+        4 => name().prop_map(|name| Expr::Variable { name, id: 0 }),
+        1 => Just(Expr::This {
+            keyword: token(TokenType::This, "this"),
+            id: 0,
+        }),
+        1 => name().prop_map(|method| Expr::Super {
+            keyword: token(TokenType::Super, "super"),
+            method,
+            id: 0,
+        }),
+    ];
+    leaf.prop_recursive(4, 24, 3, |inner| {
+        let binary = select(vec![
+            (TokenType::BangEqual, "!="),
+            (TokenType::EqualEqual, "=="),
+            (TokenType::Greater, ">"),
+            (TokenType::GreaterEqual, ">="),
+            (TokenType::Less, "<"),
+            (TokenType::LessEqual, "<="),
+            (TokenType::Minus, "-"),
+            (TokenType::Plus, "+"),
+            (TokenType::Slash, "/"),
+            (TokenType::Star, "*"),
+        ]);
+        prop_oneof![
+            (inner.clone(), binary, inner.clone()).prop_map(|(left, (ttype, text), right)| {
+                let level = operator_precedence(ttype);
+                Expr::Binary {
+                    left: at_least(left, level),
+                    operator: token(ttype, text),
+                    right: at_least(right, level + 1),
+                }
+            }),
+            (inner.clone(), any::<bool>(), inner.clone()).prop_map(|(left, or, right)| {
+                let operator = if or {
+                    token(TokenType::Or, "or")
+                } else {
+                    token(TokenType::And, "and")
+                };
+                let level = if or { 2 } else { 3 };
+                Expr::Logical {
+                    left: at_least(left, level),
+                    operator,
+                    right: at_least(right, level + 1),
+                }
+            }),
+            (
+                select(vec![(TokenType::Bang, "!"), (TokenType::Minus, "-")]),
+                inner.clone()
+            )
+                .prop_map(|((ttype, text), right)| Expr::Unary {
+                    operator: token(ttype, text),
+                    right: at_least(right, 8),
+                }),
+            (inner.clone(), vec(inner.clone(), 0..3)).prop_map(|(callee, arguments)| Expr::Call {
+                callee: at_least(callee, 9),
+                paren: token(TokenType::RightParen, ")"),
+                arguments: list(arguments),
+            }),
+            (inner.clone(), name()).prop_map(|(object, name)| Expr::Get {
+                object: at_least(object, 9),
+                name,
+            }),
+            (inner.clone(), name(), inner.clone()).prop_map(|(object, name, value)| Expr::Set {
+                object: at_least(object, 9),
+                name,
+                value: Box::new(value),
+            }),
+            (name(), inner.clone()).prop_map(|(name, value)| Expr::Assign {
+                name,
+                value: Box::new(value),
+                id: 0,
+            }),
+            inner.prop_map(|expression| Expr::Grouping {
+                expression: Box::new(expression),
+                span: Span::default(),
+            }),
+        ]
+    })
+}
+
+// Branches and loop bodies are statements, not declarations, so a
+// declaration there goes in a block.
+fn as_statement(stmt: Stmt) -> Box<Stmt> {
+    match stmt {
+        Stmt::Class { .. } | Stmt::Function { .. } | Stmt::Var { .. } => Box::new(Stmt::Block {
+            statements: list(vec![stmt]),
+        }),
+        stmt => Box::new(stmt),
+    }
+}
+
+// Whether an else after stmt would be parsed as part of it.
+fn ends_in_open_if(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::If {
+            else_branch: None, ..
+        } => true,
+        Stmt::If {
+            else_branch: Some(else_branch),
+            ..
+        } => ends_in_open_if(else_branch),
+        Stmt::While { body, .. } => ends_in_open_if(body),
+        _ => false,
+    }
+}
+
+fn function(body: impl Strategy<Value = Stmt>) -> impl Strategy<Value = Stmt> {
+    (name(), vec(name(), 0..3), vec(body, 0..3)).prop_map(|(name, params, body)| Stmt::Function {
+        name,
+        params: params.into_iter().collect(),
+        body: list(body),
+    })
+}
+
+fn declaration() -> impl Strategy<Value = Stmt> {
+    let leaf = prop_oneof![
+        expr().prop_map(|e| Stmt::Expression {
+            expression: Box::new(e)
+        }),
+        expr().prop_map(|e| Stmt::Print {
+            expression: Box::new(e)
+        }),
+        (name(), option::of(expr())).prop_map(|(name, initializer)| Stmt::Var {
+            name,
+            initializer: initializer.map(Box::new),
+        }),
+        option::of(expr()).prop_map(|value| Stmt::Return {
+            value: value.map(Box::new)
+        }),
+    ];
+    leaf.prop_recursive(3, 16, 3, |inner| {
+        prop_oneof![
+            vec(inner.clone(), 0..3).prop_map(|statements| Stmt::Block {
+                statements: list(statements)
+            }),
+            (expr(), inner.clone(), option::of(inner.clone())).prop_map(
+                |(condition, then_branch, else_branch)| {
+                    let mut then_branch = as_statement(then_branch);
+                    if else_branch.is_some() && ends_in_open_if(&then_branch) {
+                        then_branch = Box::new(Stmt::Block {
+                            statements: list(vec![*then_branch]),
+                        });
+                    }
+                    Stmt::If {
+                        condition: Box::new(condition),
+                        then_branch,
+                        else_branch: else_branch.map(as_statement),
+                    }
+                }
+            ),
+            (expr(), inner.clone()).prop_map(|(condition, body)| Stmt::While {
+                keyword: token(TokenType::While, "while"),
+                condition: Box::new(condition),
+                body: as_statement(body),
+            }),
+            function(inner.clone()),
+            (name(), option::of(name()), vec(function(inner), 0..3)).prop_map(
+                |(name, superclass, methods)| Stmt::Class {
+                    name,
+                    superclass: superclass.map(|name| Box::new(Expr::Variable { name, id: 0 })),
+                    methods: list(methods),
+                }
+            ),
+        ]
+    })
+}
+
+fn program() -> impl Strategy<Value = LinkedList<Box<Stmt>>> {
+    vec(declaration(), 1..4).prop_map(list)
+}
+
+proptest! {
+    #[test]
+    fn printed_programs_parse_back(stmts in program()) {
+        let source = print_program(&stmts);
+        match parse_source(&source) {
+            Ok(parsed) => prop_assert_eq!(parsed, stmts, "printed as:\n{}", source),
+            Err(errors) => prop_assert!(false, "{:?} parsing:\n{}", errors, source),
+        }
+    }
+
+    #[test]
+    fn printed_programs_are_formatted(stmts in program()) {
+        let source = print_program(&stmts);
+        let formatted = format_source(&source, &mut std::io::sink()).unwrap();
+        prop_assert_eq!(formatted, source);
+    }
+}
+
+// Right operands of left-associative operators, and an else after a branch
+// ending in an if, need the grouping and braces the source gave them.
+#[test]
+fn grouping_survives_printing() {
+    let source = "print 0 > (0 > 0);\n\
+                  print a - (b - c) * -(-1);\n\
+                  if (a) {\n    if (b) print 1;\n} else print 2;\n";
+    let stmts = parse_source(source).unwrap();
+    assert_eq!(print_program(&stmts), source);
+    assert_eq!(parse_source(&print_program(&stmts)).unwrap(), stmts);
+}
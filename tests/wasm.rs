@@ -0,0 +1,26 @@
+#![cfg(target_arch = "wasm32")]
+
+use js_sys::Reflect;
+use wasm_bindgen::JsValue;
+use wasm_bindgen_test::*;
+
+fn field(result: &JsValue, name: &str) -> JsValue {
+    Reflect::get(result, &name.into()).unwrap()
+}
+
+#[wasm_bindgen_test]
+fn run_captures_output() {
+    let result = lox::wasm::run("fun add(a, b) { return a + b; }\nprint add(1, 2);\n");
+    assert_eq!(field(&result, "ok"), JsValue::TRUE);
+    assert_eq!(field(&result, "output").as_string().unwrap(), "3\n");
+    assert_eq!(field(&result, "diagnostics").as_string().unwrap(), "");
+}
+
+#[wasm_bindgen_test]
+fn run_reports_errors() {
+    let result = lox::wasm::run("print 1;\nprint nil + 1;\n");
+    assert_eq!(field(&result, "ok"), JsValue::FALSE);
+    assert_eq!(field(&result, "output").as_string().unwrap(), "1\n");
+    let diagnostics = field(&result, "diagnostics").as_string().unwrap();
+    assert!(diagnostics.contains("Runtime Error"));
+}